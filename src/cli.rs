@@ -0,0 +1,254 @@
+//! 命令行参数。
+//!
+//! 不带参数时和以前一样交互运行；`-c`和`--script`非交互地执行命令，遇到第一个错误就以非0退出码结束，
+//! 全部成功时保存镜像，便于在CI中生成镜像。
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::disk_manager::error::FsError;
+use crate::shell;
+
+/// 命令行用法
+pub const USAGE: &str = "\
+Usage: file-system [options]\
+\n\
+\nOptions:\
+\n\t--image <path>: Image file to load and save, './file-sys.vd' by default.\
+\n\t--new : Format a new disk without asking.\
+\n\t--load : Load the image without asking, exit if it cannot be loaded.\
+\n\t--noatime : Do not update access times when reading files.\
+\n\t--no-save : With -c or --script, do not save the image at the end.\
+\n\t-c \"<cmd>; <cmd>\": Run the commands separated by unquoted ';' and exit.\
+\n\t--script <file>: Run the commands in a file, one per line, and exit.\
+\n\t-h, --help : Show this help.\
+\n\
+\nWith -c or --script, the image is loaded if it exists and formatted otherwise,\
+\nlines starting with '#' are skipped, and the first failing command stops the run\
+\nwith exit code 1. When all commands succeed, the image is saved (keeping the\
+\nprevious one as a backup) unless --no-save is given; a failed run never saves.";
+
+/// 启动时怎样得到磁盘
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    /// 交互地询问
+    Ask,
+    /// 格式化新磁盘
+    New,
+    /// 加载镜像文件
+    Load,
+}
+
+/// 非交互地执行的命令
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Batch {
+    /// `-c`给出的命令，用`;`分隔
+    Commands(String),
+    /// `--script`给出的命令文件，每行一条
+    Script(PathBuf),
+}
+
+/// 解析后的命令行参数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    /// 加载和保存的镜像文件
+    pub image: PathBuf,
+    pub start: Start,
+    /// 为`None`时交互运行
+    pub batch: Option<Batch>,
+    /// 读取文件时不更新访问时间
+    pub noatime: bool,
+    /// 非交互运行成功之后不保存镜像
+    pub no_save: bool,
+    pub help: bool,
+}
+impl Args {
+    /// 解析命令行参数，不包括程序名。非交互运行且没有指定`--new`或`--load`时，
+    /// 镜像文件存在就加载，否则格式化新磁盘。交互运行时用`save`命令保存，`--no-save`没有意义。
+    pub fn parse<I: IntoIterator<Item = String>>(
+        args: I,
+        default_image: &str,
+    ) -> Result<Args, FsError> {
+        let mut parsed = Args {
+            image: PathBuf::from(default_image),
+            start: Start::Ask,
+            batch: None,
+            noatime: false,
+            no_save: false,
+            help: false,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |option: &str| {
+                args.next().ok_or_else(|| {
+                    FsError::InvalidArgument(format!("option '{}' needs a value", option))
+                })
+            };
+            match arg.as_str() {
+                "--image" => parsed.image = PathBuf::from(value(&arg)?),
+                "--new" | "--load" => {
+                    if parsed.start != Start::Ask {
+                        return Err(FsError::InvalidArgument(String::from(
+                            "only one of '--new' and '--load' can be given",
+                        )));
+                    }
+                    parsed.start = if arg == "--new" {
+                        Start::New
+                    } else {
+                        Start::Load
+                    };
+                }
+                "-c" | "--script" => {
+                    if parsed.batch.is_some() {
+                        return Err(FsError::InvalidArgument(String::from(
+                            "only one of '-c' and '--script' can be given",
+                        )));
+                    }
+                    let value = value(&arg)?;
+                    parsed.batch = Some(if arg == "-c" {
+                        Batch::Commands(value)
+                    } else {
+                        Batch::Script(PathBuf::from(value))
+                    });
+                }
+                "--noatime" => parsed.noatime = true,
+                "--no-save" => parsed.no_save = true,
+                "-h" | "--help" => parsed.help = true,
+                _ => {
+                    return Err(FsError::InvalidArgument(format!(
+                        "unknown option '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+        if parsed.no_save && parsed.batch.is_none() {
+            return Err(FsError::InvalidArgument(String::from(
+                "'--no-save' needs '-c' or '--script'",
+            )));
+        }
+        if parsed.batch.is_some() && parsed.start == Start::Ask {
+            parsed.start = if parsed.image.exists() {
+                Start::Load
+            } else {
+                Start::New
+            };
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl Batch {
+    /// 要执行的命令，每条带着它的位置，用于报错
+    pub fn commands(&self) -> Result<Vec<(String, String)>, FsError> {
+        let commands = match self {
+            Batch::Commands(commands) => shell::parse::split_commands(commands)?
+                .iter()
+                .enumerate()
+                .map(|(i, command)| (format!("command {}", i + 1), command.trim().to_string()))
+                .collect::<Vec<_>>(),
+            Batch::Script(path) => fs::read_to_string(path)?
+                .lines()
+                .enumerate()
+                .map(|(i, line)| {
+                    (
+                        format!("{}:{}", path.display(), i + 1),
+                        line.trim().to_string(),
+                    )
+                })
+                .collect(),
+        };
+
+        Ok(commands
+            .into_iter()
+            .filter(|(_location, command)| !command.is_empty() && !command.starts_with('#'))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::testing::temp_path;
+
+    fn parse(args: &[&str]) -> Result<Args, FsError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()), "./file-sys.vd")
+    }
+
+    fn invalid(args: &[&str]) -> bool {
+        matches!(parse(args), Err(FsError::InvalidArgument(_)))
+    }
+
+    #[test]
+    fn no_arguments_ask_interactively() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.image, PathBuf::from("./file-sys.vd"));
+        assert_eq!(args.start, Start::Ask);
+        assert_eq!(args.batch, None);
+        assert!(!args.noatime && !args.no_save && !args.help);
+
+        let args = parse(&["--image", "a.vd", "--load", "--noatime", "-h"]).unwrap();
+        assert_eq!(args.image, PathBuf::from("a.vd"));
+        assert_eq!(args.start, Start::Load);
+        assert!(args.noatime && args.help);
+    }
+
+    #[test]
+    fn bad_arguments_are_rejected() {
+        assert!(invalid(&["--bogus"]));
+        assert!(invalid(&["--image"]));
+        assert!(invalid(&["-c"]));
+        assert!(invalid(&["--new", "--load"]));
+        assert!(invalid(&["--new", "--new"]));
+        assert!(invalid(&["-c", "ls", "--script", "x"]));
+        assert!(invalid(&["--no-save"]));
+    }
+
+    #[test]
+    fn batch_loads_existing_image_or_formats() {
+        let image = temp_path("cli-image.vd");
+        let image_arg = image.to_str().unwrap();
+        let args = parse(&["-c", "ls", "--image", image_arg]).unwrap();
+        assert_eq!(args.start, Start::New);
+        assert_eq!(args.batch, Some(Batch::Commands(String::from("ls"))));
+
+        fs::write(&image, b"").unwrap();
+        let args = parse(&["--image", image_arg, "--script", "s.txt", "--no-save"]).unwrap();
+        assert_eq!(args.start, Start::Load);
+        assert_eq!(args.batch, Some(Batch::Script(PathBuf::from("s.txt"))));
+        assert!(args.no_save);
+        // 明确指定的方式优先
+        let args = parse(&["--new", "--image", image_arg, "-c", "ls"]).unwrap();
+        assert_eq!(args.start, Start::New);
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn batch_commands_skip_blanks_and_comments() {
+        let batch = Batch::Commands(String::from("mkdir d; ; # note; cd d;"));
+        let commands = batch.commands().unwrap();
+        assert_eq!(
+            commands,
+            [
+                (String::from("command 1"), String::from("mkdir d")),
+                (String::from("command 4"), String::from("cd d")),
+            ]
+        );
+
+        let script = temp_path("cli-script.txt");
+        fs::write(&script, "# setup\nmkdir d\n\n  cd d  \n").unwrap();
+        let commands = Batch::Script(script.clone()).commands().unwrap();
+        let locations: Vec<&str> = commands.iter().map(|(at, _)| at.as_str()).collect();
+        assert_eq!(
+            locations,
+            [
+                format!("{}:2", script.display()),
+                format!("{}:4", script.display())
+            ]
+        );
+        assert_eq!(commands[1].1, "cd d");
+        fs::remove_file(&script).unwrap();
+        assert!(Batch::Script(script).commands().is_err());
+    }
+}
@@ -1,11 +1,46 @@
+pub mod acl;
+pub mod alloc;
+pub mod clock;
+pub mod defrag;
+pub mod device;
 pub mod disk;
-use disk::{Disk, FatItem, BLOCK_COUNT, BLOCK_SIZE};
+pub mod error;
+pub mod fsck;
+pub mod handle;
+pub mod host;
+pub mod image;
+pub mod journal;
+pub mod link;
+pub mod map;
+pub mod meta;
+pub mod path;
+pub mod quota;
+pub mod save;
+pub mod stat;
+pub mod symlink;
+#[cfg(test)]
+pub mod testing;
+pub mod tree;
+pub mod upgrade;
+pub mod users;
+use acl::Acl;
+use alloc::AllocatorKind;
+use clock::{Clock, SystemClock, Times};
+use device::BlockDevice;
+use disk::{Disk, DiskGeometry, FatItem};
+use error::FsError;
+use image::ROOT_CLUSTER;
+use journal::{Recovery, Snapshot};
+use meta::VolumeMeta;
+use quota::Charge;
+use users::{Perm, EXECUTE, READ, ROOT_UID, WRITE};
 
 use ansi_rgb::Foreground;
-use core::panic;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
 use std::str;
-use std::{fmt, string::String, usize, vec::Vec};
+use std::{fmt, string::String, vec::Vec};
 
 pub fn pinfo() {
     print!("{}", "[INFO]\t".fg(ansi_rgb::cyan_blue()));
@@ -13,93 +48,162 @@ pub fn pinfo() {
 pub fn pdebug() {
     print!("{}", "[DEBUG]\t".fg(ansi_rgb::magenta()));
 }
+pub fn perror() {
+    print!("{}", "[ERROR]\t".fg(ansi_rgb::red()));
+}
 
-#[derive(Serialize, Deserialize)]
 pub struct DiskManager {
     pub disk: Disk,
     pub cur_dir: Directory,
+    /// 卷元数据，在`flush`时写回
+    pub meta: VolumeMeta,
+    /// 进行中的事务开始时的状态，见`transaction`
+    snapshot: Option<Snapshot>,
+    /// 时间戳的来源
+    clock: Box<dyn Clock>,
+    /// 读取文件时不更新访问时间，省去每次读取都要写回目录
+    pub noatime: bool,
+    /// 当前登录的用户，所有操作都按这个用户检查权限，见`users`
+    uid: u32,
+    /// 正在分配的簇记在谁的账上，分配前按它检查配额，见`quota`
+    charge: Option<Charge>,
 }
 impl DiskManager {
-    /// 初始化新磁盘，返回DiskManager对象。若输入None，则自动创建默认配置。
-    pub fn new(root_dir: Option<Directory>) -> DiskManager {
+    /// 按给出的几何参数和分配策略在内存中初始化新磁盘，返回DiskManager对象。若根目录输入None，则自动创建默认配置。
+    /// 时间戳取自`clock`，通常是`clock::SystemClock`。
+    pub fn new(
+        root_dir: Option<Directory>,
+        geometry: DiskGeometry,
+        allocator: AllocatorKind,
+        clock: Box<dyn Clock>,
+    ) -> DiskManager {
         pinfo();
         println!("Creating new disk...");
-        // 生成虚拟磁盘
-        let mut disk = Disk::new();
+        // 生成虚拟磁盘，内存块设备的写入不会失败
+        let mut disk = Disk::new(geometry);
+        disk.set_allocator(allocator);
+        DiskManager::format(disk, root_dir, clock).unwrap()
+    }
+
+    /// 按给出的几何参数和分配策略在块设备上初始化新磁盘。
+    pub fn with_device(
+        device: Box<dyn BlockDevice>,
+        geometry: DiskGeometry,
+        allocator: AllocatorKind,
+        clock: Box<dyn Clock>,
+    ) -> Result<DiskManager, FsError> {
+        pinfo();
+        println!("Formatting block device...");
+        let mut disk = Disk::with_device(device, geometry)?;
+        disk.set_allocator(allocator);
+
+        DiskManager::format(disk, None, clock)
+    }
+
+    /// 在空白磁盘上放置根目录
+    fn format(
+        mut disk: Disk,
+        root_dir: Option<Directory>,
+        clock: Box<dyn Clock>,
+    ) -> Result<DiskManager, FsError> {
+        let root_dir = match root_dir {
+            // 默认根目录配置
+            None => Directory {
+                name: String::from("root"),
+                files: vec![
+                    Fcb {
+                        name: String::from(".."),
+                        file_type: FileType::Directory,
+                        first_cluster: 0,
+                        length: 0,
+                        times: Times::at(clock.now()),
+                        perm: Perm::legacy(&FileType::Directory),
+                        acl: Acl::default(),
+                    },
+                    Fcb {
+                        name: String::from("."),
+                        file_type: FileType::Directory,
+                        first_cluster: 0,
+                        length: 0,
+                        times: Times::at(clock.now()),
+                        perm: Perm::legacy(&FileType::Directory),
+                        acl: Acl::default(),
+                    },
+                ],
+            },
+            Some(dir) => dir,
+        };
         {
             // 放置第一个根目录
             let dir_data = bincode::serialize(&root_dir).unwrap();
-            disk.insert_data_by_offset(dir_data.as_slice(), 0);
+            disk.write_metadata_by_clusters(dir_data.as_slice(), &[ROOT_CLUSTER]);
         }
-        disk.fat[0] = FatItem::EoF;
+        disk.set_fat(ROOT_CLUSTER, FatItem::EoF);
 
-        DiskManager {
+        let mut virtual_disk = DiskManager {
             disk,
-            cur_dir: match root_dir {
-                // 默认根目录配置
-                None => Directory {
-                    name: String::from("root"),
-                    files: vec![
-                        Fcb {
-                            name: String::from(".."),
-                            file_type: FileType::Directory,
-                            first_cluster: 0,
-                            length: 0,
-                        },
-                        Fcb {
-                            name: String::from("."),
-                            file_type: FileType::Directory,
-                            first_cluster: 0,
-                            length: 0,
-                        },
-                    ],
-                },
-                Some(dir) => dir,
-            },
-        }
+            cur_dir: root_dir,
+            meta: VolumeMeta::default(),
+            snapshot: None,
+            clock,
+            noatime: false,
+            uid: ROOT_UID,
+            charge: None,
+        };
+        virtual_disk.flush()?;
+
+        Ok(virtual_disk)
+    }
+
+    /// 换用另一个时钟，例如加载镜像之后在测试中使用`clock::ManualClock`
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// 当前的时间戳
+    pub fn now(&self) -> u64 {
+        self.clock.now()
     }
 
     /// 返回一个状态是NotUsed的簇块号
     pub fn find_next_empty_fat(&self) -> Option<usize> {
-        let mut res = None;
-        for i in 0..(self.disk.fat.len() - 1) {
-            if let FatItem::NotUsed = self.disk.fat[i] {
-                res = Some(i);
-                break;
-            }
-        }
-
-        res
+        self.disk.free_space().first()
     }
 
-    /// 输入需要分配的簇数量，在FAT表上标记为已用（分配新空间），返回被分配的簇号数组。
+    /// 输入需要分配的簇数量，按磁盘的分配策略挑选空闲簇，在FAT表上连成一条簇链，返回被分配的簇号数组。
+    /// 空间不足或超出配额时不分配任何簇。
     pub fn allocate_free_space_on_fat(
         &mut self,
         clusters_needed: usize,
-    ) -> Result<Vec<usize>, &'static str> {
+    ) -> Result<Vec<usize>, FsError> {
+        self.allocate_chain(None, clusters_needed)
+    }
+
+    /// 同`allocate_free_space_on_fat`。`after`是新簇链将要接上的链尾时，
+    /// 优先使用紧挨着它的空闲簇，让簇链尽量保持连续。
+    fn allocate_chain(
+        &mut self,
+        after: Option<usize>,
+        clusters_needed: usize,
+    ) -> Result<Vec<usize>, FsError> {
         pinfo();
         println!("Allocating new space...");
+        self.check_quota(clusters_needed)?;
 
-        let mut clusters: Vec<usize> = Vec::with_capacity(clusters_needed);
-        for i in 0..clusters_needed {
-            // 找到新未用的簇
-            clusters.push(match self.find_next_empty_fat() {
-                Some(cluster) => cluster,
-                _ => return Err("[ERROR]\tCannot find a NotUsed FatItem!"),
-            });
-            // this_cluster：每次循环进行操作的cluster
-            let this_cluster = clusters[i];
-
-            // 对磁盘写入数据
-            pdebug();
-            println!("Found new empty cluster: {}", this_cluster);
+        let clusters = match after {
+            Some(tail) => self.disk.pick_free_clusters_after(tail, clusters_needed),
+            None => self.disk.pick_free_clusters(clusters_needed),
+        }
+        .ok_or(FsError::NoSpace)?;
+        pdebug();
+        println!("Found new empty clusters: {:?}", clusters);
+        for (i, this_cluster) in clusters.iter().enumerate() {
+            // 将上一块改写成指向当前块的FatItem，默认当前块是最后的
             if i != 0 {
-                // 中间的和最后一次的写入
-                // 将上一块改写成指向当前块的FatItem
-                self.disk.fat[clusters[i - 1]] = FatItem::ClusterNo(this_cluster);
+                self.disk
+                    .set_fat(clusters[i - 1], FatItem::ClusterNo(*this_cluster));
             }
-            // 默认当前块是最后的
-            self.disk.fat[this_cluster] = FatItem::EoF;
+            self.disk.set_fat(*this_cluster, FatItem::EoF);
         }
 
         Ok(clusters)
@@ -109,9 +213,8 @@ impl DiskManager {
     ///
     /// # 错误
     ///
-    /// 当检测到簇指向一个未使用的簇的时候，返回那个被指向的未使用的簇的索引。
-
-    fn get_file_clusters(&self, first_cluster: usize) -> Result<Vec<usize>, String> {
+    /// 当簇链指向未使用的簇、坏簇、超出FAT表范围，或者簇链成环的时候，返回`FsError::Corrupt`。
+    fn get_file_clusters(&self, first_cluster: usize) -> Result<Vec<usize>, FsError> {
         pinfo();
         println!("Searching file clusters...");
         let mut clusters: Vec<usize> = Vec::new();
@@ -122,311 +225,504 @@ impl DiskManager {
 
         // 然后循环读出之后所有簇
         loop {
-            match self.disk.fat[this_cluster] {
-                FatItem::ClusterNo(cluster) => {
+            // 簇链比FAT表还长，说明成环了
+            if clusters.len() > self.disk.fat().len() {
+                break Err(FsError::Corrupt {
+                    cluster: first_cluster,
+                    reason: String::from("cluster chain loops"),
+                });
+            }
+            match self.disk.fat().get(this_cluster) {
+                Some(FatItem::ClusterNo(cluster)) => {
                     pdebug();
                     println!("Found next cluster: {}.", cluster);
-                    clusters.push(cluster);
-                    this_cluster = cluster;
+                    clusters.push(*cluster);
+                    this_cluster = *cluster;
                 }
-                FatItem::EoF => {
+                Some(FatItem::EoF) => {
                     pdebug();
                     println!("Found EoF cluster: {}.", this_cluster);
                     break Ok(clusters);
                 }
-                FatItem::BadCluster => {
-                    // 跳过坏簇
-                    this_cluster += 1;
-                    continue;
+                Some(FatItem::BadCluster) => {
+                    break Err(FsError::Corrupt {
+                        cluster: this_cluster,
+                        reason: String::from("bad cluster in chain"),
+                    })
+                }
+                Some(FatItem::NotUsed) => {
+                    break Err(FsError::Corrupt {
+                        cluster: this_cluster,
+                        reason: String::from("cluster chain runs into an unused cluster"),
+                    })
                 }
-                _ => {
-                    break Err(format!(
-                        "[ERROR]\tBad cluster detected at {}!",
-                        this_cluster
-                    ))
+                None => {
+                    break Err(FsError::Corrupt {
+                        cluster: this_cluster,
+                        reason: String::from("cluster out of range"),
+                    })
                 }
             }
         }
     }
 
     /// 删除已经被分配的簇（置空），返回已经被删除的簇号数组。
-    fn delete_space_on_fat(&mut self, first_cluster: usize) -> Result<Vec<usize>, String> {
+    fn delete_space_on_fat(&mut self, first_cluster: usize) -> Result<Vec<usize>, FsError> {
         pinfo();
         println!("Deleting Fat space...");
-        let clusters_result = self.get_file_clusters(first_cluster);
-        let clusters = clusters_result.clone().unwrap();
-        for cluster in clusters {
-            self.disk.fat[cluster] = FatItem::NotUsed;
+        let clusters = self.get_file_clusters(first_cluster)?;
+        for cluster in &clusters {
+            self.disk.set_fat(*cluster, FatItem::NotUsed);
         }
 
-        clusters_result
+        Ok(clusters)
     }
 
-    /// 重新分配已经被分配的簇，按需要的簇数量分配，原簇将被置空。
-    fn reallocate_free_space_on_fat(
+    /// 按需要的簇数量重新分配已经被分配的簇链，返回调整后的簇号数组。
+    /// 首簇保持不变，簇不够时在链尾追加，多余时释放链尾。
+    fn resize_space_on_fat(
         &mut self,
         first_cluster: usize,
         clusters_needed: usize,
-    ) -> Vec<usize> {
+    ) -> Result<Vec<usize>, FsError> {
         pinfo();
-        println!("Realocating Fat space...");
-        // 删除原先的簇
-        self.delete_space_on_fat(first_cluster).unwrap();
-        // 分配新的簇 - 多线程下第一簇可能不同，多线程不安全
-        self.allocate_free_space_on_fat(clusters_needed).unwrap()
-    }
-
-    /// 计算写入文件需要的簇数量——针对EoF
-    /// 返回（`bool`: 是否需要插入EoF，`usize`: 需要的总簇数）
-    fn calc_clusters_needed_with_eof(length: usize) -> (bool, usize) {
-        // 判断需要写入的总簇数
-        let mut clusters_needed: f32 = length as f32 / BLOCK_COUNT as f32;
-        // 判断cluster是否是整数。如果是，就不写入结束标志。
-        let insert_eof = if (clusters_needed - clusters_needed as usize as f32) < 0.0000000001 {
-            false
-        } else {
-            clusters_needed = clusters_needed.ceil();
-            true
-        };
-        let clusters_needed: usize = clusters_needed as usize;
+        println!("Resizing Fat space...");
+        let mut clusters = self.get_file_clusters(first_cluster)?;
+        if clusters_needed < clusters.len() {
+            // 释放多余的链尾，至少保留首簇
+            for cluster in clusters.split_off(clusters_needed.max(1)) {
+                self.disk.set_fat(cluster, FatItem::NotUsed);
+            }
+            self.disk.set_fat(*clusters.last().unwrap(), FatItem::EoF);
+        } else if clusters_needed > clusters.len() {
+            // 在链尾追加新的簇
+            let tail = *clusters.last().unwrap();
+            let mut new_clusters =
+                self.allocate_chain(Some(tail), clusters_needed - clusters.len())?;
+            self.disk.set_fat(tail, FatItem::ClusterNo(new_clusters[0]));
+            clusters.append(&mut new_clusters);
+        }
 
-        (insert_eof, clusters_needed)
+        Ok(clusters)
+    }
+
+    /// 计算写入`length`字节需要的簇数量。
+    /// 空文件也占用一个簇，保证每个FCB都有合法的首簇。
+    fn calc_clusters_needed(&self, length: usize) -> usize {
+        if length == 0 {
+            1
+        } else {
+            length.div_ceil(self.disk.cluster_size())
+        }
     }
 
     /// 提供想要写入的数据，返回数据的开始簇块号，可在FAT中查找
-    pub fn write_data_to_disk(&mut self, data: &[u8]) -> usize {
+    pub fn write_data_to_disk(&mut self, data: &[u8]) -> Result<usize, FsError> {
         pinfo();
         println!("Writing data to disk...");
 
-        let (insert_eof, clusters_needed) = DiskManager::calc_clusters_needed_with_eof(data.len());
+        let clusters_needed = self.calc_clusters_needed(data.len());
 
-        let clusters = self.allocate_free_space_on_fat(clusters_needed).unwrap();
+        let clusters = self.allocate_free_space_on_fat(clusters_needed)?;
 
-        self.disk
-            .write_data_by_clusters_with_eof(data, clusters.as_slice(), insert_eof);
+        if let Err(err) = self.disk.write_data_by_clusters(data, clusters.as_slice()) {
+            // 写入失败，归还分配的簇
+            for cluster in clusters {
+                self.disk.set_fat(cluster, FatItem::NotUsed);
+            }
+            return Err(err.into());
+        }
 
         pdebug();
         println!("Writing finished. Returned clusters: {:?}", clusters);
 
-        clusters[0]
+        Ok(clusters[0])
     }
 
-    /// 提供目录名，在当前目录中新建目录，同时写入磁盘。
-    pub fn new_directory_to_disk(&mut self, name: &str) -> Result<(), &'static str> {
+    /// 按路径新建目录，同时写入磁盘。
+    pub fn new_directory_to_disk(&mut self, path: &str) -> Result<(), FsError> {
         // 新文件夹写入磁盘块
         pinfo();
-        println!("Creating dir: {}.", name);
-        pdebug();
-        println!("Trying to write to disk...");
+        println!("Creating dir: {}.", path);
 
-        if let Some(_fcb) = self.cur_dir.get_fcb_by_name(name) {
-            return Err("[ERROR]\tThere's already a directory with a same name!");
+        let (mut parent, name) = self.resolve_parent(path)?;
+        check_file_name(name)?;
+        self.check_dir_access(&parent, WRITE | EXECUTE, path)?;
+        if let Some(_fcb) = parent.get_fcb_by_name(name) {
+            return Err(FsError::AlreadyExists(String::from(path)));
         }
+        let charge = Charge {
+            owner: self.uid,
+            dir: parent.files[1].first_cluster,
+        };
+        self.transaction(|dm| {
+            dm.charged(charge, |dm| dm.create_directory_in(&mut parent, name))?;
+            dm.store_directory(&parent)?;
+            dm.touch_directory(charge.dir)
+        })?;
+        pdebug();
+        println!("Created dir {}.", path);
 
+        Ok(())
+    }
+
+    /// 新建空目录写入磁盘，并把它的FCB加入`parent`，返回新目录的FCB。`parent`由调用者写回。
+    fn create_directory_in(&mut self, parent: &mut Directory, name: &str) -> Result<Fcb, FsError> {
+        pdebug();
+        println!("Trying to write to disk...");
+        let times = Times::at(self.now());
+        let perm = self.new_perm(&FileType::Directory);
+        let acl = parent.files[1].acl.inherited(&FileType::Directory);
         let mut new_directory = Directory::new(name);
         // 加入“..”
         new_directory.files.push(Fcb {
             name: String::from(".."),
             file_type: FileType::Directory,
-            first_cluster: self.cur_dir.files[1].first_cluster,
+            first_cluster: parent.files[1].first_cluster,
             length: 0,
+            times,
+            perm: parent.files[1].perm,
+            acl: parent.files[1].acl.clone(),
         });
-        // 加入“.”
-        // TODO: 多线程不安全
+        // 加入“.”，先分配好簇，才知道“.”指向哪里
         new_directory.files.push(Fcb {
             name: String::from("."),
             file_type: FileType::Directory,
-            first_cluster: self.find_next_empty_fat().unwrap(),
+            first_cluster: 0,
             length: 0,
+            times,
+            perm,
+            acl: acl.clone(),
         });
+        let clusters_needed =
+            self.calc_clusters_needed(bincode::serialized_size(&new_directory).unwrap() as usize);
+        let clusters = self.allocate_free_space_on_fat(clusters_needed)?;
+        let first_block = clusters[0];
+        new_directory.files[1].first_cluster = first_block;
 
         let bin_dir = bincode::serialize(&new_directory).unwrap();
 
         pdebug();
         println!("Dir bytes: {:?}", bin_dir);
-        let first_block = self.write_data_to_disk(&bin_dir);
+        self.disk.write_metadata_by_clusters(&bin_dir, &clusters);
 
         pdebug();
-        println!("Trying to add dir to current dir...");
-        // 在文件夹中添加新文件夹
-        self.cur_dir.files.push(Fcb {
+        println!("Trying to add dir to parent dir...");
+        // 在父文件夹中添加新文件夹
+        let fcb = Fcb {
             name: String::from(name),
             file_type: FileType::Directory,
             first_cluster: first_block,
             length: 0,
-        });
-        pdebug();
-        println!("Created dir {}.", name);
+            times,
+            perm,
+            acl,
+        };
+        parent.files.push(fcb.clone());
 
-        Ok(())
+        Ok(fcb)
     }
 
-    /// 提供簇号，读出所有数据。
-    fn get_data_by_first_cluster(&self, first_cluster: usize) -> Vec<u8> {
+    /// 提供簇号，读出数据。`length`为`None`时读出整条簇链。
+    fn get_data_by_first_cluster(
+        &self,
+        first_cluster: usize,
+        length: Option<usize>,
+    ) -> Result<Vec<u8>, FsError> {
         pdebug();
         println!("Getting data from disk by clusters...");
 
-        let clusters = self.get_file_clusters(first_cluster).unwrap();
+        let clusters = self.get_file_clusters(first_cluster)?;
+        let length = length.unwrap_or(clusters.len() * self.disk.cluster_size());
         let data = self
             .disk
-            .read_data_by_clusters_without_eof(clusters.as_slice());
+            .read_data_by_clusters(clusters.as_slice(), length)?;
 
         pdebug();
-        println!("Data read: {:?}", &data);
+        println!("Data read: {} Bytes.", data.len());
 
-        data
+        Ok(data)
     }
 
     /// 通过FCB块找到目录项
-    fn get_directory_by_fcb(&self, dir_fcb: &Fcb) -> Directory {
+    fn get_directory_by_fcb(&self, dir_fcb: &Fcb) -> Result<Directory, FsError> {
         pinfo();
         println!("Getting dir by FCB...\n\tFCB: {:?}", dir_fcb);
         match dir_fcb.file_type {
             FileType::Directory => {
-                let data_dir = self.get_data_by_first_cluster(dir_fcb.first_cluster);
+                // 目录文件由bincode自描述长度，读出整条簇链即可，末尾的填充会被忽略
+                let data_dir = self.get_data_by_first_cluster(dir_fcb.first_cluster, None)?;
                 pdebug();
                 println!("Trying to deserialize data read from disk...");
-                let dir: Directory = bincode::deserialize(data_dir.as_slice()).unwrap();
+                let dir: Directory =
+                    bincode::deserialize(data_dir.as_slice()).map_err(|err| FsError::Corrupt {
+                        cluster: dir_fcb.first_cluster,
+                        reason: format!("unreadable directory: {}", err),
+                    })?;
+                // 每个目录至少要有“..”和“.”
+                if dir.files.len() < 2 {
+                    return Err(FsError::Corrupt {
+                        cluster: dir_fcb.first_cluster,
+                        reason: String::from("directory has no '.' or '..' entry"),
+                    });
+                }
                 pdebug();
                 println!("Getting dir finished.");
-                dir
+                Ok(dir)
             }
-            _ => panic!("[ERROR]\tGet Directory recieved a non-Directory FCB!"),
+            _ => Err(FsError::NotADirectory(dir_fcb.name.clone())),
         }
     }
 
     /// 通过FCB块找到文件
-    fn get_file_by_fcb(&self, fcb: &Fcb) -> Vec<u8> {
+    fn get_file_by_fcb(&self, fcb: &Fcb) -> Result<Vec<u8>, FsError> {
         pinfo();
         println!("Getting file data by FCB...\n\tFCB: {:?}", fcb);
         match fcb.file_type {
-            FileType::File => self.get_data_by_first_cluster(fcb.first_cluster),
-            _ => panic!("[ERROR]\tGet File recieved a non-File FCB!"),
+            FileType::Directory => Err(FsError::IsADirectory(fcb.name.clone())),
+            _ => self.get_data_by_first_cluster(fcb.first_cluster, Some(fcb.length)),
         }
     }
 
-    /// 通过FCB块删除文件
-    fn delete_file_by_fcb(&mut self, fcb: &Fcb) -> Result<(), String> {
-        self.delete_file_by_fcb_with_index(
-            fcb,
-            Some(self.cur_dir.get_index_by_name(fcb.name.as_str()).unwrap()),
-        )
-    }
-
-    /// 通过FCB块删除文件，参数中含有FCB块在dir中的序号。
-    fn delete_file_by_fcb_with_index(
-        &mut self,
-        fcb: &Fcb,
-        index: Option<usize>,
-    ) -> Result<(), String> {
-        if let FileType::Directory = fcb.file_type {
-            let dir = self.get_directory_by_fcb(fcb);
-            if dir.files.len() > 2 {
-                return Err(String::from("[ERROR]\tThe Directory is not empty!"));
-            }
-        }
+    /// 通过FCB块删除文件，释放它占用的簇。目录必须为空；与其他文件共享或者还有其他链接的簇链只减少计数。
+    fn delete_file_by_fcb(&mut self, fcb: &Fcb) -> Result<(), FsError> {
         pdebug();
         println!(
             "Trying to set all NotUsed clutster of file '{}' on FAT...",
             fcb.name
         );
-        // 直接返回删除文件的结果
-        if let Err(err) = self.delete_space_on_fat(fcb.first_cluster) {
-            return Err(err);
-        }
-        // 若给定index非None，则删除目录下的FCB条目
-        if let Some(i) = index {
-            self.cur_dir.files.remove(i);
+        if let FileType::Directory = fcb.file_type {
+            let dir = self.get_directory_by_fcb(fcb)?;
+            if dir.files.len() > 2 {
+                return Err(FsError::DirectoryNotEmpty(fcb.name.clone()));
+            }
+            self.delete_space_on_fat(fcb.first_cluster)?;
+            self.meta.quotas.dirs.remove(&fcb.first_cluster);
+        } else {
+            self.release_chain(fcb.first_cluster)?;
         }
 
         Ok(())
     }
 
-    /// 在当前文件夹创建新文件并写入
-    pub fn create_file_with_data(&mut self, name: &str, data: &[u8]) {
+    /// 按路径创建新文件并写入
+    pub fn create_file_with_data(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
         pinfo();
-        println!("Creating new file in current dir...");
-        // 写入数据
-        let first_cluster = self.write_data_to_disk(data);
-        // 创建新FCB并插入当前目录中
-        let fcb = Fcb {
-            name: String::from(name),
-            file_type: FileType::File,
-            first_cluster,
-            length: data.len(),
-        };
-        self.cur_dir.files.push(fcb);
+        println!("Creating new file '{}'...", path);
+        self.create_entry(path, FileType::File, data)
     }
 
-    /// 通过文件名读取文件
-    pub fn read_file_by_name(&self, name: &str) -> Vec<u8> {
-        let (_index, fcb) = self.cur_dir.get_fcb_by_name(name).unwrap();
-        self.get_file_by_fcb(fcb)
+    /// 按路径创建新的文件或符号链接，内容为`data`
+    fn create_entry(
+        &mut self,
+        path: &str,
+        file_type: FileType,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        let (mut parent, name) = self.resolve_parent(path)?;
+        check_file_name(name)?;
+        self.check_dir_access(&parent, WRITE | EXECUTE, path)?;
+        if parent.get_fcb_by_name(name).is_some() {
+            return Err(FsError::AlreadyExists(String::from(path)));
+        }
+        let charge = Charge {
+            owner: self.uid,
+            dir: parent.files[1].first_cluster,
+        };
+        self.transaction(|dm| {
+            // 写入数据
+            let first_cluster = dm.charged(charge, |dm| dm.write_data_to_disk(data))?;
+            // 创建新FCB并插入父目录中
+            let mut fcb = dm.new_fcb(&parent, name, file_type);
+            fcb.first_cluster = first_cluster;
+            fcb.length = data.len();
+            parent.files.push(fcb);
+            dm.store_directory(&parent)?;
+            dm.touch_directory(charge.dir)
+        })
     }
 
-    /// 通过文件名删除文件
-    pub fn delete_file_by_name(&mut self, name: &str) -> Result<(), String> {
-        let index = self.cur_dir.get_index_by_name(name).unwrap();
-        // 从dir中先删除fcb，如果删除失败再还回来
-        pdebug();
-        println!("Trying to delete file in dir file list...");
-        let fcb = self.cur_dir.files.remove(index);
-        let res = self.delete_file_by_fcb_with_index(&fcb, None);
+    /// 在`parent`中新建的文件或目录的FCB：时间是现在，权限属于当前用户，ACL从`parent`继承。
+    /// 还没有分配簇。
+    fn new_fcb(&self, parent: &Directory, name: &str, file_type: FileType) -> Fcb {
+        Fcb {
+            name: String::from(name),
+            first_cluster: 0,
+            length: 0,
+            times: Times::at(self.now()),
+            perm: self.new_perm(&file_type),
+            acl: parent.files[1].acl.inherited(&file_type),
+            file_type,
+        }
+    }
 
-        if res.is_err() {
-            self.cur_dir.files.push(fcb);
+    /// 按路径读取文件，同时更新访问时间。最后一项是符号链接时读取它指向的文件。
+    pub fn read_file_by_name(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let path = &self.follow_final(path)?;
+        let (mut parent, name) = self.resolve_parent(path)?;
+        let (index, fcb) = parent
+            .get_fcb_by_name(name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        self.check_access(fcb, READ, path)?;
+        let data = self.get_file_by_fcb(fcb)?;
+        if !self.noatime {
+            parent.files[index].times.accessed = self.now();
+            self.transaction(|dm| {
+                dm.store_directory(&parent)?;
+                dm.sync_links(&parent.files[index])
+            })?;
         }
 
-        res
+        Ok(data)
+    }
+
+    /// 按路径删除文件或空目录
+    pub fn delete_file_by_name(&mut self, path: &str) -> Result<(), FsError> {
+        let (mut parent, name) = self.resolve_parent(path)?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidArgument(format!(
+                "cannot delete '{}'",
+                path
+            )));
+        }
+        let index = parent
+            .get_index_by_name(name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        self.check_dir_access(&parent, WRITE | EXECUTE, path)?;
+        let fcb = parent.files[index].clone();
+        if fcb.first_cluster == self.cur_dir.files[1].first_cluster {
+            return Err(FsError::InvalidArgument(format!(
+                "cannot delete the current dir '{}'",
+                path
+            )));
+        }
+        // 先释放簇，成功后再从父目录中删除FCB
+        pdebug();
+        println!("Trying to delete file in dir file list...");
+        self.transaction(|dm| {
+            dm.delete_file_by_fcb(&fcb)?;
+            parent.files.remove(index);
+            dm.store_directory(&parent)?;
+            dm.touch_directory(parent.files[1].first_cluster)
+        })
     }
 
-    /// 通过文件夹名设置当前文件夹
-    pub fn set_current_directory(&mut self, name: &str) {
-        // 保存当前文件夹
-        let dir_cloned = self.cur_dir.clone();
-        self.save_directory_to_disk(&dir_cloned);
-        // 通过名字获取下一个文件夹
-        let (_index, dir_fcb) = self.cur_dir.get_fcb_by_name(name).unwrap();
+    /// 按路径设置当前文件夹
+    pub fn set_current_directory(&mut self, path: &str) -> Result<(), FsError> {
+        // 目录都已经写回磁盘，直接读出新的当前目录
+        self.cur_dir = self.resolve_directory(path)?;
 
-        let dir = self.get_directory_by_fcb(dir_fcb);
-        self.cur_dir = dir;
+        Ok(())
     }
 
-    /// 保存文件夹到磁盘，返回第一个簇号——更改被保存，原目录文件将在磁盘上被覆盖
-    pub fn save_directory_to_disk(&mut self, dir: &Directory) -> usize {
+    /// 保存文件夹到磁盘，返回第一个簇号——更改被保存，原目录文件将在磁盘上被原地覆盖，首簇不变
+    pub fn save_directory_to_disk(&mut self, dir: &Directory) -> Result<usize, FsError> {
         pdebug();
         println!("Trying to saving dir...");
         let data = bincode::serialize(dir).unwrap();
-        let (insert_eof, clusters_needed) = DiskManager::calc_clusters_needed_with_eof(data.len());
+        let clusters_needed = self.calc_clusters_needed(data.len());
         let reallocated_clusters =
-            self.reallocate_free_space_on_fat(self.cur_dir.files[1].first_cluster, clusters_needed);
-        self.disk.write_data_by_clusters_with_eof(
-            data.as_slice(),
-            reallocated_clusters.as_slice(),
-            insert_eof,
-        );
+            self.resize_space_on_fat(dir.files[1].first_cluster, clusters_needed)?;
+        self.disk
+            .write_metadata_by_clusters(data.as_slice(), reallocated_clusters.as_slice());
 
-        reallocated_clusters[0]
+        Ok(reallocated_clusters[0])
     }
 
-    /// 文件改名，没啥好说的。
-    pub fn rename_file_by_name(&mut self, old: &str, new: &str) {
-        let (index, fcb) = self.cur_dir.get_fcb_by_name(old).unwrap();
-        let new_fcb = Fcb {
+    /// 提交：把卷元数据、超级块、FAT表和改动过的目录经过日志写回块设备。
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        self.store_meta()?;
+        self.disk.flush()?;
+
+        Ok(())
+    }
+
+    /// 刷新后将整个镜像写出，镜像布局见`image`模块。
+    pub fn write_image<W: Write>(&mut self, writer: &mut W) -> Result<(), FsError> {
+        self.flush()?;
+        self.disk.write_image(writer)?;
+
+        Ok(())
+    }
+
+    /// 从镜像字节中加载磁盘，整个卷放在内存中。
+    pub fn from_image(volume: Vec<u8>) -> Result<DiskManager, FsError> {
+        pinfo();
+        println!("Loading disk image...");
+        DiskManager::from_disk(Disk::from_image(volume)?)
+    }
+
+    /// 在宿主机文件上打开镜像，块按需读出，不把整个卷读入内存。改动在保存之前不会写进这个文件。
+    pub fn open_file<P: AsRef<Path>>(path: P) -> Result<DiskManager, FsError> {
+        pinfo();
+        println!("Opening disk image in place...");
+        DiskManager::from_disk(Disk::open_file(path)?)
+    }
+
+    /// 使用已经打开的磁盘，当前目录为根目录。
+    pub fn from_disk(disk: Disk) -> Result<DiskManager, FsError> {
+        let mut virtual_disk = DiskManager {
+            disk,
+            cur_dir: Directory::new(""),
+            meta: VolumeMeta::default(),
+            snapshot: None,
+            clock: Box::new(SystemClock),
+            noatime: false,
+            uid: ROOT_UID,
+            charge: None,
+        };
+        if virtual_disk.disk.recovery != Recovery::Clean {
+            pinfo();
+            println!("Journal: {}.", virtual_disk.disk.recovery);
+        }
+        virtual_disk.load_meta()?;
+        if virtual_disk.disk.version < image::FORMAT_VERSION {
+            virtual_disk.upgrade_directories()?;
+        }
+        virtual_disk.cur_dir = virtual_disk.get_directory_by_cluster(ROOT_CLUSTER)?;
+
+        Ok(virtual_disk)
+    }
+
+    /// 文件改名，同时更新修改时间。`path`是原文件的路径，`new`是同一目录下的新名字。
+    pub fn rename_file_by_name(&mut self, path: &str, new: &str) -> Result<(), FsError> {
+        check_file_name(new)?;
+        let (mut parent, name) = self.resolve_parent(path)?;
+        check_file_name(name)?;
+        self.check_dir_access(&parent, WRITE | EXECUTE, path)?;
+        if parent.get_fcb_by_name(new).is_some() {
+            return Err(FsError::AlreadyExists(String::from(new)));
+        }
+        let (index, fcb) = parent
+            .get_fcb_by_name(name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        let mut new_fcb = Fcb {
             name: String::from(new),
             ..fcb.to_owned()
         };
-        self.cur_dir.files[index] = new_fcb;
+        new_fcb.times.modified = self.now();
+        self.transaction(|dm| {
+            if let FileType::Directory = new_fcb.file_type {
+                // 目录文件中也记着自己的名字
+                let mut dir = dm.get_directory_by_fcb(&new_fcb)?;
+                dir.name = String::from(new);
+                dm.store_directory(&dir)?;
+            }
+            parent.files[index] = new_fcb.clone();
+            dm.store_directory(&parent)?;
+            dm.sync_links(&new_fcb)?;
+            dm.touch_directory(parent.files[1].first_cluster)
+        })
     }
 
     /// 获取部分磁盘信息
     /// 返回 磁盘总大小/Byte，已分配簇数量、未分配簇的数量
     pub fn get_disk_info(&self) -> (usize, usize, usize) {
-        let disk_size = BLOCK_SIZE * BLOCK_COUNT;
+        let disk_size = self.disk.geometry.disk_size();
         let mut num_used = 0usize;
         let mut num_not_used = 0usize;
 
-        for fat_item in &self.disk.fat {
+        for fat_item in self.disk.fat() {
             match fat_item {
                 FatItem::ClusterNo(_no) => num_used += 1,
                 FatItem::EoF => num_used += 1,
@@ -437,27 +733,33 @@ impl DiskManager {
 
         (disk_size, num_used, num_not_used)
     }
+}
 
-    /// FCB的移动
-    pub fn move_fcb_between_dirs_by_name(&mut self, name: &str, des_dir: &mut Directory) {
-        let fcb = self
-            .cur_dir
-            .files
-            .remove(self.cur_dir.get_index_by_name(name).unwrap());
-        des_dir.files.push(fcb);
+/// 检查文件名是否合法：不能为空，不能是“.”或“..”，不能含有“/”。
+pub(crate) fn check_file_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidArgument(format!(
+            "'{}' is not a valid file name",
+            name
+        )));
     }
+
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FileType {
     File,
     Directory,
+    /// 符号链接，目标路径作为内容存放在它的簇链中，见`symlink`
+    Symlink,
 }
 impl fmt::Display for FileType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             FileType::Directory => write!(f, "Directory"),
             FileType::File => write!(f, "File"),
+            FileType::Symlink => write!(f, "Symlink"),
         }
     }
 }
@@ -468,6 +770,9 @@ pub struct Fcb {
     file_type: FileType,  // 文件类型
     first_cluster: usize, // 起始块号
     length: usize,        // 文件大小
+    times: Times,         // 创建、修改、访问时间
+    perm: Perm,           // 属主、属组和权限位
+    acl: Acl,             // 访问控制列表
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -525,3 +830,58 @@ impl fmt::Display for Directory {
         fmt::Result::Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::testing::new_disk;
+
+    /// 写入后读出，再保存、加载一次镜像后读出
+    fn round_trip(data: &[u8]) {
+        let mut dm = new_disk();
+        dm.create_file_with_data("/f", data).unwrap();
+        assert_eq!(dm.read_file_by_name("/f").unwrap(), data);
+
+        let mut image = Vec::new();
+        dm.write_image(&mut image).unwrap();
+        let mut dm = DiskManager::from_image(image).unwrap();
+        assert_eq!(dm.read_file_by_name("/f").unwrap(), data);
+        assert_eq!(dm.stat("/f").unwrap().length, data.len());
+    }
+
+    #[test]
+    fn binary_data_round_trips() {
+        // 原来的结束标记0xFF和填充用的0x00都是普通数据
+        let mut data: Vec<u8> = (0..=255).collect();
+        data.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF, 0x00]);
+        round_trip(&data);
+        round_trip(&[0x00; 100]);
+        round_trip(&[0xFF; 100]);
+    }
+
+    #[test]
+    fn empty_files_round_trip() {
+        round_trip(b"");
+        let mut dm = new_disk();
+        dm.create_file_with_data("/empty", b"").unwrap();
+        // 空文件也占一个簇
+        assert_eq!(dm.stat("/empty").unwrap().clusters, 1);
+    }
+
+    #[test]
+    fn whole_clusters_round_trip() {
+        let cluster_size = new_disk().disk.cluster_size();
+        for clusters in 1..=3 {
+            let data: Vec<u8> = (0..cluster_size * clusters)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            round_trip(&data);
+
+            let mut dm = new_disk();
+            dm.create_file_with_data("/f", &data).unwrap();
+            assert_eq!(dm.stat("/f").unwrap().clusters, clusters);
+        }
+        round_trip(&vec![0xFF; cluster_size + 1]);
+        round_trip(&vec![0x00; cluster_size - 1]);
+    }
+}
@@ -0,0 +1,381 @@
+//! 访问控制列表（ACL）。
+//!
+//! 权限位之外，每个FCB还可以带有一串ACL条目，给指定的用户或用户组额外允许或者拒绝读、写、执行。
+//! 检查时先按属主、属组、其他人取出权限位，加上匹配当前用户的允许条目，再去掉匹配的拒绝条目，
+//! 拒绝总是优先。ACL和权限位一样，同时记在上一级目录的FCB和目录自己的“.”中。
+//!
+//! 目录上标为`default`的条目不作用于目录自己，而是在目录中新建文件和目录时被继承：
+//! 新文件得到对应的普通条目，新目录还会保留`default`条目，继续传给下一级。
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::FsError;
+use super::stat::Stat;
+use super::users::{Accounts, Perm, EXECUTE, READ, ROOT_UID, WRITE};
+use super::{DiskManager, FileType};
+
+/// 允许还是拒绝
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclKind {
+    Allow,
+    Deny,
+}
+
+/// 条目针对的用户或用户组
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Principal {
+    User(u32),
+    Group(u32),
+}
+
+/// 一条ACL
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub kind: AclKind,
+    pub who: Principal,
+    /// 允许或拒绝的rwx
+    pub perms: u16,
+    /// 只在目录中新建文件和目录时继承，不作用于目录自己
+    pub default: bool,
+}
+impl AclEntry {
+    /// 是否针对同一个对象，设置条目时替换掉这样的旧条目
+    fn same_target(&self, other: &AclEntry) -> bool {
+        self.kind == other.kind && self.who == other.who && self.default == other.default
+    }
+
+    /// 按`[default:]allow|deny:user|group:<name>:<rwx>`的格式显示，名字从`accounts`中查找
+    pub fn display<'a>(&'a self, accounts: &'a Accounts) -> EntryDisplay<'a> {
+        EntryDisplay(self, accounts)
+    }
+}
+
+/// 显示一条ACL，见`AclEntry::display`
+pub struct EntryDisplay<'a>(&'a AclEntry, &'a Accounts);
+impl fmt::Display for EntryDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let EntryDisplay(entry, accounts) = self;
+        if entry.default {
+            write!(f, "default:")?;
+        }
+        let kind = match entry.kind {
+            AclKind::Allow => "allow",
+            AclKind::Deny => "deny",
+        };
+        let (class, name) = match entry.who {
+            Principal::User(uid) => ("user", accounts.user_name(uid)),
+            Principal::Group(gid) => ("group", accounts.group_name(gid)),
+        };
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            kind,
+            class,
+            name,
+            format_perms(entry.perms)
+        )
+    }
+}
+
+/// 一个文件或目录的全部ACL条目
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl(pub Vec<AclEntry>);
+impl Acl {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 在权限位`bits`上加上匹配的允许条目、去掉匹配的拒绝条目，`matches`判断条目是否针对当前用户
+    pub fn apply(&self, bits: u16, matches: impl Fn(&Principal) -> bool) -> u16 {
+        let mut allowed = bits;
+        let mut denied = 0;
+        for entry in self.0.iter().filter(|entry| !entry.default) {
+            if matches(&entry.who) {
+                match entry.kind {
+                    AclKind::Allow => allowed |= entry.perms,
+                    AclKind::Deny => denied |= entry.perms,
+                }
+            }
+        }
+
+        allowed & !denied
+    }
+
+    /// 在这个目录中新建的文件或目录继承到的ACL
+    pub fn inherited(&self, file_type: &FileType) -> Acl {
+        // 符号链接不参与权限检查
+        if let FileType::Symlink = file_type {
+            return Acl::default();
+        }
+        let defaults = self.0.iter().filter(|entry| entry.default);
+        let mut entries: Vec<AclEntry> = defaults
+            .clone()
+            .map(|entry| AclEntry {
+                default: false,
+                ..*entry
+            })
+            .collect();
+        if let FileType::Directory = file_type {
+            entries.extend(defaults.copied());
+        }
+
+        Acl(entries)
+    }
+
+    /// 加入条目，替换针对同一对象的旧条目
+    pub fn set(&mut self, entry: AclEntry) {
+        match self.0.iter_mut().find(|old| old.same_target(&entry)) {
+            Some(old) => *old = entry,
+            None => self.0.push(entry),
+        }
+    }
+
+    /// 删除针对同一对象的条目，不管允许或拒绝的是哪些权限
+    pub fn remove(&mut self, entry: &AclEntry) {
+        self.0.retain(|old| !old.same_target(entry));
+    }
+}
+
+/// 显示为`rwx`，没有的权限用`-`
+fn format_perms(perms: u16) -> String {
+    [(READ, 'r'), (WRITE, 'w'), (EXECUTE, 'x')]
+        .iter()
+        .map(|(bit, c)| if perms & bit != 0 { *c } else { '-' })
+        .collect()
+}
+
+/// 解析`rwx`、`r-x`、`w`这样的权限
+fn parse_perms(text: &str) -> Option<u16> {
+    let mut perms = 0;
+    for c in text.chars() {
+        perms |= match c {
+            'r' => READ,
+            'w' => WRITE,
+            'x' => EXECUTE,
+            '-' => 0,
+            _ => return None,
+        };
+    }
+
+    Some(perms)
+}
+
+/// 解析一条ACL：`[default:|d:]allow|deny:user|u|group|g:<name>:<rwx>`。
+/// `with_perms`为假时（删除条目）可以省略最后的权限。
+pub fn parse_entry(text: &str, accounts: &Accounts, with_perms: bool) -> Result<AclEntry, FsError> {
+    let invalid = |reason: &str| {
+        FsError::InvalidArgument(format!("'{}' is not a valid ACL entry, {}", text, reason))
+    };
+    let mut fields: Vec<&str> = text.split(':').collect();
+    let default = matches!(fields.first(), Some(&"default") | Some(&"d"));
+    if default {
+        fields.remove(0);
+    }
+    let (kind, class, name, perms) = match fields.as_slice() {
+        [kind, class, name, perms] => (*kind, *class, *name, Some(*perms)),
+        [kind, class, name] if !with_perms => (*kind, *class, *name, None),
+        _ => {
+            return Err(invalid(
+                "expected [default:]allow|deny:user|group:<name>:<rwx>",
+            ))
+        }
+    };
+    let kind = match kind {
+        "allow" => AclKind::Allow,
+        "deny" => AclKind::Deny,
+        _ => return Err(invalid("expected 'allow' or 'deny'")),
+    };
+    let who = match class {
+        "user" | "u" => Principal::User(
+            accounts
+                .user_by_name(name)
+                .ok_or_else(|| invalid("no such user"))?
+                .uid,
+        ),
+        "group" | "g" => Principal::Group(
+            accounts
+                .group_by_name(name)
+                .ok_or_else(|| invalid("no such group"))?
+                .gid,
+        ),
+        _ => return Err(invalid("expected 'user' or 'group'")),
+    };
+    let perms = match perms {
+        Some(perms) => parse_perms(perms).ok_or_else(|| invalid("bad permissions"))?,
+        None => 0,
+    };
+
+    Ok(AclEntry {
+        kind,
+        who,
+        perms,
+        default,
+    })
+}
+
+/// `getfacl`的输出：属主、属组、权限位和所有ACL条目
+pub struct FaclListing {
+    pub path: String,
+    pub stat: Stat,
+    /// 已经按`AclEntry::display`格式化的条目
+    pub entries: Vec<String>,
+}
+impl fmt::Display for FaclListing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Perm { mode, .. } = self.stat.perm;
+        writeln!(f, "# file: {}", self.path)?;
+        writeln!(f, "# owner: {}", self.stat.owner)?;
+        writeln!(f, "# group: {}", self.stat.group)?;
+        writeln!(f, "user::{}", format_perms(mode >> 6))?;
+        writeln!(f, "group::{}", format_perms(mode >> 3))?;
+        write!(f, "other::{}", format_perms(mode))?;
+        for entry in &self.entries {
+            write!(f, "\n{}", entry)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskManager {
+    /// 当前用户是否匹配条目针对的对象
+    pub(crate) fn is_principal(&self, who: &Principal) -> bool {
+        match who {
+            Principal::User(uid) => *uid == self.uid(),
+            Principal::Group(gid) => self.meta.accounts.in_group(self.uid(), *gid),
+        }
+    }
+
+    /// 按文本解析多条ACL，条目之间用`,`分隔
+    pub fn parse_acl(&self, spec: &str, with_perms: bool) -> Result<Vec<AclEntry>, FsError> {
+        spec.split(',')
+            .map(|text| parse_entry(text, &self.meta.accounts, with_perms))
+            .collect()
+    }
+
+    /// 取得`path`的权限位和ACL
+    pub fn getfacl(&self, path: &str) -> Result<FaclListing, FsError> {
+        let stat = self.stat(path)?;
+        let entries = stat
+            .acl
+            .0
+            .iter()
+            .map(|entry| entry.display(&self.meta.accounts).to_string())
+            .collect();
+
+        Ok(FaclListing {
+            path: String::from(path),
+            stat,
+            entries,
+        })
+    }
+
+    /// 修改`path`的ACL，只有属主和root可以。`update`在原来的ACL上做修改。
+    pub fn setfacl(&mut self, path: &str, update: impl FnOnce(&mut Acl)) -> Result<(), FsError> {
+        self.update_access(path, |dm, perm, acl| {
+            if dm.uid() != ROOT_UID && dm.uid() != perm.owner {
+                return Err(FsError::PermissionDenied(String::from(path)));
+            }
+            update(acl);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::{denied, shared_disk};
+
+    fn set(dm: &mut DiskManager, spec: &str, path: &str) -> Result<(), FsError> {
+        let entries = dm.parse_acl(spec, true)?;
+        dm.setfacl(path, |acl| entries.iter().for_each(|entry| acl.set(*entry)))
+    }
+
+    #[test]
+    fn allow_and_deny_entries_adjust_mode_bits() {
+        let mut dm = shared_disk();
+        set(
+            &mut dm,
+            "allow:user:bob:rw,deny:group:dev:r",
+            "/home/alice/f",
+        )
+        .unwrap();
+        set(&mut dm, "allow:user:bob:rwx", "/home/alice").unwrap();
+        set(&mut dm, "deny:user:alice:w", "/home/alice/f").unwrap();
+        // 拒绝条目对属主也有效
+        assert!(denied(
+            dm.open("/home/alice/f", OpenOptions::new().write(true))
+        ));
+
+        let mut image = Vec::new();
+        dm.write_image(&mut image).unwrap();
+        let mut dm = DiskManager::from_image(image).unwrap();
+        dm.login("bob").unwrap();
+        dm.open("/home/alice/f", OpenOptions::new().write(true))
+            .unwrap()
+            .close()
+            .unwrap();
+        dm.create_file_with_data("/home/alice/g", b"").unwrap();
+        dm.login("carol").unwrap();
+        assert!(denied(dm.read_file_by_name("/home/alice/f")));
+        assert!(denied(dm.delete_file_by_name("/home/alice/g")));
+        dm.logout();
+        dm.read_file_by_name("/home/alice/f").unwrap();
+    }
+
+    #[test]
+    fn default_entries_are_inherited_on_create() {
+        let mut dm = shared_disk();
+        set(&mut dm, "default:allow:user:bob:rwx", "/home/alice").unwrap();
+        dm.new_directory_to_disk("/home/alice/sub").unwrap();
+        dm.create_file_with_data("/home/alice/sub/f", b"").unwrap();
+        let entries = |dm: &DiskManager, path| dm.getfacl(path).unwrap().entries;
+        assert_eq!(
+            entries(&dm, "/home/alice/sub"),
+            ["allow:user:bob:rwx", "default:allow:user:bob:rwx"]
+        );
+        assert_eq!(
+            entries(&dm, "/home/alice/sub/."),
+            entries(&dm, "/home/alice/sub")
+        );
+        assert_eq!(entries(&dm, "/home/alice/sub/f"), ["allow:user:bob:rwx"]);
+        // 已经存在的文件不变
+        assert!(entries(&dm, "/home/alice/f").is_empty());
+
+        dm.login("bob").unwrap();
+        // 目录自己不受default条目影响
+        assert!(denied(dm.create_file_with_data("/home/alice/h", b"")));
+        dm.create_file_with_data("/home/alice/sub/g", b"").unwrap();
+        assert!(denied(set(&mut dm, "allow:user:bob:r", "/home/alice/sub")));
+    }
+
+    #[test]
+    fn entries_parse_and_display() {
+        let dm = shared_disk();
+        let accounts = &dm.meta.accounts;
+        let entry = parse_entry("d:deny:g:dev:-w-", accounts, true).unwrap();
+        assert!(entry.default);
+        assert_eq!(entry.perms, WRITE);
+        assert_eq!(
+            entry.display(accounts).to_string(),
+            "default:deny:group:dev:-w-"
+        );
+        assert!(parse_entry("allow:user:bob", accounts, false).is_ok());
+        assert!(parse_entry("allow:user:bob", accounts, true).is_err());
+        assert!(parse_entry("allow:user:nobody:r", accounts, true).is_err());
+        assert!(parse_entry("permit:user:bob:r", accounts, true).is_err());
+        assert!(parse_entry("allow:user:bob:rq", accounts, true).is_err());
+
+        let mut acl = Acl::default();
+        acl.set(parse_entry("allow:u:bob:r", accounts, true).unwrap());
+        acl.set(parse_entry("allow:u:bob:rw", accounts, true).unwrap());
+        acl.set(parse_entry("deny:u:bob:x", accounts, true).unwrap());
+        assert_eq!(acl.0.len(), 2);
+        acl.remove(&parse_entry("allow:u:bob", accounts, false).unwrap());
+        assert_eq!(acl.0.len(), 1);
+    }
+}
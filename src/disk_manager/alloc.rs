@@ -0,0 +1,423 @@
+//! 簇分配策略。
+//!
+//! 空闲簇记在`FreeSpace`索引中，同时按长度索引连续的空闲段，分配时不再扫描FAT表。分配策略在格式化时选定，并保存在超级块中。
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use super::disk::FatItem;
+use super::error::FsError;
+
+/// 空闲簇索引：所有未使用的数据簇，按簇号排序，同时按长度索引所有连续的空闲段
+#[derive(Debug, Clone, Default)]
+pub struct FreeSpace {
+    free: BTreeSet<usize>,
+    /// 连续的空闲段：起始簇 → 长度
+    runs: BTreeMap<usize, usize>,
+    /// 同样的空闲段，按(长度, 起始簇)排序
+    by_len: BTreeSet<(usize, usize)>,
+}
+impl FreeSpace {
+    /// 扫描一遍FAT表建立索引
+    pub fn from_fat(fat: &[FatItem]) -> FreeSpace {
+        let mut free = FreeSpace::default();
+        for (cluster, item) in fat.iter().enumerate() {
+            if let FatItem::NotUsed = item {
+                free.insert(cluster);
+            }
+        }
+
+        free
+    }
+
+    /// 空闲簇数量
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    pub fn contains(&self, cluster: usize) -> bool {
+        self.free.contains(&cluster)
+    }
+
+    /// 簇号最小的空闲簇
+    pub fn first(&self) -> Option<usize> {
+        self.free.iter().next().copied()
+    }
+
+    /// 从`start`开始按簇号顺序列出空闲簇，到末尾后再从头开始
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = usize> + '_ {
+        self.free
+            .range(start..)
+            .chain(self.free.range(..start))
+            .copied()
+    }
+
+    /// 所有连续的空闲簇段：(起始簇, 长度)，按起始簇排序
+    pub fn runs(&self) -> Vec<(usize, usize)> {
+        self.runs
+            .iter()
+            .map(|(start, len)| (*start, *len))
+            .collect()
+    }
+
+    /// 长度至少为`len`的空闲段中最短的一个，一样短的取簇号最小的
+    pub fn shortest_run_of(&self, len: usize) -> Option<(usize, usize)> {
+        self.by_len
+            .range((len, 0)..)
+            .next()
+            .map(|(len, start)| (*start, *len))
+    }
+
+    /// 按长度从长到短列出空闲段，一样长的按簇号排
+    pub fn runs_longest_first(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let longest = self.by_len.iter().next_back().map(|(len, _start)| *len);
+        let shorter = move |len: &usize| {
+            self.by_len
+                .range(..(*len, 0))
+                .next_back()
+                .map(|(len, _start)| *len)
+        };
+        std::iter::successors(longest, shorter).flat_map(move |len| {
+            self.by_len
+                .range((len, 0)..=(len, usize::MAX))
+                .map(|(len, start)| (*start, *len))
+        })
+    }
+
+    fn add_run(&mut self, start: usize, len: usize) {
+        self.runs.insert(start, len);
+        self.by_len.insert((len, start));
+    }
+
+    fn remove_run(&mut self, start: usize, len: usize) {
+        self.runs.remove(&start);
+        self.by_len.remove(&(len, start));
+    }
+
+    pub(crate) fn insert(&mut self, cluster: usize) {
+        if !self.free.insert(cluster) {
+            return;
+        }
+        // 和前后相邻的空闲段合并
+        let (mut start, mut len) = (cluster, 1);
+        let before = self.runs.range(..cluster).next_back();
+        if let Some((&before_start, &before_len)) = before {
+            if before_start + before_len == cluster {
+                self.remove_run(before_start, before_len);
+                start = before_start;
+                len += before_len;
+            }
+        }
+        if let Some(&after_len) = self.runs.get(&(cluster + 1)) {
+            self.remove_run(cluster + 1, after_len);
+            len += after_len;
+        }
+        self.add_run(start, len);
+    }
+
+    pub(crate) fn remove(&mut self, cluster: usize) {
+        if !self.free.remove(&cluster) {
+            return;
+        }
+        // 把所在的空闲段从这个簇处断开
+        let (&start, &len) = self.runs.range(..=cluster).next_back().unwrap();
+        self.remove_run(start, len);
+        if cluster > start {
+            self.add_run(start, cluster - start);
+        }
+        if start + len > cluster + 1 {
+            self.add_run(cluster + 1, start + len - cluster - 1);
+        }
+    }
+}
+
+/// 分配策略：从空闲簇中选出`count`个簇，按簇链的顺序返回。空闲簇不够时返回`None`。
+/// 策略只负责挑选，标记为已用由调用者完成。
+pub trait Allocator {
+    fn kind(&self) -> AllocatorKind;
+    fn allocate(&mut self, free: &FreeSpace, count: usize) -> Option<Vec<usize>>;
+}
+
+/// 首次适应：总是使用簇号最小的空闲簇
+pub struct FirstFit;
+impl Allocator for FirstFit {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::FirstFit
+    }
+
+    fn allocate(&mut self, free: &FreeSpace, count: usize) -> Option<Vec<usize>> {
+        if free.len() < count {
+            return None;
+        }
+
+        Some(free.iter_from(0).take(count).collect())
+    }
+}
+
+/// 循环首次适应：从上次分配结束的位置继续向后找，到末尾后从头开始
+#[derive(Default)]
+pub struct NextFit {
+    cursor: usize,
+}
+impl Allocator for NextFit {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::NextFit
+    }
+
+    fn allocate(&mut self, free: &FreeSpace, count: usize) -> Option<Vec<usize>> {
+        if free.len() < count {
+            return None;
+        }
+        let clusters: Vec<usize> = free.iter_from(self.cursor).take(count).collect();
+        if let Some(last) = clusters.last() {
+            self.cursor = last + 1;
+        }
+
+        Some(clusters)
+    }
+}
+
+/// 最佳适应：使用能放下整个簇链的最短连续空闲段；没有这样的段时，从最长的段开始依次使用，
+/// 使簇链的段数尽量少
+pub struct BestFit;
+impl Allocator for BestFit {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::BestFit
+    }
+
+    fn allocate(&mut self, free: &FreeSpace, count: usize) -> Option<Vec<usize>> {
+        if free.len() < count {
+            return None;
+        }
+        if let Some((start, _len)) = free.shortest_run_of(count) {
+            return Some((start..start + count).collect());
+        }
+
+        let mut clusters = Vec::with_capacity(count);
+        for (start, len) in free.runs_longest_first() {
+            let take = len.min(count - clusters.len());
+            clusters.extend(start..start + take);
+            if clusters.len() == count {
+                break;
+            }
+        }
+
+        Some(clusters)
+    }
+}
+
+/// 分配策略的种类，保存在超级块中
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(clippy::enum_variant_names)]
+pub enum AllocatorKind {
+    #[default]
+    FirstFit,
+    NextFit,
+    BestFit,
+}
+impl AllocatorKind {
+    pub const ALL: [AllocatorKind; 3] = [
+        AllocatorKind::FirstFit,
+        AllocatorKind::NextFit,
+        AllocatorKind::BestFit,
+    ];
+
+    /// 生成对应的分配器
+    pub fn build(self) -> Box<dyn Allocator> {
+        match self {
+            AllocatorKind::FirstFit => Box::new(FirstFit),
+            AllocatorKind::NextFit => Box::new(NextFit::default()),
+            AllocatorKind::BestFit => Box::new(BestFit),
+        }
+    }
+
+    /// 在超级块中的编号
+    pub fn id(self) -> u32 {
+        match self {
+            AllocatorKind::FirstFit => 0,
+            AllocatorKind::NextFit => 1,
+            AllocatorKind::BestFit => 2,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Option<AllocatorKind> {
+        AllocatorKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.id() == id)
+    }
+}
+impl fmt::Display for AllocatorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AllocatorKind::FirstFit => "first-fit",
+            AllocatorKind::NextFit => "next-fit",
+            AllocatorKind::BestFit => "best-fit",
+        };
+        write!(f, "{}", name)
+    }
+}
+impl FromStr for AllocatorKind {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AllocatorKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.to_string() == s)
+            .ok_or_else(|| {
+                FsError::InvalidArgument(format!(
+                    "unknown allocator '{}', expected first-fit, next-fit or best-fit",
+                    s
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::clock::ManualClock;
+    use crate::disk_manager::disk::DiskGeometry;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::START;
+    use crate::disk_manager::DiskManager;
+
+    /// 20个簇中只有`used`是已用的
+    fn free_space(used: &[usize]) -> FreeSpace {
+        let fat: Vec<FatItem> = (0..20)
+            .map(|cluster| {
+                if used.contains(&cluster) {
+                    FatItem::EoF
+                } else {
+                    FatItem::NotUsed
+                }
+            })
+            .collect();
+        FreeSpace::from_fat(&fat)
+    }
+
+    #[test]
+    fn free_space_indexes_runs() {
+        let mut free = free_space(&[0, 3, 4, 8, 15]);
+        assert_eq!(free.runs(), [(1, 2), (5, 3), (9, 6), (16, 4)]);
+        assert_eq!(free.shortest_run_of(3), Some((5, 3)));
+        assert_eq!(free.shortest_run_of(5), Some((9, 6)));
+        assert_eq!(free.shortest_run_of(7), None);
+        let longest: Vec<(usize, usize)> = free.runs_longest_first().collect();
+        assert_eq!(longest, [(9, 6), (16, 4), (5, 3), (1, 2)]);
+
+        // 释放的簇和两边的段合并，占用的簇把段断开
+        free.insert(4);
+        free.insert(3);
+        assert_eq!(free.runs()[0], (1, 7));
+        free.remove(12);
+        assert_eq!(free.runs()[1..], [(9, 3), (13, 2), (16, 4)]);
+        free.remove(1);
+        free.remove(1);
+        assert_eq!(free.runs()[0], (2, 6));
+        assert_eq!(free.len(), 15);
+    }
+
+    #[test]
+    fn first_fit_takes_lowest_clusters() {
+        let free = free_space(&[0, 3, 4, 8]);
+        assert_eq!(FirstFit.allocate(&free, 4), Some(vec![1, 2, 5, 6]));
+        assert_eq!(FirstFit.allocate(&free, 17), None);
+    }
+
+    #[test]
+    fn next_fit_continues_after_last_allocation() {
+        let free = free_space(&[0, 3, 4, 8]);
+        let mut next_fit = NextFit::default();
+        assert_eq!(next_fit.allocate(&free, 2), Some(vec![1, 2]));
+        // 分配器只负责挑选，索引没有变，下一次仍然从上次结束的地方开始
+        assert_eq!(next_fit.allocate(&free, 2), Some(vec![5, 6]));
+        let free = free_space(&[0, 3, 4, 8, 18, 19]);
+        let mut next_fit = NextFit { cursor: 16 };
+        assert_eq!(next_fit.allocate(&free, 3), Some(vec![16, 17, 1]));
+    }
+
+    #[test]
+    fn best_fit_takes_shortest_fitting_run() {
+        let free = free_space(&[0, 3, 4, 8, 15]);
+        assert_eq!(BestFit.allocate(&free, 3), Some(vec![5, 6, 7]));
+        assert_eq!(BestFit.allocate(&free, 4), Some(vec![16, 17, 18, 19]));
+        // 没有放得下的段时从最长的段开始用
+        assert_eq!(
+            BestFit.allocate(&free, 8),
+            Some(vec![9, 10, 11, 12, 13, 14, 16, 17])
+        );
+    }
+
+    fn disk_with(kind: AllocatorKind) -> DiskManager {
+        DiskManager::new(
+            None,
+            DiskGeometry::default(),
+            kind,
+            Box::new(ManualClock::new(START)),
+        )
+    }
+
+    /// 先写入一些文件，删掉其中几个留下大小不一的空洞，再写入新文件，返回新文件的碎片段数
+    fn fragments_after_holes(kind: AllocatorKind) -> usize {
+        let mut dm = disk_with(kind);
+        let cluster_size = dm.disk.cluster_size();
+        let sizes = [3, 1, 4, 1, 5, 6, 2, 9];
+        for (i, size) in sizes.iter().enumerate() {
+            let data = vec![1u8; size * cluster_size];
+            dm.create_file_with_data(&format!("/f{}", i), &data)
+                .unwrap();
+        }
+        for i in [1, 3, 5] {
+            dm.delete_file_by_name(&format!("/f{}", i)).unwrap();
+        }
+        for (name, size) in [("/new6", 6), ("/new2", 2)] {
+            dm.create_file_with_data(name, &vec![2u8; size * cluster_size])
+                .unwrap();
+        }
+
+        let report = dm.frag_report().unwrap();
+        report
+            .chains
+            .iter()
+            .filter(|chain| chain.path.starts_with("/new"))
+            .map(|chain| chain.fragments)
+            .sum()
+    }
+
+    #[test]
+    fn best_fit_fragments_least() {
+        let first_fit = fragments_after_holes(AllocatorKind::FirstFit);
+        let next_fit = fragments_after_holes(AllocatorKind::NextFit);
+        let best_fit = fragments_after_holes(AllocatorKind::BestFit);
+        // 首次适应把6个簇拆进了前面的小空洞
+        assert!(first_fit > 2);
+        assert_eq!(best_fit, 2);
+        assert!(best_fit <= next_fit && next_fit <= first_fit);
+    }
+
+    #[test]
+    fn growing_file_stays_contiguous() {
+        for kind in AllocatorKind::ALL {
+            let mut dm = disk_with(kind);
+            let cluster_size = dm.disk.cluster_size();
+            dm.create_file_with_data("/gap", b"gap").unwrap();
+            dm.create_file_with_data("/f", b"data").unwrap();
+            dm.delete_file_by_name("/gap").unwrap();
+
+            // 前面有空洞，仍然接着链尾往后长
+            let mut file = dm.open("/f", OpenOptions::new().write(true)).unwrap();
+            file.set_len(3 * cluster_size).unwrap();
+            drop(file);
+            let report = dm.frag_report().unwrap();
+            let chain = report.chains.iter().find(|chain| chain.path == "/f");
+            assert_eq!(chain.unwrap().fragments, 1, "{}", kind);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! 文件时间戳使用的时钟。
+//!
+//! 时间戳是从1970-01-01 00:00:00 UTC开始的秒数，0表示不知道（例如从旧版本镜像升级来的文件）。
+//! `DiskManager`通过`Clock`特征取得当前时间，默认是系统时钟，测试中可以换成手动拨动的时钟，
+//! 使时间戳可以预知。
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// 取得当前时间
+pub trait Clock {
+    /// 当前的时间戳
+    fn now(&self) -> u64;
+}
+
+/// 系统时钟
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        from_system_time(SystemTime::now())
+    }
+}
+
+/// 手动拨动的时钟，复制出来的时钟共用同一个时间
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Rc<Cell<u64>>);
+impl ManualClock {
+    pub fn new(now: u64) -> ManualClock {
+        ManualClock(Rc::new(Cell::new(now)))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.set(now);
+    }
+
+    /// 向后拨`seconds`秒
+    pub fn advance(&self, seconds: u64) {
+        self.0.set(self.0.get() + seconds);
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+/// 文件的三个时间戳
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Times {
+    /// 创建时间
+    pub created: u64,
+    /// 内容最后一次修改的时间，改名也算
+    pub modified: u64,
+    /// 最后一次读取的时间
+    pub accessed: u64,
+}
+impl Times {
+    /// 三个时间都是`now`
+    pub fn at(now: u64) -> Times {
+        Times {
+            created: now,
+            modified: now,
+            accessed: now,
+        }
+    }
+}
+
+/// 宿主机时间转为时间戳，1970年以前的时间当作不知道
+pub fn from_system_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// 时间戳转为宿主机时间，不知道的时间为`None`
+pub fn to_system_time(timestamp: u64) -> Option<SystemTime> {
+    match timestamp {
+        0 => None,
+        _ => Some(UNIX_EPOCH + Duration::from_secs(timestamp)),
+    }
+}
+
+/// 按UTC显示为`YYYY-MM-DD hh:mm:ss`，不知道的时间显示为`-`
+pub fn format_time(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return String::from("-");
+    }
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+    // 从1970-01-01起的天数换算成公历日期，以3月1日为一年的开始，闰日正好落在年末
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,450 @@
+//! 碎片整理。
+//!
+//! 按目录树的顺序（目录在前，接着是它的文件，再递归进入子目录）把每条簇链依次搬到数据区开头，
+//! 连成一段连续的簇，卷元数据的簇链放在最后。簇只搬到提交时空闲的位置，一批搬完再提交，
+//! 磁盘仍然是一致的；腾出来的簇要提交之后才能再用。每整理好一条簇链就在卷元数据中记下整理到的位置。
+//! 中途出错或者被打断时，再运行一次会读出这个位置，跳过之前已经就位的簇链，从中断的地方继续。
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::disk::FatItem;
+use super::error::FsError;
+use super::fsck::Repair;
+use super::image::ROOT_CLUSTER;
+use super::{pdebug, pinfo, DiskManager, FileType};
+
+/// 一次碎片整理的统计
+#[derive(Debug, Default, Clone)]
+pub struct DefragReport {
+    /// 整理前的碎片率，见`DiskManager::fragmentation`
+    pub before: f64,
+    /// 整理后的碎片率
+    pub after: f64,
+    /// 整理过的簇链数量
+    pub chains: usize,
+    /// 搬动的簇数量，包括为腾出位置而临时搬走的簇
+    pub moved: usize,
+    /// 上一次被中断时已经整理好的簇数量
+    pub resumed_from: Option<usize>,
+}
+impl fmt::Display for DefragReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(placed) = self.resumed_from {
+            writeln!(f, "Resumed with {} clusters already in place.", placed)?;
+        }
+        write!(
+            f,
+            "Defragmented {} chains, {} clusters moved. Fragmentation {:.1}% -> {:.1}%.",
+            self.chains, self.moved, self.before, self.after
+        )
+    }
+}
+
+/// 一次碎片整理过程中的状态
+struct Defragmenter<'a> {
+    dm: &'a mut DiskManager,
+    /// 簇 → 簇链中指向它的上一个簇。没有上一个簇的已用簇就是链头
+    prev: HashMap<usize, usize>,
+    /// 按整理顺序排列的所有链头
+    heads: Vec<usize>,
+    report: DefragReport,
+}
+impl Defragmenter<'_> {
+    /// 按FAT表重建`prev`
+    fn index_fat(&mut self) {
+        self.prev.clear();
+        for (cluster, item) in self.dm.disk.fat().iter().enumerate() {
+            if let FatItem::ClusterNo(next) = item {
+                self.prev.insert(*next, cluster);
+            }
+        }
+    }
+
+    /// 从`dir_cluster`开始收集链头：先是目录里的文件，再递归进入子目录。共享的簇链只收集一次。
+    fn collect_heads(
+        &mut self,
+        dir_cluster: usize,
+        seen: &mut HashSet<usize>,
+    ) -> Result<(), FsError> {
+        let dir = self.dm.get_directory_by_cluster(dir_cluster)?;
+        for fcb in dir.files.iter().skip(2) {
+            if !matches!(fcb.file_type, FileType::Directory) && seen.insert(fcb.first_cluster) {
+                self.heads.push(fcb.first_cluster);
+            }
+        }
+        for fcb in dir.files.iter().skip(2) {
+            if let FileType::Directory = fcb.file_type {
+                if seen.insert(fcb.first_cluster) {
+                    self.heads.push(fcb.first_cluster);
+                    self.collect_heads(fcb.first_cluster, seen)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 把`from`簇搬到空闲的`to`簇，修改指向它的FAT项或FCB。调用者负责提交
+    fn move_cluster(&mut self, from: usize, to: usize) -> Result<(), FsError> {
+        pdebug();
+        println!("Moving cluster {} to {}...", from, to);
+        let data = self.dm.disk.read_data_by_cluster(from)?;
+        self.dm.disk.insert_data_by_cluster(&data, to)?;
+        let next = self.dm.disk.fat()[from].clone();
+        if let FatItem::ClusterNo(next) = next {
+            self.prev.insert(next, to);
+        }
+        self.dm.disk.set_fat(to, next);
+        match self.prev.remove(&from) {
+            Some(prev) => {
+                self.dm.disk.set_fat(prev, FatItem::ClusterNo(to));
+                self.prev.insert(to, prev);
+            }
+            None => {
+                // 链头搬走了，所有指向它的地方都要改
+                self.dm.retarget_chain(from, to)?;
+                for head in self.heads.iter_mut().filter(|head| **head == from) {
+                    *head = to;
+                }
+            }
+        }
+        self.dm.disk.set_fat(from, FatItem::NotUsed);
+        self.report.moved += 1;
+
+        Ok(())
+    }
+
+    /// 从`start`开始的`count`个簇位置，跳过坏簇
+    fn targets(&self, start: usize, count: usize) -> Vec<usize> {
+        let fat = self.dm.disk.fat();
+        (start..fat.len())
+            .filter(|cluster| !matches!(fat[*cluster], FatItem::BadCluster))
+            .take(count)
+            .collect()
+    }
+
+    /// 把首簇为`head`的簇链搬到从`start`开始的连续簇上，跳过坏簇。返回簇链之后的下一个簇。
+    ///
+    /// 腾出来的簇提交之前不能再用，所以分两步：先把占着目标位置的簇（包括这条链自己放错了位置的簇）
+    /// 搬到目标范围以外并提交，再把整条链搬进已经空出来的位置并提交。提交时保存卷元数据可能又占了
+    /// 目标位置，这时重复这两步。
+    fn place_chain(&mut self, mut head: usize, start: usize) -> Result<usize, FsError> {
+        loop {
+            let clusters = self.dm.get_file_clusters(head)?;
+            let targets = self.targets(start, clusters.len());
+            let end = targets.last().map_or(start, |last| last + 1);
+            if clusters == targets {
+                return Ok(end);
+            }
+
+            let free = self.dm.disk.free_space();
+            let blockers: Vec<usize> = targets
+                .iter()
+                .zip(&clusters)
+                .filter(|(target, cluster)| target != cluster && !free.contains(**target))
+                .map(|(target, _cluster)| *target)
+                .collect();
+            if blockers.is_empty() {
+                for (cluster, target) in clusters.iter().zip(&targets) {
+                    if cluster != target {
+                        self.move_cluster(*cluster, *target)?;
+                    }
+                }
+                head = targets[0];
+            } else {
+                for blocker in blockers {
+                    // 备用的位置不能落在目标范围之内
+                    let spare = self
+                        .dm
+                        .disk
+                        .free_space()
+                        .iter_from(end)
+                        .find(|cluster| *cluster < start || *cluster >= end)
+                        .ok_or(FsError::NoSpace)?;
+                    self.move_cluster(blocker, spare)?;
+                    if blocker == head {
+                        head = spare;
+                    }
+                }
+            }
+            // 保存元数据可能会分配或释放簇，之后重建索引
+            self.dm.flush()?;
+            self.index_fat();
+        }
+    }
+
+    /// 首簇为`head`的簇链已经连续地放在`start`开始的位置上时，返回它之后的下一个簇
+    fn in_place(&self, head: usize, start: usize) -> Result<Option<usize>, FsError> {
+        let clusters = self.dm.get_file_clusters(head)?;
+        let targets = self.targets(start, clusters.len());
+        if clusters != targets {
+            return Ok(None);
+        }
+
+        Ok(Some(targets.last().map_or(start, |last| last + 1)))
+    }
+
+    /// 依次整理所有簇链，最后整理卷元数据
+    fn run(&mut self) -> Result<(), FsError> {
+        self.index_fat();
+        // 根目录永远在数据簇0，是第一条簇链
+        let mut seen = HashSet::new();
+        seen.insert(ROOT_CLUSTER);
+        self.heads.push(ROOT_CLUSTER);
+        self.collect_heads(ROOT_CLUSTER, &mut seen)?;
+
+        // 上次中断之前整理好的簇链还在原处，直接跳过，不再重新提交进度
+        let resume = self.dm.meta.defrag.unwrap_or(0);
+        let mut placed = 0;
+        for i in 0..self.heads.len() {
+            if placed < resume {
+                if let Some(end) = self.in_place(self.heads[i], placed)? {
+                    placed = end;
+                    continue;
+                }
+            }
+            placed = self.place_chain(self.heads[i], placed)?;
+            self.report.chains += 1;
+            // 记下进度。保存元数据可能会分配或释放簇，之后重建索引
+            self.dm.meta.defrag = Some(placed);
+            self.dm.flush()?;
+            self.index_fat();
+        }
+
+        self.dm.meta.defrag = None;
+        self.dm.flush()?;
+        self.index_fat();
+        if let Some(meta_cluster) = self.dm.disk.meta_cluster {
+            self.place_chain(meta_cluster, placed)?;
+            self.report.chains += 1;
+            self.dm.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskManager {
+    /// 碎片率：簇链中下一个簇不紧跟在当前簇后面的比例（百分比）。0表示所有簇链都是连续的。
+    pub fn fragmentation(&self) -> f64 {
+        let mut links = 0usize;
+        let mut breaks = 0usize;
+        for (cluster, item) in self.disk.fat().iter().enumerate() {
+            if let FatItem::ClusterNo(next) = item {
+                links += 1;
+                if *next != cluster + 1 {
+                    breaks += 1;
+                }
+            }
+        }
+        if links == 0 {
+            return 0.0;
+        }
+
+        breaks as f64 * 100.0 / links as f64
+    }
+
+    /// 碎片整理：把每条簇链搬成连续的一段，并修改所有指向被搬动链头的FCB。
+    /// 磁盘必须能通过检查；被中断后再次调用会从中断处继续。只有root可以整理。
+    pub fn defragment(&mut self) -> Result<DefragReport, FsError> {
+        self.require_root("defrag")?;
+        pinfo();
+        println!("Defragmenting...");
+        let fsck = self.fsck(Repair::None)?;
+        if !fsck.is_clean() {
+            return Err(FsError::InvalidArgument(format!(
+                "disk has {} problems, run fsck --repair first",
+                fsck.problems.len()
+            )));
+        }
+
+        let report = DefragReport {
+            before: self.fragmentation(),
+            resumed_from: self.meta.defrag,
+            ..DefragReport::default()
+        };
+        let mut defragmenter = Defragmenter {
+            dm: self,
+            prev: HashMap::new(),
+            heads: Vec::new(),
+            report,
+        };
+        defragmenter.run()?;
+        let mut report = defragmenter.report;
+        report.after = self.fragmentation();
+
+        Ok(report)
+    }
+
+    /// 链头从`from`搬到了`to`：修改所有目录中指向它的FCB（包括“.”和“..”）、共享计数、链接数、目录配额和卷元数据的首簇，
+    /// 再重新读出当前目录。`from`的数据必须已经复制到`to`。
+    fn retarget_chain(&mut self, from: usize, to: usize) -> Result<(), FsError> {
+        self.retarget_in(ROOT_CLUSTER, from, to, &mut HashSet::new())?;
+        if let Some(count) = self.meta.shared.remove(&from) {
+            self.meta.shared.insert(to, count);
+        }
+        if let Some(count) = self.meta.links.remove(&from) {
+            self.meta.links.insert(to, count);
+        }
+        if let Some(limits) = self.meta.quotas.dirs.remove(&from) {
+            self.meta.quotas.dirs.insert(to, limits);
+        }
+        if self.disk.meta_cluster == Some(from) {
+            self.disk.meta_cluster = Some(to);
+        }
+        let mut cur_cluster = self.cur_dir.files[1].first_cluster;
+        if cur_cluster == from {
+            cur_cluster = to;
+        }
+        self.cur_dir = self.get_directory_by_cluster(cur_cluster)?;
+
+        Ok(())
+    }
+
+    /// 在首簇为`dir_cluster`的目录及其子目录中，把指向`from`的FCB改为指向`to`
+    fn retarget_in(
+        &mut self,
+        dir_cluster: usize,
+        from: usize,
+        to: usize,
+        visited: &mut HashSet<usize>,
+    ) -> Result<(), FsError> {
+        if !visited.insert(dir_cluster) {
+            return Ok(());
+        }
+        let mut dir = self.get_directory_by_cluster(dir_cluster)?;
+        let mut changed = false;
+        for fcb in dir.files.iter_mut().filter(|fcb| fcb.first_cluster == from) {
+            fcb.first_cluster = to;
+            changed = true;
+        }
+        if changed {
+            self.store_directory(&dir)?;
+        }
+        for fcb in dir.files.iter().skip(2) {
+            if let FileType::Directory = fcb.file_type {
+                self.retarget_in(fcb.first_cluster, from, to, visited)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::alloc::AllocatorKind;
+    use crate::disk_manager::clock::ManualClock;
+    use crate::disk_manager::device::{BlockDevice, FaultyDevice};
+    use crate::disk_manager::disk::{Disk, DiskGeometry};
+    use crate::disk_manager::testing::{SharedDevice, START};
+
+    const CLUSTER_SIZE: usize = 512;
+    const CLUSTER_COUNT: usize = 256;
+
+    /// 整理之后仍然要在的文件和内容
+    const FILES: [(&str, usize, u8); 4] = [
+        ("/a", 700, b'a'),
+        ("/dir/e", 1500, b'e'),
+        ("/dir/sub/f", 2000, b'f'),
+        ("/g", 3, b'g'),
+    ];
+
+    /// 建好一个文件和目录交错、簇链分成几段的磁盘，返回整个卷的内容
+    fn fragmented_volume() -> Vec<u8> {
+        let device =
+            SharedDevice::from_bytes(CLUSTER_SIZE, &vec![0u8; CLUSTER_SIZE * CLUSTER_COUNT]);
+        let mut dm = DiskManager::with_device(
+            Box::new(device.clone()),
+            DiskGeometry::new(CLUSTER_SIZE, CLUSTER_COUNT).unwrap(),
+            AllocatorKind::FirstFit,
+            Box::new(ManualClock::new(START)),
+        )
+        .unwrap();
+        for name in ["/x", "/y", "/z"] {
+            dm.create_file_with_data(name, b"hole").unwrap();
+            dm.create_file_with_data(&format!("{}-keep", name), b"keep")
+                .unwrap();
+        }
+        dm.new_directory_to_disk("/dir").unwrap();
+        for name in ["/x", "/y", "/z"] {
+            dm.delete_file_by_name(name).unwrap();
+        }
+        // 之后的文件都要拆开放进前面的空洞
+        dm.new_directory_to_disk("/dir/sub").unwrap();
+        for (path, len, byte) in FILES {
+            dm.create_file_with_data(path, &vec![byte; len]).unwrap();
+        }
+        assert!(dm.fragmentation() > 0.0);
+        drop(dm);
+
+        device.bytes()
+    }
+
+    fn open(device: impl BlockDevice + 'static) -> DiskManager {
+        DiskManager::from_disk(Disk::open(Box::new(device)).unwrap()).unwrap()
+    }
+
+    /// 磁盘能通过检查，文件都还在
+    fn assert_intact(dm: &mut DiskManager) {
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+        dm.noatime = true;
+        for (path, len, byte) in FILES {
+            assert_eq!(dm.read_file_by_name(path).unwrap(), vec![byte; len]);
+        }
+    }
+
+    #[test]
+    fn defrag_makes_every_chain_contiguous() {
+        let mut dm = open(SharedDevice::from_bytes(CLUSTER_SIZE, &fragmented_volume()));
+        let report = dm.defragment().unwrap();
+        assert!(report.before > 0.0);
+        assert_eq!(report.after, 0.0);
+        assert_eq!(report.resumed_from, None);
+        assert!(dm
+            .frag_report()
+            .unwrap()
+            .chains
+            .iter()
+            .all(|chain| chain.fragments == 1));
+        assert_intact(&mut dm);
+
+        // 已经整理好的磁盘不用再搬
+        assert_eq!(dm.defragment().unwrap().moved, 0);
+    }
+
+    #[test]
+    fn interrupted_defrag_resumes() {
+        let base = fragmented_volume();
+        let full = open(SharedDevice::from_bytes(CLUSTER_SIZE, &base))
+            .defragment()
+            .unwrap();
+        let mut resumed = 0;
+        for k in 0.. {
+            let shared = SharedDevice::from_bytes(CLUSTER_SIZE, &base);
+            let mut device = FaultyDevice::new(shared.clone());
+            device.crash_after_writes(k);
+            let done = open(device).defragment().is_ok();
+
+            let mut dm = open(SharedDevice::from_bytes(CLUSTER_SIZE, &shared.bytes()));
+            assert_intact(&mut dm);
+            if done {
+                assert_eq!(dm.fragmentation(), 0.0);
+                break;
+            }
+            let saved = dm.meta.defrag;
+            let report = dm.defragment().unwrap();
+            assert_eq!(report.resumed_from, saved);
+            assert_eq!(report.after, 0.0, "crash after {} writes", k);
+            assert_intact(&mut dm);
+            if saved.is_some() {
+                // 已经就位的簇链跳过了，没有再整理一遍
+                assert!(report.chains < full.chains);
+                resumed += 1;
+            }
+        }
+        assert!(resumed > 0);
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// 块设备：按块读写的存储介质，块大小与簇大小相同，块号即卷上的簇号。
+pub trait BlockDevice {
+    /// 块大小（字节）
+    fn block_size(&self) -> usize;
+    /// 块数量
+    fn block_count(&self) -> usize;
+    /// 读出第`index`块，`buf`长度必须等于块大小。
+    fn read_block(&self, index: usize, buf: &mut [u8]) -> io::Result<()>;
+    /// 写入第`index`块，`data`长度必须等于块大小。
+    fn write_block(&mut self, index: usize, data: &[u8]) -> io::Result<()>;
+    /// 确保之前的写入已经落到介质上。
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// 检查块号和缓冲区长度，避免越界读写。
+fn check_block_args(device: &dyn BlockDevice, index: usize, len: usize) -> io::Result<()> {
+    if index >= device.block_count() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "block {} out of range, device has {} blocks",
+                index,
+                device.block_count()
+            ),
+        ));
+    }
+    if len != device.block_size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "buffer sized {} Bytes, block size is {} Bytes",
+                len,
+                device.block_size()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// 内存块设备：整个卷都放在内存中的字节数组里。
+pub struct MemoryDevice {
+    block_size: usize,
+    data: Vec<u8>,
+}
+impl MemoryDevice {
+    /// 新建全0的内存块设备
+    pub fn new(block_size: usize, block_count: usize) -> MemoryDevice {
+        MemoryDevice {
+            block_size,
+            data: vec![0u8; block_size * block_count],
+        }
+    }
+
+    /// 用已有的字节新建内存块设备，字节长度必须是块大小的整数倍。
+    pub fn from_bytes(block_size: usize, data: Vec<u8>) -> io::Result<MemoryDevice> {
+        if !data.len().is_multiple_of(block_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "image sized {} Bytes is not a multiple of block size {}",
+                    data.len(),
+                    block_size
+                ),
+            ));
+        }
+
+        Ok(MemoryDevice { block_size, data })
+    }
+
+    /// 设备上的全部字节
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+impl BlockDevice for MemoryDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> usize {
+        self.data.len() / self.block_size
+    }
+
+    fn read_block(&self, index: usize, buf: &mut [u8]) -> io::Result<()> {
+        check_block_args(self, index, buf.len())?;
+        let offset = index * self.block_size;
+        buf.copy_from_slice(&self.data[offset..offset + self.block_size]);
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        check_block_args(self, index, data.len())?;
+        let offset = index * self.block_size;
+        self.data[offset..offset + self.block_size].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 文件块设备：在宿主机文件上按位置读写，整个卷不必全部放入内存。
+pub struct FileDevice {
+    file: File,
+    block_size: usize,
+    block_count: usize,
+}
+impl FileDevice {
+    /// 使用已打开的宿主机文件，块数量由文件长度决定。
+    pub fn from_file(file: File, block_size: usize) -> io::Result<FileDevice> {
+        let len = file.metadata()?.len() as usize;
+        if !len.is_multiple_of(block_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "image sized {} Bytes is not a multiple of block size {}",
+                    len, block_size
+                ),
+            ));
+        }
+
+        Ok(FileDevice {
+            file,
+            block_size,
+            block_count: len / block_size,
+        })
+    }
+}
+impl BlockDevice for FileDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    fn read_block(&self, index: usize, buf: &mut [u8]) -> io::Result<()> {
+        check_block_args(self, index, buf.len())?;
+        read_exact_at(&self.file, buf, (index * self.block_size) as u64)
+    }
+
+    fn write_block(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        check_block_args(self, index, data.len())?;
+        write_all_at(&self.file, data, (index * self.block_size) as u64)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// `OverlayDevice`默认最多在内存中暂存的字节数，超出的块写入暂存文件
+pub const OVERLAY_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+
+/// 用来给暂存文件取不重复的名字
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// 暂存块设备：包装另一个块设备，读取时优先使用暂存的块，写入只暂存起来，底层设备不会被改动。
+/// 加载镜像时包装只读的`FileDevice`，`save`时才把整个卷写进新文件。
+/// 内存中最多暂存`memory_limit`块，再写入新的块时放进系统临时目录中的暂存文件，
+/// 所以改动再多也不会把整个卷留在内存里。暂存文件在设备丢掉时删除，Unix上创建之后马上删除名字。
+pub struct OverlayDevice<D: BlockDevice> {
+    inner: D,
+    /// 块号 → 暂存在内存中的内容
+    blocks: HashMap<usize, Vec<u8>>,
+    /// 内存中最多暂存的块数
+    memory_limit: usize,
+    /// 内存中放不下的块，第一次用到时才创建
+    spill: Option<SpillFile>,
+}
+impl<D: BlockDevice> OverlayDevice<D> {
+    /// 内存中最多暂存`OVERLAY_MEMORY_BYTES`字节
+    pub fn new(inner: D) -> OverlayDevice<D> {
+        let memory_limit = OVERLAY_MEMORY_BYTES / inner.block_size();
+        OverlayDevice::with_memory_limit(inner, memory_limit)
+    }
+
+    /// 内存中最多暂存`memory_limit`块
+    pub fn with_memory_limit(inner: D, memory_limit: usize) -> OverlayDevice<D> {
+        OverlayDevice {
+            inner,
+            blocks: HashMap::new(),
+            memory_limit,
+            spill: None,
+        }
+    }
+
+    /// 暂存的块数量，包括暂存文件中的块
+    pub fn dirty_blocks(&self) -> usize {
+        self.blocks.len() + self.spilled_blocks()
+    }
+
+    /// 暂存文件中的块数量
+    pub fn spilled_blocks(&self) -> usize {
+        self.spill.as_ref().map_or(0, |spill| spill.slots.len())
+    }
+}
+impl<D: BlockDevice> BlockDevice for OverlayDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.inner.block_count()
+    }
+
+    fn read_block(&self, index: usize, buf: &mut [u8]) -> io::Result<()> {
+        check_block_args(self, index, buf.len())?;
+        if let Some(data) = self.blocks.get(&index) {
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+        if let Some(spill) = &self.spill {
+            if let Some(&slot) = spill.slots.get(&index) {
+                return read_exact_at(&spill.file, buf, (slot * buf.len()) as u64);
+            }
+        }
+        self.inner.read_block(index, buf)
+    }
+
+    fn write_block(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        check_block_args(self, index, data.len())?;
+        if let Some(block) = self.blocks.get_mut(&index) {
+            block.copy_from_slice(data);
+            return Ok(());
+        }
+        let spilled = self
+            .spill
+            .as_ref()
+            .is_some_and(|spill| spill.slots.contains_key(&index));
+        if !spilled && self.blocks.len() < self.memory_limit {
+            self.blocks.insert(index, data.to_vec());
+            return Ok(());
+        }
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => self.spill.insert(SpillFile::create()?),
+        };
+        let next = spill.slots.len();
+        let slot = *spill.slots.entry(index).or_insert(next);
+        write_all_at(&spill.file, data, (slot * data.len()) as u64)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `OverlayDevice`的暂存文件，块按第一次写入的顺序一个接一个地存放
+struct SpillFile {
+    file: File,
+    path: PathBuf,
+    /// 块号 → 在暂存文件中的位置（块）
+    slots: HashMap<usize, usize>,
+}
+impl SpillFile {
+    fn create() -> io::Result<SpillFile> {
+        let path = env::temp_dir().join(format!(
+            "file-system-overlay-{}-{}",
+            process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Unix上打开之后就可以删掉名字，程序异常退出也不会留下暂存文件
+        #[cfg(unix)]
+        let _ = fs::remove_file(&path);
+
+        Ok(SpillFile {
+            file,
+            path,
+            slots: HashMap::new(),
+        })
+    }
+}
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// 在文件的指定位置读满`buf`
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+/// 在文件的指定位置写入全部`data`
+#[cfg(unix)]
+pub fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+/// 在文件的指定位置读满`buf`
+#[cfg(windows)]
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 在文件的指定位置写入全部`data`
+#[cfg(windows)]
+pub fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            n => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 故障注入块设备：包装另一个块设备，在指定的块上制造读写错误和写入撕裂，用于测试。
+pub struct FaultyDevice<D: BlockDevice> {
+    inner: D,
+    /// 读取时报错的块
+    read_errors: HashSet<usize>,
+    /// 写入时报错的块，数据不会写入
+    write_errors: HashSet<usize>,
+    /// 写入撕裂的块：只写入前若干字节，然后报错
+    torn_writes: HashMap<usize, usize>,
+    /// 还能成功写入的次数，用完之后所有写入都报错且不会写入，模拟断电
+    writes_left: Option<usize>,
+}
+impl<D: BlockDevice> FaultyDevice<D> {
+    pub fn new(inner: D) -> FaultyDevice<D> {
+        FaultyDevice {
+            inner,
+            read_errors: HashSet::new(),
+            write_errors: HashSet::new(),
+            torn_writes: HashMap::new(),
+            writes_left: None,
+        }
+    }
+
+    /// 读取第`index`块时报错
+    pub fn fail_reads_on(&mut self, index: usize) -> &mut Self {
+        self.read_errors.insert(index);
+        self
+    }
+
+    /// 写入第`index`块时报错，块内容保持不变
+    pub fn fail_writes_on(&mut self, index: usize) -> &mut Self {
+        self.write_errors.insert(index);
+        self
+    }
+
+    /// 写入第`index`块时只写入前`bytes`字节，然后报错
+    pub fn tear_writes_on(&mut self, index: usize, bytes: usize) -> &mut Self {
+        self.torn_writes.insert(index, bytes);
+        self
+    }
+
+    /// 再成功写入`writes`块之后“断电”：之后的写入都报错，读取不受影响
+    pub fn crash_after_writes(&mut self, writes: usize) -> &mut Self {
+        self.writes_left = Some(writes);
+        self
+    }
+
+    /// 清除所有注入的故障
+    pub fn clear_faults(&mut self) {
+        self.read_errors.clear();
+        self.write_errors.clear();
+        self.torn_writes.clear();
+        self.writes_left = None;
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn injected_error(kind: &str, index: usize) -> io::Error {
+        io::Error::other(format!("injected {} fault on block {}", kind, index))
+    }
+}
+impl<D: BlockDevice> BlockDevice for FaultyDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.inner.block_count()
+    }
+
+    fn read_block(&self, index: usize, buf: &mut [u8]) -> io::Result<()> {
+        if self.read_errors.contains(&index) {
+            return Err(FaultyDevice::<D>::injected_error("read", index));
+        }
+        self.inner.read_block(index, buf)
+    }
+
+    fn write_block(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        check_block_args(self, index, data.len())?;
+        match &mut self.writes_left {
+            Some(0) => return Err(FaultyDevice::<D>::injected_error("crash", index)),
+            Some(left) => *left -= 1,
+            None => (),
+        }
+        if self.write_errors.contains(&index) {
+            return Err(FaultyDevice::<D>::injected_error("write", index));
+        }
+        if let Some(&bytes) = self.torn_writes.get(&index) {
+            // 只有前面一部分落到介质上
+            let mut block = vec![0u8; self.block_size()];
+            self.inner.read_block(index, &mut block)?;
+            let bytes = bytes.min(block.len());
+            block[..bytes].copy_from_slice(&data[..bytes]);
+            self.inner.write_block(index, &block)?;
+            return Err(FaultyDevice::<D>::injected_error("torn write", index));
+        }
+        self.inner.write_block(index, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::disk_manager::testing::temp_path;
+
+    const BLOCK_SIZE: usize = 512;
+
+    /// 有`blocks`块、内容全为0的宿主机文件
+    fn zeroed_file(name: &str, blocks: usize) -> (File, PathBuf) {
+        let path = temp_path(name);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len((BLOCK_SIZE * blocks) as u64).unwrap();
+        (file, path)
+    }
+
+    fn block(device: &dyn BlockDevice, index: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; device.block_size()];
+        device.read_block(index, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn file_device_reads_and_writes_in_place() {
+        let (file, path) = zeroed_file("device-rw", 4);
+        let mut device = FileDevice::from_file(file, BLOCK_SIZE).unwrap();
+        assert_eq!(device.block_count(), 4);
+        device.write_block(2, &[0xAB; BLOCK_SIZE]).unwrap();
+        device.flush().unwrap();
+        assert_eq!(block(&device, 2), [0xAB; BLOCK_SIZE]);
+        assert_eq!(block(&device, 1), [0u8; BLOCK_SIZE]);
+
+        // 数据就在文件中对应的位置上
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes[2 * BLOCK_SIZE..3 * BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == 0xAB));
+        assert!(bytes[3 * BLOCK_SIZE..].iter().all(|b| *b == 0));
+
+        assert!(device.write_block(4, &[0u8; BLOCK_SIZE]).is_err());
+        assert!(device.read_block(0, &mut [0u8; 16]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_device_needs_whole_blocks() {
+        let (file, path) = zeroed_file("device-odd", 2);
+        file.set_len((BLOCK_SIZE * 2 + 1) as u64).unwrap();
+        assert!(FileDevice::from_file(file, BLOCK_SIZE).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overlay_device_leaves_the_file_alone() {
+        let (file, path) = zeroed_file("device-overlay", 4);
+        let mut device = OverlayDevice::new(FileDevice::from_file(file, BLOCK_SIZE).unwrap());
+        device.write_block(1, &[7u8; BLOCK_SIZE]).unwrap();
+        device.flush().unwrap();
+        assert_eq!(block(&device, 1), [7u8; BLOCK_SIZE]);
+        assert_eq!(block(&device, 2), [0u8; BLOCK_SIZE]);
+        assert_eq!(device.dirty_blocks(), 1);
+        assert!(device.write_block(4, &[0u8; BLOCK_SIZE]).is_err());
+
+        assert!(fs::read(&path).unwrap().iter().all(|b| *b == 0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overlay_device_spills_beyond_its_memory_limit() {
+        let (file, path) = zeroed_file("device-spill", 8);
+        let inner = FileDevice::from_file(file, BLOCK_SIZE).unwrap();
+        let mut device = OverlayDevice::with_memory_limit(inner, 2);
+        for index in 0..5 {
+            device
+                .write_block(index, &[index as u8 + 1; BLOCK_SIZE])
+                .unwrap();
+        }
+        assert_eq!((device.dirty_blocks(), device.spilled_blocks()), (5, 3));
+        // 改写已经暂存的块不会多占位置
+        device.write_block(0, &[9u8; BLOCK_SIZE]).unwrap();
+        device.write_block(4, &[8u8; BLOCK_SIZE]).unwrap();
+        assert_eq!((device.dirty_blocks(), device.spilled_blocks()), (5, 3));
+        for (index, byte) in [(0, 9), (1, 2), (2, 3), (3, 4), (4, 8), (5, 0)] {
+            assert_eq!(block(&device, index), [byte; BLOCK_SIZE], "block {}", index);
+        }
+        let spill = device.spill.as_ref().unwrap();
+        assert_eq!(spill.file.metadata().unwrap().len(), 3 * BLOCK_SIZE as u64);
+        let spill = spill.path.clone();
+
+        assert!(fs::read(&path).unwrap().iter().all(|b| *b == 0));
+        drop(device);
+        assert!(!spill.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn faulty_device_injects_faults_on_chosen_blocks() {
+        let mut device = FaultyDevice::new(MemoryDevice::new(BLOCK_SIZE, 8));
+        device
+            .fail_reads_on(1)
+            .fail_writes_on(2)
+            .tear_writes_on(3, 10);
+
+        assert!(device.read_block(1, &mut [0u8; BLOCK_SIZE]).is_err());
+        assert_eq!(block(&device, 0), [0u8; BLOCK_SIZE]);
+        assert!(device.write_block(2, &[1u8; BLOCK_SIZE]).is_err());
+        assert_eq!(block(device.inner(), 2), [0u8; BLOCK_SIZE]);
+        // 撕裂的写入只留下前10字节
+        assert!(device.write_block(3, &[1u8; BLOCK_SIZE]).is_err());
+        let torn = block(device.inner(), 3);
+        assert!(torn[..10].iter().all(|b| *b == 1));
+        assert!(torn[10..].iter().all(|b| *b == 0));
+        device.write_block(4, &[1u8; BLOCK_SIZE]).unwrap();
+
+        device.clear_faults();
+        device.read_block(1, &mut [0u8; BLOCK_SIZE]).unwrap();
+        device.write_block(2, &[1u8; BLOCK_SIZE]).unwrap();
+        assert_eq!(block(&device, 2), [1u8; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn faulty_device_stops_writing_after_a_crash() {
+        let mut device = FaultyDevice::new(MemoryDevice::new(BLOCK_SIZE, 8));
+        device.crash_after_writes(2);
+        device.write_block(0, &[1u8; BLOCK_SIZE]).unwrap();
+        device.write_block(1, &[1u8; BLOCK_SIZE]).unwrap();
+        assert!(device.write_block(2, &[1u8; BLOCK_SIZE]).is_err());
+        assert!(device.write_block(0, &[2u8; BLOCK_SIZE]).is_err());
+        // 读取不受影响，崩溃之后的写入都没有落到介质上
+        assert_eq!(block(&device, 0), [1u8; BLOCK_SIZE]);
+        assert_eq!(block(&device, 2), [0u8; BLOCK_SIZE]);
+
+        let inner = device.into_inner();
+        assert_eq!(&inner.as_bytes()[..BLOCK_SIZE], &[1u8; BLOCK_SIZE][..]);
+    }
+}
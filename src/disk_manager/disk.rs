@@ -1,108 +1,667 @@
-use std::mem::size_of;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
 
-use serde::{Deserialize, Serialize};
+use super::alloc::{Allocator, AllocatorKind, FreeSpace};
+use super::device::{self, BlockDevice, FileDevice, MemoryDevice, OverlayDevice};
+use super::error::FsError;
+use super::image::{
+    self, Superblock, FAT_ENTRY_SIZE, FAT_START_CLUSTER, MAX_CLUSTER_NO, SUPERBLOCK_CLUSTER,
+    SUPERBLOCK_SIZE,
+};
+use super::journal::{self, Recovery};
 
-/// 簇大小：1KiB
-pub const BLOCK_SIZE: usize = 1024;
-/// 簇数量
-pub const BLOCK_COUNT: usize = 1000;
-/// 定义从后向前扫描时的EoF
-pub const EOF_BYTE: u8 = 255;
+/// 默认簇大小：1KiB
+pub const DEFAULT_CLUSTER_SIZE: usize = 1024;
+/// 默认簇数量
+pub const DEFAULT_CLUSTER_COUNT: usize = 1000;
+/// 允许的最小簇大小
+pub const MIN_CLUSTER_SIZE: usize = 512;
+/// 允许的最大簇大小
+pub const MAX_CLUSTER_SIZE: usize = 64 * 1024;
+
+/// 磁盘几何参数，在格式化时确定，并保存在镜像的超级块中。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskGeometry {
+    /// 簇大小（字节）
+    pub cluster_size: usize,
+    /// 卷上的簇总数，包括保留簇
+    pub cluster_count: usize,
+    /// 卷开头保留、不存放数据的簇数量
+    pub reserved_clusters: usize,
+    /// 日志区的簇数量，位于保留区的末尾，0表示没有日志区
+    pub journal_clusters: usize,
+}
+impl DiskGeometry {
+    /// 根据簇大小和簇数量生成几何参数，保留簇和日志区的数量按容纳超级块和FAT表所需自动计算。
+    pub fn new(cluster_size: usize, cluster_count: usize) -> Result<DiskGeometry, FsError> {
+        DiskGeometry::check_cluster_size(cluster_size)?;
+        // 每一个簇都有一个对应的FAT项，所以需要在总数中减去FAT项占用的簇
+        let fat_clusters = image::fat_clusters(cluster_size, cluster_count);
+        let journal_clusters = journal::default_clusters(fat_clusters);
+        let reserved_clusters = FAT_START_CLUSTER + fat_clusters + journal_clusters;
+        DiskGeometry::with_reserved(
+            cluster_size,
+            cluster_count,
+            reserved_clusters,
+            journal_clusters,
+        )
+    }
+
+    /// 指定全部参数生成几何参数，并检查参数是否合法。
+    pub fn with_reserved(
+        cluster_size: usize,
+        cluster_count: usize,
+        reserved_clusters: usize,
+        journal_clusters: usize,
+    ) -> Result<DiskGeometry, FsError> {
+        DiskGeometry::check_cluster_size(cluster_size)?;
+        if cluster_count > MAX_CLUSTER_NO {
+            return Err(FsError::InvalidArgument(format!(
+                "too many clusters, at most {} allowed",
+                MAX_CLUSTER_NO
+            )));
+        }
+        // 至少要能放下根目录和一个文件
+        if cluster_count < reserved_clusters + 2 {
+            return Err(FsError::InvalidArgument(format!(
+                "too few clusters, {} reserved, {} in total",
+                reserved_clusters, cluster_count
+            )));
+        }
+        // 保留区要能放下超级块、整个FAT表和日志区
+        let fat_clusters = image::fat_clusters(cluster_size, cluster_count - reserved_clusters);
+        if reserved_clusters < FAT_START_CLUSTER + fat_clusters + journal_clusters {
+            return Err(FsError::InvalidArgument(format!(
+                "too few reserved clusters, FAT needs {} and journal {}",
+                fat_clusters, journal_clusters
+            )));
+        }
+        if journal_clusters == 1 {
+            return Err(FsError::InvalidArgument(String::from(
+                "journal needs at least 2 clusters",
+            )));
+        }
+
+        Ok(DiskGeometry {
+            cluster_size,
+            cluster_count,
+            reserved_clusters,
+            journal_clusters,
+        })
+    }
+
+    /// FAT区占用的簇数量，FAT区一直延伸到日志区之前
+    pub fn fat_clusters(&self) -> usize {
+        self.journal_start() - FAT_START_CLUSTER
+    }
+
+    /// 日志区的起始簇
+    pub fn journal_start(&self) -> usize {
+        self.reserved_clusters - self.journal_clusters
+    }
+
+    /// 簇大小必须是2的幂，且在允许的范围内
+    fn check_cluster_size(cluster_size: usize) -> Result<(), FsError> {
+        if !cluster_size.is_power_of_two()
+            || !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&cluster_size)
+        {
+            return Err(FsError::InvalidArgument(format!(
+                "cluster size must be a power of two between {} and {} Bytes",
+                MIN_CLUSTER_SIZE, MAX_CLUSTER_SIZE
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// 可用于存放数据的簇数量，即FAT表的项数
+    pub fn data_clusters(&self) -> usize {
+        self.cluster_count - self.reserved_clusters
+    }
+
+    /// 磁盘总大小（字节）
+    pub fn disk_size(&self) -> usize {
+        self.cluster_size * self.cluster_count
+    }
+}
+impl Default for DiskGeometry {
+    fn default() -> Self {
+        DiskGeometry::new(DEFAULT_CLUSTER_SIZE, DEFAULT_CLUSTER_COUNT).unwrap()
+    }
+}
 
-#[derive(Serialize, Deserialize)]
 pub struct Disk {
-    pub fat: Vec<FatItem>,
-    data: Vec<u8>,
+    pub geometry: DiskGeometry,
+    /// 只能通过`set_fat`修改，以便同时更新空闲簇索引
+    fat: Vec<FatItem>,
+    /// 空闲簇索引
+    free: FreeSpace,
+    /// 分配策略
+    allocator: Box<dyn Allocator>,
+    /// 卷元数据的首个数据簇
+    pub meta_cluster: Option<usize>,
+    /// 存放整个卷的块设备，布局见`image`模块
+    device: Box<dyn BlockDevice>,
+    /// 还没有提交的元数据块：卷上的块号 → 新内容，读取时优先使用
+    pending: BTreeMap<usize, Vec<u8>>,
+    /// 改动过、还没有提交的FAT块，按FAT区内的序号
+    dirty_fat: BTreeSet<usize>,
+    /// 还没有提交就被释放的簇。磁盘上的旧状态可能还在使用它们，提交之前不能再分配
+    released: BTreeSet<usize>,
+    /// 进行中的事务的撤销记录
+    undo: Option<Undo>,
+    /// 加载时日志区的处理结果
+    pub recovery: Recovery,
+    /// 加载时镜像的格式版本，新磁盘为当前版本
+    pub version: u32,
 }
+
+/// 撤销一个事务需要的信息
+struct Undo {
+    /// 改动过的FAT项原来的值，按改动的顺序排列
+    fat: Vec<(usize, FatItem)>,
+    pending: BTreeMap<usize, Vec<u8>>,
+    released: BTreeSet<usize>,
+    meta_cluster: Option<usize>,
+}
+
 impl Disk {
-    pub fn new() -> Disk {
-        Disk {
-            // 创建FAT文件分配表
-            fat: vec![FatItem::NotUsed; BLOCK_COUNT],
-            // 数据区，初始值为0，块大小为1024.
-            // 每一个块都有一个对应的FAT项，所以真实的数据区域需要在总数中减去FAT项的数据大小
-            data: vec![
-                0u8;
-                (BLOCK_COUNT - size_of::<FatItem>() * BLOCK_COUNT / BLOCK_SIZE - 1)
-                    * BLOCK_SIZE
-            ],
-        }
-    }
-
-    /// 向disk的data中插入数据。插入的数据将覆写相应位置的数据。
-    pub fn insert_data_by_offset(&mut self, data: &[u8], offset: usize) {
-        self.data
-            .splice(offset..(offset + data.len()), data.iter().cloned());
-    }
-    /// 向disk中的data插入数据。插入数据将覆写相应的位置。
-    pub fn insert_data_by_cluster(&mut self, data: &[u8], cluster: usize) {
-        self.insert_data_by_offset(data, cluster * BLOCK_SIZE);
-    }
-
-    /// 向disk中的data插入数据。插入数据将覆写相应的位置。
-    pub fn write_data_by_clusters_with_eof(
-        &mut self,
-        data: &[u8],
-        clusters: &[usize],
-        insert_eof: bool,
-    ) {
-        for i in 0..clusters.len() {
-            if i < clusters.len() - 1 {
-                // 正常分BLOCK_SIZE写入簇
-                self.insert_data_by_cluster(
-                    &data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE],
-                    clusters[i],
-                );
-            } else {
-                // 开始写入最后一个块
-                let mut buffer: Vec<u8> = Vec::with_capacity(BLOCK_SIZE);
-                buffer.extend((&data[i * BLOCK_SIZE..data.len()]).iter());
-                if insert_eof {
-                    // 插入EoF
-                    buffer.push(EOF_BYTE);
+    /// 在内存块设备上新建磁盘
+    pub fn new(geometry: DiskGeometry) -> Disk {
+        let device = MemoryDevice::new(geometry.cluster_size, geometry.cluster_count);
+        Disk::with_device(Box::new(device), geometry).unwrap()
+    }
+
+    /// 在给出的块设备上新建磁盘，设备的块大小和块数量必须与几何参数一致。
+    pub fn with_device(
+        mut device: Box<dyn BlockDevice>,
+        geometry: DiskGeometry,
+    ) -> Result<Disk, FsError> {
+        Disk::check_device(device.as_ref(), &geometry)?;
+        // 格式化时直接清空FAT区和日志区，不经过日志
+        let zeros = vec![0u8; geometry.cluster_size];
+        for block in FAT_START_CLUSTER..geometry.reserved_clusters {
+            device.write_block(block, &zeros)?;
+        }
+        device.flush()?;
+        // 创建FAT文件分配表，每一个数据簇都有一个对应的FAT项
+        let fat = vec![FatItem::NotUsed; geometry.data_clusters()];
+
+        Ok(Disk {
+            geometry,
+            free: FreeSpace::from_fat(&fat),
+            fat,
+            allocator: AllocatorKind::default().build(),
+            meta_cluster: None,
+            device,
+            pending: BTreeMap::new(),
+            // 超级块在第一次提交时写入
+            dirty_fat: BTreeSet::new(),
+            released: BTreeSet::new(),
+            undo: None,
+            recovery: Recovery::Clean,
+            version: image::FORMAT_VERSION,
+        })
+    }
+
+    /// 从块设备中读出磁盘，处理日志区，然后检查超级块并解码FAT表。
+    pub fn open(mut device: Box<dyn BlockDevice>) -> Result<Disk, FsError> {
+        let mut superblock = vec![0u8; device.block_size()];
+        device.read_block(SUPERBLOCK_CLUSTER, &mut superblock)?;
+        let geometry = image::decode_superblock(&superblock)?.geometry;
+        Disk::check_device(device.as_ref(), &geometry)?;
+        let recovery = journal::recover(device.as_mut(), &geometry)?;
+
+        // 重放事务可能改写了超级块
+        device.read_block(SUPERBLOCK_CLUSTER, &mut superblock)?;
+        let Superblock {
+            version,
+            geometry,
+            meta_cluster,
+            allocator,
+        } = image::decode_superblock(&superblock)?;
+
+        // 按块读出整个FAT区
+        let mut fat_bytes = vec![0u8; geometry.fat_clusters() * geometry.cluster_size];
+        for (i, block) in fat_bytes.chunks_mut(geometry.cluster_size).enumerate() {
+            device.read_block(FAT_START_CLUSTER + i, block)?;
+        }
+        let fat = fat_bytes
+            .chunks_exact(FAT_ENTRY_SIZE)
+            .take(geometry.data_clusters())
+            .map(|chunk| {
+                let mut bytes = [0u8; FAT_ENTRY_SIZE];
+                bytes.copy_from_slice(chunk);
+                image::decode_fat_item(bytes)
+            })
+            .collect::<Vec<FatItem>>();
+
+        Ok(Disk {
+            geometry,
+            free: FreeSpace::from_fat(&fat),
+            fat,
+            allocator: allocator.build(),
+            meta_cluster,
+            device,
+            pending: BTreeMap::new(),
+            dirty_fat: BTreeSet::new(),
+            released: BTreeSet::new(),
+            undo: None,
+            recovery,
+            version,
+        })
+    }
+
+    /// 从镜像字节中读出磁盘，整个卷放在内存块设备中。
+    pub fn from_image(volume: Vec<u8>) -> Result<Disk, FsError> {
+        let geometry = image::decode_superblock(&volume)?.geometry;
+        let device = MemoryDevice::from_bytes(geometry.cluster_size, volume)?;
+        Disk::open(Box::new(device))
+    }
+
+    /// 以宿主机文件作为块设备打开镜像，块在用到时才从文件中读出。文件本身不会被改动，
+    /// 改动过的块暂存在`OverlayDevice`中，直到把镜像保存到新文件。
+    pub fn open_file<P: AsRef<Path>>(path: P) -> Result<Disk, FsError> {
+        Disk::open(Disk::file_device(path)?)
+    }
+
+    /// 把块设备换成宿主机文件`path`，文件必须是刚刚由`write_image`写出的同一个卷。
+    /// 保存之后调用，之前暂存在内存中的块都已经写进了文件。
+    pub fn reopen_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), FsError> {
+        let device = Disk::file_device(path)?;
+        Disk::check_device(device.as_ref(), &self.geometry)?;
+        self.device = device;
+
+        Ok(())
+    }
+
+    /// 宿主机文件`path`上只读的文件块设备，外面包着暂存写入的`OverlayDevice`
+    fn file_device<P: AsRef<Path>>(path: P) -> Result<Box<dyn BlockDevice>, FsError> {
+        let file = File::open(path)?;
+        let mut superblock = [0u8; SUPERBLOCK_SIZE];
+        device::read_exact_at(&file, &mut superblock, 0)
+            .map_err(|_| FsError::InvalidImage(String::from("image is too short")))?;
+        let geometry = image::decode_superblock(&superblock)?.geometry;
+        let device = FileDevice::from_file(file, geometry.cluster_size)?;
+
+        Ok(Box::new(OverlayDevice::new(device)))
+    }
+
+    /// 块设备与几何参数必须一致
+    fn check_device(device: &dyn BlockDevice, geometry: &DiskGeometry) -> Result<(), FsError> {
+        if device.block_size() != geometry.cluster_size
+            || device.block_count() != geometry.cluster_count
+        {
+            return Err(FsError::InvalidArgument(format!(
+                "device has {} blocks of {} Bytes, disk needs {} clusters of {} Bytes",
+                device.block_count(),
+                device.block_size(),
+                geometry.cluster_count,
+                geometry.cluster_size
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// 提交：把超级块、改动过的FAT块和暂存的元数据块作为一个事务写入块设备，见`journal`模块。
+    /// 写入失败时改动仍然留在内存中，下次提交时一起写入。事务太大、日志区和空闲簇都放不下时
+    /// 返回`FsError::NoSpace`，这时什么也没有写，进行中的事务仍然可以撤销。
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut blocks = BTreeMap::new();
+        blocks.insert(
+            SUPERBLOCK_CLUSTER,
+            image::encode_superblock(&Superblock {
+                version: image::FORMAT_VERSION,
+                geometry: self.geometry,
+                meta_cluster: self.meta_cluster,
+                allocator: self.allocator.kind(),
+            }),
+        );
+        for index in &self.dirty_fat {
+            blocks.insert(FAT_START_CLUSTER + index, self.encode_fat_block(*index));
+        }
+        for (block, data) in &self.pending {
+            blocks.insert(*block, data.clone());
+        }
+        // 日志区放不下的块暂存在空闲簇中，提交之前和之后的状态都不会用到它们
+        let needed = journal::spill_needed(&self.geometry, blocks.len()).ok_or(FsError::NoSpace)?;
+        let spill: Vec<usize> = self
+            .free
+            .iter_from(0)
+            .take(needed)
+            .map(|cluster| self.block_of(cluster))
+            .collect();
+        if spill.len() < needed {
+            return Err(FsError::NoSpace.into());
+        }
+        self.undo = None;
+        journal::write_transaction(self.device.as_mut(), &self.geometry, &blocks, &spill)?;
+
+        self.dirty_fat.clear();
+        self.pending.clear();
+        // 提交之后，释放的簇才能再分配
+        for cluster in std::mem::take(&mut self.released) {
+            self.free.insert(cluster);
+        }
+
+        Ok(())
+    }
+
+    /// FAT区中第`index`块的内容，FAT表以外的部分为0
+    fn encode_fat_block(&self, index: usize) -> Vec<u8> {
+        let cluster_size = self.cluster_size();
+        let per_block = cluster_size / FAT_ENTRY_SIZE;
+        let mut block = Vec::with_capacity(cluster_size);
+        for item in self.fat.iter().skip(index * per_block).take(per_block) {
+            block.extend_from_slice(&image::encode_fat_item(item));
+        }
+        block.resize(cluster_size, 0u8);
+
+        block
+    }
+
+    /// 开始一个事务，之后对FAT表和元数据的改动可以用`rollback`撤销
+    pub fn begin(&mut self) {
+        self.undo = Some(Undo {
+            fat: Vec::new(),
+            pending: self.pending.clone(),
+            released: self.released.clone(),
+            meta_cluster: self.meta_cluster,
+        });
+    }
+
+    /// 事务开始之后还没有写过块设备，可以撤销
+    pub fn can_rollback(&self) -> bool {
+        self.undo.is_some()
+    }
+
+    /// 撤销事务开始以来对FAT表、暂存的元数据块和卷元数据首簇的改动。
+    /// 直接写入数据簇的文件内容不会被撤销，这些新分配的簇在撤销之后又是空闲的。
+    pub fn rollback(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            for (cluster, item) in undo.fat.into_iter().rev() {
+                match item {
+                    FatItem::NotUsed if !undo.released.contains(&cluster) => {
+                        self.free.insert(cluster)
+                    }
+                    _ => self.free.remove(cluster),
                 }
-                if buffer.len() < BLOCK_SIZE {
-                    // 若未到 BLOCK_SIZE 则用0填充
-                    let mut zero = vec![0u8; BLOCK_SIZE - buffer.len()];
-                    buffer.append(&mut zero);
+                self.fat[cluster] = item;
+            }
+            self.pending = undo.pending;
+            self.released = undo.released;
+            self.meta_cluster = undo.meta_cluster;
+        }
+    }
+
+    /// 刷新后将整个镜像按块写出。
+    pub fn write_image<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.flush()?;
+        let mut buffer = vec![0u8; self.cluster_size()];
+        for index in 0..self.geometry.cluster_count {
+            self.device.read_block(index, &mut buffer)?;
+            writer.write_all(&buffer)?;
+        }
+
+        writer.flush()
+    }
+
+    /// FAT表
+    pub fn fat(&self) -> &[FatItem] {
+        &self.fat
+    }
+
+    /// 修改一个FAT项，同时更新空闲簇索引。释放的簇要等提交之后才能再分配。
+    pub fn set_fat(&mut self, cluster: usize, item: FatItem) {
+        if let Some(undo) = &mut self.undo {
+            undo.fat.push((cluster, self.fat[cluster].clone()));
+        }
+        match item {
+            FatItem::NotUsed => {
+                if !self.free.contains(cluster) {
+                    self.released.insert(cluster);
                 }
-                self.insert_data_by_cluster(buffer.as_slice(), clusters[i])
+            }
+            _ => {
+                self.free.remove(cluster);
+                self.released.remove(&cluster);
+            }
+        }
+        self.dirty_fat
+            .insert(cluster * FAT_ENTRY_SIZE / self.cluster_size());
+        self.fat[cluster] = item;
+    }
+
+    /// 空闲簇索引
+    pub fn free_space(&self) -> &FreeSpace {
+        &self.free
+    }
+
+    /// 当前的分配策略
+    pub fn allocator(&self) -> AllocatorKind {
+        self.allocator.kind()
+    }
+
+    /// 更换分配策略，保存时写入超级块
+    pub fn set_allocator(&mut self, kind: AllocatorKind) {
+        self.allocator = kind.build();
+    }
+
+    /// 按分配策略挑选`count`个空闲簇，但不标记为已用。空闲簇不够时返回`None`。
+    pub fn pick_free_clusters(&mut self, count: usize) -> Option<Vec<usize>> {
+        self.allocator.allocate(&self.free, count)
+    }
+
+    /// 为接在`tail`后面的簇链挑选`count`个空闲簇，但不标记为已用：紧挨着`tail`的空闲簇优先，
+    /// 剩下的按分配策略挑选。空闲簇不够时返回`None`。
+    pub fn pick_free_clusters_after(&mut self, tail: usize, count: usize) -> Option<Vec<usize>> {
+        let mut clusters: Vec<usize> = (tail + 1..)
+            .take_while(|cluster| self.free.contains(*cluster))
+            .take(count)
+            .collect();
+        if clusters.len() < count {
+            // 挑剩下的时候不能再选到已经挑出来的簇
+            for cluster in &clusters {
+                self.free.remove(*cluster);
+            }
+            let rest = self.allocator.allocate(&self.free, count - clusters.len());
+            for cluster in &clusters {
+                self.free.insert(*cluster);
+            }
+            clusters.extend(rest?);
+        }
+
+        Some(clusters)
+    }
+
+    /// 簇大小（字节）
+    pub fn cluster_size(&self) -> usize {
+        self.geometry.cluster_size
+    }
+
+    /// 数据簇在卷上的块号
+    fn block_of(&self, cluster: usize) -> usize {
+        self.geometry.reserved_clusters + cluster
+    }
+
+    /// 向disk中的数据簇插入数据，从簇的开头覆写，簇中其余部分保持不变。
+    pub fn insert_data_by_cluster(&mut self, data: &[u8], cluster: usize) -> io::Result<()> {
+        self.insert_data_at(data, cluster, 0)
+    }
+
+    /// 从簇内偏移`offset`处写入数据，簇内其他数据不变。数据不能超出簇的末尾。
+    /// 数据直接写入块设备，不经过日志。
+    pub fn insert_data_at(&mut self, data: &[u8], cluster: usize, offset: usize) -> io::Result<()> {
+        let block = self.block_of(cluster);
+        if offset == 0 && data.len() == self.cluster_size() {
+            // 这个簇暂存的旧内容已经没有用了，不能在提交时盖掉新数据
+            self.pending.remove(&block);
+            self.device.write_block(block, data)
+        } else {
+            let mut buffer = self.read_data_by_cluster(cluster)?;
+            buffer[offset..offset + data.len()].copy_from_slice(data);
+            self.pending.remove(&block);
+            self.device.write_block(block, &buffer)
+        }
+    }
+
+    /// 按给出的簇号顺序暂存元数据（目录文件、卷元数据），提交时经过日志写入。
+    /// 最后一个簇不足一个簇大小的部分用0填充。
+    pub fn write_metadata_by_clusters(&mut self, data: &[u8], clusters: &[usize]) {
+        let cluster_size = self.cluster_size();
+        for (i, cluster) in clusters.iter().enumerate() {
+            let start = (i * cluster_size).min(data.len());
+            let end = ((i + 1) * cluster_size).min(data.len());
+            let mut buffer = data[start..end].to_vec();
+            buffer.resize(cluster_size, 0u8);
+            self.pending.insert(self.block_of(*cluster), buffer);
+        }
+    }
+
+    /// 按给出的簇号顺序写入数据，最后一个簇不足一个簇大小的部分用0填充。
+    /// 数据的真实长度不写入簇中，由FCB中的`length`记录。
+    pub fn write_data_by_clusters(&mut self, data: &[u8], clusters: &[usize]) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        for (i, cluster) in clusters.iter().enumerate() {
+            let start = (i * cluster_size).min(data.len());
+            let end = ((i + 1) * cluster_size).min(data.len());
+            if end - start == cluster_size {
+                // 正常按簇大小写入簇
+                self.insert_data_by_cluster(&data[start..end], *cluster)?;
+            } else {
+                // 不足一个簇的部分用0填充
+                let mut buffer: Vec<u8> = Vec::with_capacity(cluster_size);
+                buffer.extend_from_slice(&data[start..end]);
+                buffer.resize(cluster_size, 0u8);
+                self.insert_data_by_cluster(buffer.as_slice(), *cluster)?;
             }
         }
+
+        Ok(())
     }
 
-    /// 从disk中读取数据。
-    pub fn read_data_by_cluster(&self, cluster: usize) -> Vec<u8> {
-        (&self.data[cluster * BLOCK_SIZE..(cluster + 1) * BLOCK_SIZE]).to_vec()
+    /// 从disk中读取数据，暂存的元数据优先。
+    pub fn read_data_by_cluster(&self, cluster: usize) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.pending.get(&self.block_of(cluster)) {
+            return Ok(data.clone());
+        }
+        let mut buffer = vec![0u8; self.cluster_size()];
+        self.device
+            .read_block(self.block_of(cluster), &mut buffer)?;
+
+        Ok(buffer)
     }
 
-    /// 工具给出的簇号，读出所有数据，并且检测EoF。
-    pub fn read_data_by_clusters_without_eof(&self, clusters: &[usize]) -> Vec<u8> {
-        let mut data: Vec<u8> = Vec::with_capacity(clusters.len() * BLOCK_SIZE);
+    /// 按给出的簇号顺序读出数据，并截断为`length`字节。
+    pub fn read_data_by_clusters(&self, clusters: &[usize], length: usize) -> io::Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(clusters.len() * self.cluster_size());
 
         // 循环读出所有数据
         for cluster in clusters {
-            let mut buffer = self.read_data_by_cluster(*cluster);
+            let mut buffer = self.read_data_by_cluster(*cluster)?;
             data.append(&mut buffer);
         }
-        // 从后向前查找，从EoF开始截断。若未找到EoF则直接返回。
-        for i in 1..BLOCK_SIZE {
-            let index = data.len() - i;
-            if data[index] == EOF_BYTE {
-                // 不加不减，刚好将EoF截断在外
-                data.truncate(index);
-                break;
-            }
-        }
+        // 最后一个簇中的填充部分不属于文件
+        data.truncate(length);
 
-        data
+        Ok(data)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FatItem {
     NotUsed,          // 未使用的簇
     ClusterNo(usize), // 指向下一个的簇号
     BadCluster,       // 坏簇
     EoF,              // 文件结束
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::clock::ManualClock;
+    use crate::disk_manager::testing::{temp_path, START};
+    use crate::disk_manager::DiskManager;
+
+    fn invalid(result: Result<DiskGeometry, FsError>) -> bool {
+        matches!(result, Err(FsError::InvalidArgument(_)))
+    }
+
+    #[test]
+    fn geometry_rejects_bad_cluster_sizes() {
+        for cluster_size in [0, 1000, 1536, MIN_CLUSTER_SIZE / 2, MAX_CLUSTER_SIZE * 2] {
+            assert!(
+                invalid(DiskGeometry::new(cluster_size, 100)),
+                "{}",
+                cluster_size
+            );
+            assert!(invalid(DiskGeometry::with_reserved(
+                cluster_size,
+                100,
+                2,
+                0
+            )));
+        }
+        for cluster_size in [MIN_CLUSTER_SIZE, 4096, MAX_CLUSTER_SIZE] {
+            assert!(DiskGeometry::new(cluster_size, 100).is_ok());
+        }
+    }
+
+    #[test]
+    fn geometry_needs_room_for_fat_and_root() {
+        // 超级块、FAT和日志之外至少还要有两个簇
+        let geometry = DiskGeometry::new(512, 100).unwrap();
+        let smallest = geometry.reserved_clusters + 2;
+        assert!(invalid(DiskGeometry::new(512, 3)));
+        assert!(invalid(DiskGeometry::with_reserved(512, 3, 2, 0)));
+        assert!(DiskGeometry::with_reserved(512, 4, 2, 0).is_ok());
+        assert!(DiskGeometry::with_reserved(
+            512,
+            smallest,
+            geometry.reserved_clusters,
+            geometry.journal_clusters
+        )
+        .is_ok());
+        assert!(invalid(DiskGeometry::with_reserved(
+            512,
+            smallest - 1,
+            geometry.reserved_clusters,
+            geometry.journal_clusters
+        )));
+        // 保留区放不下FAT或日志区
+        assert!(invalid(DiskGeometry::with_reserved(512, 1000, 2, 0)));
+        assert!(invalid(DiskGeometry::with_reserved(512, 100, 3, 2)));
+        assert!(invalid(DiskGeometry::with_reserved(512, 100, 3, 1)));
+        assert!(invalid(DiskGeometry::new(512, MAX_CLUSTER_NO + 1)));
+    }
+
+    #[test]
+    fn geometry_is_kept_by_save_and_load() {
+        let geometry = DiskGeometry::new(4096, 200).unwrap();
+        assert_ne!(geometry, DiskGeometry::default());
+        let mut dm = DiskManager::new(
+            None,
+            geometry,
+            AllocatorKind::BestFit,
+            Box::new(ManualClock::new(START)),
+        );
+        dm.create_file_with_data("/f", &vec![1u8; 3 * 4096])
+            .unwrap();
+        let info = dm.get_disk_info();
+        assert_eq!(info.0, 4096 * 200);
+        assert_eq!(info.1 + info.2, geometry.data_clusters());
+
+        let path = temp_path("disk-geometry.vd");
+        dm.save_to_file(&path, 0).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4096 * 200);
+        let mut loaded = DiskManager::open_file(&path).unwrap();
+        assert_eq!(loaded.disk.geometry, geometry);
+        assert_eq!(loaded.disk.cluster_size(), 4096);
+        assert_eq!(loaded.get_disk_info(), info);
+        assert_eq!(loaded.read_file_by_name("/f").unwrap(), vec![1u8; 3 * 4096]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
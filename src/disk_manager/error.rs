@@ -0,0 +1,91 @@
+use std::{error, fmt, io};
+
+/// 文件系统操作的错误
+#[derive(Debug)]
+pub enum FsError {
+    /// 找不到文件或目录
+    NotFound(String),
+    /// 同名的文件或目录已经存在
+    AlreadyExists(String),
+    /// 需要目录，但给出的是文件
+    NotADirectory(String),
+    /// 需要文件，但给出的是目录
+    IsADirectory(String),
+    /// 目录非空，不能删除
+    DirectoryNotEmpty(String),
+    /// 磁盘上没有足够的空簇
+    NoSpace,
+    /// 簇链或目录文件损坏
+    Corrupt { cluster: usize, reason: String },
+    /// 参数不合法，例如文件名或磁盘几何参数
+    InvalidArgument(String),
+    /// 当前用户没有权限
+    PermissionDenied(String),
+    /// 超出了用户或目录的硬配额
+    QuotaExceeded(String),
+    /// 跟随的符号链接太多，多半是成环了
+    SymlinkLoop(String),
+    /// 不是合法的镜像，或镜像版本不受支持
+    InvalidImage(String),
+    /// 块设备或宿主机I/O错误
+    Io(io::Error),
+}
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound(name) => write!(f, "No such file or directory: '{}'.", name),
+            FsError::AlreadyExists(name) => write!(f, "'{}' already exists.", name),
+            FsError::NotADirectory(name) => write!(f, "'{}' is not a directory.", name),
+            FsError::IsADirectory(name) => write!(f, "'{}' is a directory.", name),
+            FsError::DirectoryNotEmpty(name) => write!(f, "Directory '{}' is not empty.", name),
+            FsError::NoSpace => write!(f, "No space left on disk."),
+            FsError::Corrupt { cluster, reason } => {
+                write!(f, "Disk corrupt at cluster {}: {}.", cluster, reason)
+            }
+            FsError::InvalidArgument(reason) => write!(f, "Invalid argument: {}.", reason),
+            FsError::PermissionDenied(name) => write!(f, "Permission denied: '{}'.", name),
+            FsError::QuotaExceeded(target) => write!(f, "Disk quota exceeded: {}.", target),
+            FsError::SymlinkLoop(path) => {
+                write!(f, "Too many levels of symbolic links: '{}'.", path)
+            }
+            FsError::InvalidImage(reason) => write!(f, "Invalid disk image: {}.", reason),
+            FsError::Io(err) => write!(f, "I/O failed: {}.", err),
+        }
+    }
+}
+impl error::Error for FsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FsError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for FsError {
+    fn from(err: io::Error) -> Self {
+        // 经过io::Read/Write传出来的FsError，拆出原来的错误
+        if err.get_ref().is_some_and(|inner| inner.is::<FsError>()) {
+            return *err.into_inner().unwrap().downcast::<FsError>().unwrap();
+        }
+        FsError::Io(err)
+    }
+}
+impl From<FsError> for io::Error {
+    fn from(err: FsError) -> Self {
+        // 底层I/O错误原样交还
+        if let FsError::Io(err) = err {
+            return err;
+        }
+        let kind = match &err {
+            FsError::NotFound(_) => io::ErrorKind::NotFound,
+            FsError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            FsError::InvalidArgument(_) => io::ErrorKind::InvalidInput,
+            FsError::PermissionDenied(_) => io::ErrorKind::PermissionDenied,
+            FsError::QuotaExceeded(_) => io::ErrorKind::QuotaExceeded,
+            FsError::Corrupt { .. } | FsError::InvalidImage(_) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+
+        io::Error::new(kind, err)
+    }
+}
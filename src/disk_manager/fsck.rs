@@ -0,0 +1,705 @@
+//! 一致性检查。
+//!
+//! 从根目录出发遍历整棵目录树，沿FAT走完每一条簇链，记下每个簇属于谁，从而找出交叉链接、成环、
+//! 越界、断开的簇链，错误的“.”/“..”，长度不符，以及与卷元数据对不上的共享计数和链接数。
+//! 已经分配却从根目录无法到达的簇链就是丢失的簇链，修复时释放它们，或者作为文件放进`/lost+found`。
+//! 检查只读取磁盘，不更新访问时间。
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::acl::Acl;
+use super::clock::Times;
+use super::disk::FatItem;
+use super::error::FsError;
+use super::image::ROOT_CLUSTER;
+use super::path::is_absolute;
+use super::users::{Perm, ROOT_GID, ROOT_UID};
+use super::{pdebug, pinfo, Directory, DiskManager, Fcb, FileType};
+
+/// 检查时发现的问题
+#[derive(Debug, Clone)]
+pub enum Problem {
+    /// 同一个簇被两个文件的簇链同时占用
+    CrossLinked {
+        cluster: usize,
+        first: String,
+        second: String,
+    },
+    /// 已分配但从根目录无法到达的簇链
+    LostChain {
+        first_cluster: usize,
+        clusters: usize,
+    },
+    /// 簇链成环
+    Cycle { path: String, cluster: usize },
+    /// 簇链指向了FAT表以外的簇
+    OutOfRange { path: String, cluster: usize },
+    /// 簇链中出现了未使用的簇或坏簇
+    BrokenChain {
+        path: String,
+        cluster: usize,
+        item: FatItem,
+    },
+    /// 目录的“.”或“..”没有指向正确的簇
+    WrongDotEntry {
+        path: String,
+        name: String,
+        expected: usize,
+        found: Option<usize>,
+    },
+    /// FCB中的长度与簇链长度不符
+    LengthMismatch {
+        path: String,
+        length: usize,
+        clusters: usize,
+    },
+    /// 目录文件无法解析
+    UnreadableDirectory { path: String, reason: String },
+    /// 卷元数据中记录的共享计数与实际共享簇链的FCB数量不符
+    WrongShareCount {
+        cluster: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// 卷元数据中记录的链接数与实际指向簇链的FCB数量不符
+    WrongLinkCount {
+        cluster: usize,
+        expected: usize,
+        found: usize,
+    },
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::CrossLinked {
+                cluster,
+                first,
+                second,
+            } => write!(
+                f,
+                "Cluster {} is cross-linked between '{}' and '{}'.",
+                cluster, first, second
+            ),
+            Problem::LostChain {
+                first_cluster,
+                clusters,
+            } => write!(
+                f,
+                "Lost chain of {} clusters starting at {}.",
+                clusters, first_cluster
+            ),
+            Problem::Cycle { path, cluster } => {
+                write!(f, "Chain of '{}' loops back to cluster {}.", path, cluster)
+            }
+            Problem::OutOfRange { path, cluster } => write!(
+                f,
+                "Chain of '{}' runs past the end of the FAT at cluster {}.",
+                path, cluster
+            ),
+            Problem::BrokenChain {
+                path,
+                cluster,
+                item,
+            } => write!(
+                f,
+                "Chain of '{}' reaches cluster {} marked {:?}.",
+                path, cluster, item
+            ),
+            Problem::WrongDotEntry {
+                path,
+                name,
+                expected,
+                found,
+            } => match found {
+                Some(found) => write!(
+                    f,
+                    "Entry '{}' of dir '{}' points to cluster {}, expected {}.",
+                    name, path, found, expected
+                ),
+                None => write!(f, "Dir '{}' has no '{}' entry.", path, name),
+            },
+            Problem::LengthMismatch {
+                path,
+                length,
+                clusters,
+            } => write!(
+                f,
+                "File '{}' is {} Bytes long but its chain has {} clusters.",
+                path, length, clusters
+            ),
+            Problem::UnreadableDirectory { path, reason } => {
+                write!(f, "Dir '{}' cannot be read: {}", path, reason)
+            }
+            Problem::WrongShareCount {
+                cluster,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Chain starting at {} is shared by {} files, metadata says {}.",
+                cluster, found, expected
+            ),
+            Problem::WrongLinkCount {
+                cluster,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Chain starting at {} has {} links, metadata says {}.",
+                cluster, found, expected
+            ),
+        }
+    }
+}
+
+/// 修复方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// 只检查，不修改磁盘
+    None,
+    /// 释放丢失的簇链
+    FreeLostChains,
+    /// 将丢失的簇链作为文件放入根目录下的`lost+found`
+    LostAndFound,
+}
+
+/// 检查结果
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub directories: usize,
+    pub files: usize,
+    /// 从根目录可以到达的簇数量
+    pub clusters_reachable: usize,
+    pub problems: Vec<Problem>,
+    /// 目标不存在的符号链接：(链接的路径, 目标)。悬空的链接是允许的，不算问题
+    pub dangling: Vec<(String, String)>,
+    /// 已经执行的修复操作
+    pub repairs: Vec<String>,
+}
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Checked {} dirs, {} files, {} clusters reachable.",
+            self.directories, self.files, self.clusters_reachable
+        )?;
+        if self.problems.is_empty() {
+            writeln!(f, "No problems found.")?;
+        } else {
+            writeln!(f, "{} problems found:", self.problems.len())?;
+            for problem in &self.problems {
+                writeln!(f, "\t{}", problem)?;
+            }
+        }
+        for (path, target) in &self.dangling {
+            writeln!(f, "Dangling symlink: '{}' -> '{}'.", path, target)?;
+        }
+        for repair in &self.repairs {
+            writeln!(f, "Repaired: {}", repair)?;
+        }
+
+        fmt::Result::Ok(())
+    }
+}
+
+/// 保存丢失簇链的目录名
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// 一次检查过程中的状态
+struct Checker<'a> {
+    dm: &'a DiskManager,
+    /// 每个簇被哪个文件占用
+    owners: Vec<Option<String>>,
+    /// 共享或有多个链接的簇链的首簇 → 实际指向它的FCB数量
+    sharers: HashMap<usize, usize>,
+    report: FsckReport,
+}
+impl Checker<'_> {
+    /// 沿FAT走完一条簇链，记录占用情况。遇到问题时返回已经走过的簇。
+    fn walk_chain(&mut self, path: &str, first_cluster: usize) -> Vec<usize> {
+        let fat = &self.dm.disk.fat();
+        let mut clusters = Vec::new();
+        let mut visited = HashSet::new();
+        let mut this_cluster = first_cluster;
+        loop {
+            if this_cluster >= fat.len() {
+                self.report.problems.push(Problem::OutOfRange {
+                    path: path.to_string(),
+                    cluster: this_cluster,
+                });
+                break;
+            }
+            if !visited.insert(this_cluster) {
+                self.report.problems.push(Problem::Cycle {
+                    path: path.to_string(),
+                    cluster: this_cluster,
+                });
+                break;
+            }
+            match &self.owners[this_cluster] {
+                Some(owner) => {
+                    self.report.problems.push(Problem::CrossLinked {
+                        cluster: this_cluster,
+                        first: owner.clone(),
+                        second: path.to_string(),
+                    });
+                }
+                None => {
+                    self.owners[this_cluster] = Some(path.to_string());
+                    self.report.clusters_reachable += 1;
+                }
+            }
+            clusters.push(this_cluster);
+            match &fat[this_cluster] {
+                FatItem::ClusterNo(next) => this_cluster = *next,
+                FatItem::EoF => break,
+                item => {
+                    self.report.problems.push(Problem::BrokenChain {
+                        path: path.to_string(),
+                        cluster: this_cluster,
+                        item: item.clone(),
+                    });
+                    break;
+                }
+            }
+        }
+
+        clusters
+    }
+
+    /// 检查“.”和“..”
+    fn check_dot_entry(&mut self, path: &str, dir: &Directory, index: usize, expected: usize) {
+        let name = if index == 0 { ".." } else { "." };
+        let found = match dir.files.get(index) {
+            Some(fcb) if fcb.name == name => Some(fcb.first_cluster),
+            _ => None,
+        };
+        if found != Some(expected) {
+            self.report.problems.push(Problem::WrongDotEntry {
+                path: path.to_string(),
+                name: name.to_string(),
+                expected,
+                found,
+            });
+        }
+    }
+
+    /// 从根目录开始深度优先检查所有目录和文件
+    fn check_tree(&mut self) {
+        // (路径, 首簇, 父目录首簇)
+        let mut stack = vec![(String::from("/"), ROOT_CLUSTER, ROOT_CLUSTER)];
+        let mut visited_dirs = HashSet::new();
+        while let Some((path, first_cluster, parent_cluster)) = stack.pop() {
+            pdebug();
+            println!("Checking dir '{}'...", path);
+            if !visited_dirs.insert(first_cluster) {
+                // 目录被多个FCB指向，交叉链接已经在簇链中报告过
+                continue;
+            }
+            self.report.directories += 1;
+
+            let clusters = self.walk_chain(&path, first_cluster);
+            let cluster_size = self.dm.disk.cluster_size();
+            let data = match self
+                .dm
+                .disk
+                .read_data_by_clusters(&clusters, clusters.len() * cluster_size)
+            {
+                Ok(data) => data,
+                Err(err) => {
+                    self.report.problems.push(Problem::UnreadableDirectory {
+                        path,
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let dir: Directory = match bincode::deserialize(&data) {
+                Ok(dir) => dir,
+                Err(err) => {
+                    self.report.problems.push(Problem::UnreadableDirectory {
+                        path,
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+            self.check_dot_entry(&path, &dir, 0, parent_cluster);
+            self.check_dot_entry(&path, &dir, 1, first_cluster);
+
+            for fcb in dir.files.iter().filter(|fcb| !is_dot_entry(fcb)) {
+                let child_path = if path == "/" {
+                    format!("/{}", fcb.name)
+                } else {
+                    format!("{}/{}", path, fcb.name)
+                };
+                match fcb.file_type {
+                    FileType::Directory => {
+                        stack.push((child_path, fcb.first_cluster, first_cluster));
+                    }
+                    FileType::File | FileType::Symlink => {
+                        self.report.files += 1;
+                        if let FileType::Symlink = fcb.file_type {
+                            self.check_symlink(&path, &child_path, fcb);
+                        }
+                        if self.dm.meta.shared.contains_key(&fcb.first_cluster)
+                            || self.dm.meta.links.contains_key(&fcb.first_cluster)
+                        {
+                            // 共享或有多个链接的簇链只检查一次
+                            let sharers = self.sharers.entry(fcb.first_cluster).or_insert(0);
+                            *sharers += 1;
+                            if *sharers > 1 {
+                                continue;
+                            }
+                        }
+                        let clusters = self.walk_chain(&child_path, fcb.first_cluster);
+                        let expected = self.dm.calc_clusters_needed(fcb.length);
+                        if clusters.len() != expected {
+                            self.report.problems.push(Problem::LengthMismatch {
+                                path: child_path,
+                                length: fcb.length,
+                                clusters: clusters.len(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// 符号链接的目标不存在时记下来。目标读不出来时簇链的问题由`walk_chain`报告
+    fn check_symlink(&mut self, dir_path: &str, path: &str, fcb: &Fcb) {
+        let target = match self.dm.link_target(fcb) {
+            Ok(target) => target,
+            Err(_) => return,
+        };
+        // 相对的目标从链接所在的目录开始查找
+        let full = if is_absolute(&target) {
+            target.clone()
+        } else {
+            format!("{}/{}", dir_path, target)
+        };
+        if let Ok(false) = self.dm.target_exists(&full) {
+            self.report.dangling.push((String::from(path), target));
+        }
+    }
+
+    /// 检查卷元数据：元数据自己的簇链，以及共享计数和链接数
+    fn check_meta(&mut self) {
+        if let Some(cluster) = self.dm.disk.meta_cluster {
+            self.walk_chain("<volume metadata>", cluster);
+        }
+        for (cluster, expected) in &self.dm.meta.shared {
+            let found = self.sharers.get(cluster).copied().unwrap_or(0);
+            if found != *expected {
+                self.report.problems.push(Problem::WrongShareCount {
+                    cluster: *cluster,
+                    expected: *expected,
+                    found,
+                });
+            }
+        }
+        for (cluster, expected) in &self.dm.meta.links {
+            let found = self.sharers.get(cluster).copied().unwrap_or(0);
+            if found != *expected {
+                self.report.problems.push(Problem::WrongLinkCount {
+                    cluster: *cluster,
+                    expected: *expected,
+                    found,
+                });
+            }
+        }
+    }
+
+    /// 找出所有已分配但无法到达的簇链，返回每条链的簇号
+    fn find_lost_chains(&self) -> Vec<Vec<usize>> {
+        let fat = &self.dm.disk.fat();
+        let is_lost = |cluster: usize| {
+            self.owners[cluster].is_none()
+                && matches!(fat[cluster], FatItem::ClusterNo(_) | FatItem::EoF)
+        };
+        // 被其他丢失簇指向的簇不是链头
+        let mut pointed = vec![false; fat.len()];
+        for cluster in (0..fat.len()).filter(|c| is_lost(*c)) {
+            if let FatItem::ClusterNo(next) = fat[cluster] {
+                if next < fat.len() {
+                    pointed[next] = true;
+                }
+            }
+        }
+
+        let mut claimed = vec![false; fat.len()];
+        let mut chains = Vec::new();
+        // 先从链头开始收集，剩下的只可能是没有链头的环
+        let heads: Vec<usize> = (0..fat.len())
+            .filter(|c| is_lost(*c) && !pointed[*c])
+            .chain((0..fat.len()).filter(|c| is_lost(*c)))
+            .collect();
+        for head in heads {
+            if claimed[head] {
+                continue;
+            }
+            let mut chain = Vec::new();
+            let mut this_cluster = head;
+            while this_cluster < fat.len() && is_lost(this_cluster) && !claimed[this_cluster] {
+                claimed[this_cluster] = true;
+                chain.push(this_cluster);
+                match fat[this_cluster] {
+                    FatItem::ClusterNo(next) => this_cluster = next,
+                    _ => break,
+                }
+            }
+            chains.push(chain);
+        }
+
+        chains
+    }
+}
+
+/// 是否为“.”或“..”
+fn is_dot_entry(fcb: &Fcb) -> bool {
+    fcb.name == "." || fcb.name == ".."
+}
+
+impl DiskManager {
+    /// 检查整个文件系统的一致性：从根目录遍历所有目录，沿FAT检查每一条簇链。
+    /// `repair`不为`Repair::None`时，处理丢失的簇链，只有root可以修复。
+    pub fn fsck(&mut self, repair: Repair) -> Result<FsckReport, FsError> {
+        if repair != Repair::None {
+            self.require_root("fsck --repair")?;
+        }
+        pinfo();
+        println!("Checking file system...");
+        let mut checker = Checker {
+            dm: self,
+            owners: vec![None; self.disk.fat().len()],
+            sharers: HashMap::new(),
+            report: FsckReport::default(),
+        };
+        checker.check_tree();
+        checker.check_meta();
+        let lost_chains = checker.find_lost_chains();
+        let mut report = checker.report;
+        for chain in &lost_chains {
+            report.problems.push(Problem::LostChain {
+                first_cluster: chain[0],
+                clusters: chain.len(),
+            });
+        }
+
+        match repair {
+            Repair::None => (),
+            Repair::FreeLostChains => self.transaction(|dm| {
+                for chain in &lost_chains {
+                    for cluster in chain {
+                        dm.disk.set_fat(*cluster, FatItem::NotUsed);
+                    }
+                    report.repairs.push(format!(
+                        "freed {} clusters starting at {}.",
+                        chain.len(),
+                        chain[0]
+                    ));
+                }
+
+                Ok(())
+            })?,
+            Repair::LostAndFound => {
+                if !lost_chains.is_empty() {
+                    self.transaction(|dm| dm.move_to_lost_and_found(&lost_chains, &mut report))?;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// 将丢失的簇链作为文件放入根目录下的`lost+found`
+    fn move_to_lost_and_found(
+        &mut self,
+        chains: &[Vec<usize>],
+        report: &mut FsckReport,
+    ) -> Result<(), FsError> {
+        let lost_path = format!("/{}", LOST_AND_FOUND);
+        let root = self.get_directory_by_cluster(ROOT_CLUSTER)?;
+        if root.get_fcb_by_name(LOST_AND_FOUND).is_none() {
+            self.new_directory_to_disk(&lost_path)?;
+        }
+        let mut lost_dir = self.resolve_directory(&lost_path)?;
+
+        let cluster_size = self.disk.cluster_size();
+        let times = Times::at(self.now());
+        for chain in chains {
+            // 丢失的链可能没有正常结束
+            self.disk.set_fat(*chain.last().unwrap(), FatItem::EoF);
+            // 以前修复时留下的文件可能已经占用了这个名字
+            let base = format!("lost-{}", chain[0]);
+            let mut name = base.clone();
+            let mut n = 0;
+            while lost_dir.get_fcb_by_name(&name).is_some() {
+                n += 1;
+                name = format!("{}-{}", base, n);
+            }
+            lost_dir.files.push(Fcb {
+                name: name.clone(),
+                file_type: FileType::File,
+                first_cluster: chain[0],
+                length: chain.len() * cluster_size,
+                times,
+                // 不知道原来属于谁，只让root读
+                perm: Perm {
+                    owner: ROOT_UID,
+                    group: ROOT_GID,
+                    mode: 0o600,
+                },
+                acl: Acl::default(),
+            });
+            report.repairs.push(format!(
+                "moved {} clusters to {}/{}.",
+                chain.len(),
+                lost_path,
+                name
+            ));
+        }
+        self.store_directory(&lost_dir)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::testing::{denied, new_disk};
+
+    /// 分配一条没有任何FCB指向的簇链，返回它的簇
+    fn lose_chain(dm: &mut DiskManager, clusters: usize) -> Vec<usize> {
+        let chain = dm.allocate_free_space_on_fat(clusters).unwrap();
+        dm.flush().unwrap();
+        chain
+    }
+
+    fn chain_of(dm: &DiskManager, path: &str) -> Vec<usize> {
+        dm.get_file_clusters(dm.stat(path).unwrap().first_cluster)
+            .unwrap()
+    }
+
+    #[test]
+    fn broken_chains_are_reported() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        dm.create_file_with_data("/a", &vec![1u8; cluster_size * 2])
+            .unwrap();
+        dm.create_file_with_data("/b", b"b").unwrap();
+        dm.create_file_with_data("/c", &vec![1u8; cluster_size * 2])
+            .unwrap();
+        dm.create_file_with_data("/d", &vec![1u8; cluster_size * 2])
+            .unwrap();
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+
+        let a = chain_of(&dm, "/a");
+        let b = chain_of(&dm, "/b");
+        let c = chain_of(&dm, "/c");
+        let d = chain_of(&dm, "/d");
+        // /b接到了/a的第二个簇上，/c成环，/d断在一个空闲簇上
+        dm.disk.set_fat(b[0], FatItem::ClusterNo(a[1]));
+        dm.disk.set_fat(c[1], FatItem::ClusterNo(c[0]));
+        dm.disk.set_fat(d[1], FatItem::NotUsed);
+        dm.disk.set_fat(d[0], FatItem::ClusterNo(d[1]));
+
+        let report = dm.fsck(Repair::None).unwrap();
+        let problems = &report.problems;
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            Problem::CrossLinked { cluster, .. } if *cluster == a[1]
+        )));
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            Problem::LengthMismatch { path, clusters: 2, .. } if path == "/b"
+        )));
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            Problem::Cycle { path, .. } if path == "/c"
+        )));
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            Problem::BrokenChain { path, item: FatItem::NotUsed, .. } if path == "/d"
+        )));
+    }
+
+    #[test]
+    fn wrong_dot_entries_are_reported() {
+        let mut dm = new_disk();
+        dm.new_directory_to_disk("/d").unwrap();
+        dm.new_directory_to_disk("/e").unwrap();
+        let e = dm.stat("/e").unwrap().first_cluster;
+        let mut dir = dm.resolve_directory("/d").unwrap();
+        dir.files[0].first_cluster = e;
+        dm.transaction(|dm| dm.store_directory(&dir)).unwrap();
+
+        let report = dm.fsck(Repair::None).unwrap();
+        assert!(matches!(
+            report.problems.as_slice(),
+            [Problem::WrongDotEntry { path, name, expected: ROOT_CLUSTER, found: Some(found) }]
+                if path == "/d" && name == ".." && *found == e
+        ));
+    }
+
+    #[test]
+    fn lost_chains_are_freed() {
+        let mut dm = new_disk();
+        dm.useradd("alice", None).unwrap();
+        let free = dm.disk.free_space().len();
+        let chain = lose_chain(&mut dm, 3);
+        let report = dm.fsck(Repair::None).unwrap();
+        assert!(matches!(
+            report.problems.as_slice(),
+            [Problem::LostChain { first_cluster, clusters: 3 }] if *first_cluster == chain[0]
+        ));
+
+        dm.login("alice").unwrap();
+        assert!(denied(dm.fsck(Repair::FreeLostChains)));
+        dm.logout();
+        let report = dm.fsck(Repair::FreeLostChains).unwrap();
+        assert_eq!(report.repairs.len(), 1);
+        dm.flush().unwrap();
+        assert_eq!(dm.disk.free_space().len(), free);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+
+    #[test]
+    fn lost_chains_get_unique_names_in_lost_and_found() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        let chain = lose_chain(&mut dm, 2);
+        // 以前修复时留下的同名文件
+        let name = format!("/lost+found/lost-{}", chain[0]);
+        dm.new_directory_to_disk("/lost+found").unwrap();
+        dm.create_file_with_data(&name, b"old").unwrap();
+        dm.create_file_with_data(&format!("{}-1", name), b"old")
+            .unwrap();
+
+        let report = dm.fsck(Repair::LostAndFound).unwrap();
+        assert_eq!(report.repairs.len(), 1);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+        let found = format!("{}-2", name);
+        assert_eq!(dm.stat(&found).unwrap().length, cluster_size * 2);
+        assert_eq!(dm.read_file_by_name(&name).unwrap(), b"old");
+
+        // 再修复一次不会有重复的名字
+        let second = lose_chain(&mut dm, 1);
+        dm.fsck(Repair::LostAndFound).unwrap();
+        let lost = dm.resolve_directory("/lost+found").unwrap();
+        let mut names: Vec<&str> = lost.files.iter().map(|fcb| fcb.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), lost.files.len());
+        assert!(dm.stat(&format!("/lost+found/lost-{}", second[0])).is_ok());
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+}
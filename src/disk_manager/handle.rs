@@ -0,0 +1,595 @@
+//! 文件句柄：按位置读写文件，只改动涉及到的簇。
+//!
+//! 文件长度以外的簇内数据没有意义，文件变长时会先把新露出来的部分填0。
+//! 每次写入或改变长度都在一个事务中进行，同时更新修改时间；读取过的文件在下一次提交
+//! 或者关闭时更新访问时间（`noatime`时不更新）。
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::error::FsError;
+use super::quota::Charge;
+use super::users::{READ, WRITE};
+use super::{pdebug, perror, pinfo, Directory, DiskManager, FileType};
+
+/// 打开文件的方式，用法同`std::fs::OpenOptions`。
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+impl OpenOptions {
+    /// 所有选项都关闭
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// 每次写入前都移到文件末尾，隐含`write`
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// 打开时把文件长度截为0，需要`write`
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// 文件不存在时新建，需要`write`
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// 总是新建，文件已经存在时报错，需要`write`
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    fn writable(&self) -> bool {
+        self.write || self.append
+    }
+
+    /// 检查选项组合是否合法
+    fn check(&self) -> Result<(), FsError> {
+        if !self.read && !self.writable() {
+            return Err(FsError::InvalidArgument(String::from(
+                "file must be opened for reading or writing",
+            )));
+        }
+        if (self.truncate || self.create || self.create_new) && !self.writable() {
+            return Err(FsError::InvalidArgument(String::from(
+                "creating or truncating a file needs write access",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// 打开的文件在磁盘上的位置和大小。修改时先改一份副本，事务提交之后才换上。
+#[derive(Clone)]
+struct OpenFile {
+    /// 文件所在目录的首簇
+    parent_cluster: usize,
+    /// 文件名。句柄借用着整个DiskManager，打开期间文件不会被改名
+    name: String,
+    /// 文件的属主，文件变长时占用的是属主的配额
+    owner: u32,
+    first_cluster: usize,
+    /// 文件的簇链，长度变化时重新读取
+    clusters: Vec<usize>,
+    length: usize,
+}
+impl OpenFile {
+    /// 簇链与其他文件共享时，先复制一份再修改（写时复制）
+    fn unshare(&mut self, dm: &mut DiskManager) -> Result<(), FsError> {
+        if dm.share_count(self.first_cluster) <= 1 {
+            return Ok(());
+        }
+        pdebug();
+        println!("Copying shared chain of '{}' before writing...", self.name);
+        let (charge, shared) = (self.charge(), self.first_cluster);
+        let first_cluster = dm.charged(charge, |dm| dm.unshare_chain(shared))?;
+        let mut parent = dm.get_directory_by_cluster(self.parent_cluster)?;
+        let index = self.index_in(&parent)?;
+        parent.files[index].first_cluster = first_cluster;
+        dm.store_directory(&parent)?;
+        self.first_cluster = first_cluster;
+        self.clusters = dm.get_file_clusters(first_cluster)?;
+
+        Ok(())
+    }
+
+    /// 调整簇链，使之刚好能放下`length`字节
+    fn resize(&mut self, dm: &mut DiskManager, length: usize) -> Result<(), FsError> {
+        let clusters_needed = dm.calc_clusters_needed(length);
+        if clusters_needed != self.clusters.len() {
+            let (charge, first_cluster) = (self.charge(), self.first_cluster);
+            self.clusters = dm.charged(charge, |dm| {
+                dm.resize_space_on_fat(first_cluster, clusters_needed)
+            })?;
+        }
+        self.length = length;
+
+        Ok(())
+    }
+
+    /// 新分配的簇记在文件属主和所在目录的账上
+    fn charge(&self) -> Charge {
+        Charge {
+            owner: self.owner,
+            dir: self.parent_cluster,
+        }
+    }
+
+    /// 把`[start, end)`范围内的数据写成0
+    fn zero_range(&self, dm: &mut DiskManager, start: usize, end: usize) -> Result<(), FsError> {
+        let zeros = vec![0u8; dm.disk.cluster_size()];
+        let mut pos = start;
+        while pos < end {
+            let len = (end - pos).min(zeros.len() - pos % zeros.len());
+            self.write_at(dm, pos, &zeros[..len])?;
+            pos += len;
+        }
+
+        Ok(())
+    }
+
+    /// 从`pos`开始写入，簇链必须已经足够长
+    fn write_at(
+        &self,
+        dm: &mut DiskManager,
+        mut pos: usize,
+        mut data: &[u8],
+    ) -> Result<(), FsError> {
+        let cluster_size = dm.disk.cluster_size();
+        while !data.is_empty() {
+            let offset = pos % cluster_size;
+            let len = data.len().min(cluster_size - offset);
+            let cluster = self.clusters[pos / cluster_size];
+            dm.disk.insert_data_at(&data[..len], cluster, offset)?;
+            data = &data[len..];
+            pos += len;
+        }
+
+        Ok(())
+    }
+
+    /// 文件在父目录中的位置
+    fn index_in(&self, parent: &Directory) -> Result<usize, FsError> {
+        parent
+            .get_index_by_name(&self.name)
+            .ok_or_else(|| FsError::NotFound(self.name.clone()))
+    }
+
+    /// 把长度和时间戳写回父目录，调用者负责提交
+    fn store(&self, dm: &mut DiskManager, modified: bool, accessed: bool) -> Result<(), FsError> {
+        pdebug();
+        println!("Syncing file length {} to its dir...", self.length);
+        let mut parent = dm.get_directory_by_cluster(self.parent_cluster)?;
+        let index = self.index_in(&parent)?;
+        let now = dm.now();
+        let fcb = &mut parent.files[index];
+        fcb.length = self.length;
+        if modified {
+            fcb.times.modified = now;
+        }
+        if accessed {
+            fcb.times.accessed = now;
+        }
+        dm.store_directory(&parent)?;
+        dm.sync_links(&parent.files[index])
+    }
+}
+
+/// 打开的文件。每次写入和改变长度都是一个事务，簇链的变化和新的长度一起提交；
+/// 出错时簇链的变化全部撤销，句柄保持原样。关闭（或者离开作用域）时更新访问时间。
+pub struct FileHandle<'a> {
+    dm: &'a mut DiskManager,
+    file: OpenFile,
+    pos: usize,
+    options: OpenOptions,
+    /// 读取过，但还没有更新访问时间
+    accessed: bool,
+}
+impl FileHandle<'_> {
+    /// 文件当前的长度
+    pub fn len(&self) -> usize {
+        self.file.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file.length == 0
+    }
+
+    /// 当前的读写位置
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// 改变文件长度。变短时释放多余的簇，变长时分配新簇并填0。读写位置不变。
+    pub fn set_len(&mut self, length: usize) -> Result<(), FsError> {
+        self.check_writable()?;
+        self.modify(|dm, file| {
+            let old_length = file.length;
+            file.resize(dm, length)?;
+            if length > old_length {
+                file.zero_range(dm, old_length, length)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// 更新访问时间，然后关闭文件。
+    pub fn close(mut self) -> Result<(), FsError> {
+        self.sync()
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if !self.options.writable() {
+            return Err(FsError::InvalidArgument(String::from(
+                "file is not opened for writing",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// 在一个事务中修改文件的副本，成功后把长度和修改时间写回父目录一起提交，再换上副本。
+    /// 已有范围内写入的数据直接落在簇中，不会撤销。
+    fn modify<T>(
+        &mut self,
+        f: impl FnOnce(&mut DiskManager, &mut OpenFile) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let mut file = self.file.clone();
+        let accessed = self.accessed && !self.dm.noatime;
+        let value = self.dm.transaction(|dm| {
+            file.unshare(dm)?;
+            let value = f(dm, &mut file)?;
+            file.store(dm, true, accessed)?;
+            Ok(value)
+        })?;
+        self.file = file;
+        if accessed {
+            self.accessed = false;
+        }
+
+        Ok(value)
+    }
+
+    /// 读取过的文件更新访问时间并提交
+    fn sync(&mut self) -> Result<(), FsError> {
+        if !self.accessed || self.dm.noatime {
+            return Ok(());
+        }
+        let file = &self.file;
+        self.dm.transaction(|dm| file.store(dm, false, true))?;
+        self.accessed = false;
+
+        Ok(())
+    }
+}
+impl Read for FileHandle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.options.read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not opened for reading",
+            ));
+        }
+        if self.pos >= self.file.length {
+            return Ok(0);
+        }
+        // 每次最多读到当前簇的末尾
+        let cluster_size = self.dm.disk.cluster_size();
+        let offset = self.pos % cluster_size;
+        let len = buf
+            .len()
+            .min(cluster_size - offset)
+            .min(self.file.length - self.pos);
+        let data = self
+            .dm
+            .disk
+            .read_data_by_cluster(self.file.clusters[self.pos / cluster_size])?;
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.pos += len;
+        self.accessed = true;
+
+        Ok(len)
+    }
+}
+impl Write for FileHandle<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.options.writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not opened for writing",
+            ));
+        }
+        if self.options.append {
+            self.pos = self.file.length;
+        }
+        let pos = self.pos;
+        self.modify(|dm, file| {
+            let (old_length, end) = (file.length, pos + buf.len());
+            if end > old_length {
+                file.resize(dm, end)?;
+                // 在文件末尾之后写入，中间的空洞填0
+                if pos > old_length {
+                    file.zero_range(dm, old_length, pos)?;
+                }
+            }
+            file.write_at(dm, pos, buf)
+        })?;
+        self.pos += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync()?;
+
+        Ok(())
+    }
+}
+impl Seek for FileHandle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(offset) => (self.file.length as i64, offset),
+            SeekFrom::Current(offset) => (self.pos as i64, offset),
+        };
+        match base.checked_add(offset) {
+            Some(pos) if pos >= 0 => {
+                self.pos = pos as usize;
+                Ok(self.pos as u64)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )),
+        }
+    }
+}
+impl Drop for FileHandle<'_> {
+    fn drop(&mut self) {
+        // 没有调用close时也要更新访问时间，出错时只能报告
+        if let Err(err) = self.sync() {
+            perror();
+            println!("Closing '{}' failed: {}", self.file.name, err);
+        }
+    }
+}
+
+impl DiskManager {
+    /// 按路径打开文件，返回文件句柄。最后一项是符号链接时打开（或者新建）它指向的文件。
+    pub fn open(&mut self, path: &str, options: &OpenOptions) -> Result<FileHandle<'_>, FsError> {
+        pinfo();
+        println!("Opening file '{}'...", path);
+        options.check()?;
+        let path = &self.follow_final(path)?;
+        let (parent, name) = self.resolve_parent(path)?;
+        // 读需要读权限，写、追加和截断需要写权限
+        let mut access = 0;
+        if options.read {
+            access |= READ;
+        }
+        if options.write || options.append || options.truncate {
+            access |= WRITE;
+        }
+        let fcb = match parent.get_fcb_by_name(name) {
+            Some(_) if options.create_new => {
+                return Err(FsError::AlreadyExists(String::from(path)))
+            }
+            Some((_index, fcb)) => fcb.clone(),
+            None if options.create || options.create_new => {
+                // 按新文件将会得到的权限检查，通过了才新建
+                self.check_access(&self.new_fcb(&parent, name, FileType::File), access, path)?;
+                self.create_file_with_data(path, &[])?;
+                self.resolve_fcb(path)?
+            }
+            None => return Err(FsError::NotFound(String::from(path))),
+        };
+        if let FileType::Directory = fcb.file_type {
+            return Err(FsError::IsADirectory(String::from(path)));
+        }
+        self.check_access(&fcb, access, path)?;
+
+        let clusters = self.get_file_clusters(fcb.first_cluster)?;
+        let mut handle = FileHandle {
+            dm: self,
+            file: OpenFile {
+                parent_cluster: parent.files[1].first_cluster,
+                name: String::from(name),
+                owner: fcb.perm.owner,
+                first_cluster: fcb.first_cluster,
+                clusters,
+                length: fcb.length,
+            },
+            pos: 0,
+            options: options.clone(),
+            accessed: false,
+        };
+        if options.truncate {
+            handle.set_len(0)?;
+        }
+
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::fsck::Repair;
+    use crate::disk_manager::quota::Limits;
+    use crate::disk_manager::testing::{denied, new_disk, shared_disk};
+
+    fn read_write() -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        options
+    }
+
+    #[test]
+    fn seek_read_and_write() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        dm.create_file_with_data("/f", b"hello world").unwrap();
+        let mut file = dm.open("/f", &read_write()).unwrap();
+
+        assert_eq!(file.seek(SeekFrom::Start(6)).unwrap(), 6);
+        file.write_all(b"WORLD").unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(-5)).unwrap(), 6);
+        let mut buf = [0u8; 5];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"WORLD");
+        assert_eq!(file.seek(SeekFrom::End(-11)).unwrap(), 0);
+        assert!(file.seek(SeekFrom::Current(-1)).is_err());
+        assert_eq!(file.position(), 0);
+
+        // 跨簇写到文件末尾之后，中间的空洞读出来是0
+        let end = cluster_size + 10;
+        file.seek(SeekFrom::Start(end as u64)).unwrap();
+        file.write_all(b"tail").unwrap();
+        assert_eq!(file.len(), end + 4);
+        file.close().unwrap();
+
+        let data = dm.read_file_by_name("/f").unwrap();
+        assert_eq!(&data[..11], b"hello WORLD");
+        assert!(data[11..end].iter().all(|byte| *byte == 0));
+        assert_eq!(&data[end..], b"tail");
+        assert_eq!(dm.stat("/f").unwrap().clusters, 2);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+
+    #[test]
+    fn append_and_modes() {
+        let mut dm = new_disk();
+        dm.create_file_with_data("/f", b"one").unwrap();
+        let mut file = dm.open("/f", OpenOptions::new().append(true)).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b" two").unwrap();
+        assert!(file.read(&mut [0u8; 4]).is_err());
+        drop(file);
+        assert_eq!(dm.read_file_by_name("/f").unwrap(), b"one two");
+
+        let mut file = dm.open("/f", OpenOptions::new().read(true)).unwrap();
+        assert!(file.write(b"x").is_err());
+        assert!(file.set_len(0).is_err());
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"one two");
+    }
+
+    #[test]
+    fn set_len_and_truncate() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        let free = dm.get_disk_info().2;
+        dm.create_file_with_data("/f", &vec![1u8; 3 * cluster_size])
+            .unwrap();
+
+        let mut file = dm.open("/f", &read_write()).unwrap();
+        file.set_len(cluster_size + 1).unwrap();
+        file.set_len(cluster_size + 5).unwrap();
+        assert_eq!(file.len(), cluster_size + 5);
+        // 变短之后再变长，新露出来的部分是0而不是原来的数据
+        file.seek(SeekFrom::Start(cluster_size as u64)).unwrap();
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1, 0, 0, 0, 0]);
+        drop(file);
+        assert_eq!(dm.stat("/f").unwrap().clusters, 2);
+
+        let file = dm
+            .open("/f", OpenOptions::new().write(true).truncate(true))
+            .unwrap();
+        assert!(file.is_empty());
+        drop(file);
+        assert_eq!(dm.read_file_by_name("/f").unwrap(), b"");
+        // 空文件仍然占着首簇
+        assert_eq!(dm.get_disk_info().2, free - 1);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+
+    #[test]
+    fn failed_write_rolls_back() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        dm.new_directory_to_disk("/d").unwrap();
+        dm.create_file_with_data("/d/a", b"shared").unwrap();
+        dm.copy_by_path("/d/a", "/d/b", false, true).unwrap();
+        dm.set_dir_quota("/d", Limits { soft: 0, hard: 100 })
+            .unwrap();
+        let used = dm.quota_report().unwrap().rows[1].used;
+        // 写时复制还放得下，再变长就超过配额
+        dm.set_dir_quota(
+            "/d",
+            Limits {
+                soft: 0,
+                hard: used + 1,
+            },
+        )
+        .unwrap();
+        let first_cluster = dm.stat("/d/b").unwrap().first_cluster;
+        let free = dm.get_disk_info().2;
+
+        let mut file = dm.open("/d/b", &read_write()).unwrap();
+        file.seek(SeekFrom::Start(2 * cluster_size as u64)).unwrap();
+        let err = file.write(b"more").unwrap_err();
+        assert!(matches!(FsError::from(err), FsError::QuotaExceeded(_)));
+        assert_eq!(file.len(), 6);
+        drop(file);
+
+        // 复制出来的簇链也一起撤销，两个文件仍然共享
+        assert_eq!(dm.get_disk_info().2, free);
+        assert_eq!(dm.share_count(first_cluster), 2);
+        assert_eq!(dm.read_file_by_name("/d/b").unwrap(), b"shared");
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+
+        let mut file = dm.open("/d/b", &read_write()).unwrap();
+        file.write_all(b"S").unwrap();
+        drop(file);
+        assert_eq!(dm.read_file_by_name("/d/a").unwrap(), b"shared");
+        assert_eq!(dm.read_file_by_name("/d/b").unwrap(), b"Shared");
+    }
+
+    #[test]
+    fn open_checks_access_before_creating() {
+        let mut dm = shared_disk();
+        let entries = dm.parse_acl("default:deny:user:alice:r", true).unwrap();
+        dm.setfacl("/home/alice", |acl| {
+            entries.iter().for_each(|entry| acl.set(*entry))
+        })
+        .unwrap();
+
+        // 新文件继承的ACL不让alice读，不能留下一个空文件
+        let mut options = read_write();
+        options.create(true);
+        assert!(denied(dm.open("/home/alice/g", &options)));
+        assert!(dm.stat("/home/alice/g").is_err());
+
+        let file = dm
+            .open("/home/alice/g", OpenOptions::new().write(true).create(true))
+            .unwrap();
+        drop(file);
+        assert!(denied(dm.read_file_by_name("/home/alice/g")));
+    }
+}
@@ -0,0 +1,283 @@
+//! 宿主机和虚拟磁盘之间的文件导入导出。
+//!
+//! 文件通过`FileHandle`流式复制，不会整个读进内存。一次导入是一个事务，中途失败时
+//! 已经导入的文件和目录全部撤销。创建、修改和访问时间在两边之间保留，
+//! 宿主机不支持创建时间时用修改时间代替。
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::clock::{from_system_time, to_system_time, Times};
+use super::error::FsError;
+use super::handle::OpenOptions;
+use super::path::split_last;
+use super::{pinfo, DiskManager, FileType};
+
+/// 一次导入或导出的统计
+#[derive(Debug, Default, Clone)]
+pub struct TransferReport {
+    pub files: usize,
+    pub directories: usize,
+    /// 文件数据的总字节数
+    pub bytes: usize,
+    /// 导入时是新占用的簇数量（包括目录），导出时是文件占用的簇数量
+    pub clusters: usize,
+}
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files, {} dirs, {} Bytes, {} clusters.",
+            self.files, self.directories, self.bytes, self.clusters
+        )
+    }
+}
+
+/// 路径的最后一项，宿主机上的路径按宿主机规则拆分
+fn host_file_name(path: &Path) -> Result<String, FsError> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| {
+            FsError::InvalidArgument(format!("host path '{}' has no file name", path.display()))
+        })
+}
+
+/// 宿主机文件的时间戳
+fn host_times(metadata: &fs::Metadata) -> Times {
+    let modified = metadata.modified().map(from_system_time).unwrap_or(0);
+    Times {
+        created: metadata.created().map(from_system_time).unwrap_or(modified),
+        modified,
+        accessed: metadata
+            .accessed()
+            .map(from_system_time)
+            .unwrap_or(modified),
+    }
+}
+
+/// 把时间戳设置到宿主机文件上，不知道的时间保持不变
+fn set_host_times(file: &fs::File, times: &Times) -> io::Result<()> {
+    let mut file_times = fs::FileTimes::new();
+    if let Some(modified) = to_system_time(times.modified) {
+        file_times = file_times.set_modified(modified);
+    }
+    if let Some(accessed) = to_system_time(times.accessed) {
+        file_times = file_times.set_accessed(accessed);
+    }
+    file.set_times(file_times)
+}
+
+/// 拼接虚拟磁盘上的路径
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), name)
+    }
+}
+
+impl DiskManager {
+    /// 把宿主机上的`host_path`导入为虚拟磁盘上的`path`。
+    /// `path`为`None`或者是已经存在的目录时，使用宿主机文件名放入该目录。
+    /// 宿主机路径是目录时需要`recursive`，整棵目录树都会被导入。
+    pub fn import_from_host(
+        &mut self,
+        host_path: &Path,
+        path: Option<&str>,
+        recursive: bool,
+    ) -> Result<TransferReport, FsError> {
+        pinfo();
+        println!("Importing '{}'...", host_path.display());
+        let name = host_file_name(host_path)?;
+        let path = match path {
+            None => name,
+            Some(path) => match self.resolve_directory(path) {
+                Ok(_) => join_path(path, &name),
+                Err(_) => String::from(path),
+            },
+        };
+        if fs::metadata(host_path)?.is_dir() && !recursive {
+            return Err(FsError::IsADirectory(host_path.display().to_string()));
+        }
+
+        let (_size, used_before, _free) = self.get_disk_info();
+        let mut report = TransferReport::default();
+        // 中途失败时整棵树一起撤销，不留下导入了一半的目录
+        self.transaction(|dm| dm.import_entry(host_path, &path, &mut report))?;
+        let (_size, used_after, _free) = self.get_disk_info();
+        report.clusters = used_after.saturating_sub(used_before);
+
+        Ok(report)
+    }
+
+    fn import_entry(
+        &mut self,
+        host_path: &Path,
+        path: &str,
+        report: &mut TransferReport,
+    ) -> Result<(), FsError> {
+        let metadata = fs::metadata(host_path)?;
+        if metadata.is_dir() {
+            self.new_directory_to_disk(path)?;
+            report.directories += 1;
+            // 按名字排序，保证每次导入的顺序相同
+            let mut entries = fs::read_dir(host_path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<PathBuf>>>()?;
+            entries.sort();
+            for entry in entries {
+                let child = join_path(path, &host_file_name(&entry)?);
+                self.import_entry(&entry, &child, report)?;
+            }
+        } else if metadata.is_file() {
+            let mut host_file = fs::File::open(host_path)?;
+            let mut handle = self.open(path, OpenOptions::new().write(true).create_new(true))?;
+            let bytes = io::copy(&mut host_file, &mut handle)?;
+            handle.close()?;
+            report.files += 1;
+            report.bytes += bytes as usize;
+        } else {
+            // 其他类型的宿主机文件（设备、管道等）直接跳过
+            return Ok(());
+        }
+        // 目录的内容导入完之后再设置时间
+        self.set_times(path, host_times(&metadata))?;
+
+        Ok(())
+    }
+
+    /// 把虚拟磁盘上的`path`导出到宿主机的`host_path`。
+    /// `host_path`为`None`或者是已经存在的目录时，使用虚拟磁盘上的文件名放入该目录。
+    /// `path`是目录时需要`recursive`，整棵目录树都会被导出。
+    pub fn export_to_host(
+        &mut self,
+        path: &str,
+        host_path: Option<&Path>,
+        recursive: bool,
+    ) -> Result<TransferReport, FsError> {
+        pinfo();
+        println!("Exporting '{}'...", path);
+        let is_dir = matches!(self.stat(path)?.file_type, FileType::Directory);
+        let name = match split_last(path).1 {
+            // 根目录和“..”之类的路径没有可用的名字，用目录自己记着的名字
+            "" | "." | ".." if is_dir => self.resolve_directory(path)?.name,
+            name => String::from(name),
+        };
+        let host_path = match host_path {
+            None => PathBuf::from(name),
+            Some(host_path) if host_path.is_dir() => host_path.join(name),
+            Some(host_path) => host_path.to_path_buf(),
+        };
+        if is_dir && !recursive {
+            return Err(FsError::IsADirectory(String::from(path)));
+        }
+
+        let mut report = TransferReport::default();
+        self.export_entry(path, &host_path, &mut report)?;
+
+        Ok(report)
+    }
+
+    fn export_entry(
+        &mut self,
+        path: &str,
+        host_path: &Path,
+        report: &mut TransferReport,
+    ) -> Result<(), FsError> {
+        // 读取之前的时间，读取会更新访问时间
+        let stat = self.stat(path)?;
+        let times = stat.times;
+        if let FileType::Directory = stat.file_type {
+            let dir = self.list_directory(Some(path))?;
+            fs::create_dir(host_path)?;
+            report.directories += 1;
+            for fcb in dir.files.iter().skip(2) {
+                let child = join_path(path, &fcb.name);
+                self.export_entry(&child, &host_path.join(&fcb.name), report)?;
+            }
+            // 内容导出完之后再设置时间。有的宿主机（例如Windows）不能这样打开目录，只能放弃
+            if let Ok(host_dir) = fs::File::open(host_path) {
+                let _ = set_host_times(&host_dir, &times);
+            }
+        } else if let FileType::Symlink = stat.file_type {
+            // 宿主机上不一定能建立符号链接，目标也可能不在导出的范围内
+            pinfo();
+            println!(
+                "Skipping symlink '{}' -> '{}'.",
+                path,
+                self.read_link(path)?
+            );
+        } else {
+            let mut handle = self.open(path, OpenOptions::new().read(true))?;
+            let mut host_file = fs::File::create(host_path)?;
+            let bytes = io::copy(&mut handle, &mut host_file)? as usize;
+            drop(handle);
+            set_host_times(&host_file, &times)?;
+            report.files += 1;
+            report.bytes += bytes;
+            report.clusters += self.calc_clusters_needed(bytes);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::fsck::Repair;
+    use crate::disk_manager::quota::Limits;
+    use crate::disk_manager::testing::{new_disk, temp_path};
+
+    /// 宿主机上的目录`tree`，里面有一个小文件`a`和占`clusters`个簇的文件`b`
+    fn host_tree(name: &str, cluster_size: usize, clusters: usize) -> PathBuf {
+        let root = temp_path(name);
+        let tree = root.join("tree");
+        fs::create_dir_all(&tree).unwrap();
+        fs::write(tree.join("a"), b"small").unwrap();
+        fs::write(tree.join("b"), vec![7u8; clusters * cluster_size]).unwrap();
+        tree
+    }
+
+    #[test]
+    fn tree_round_trips() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        let tree = host_tree("host-round-trip", cluster_size, 2);
+        let report = dm.import_from_host(&tree, None, true).unwrap();
+        assert_eq!((report.files, report.directories), (2, 1));
+        assert_eq!(report.bytes, 5 + 2 * cluster_size);
+        // 目录、a和b
+        assert_eq!(report.clusters, 1 + 1 + 2);
+        assert_eq!(dm.read_file_by_name("/tree/a").unwrap(), b"small");
+
+        let out = tree.parent().unwrap().join("out");
+        let report = dm.export_to_host("/tree", Some(&out), true).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(
+            fs::read(out.join("b")).unwrap(),
+            fs::read(tree.join("b")).unwrap()
+        );
+        fs::remove_dir_all(tree.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn failed_import_leaves_nothing() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        let tree = host_tree("host-rollback", cluster_size, 4);
+        dm.new_directory_to_disk("/d").unwrap();
+        // 目录和a放得下，b放不下
+        dm.set_dir_quota("/d", Limits { soft: 0, hard: 4 }).unwrap();
+        let free = dm.get_disk_info().2;
+
+        let err = dm.import_from_host(&tree, Some("/d"), true).unwrap_err();
+        assert!(matches!(err, FsError::QuotaExceeded(_)));
+        assert!(dm.stat("/d/tree").is_err());
+        assert_eq!(dm.get_disk_info().2, free);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+        fs::remove_dir_all(tree.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! 虚拟磁盘镜像的字节布局。
+//!
+//! 镜像文件就是整个卷，由`cluster_count`个大小为`cluster_size`的簇组成，所有整数均为小端序：
+//!
+//! | 位置                          | 内容                                     |
+//! | ----------------------------- | ---------------------------------------- |
+//! | 簇 0                          | 超级块（见下表），其余部分填0            |
+//! | 簇 1 .. 日志区起始簇          | FAT区，每个数据簇对应一个4字节的FAT项    |
+//! | 日志区起始簇 .. `reserved_clusters` | 日志区（版本4起），格式见`journal`模块 |
+//! | 簇 `reserved_clusters` .. 末尾 | 数据区，数据簇`n`位于卷的第`reserved_clusters + n`簇 |
+//!
+//! 超级块：
+//!
+//! | 偏移 | 长度 | 内容                               |
+//! | ---- | ---- | ---------------------------------- |
+//! | 0    | 8    | 魔数 `IVANDFS\0`                   |
+//! | 8    | 4    | 格式版本号                         |
+//! | 12   | 4    | 簇大小（字节）                     |
+//! | 16   | 4    | 簇总数，包括保留簇                 |
+//! | 20   | 4    | 保留簇数量，即数据区的起始簇       |
+//! | 24   | 4    | FAT区起始簇                        |
+//! | 28   | 4    | FAT区占用的簇数量，FAT区一直延伸到日志区之前 |
+//! | 32   | 4    | 根目录的首个数据簇                 |
+//! | 36   | 4    | 卷元数据的首个数据簇，0表示没有（版本2起） |
+//! | 40   | 4    | 分配策略：0首次适应，1循环首次适应，2最佳适应（版本3起） |
+//! | 44   | 4    | 日志区的起始簇，0表示没有日志区（版本4起） |
+//! | 48   | 4    | 日志区占用的簇数量（版本4起）      |
+//!
+//! FAT项：`0x00000000`未使用，`0xFFFFFFF7`坏簇，`0xFFFFFFFF`文件结束，其他值为下一个数据簇号。
+//! 根目录永远从数据簇0开始，没有簇链会指向它，所以0可以用来表示未使用。
+//!
+//! 目录文件以bincode（定长整数、小端序）编码的`Directory`结构存放在数据区中。
+//! 版本5起每个FCB带有创建、修改、访问时间，版本6起带有属主、属组和权限位，版本7起带有ACL，
+//! 更早的目录在加载时由`upgrade`模块转换。
+//! 卷元数据（见`meta`模块）同样存放在数据区的一条簇链中。
+//!
+//! 旧版本的镜像中，新增字段的位置是填充的0，正好是这些字段的默认值，所以可以直接读出。
+
+use super::alloc::AllocatorKind;
+use super::disk::{DiskGeometry, FatItem};
+use super::error::FsError;
+
+/// 镜像魔数
+pub const MAGIC: &[u8; 8] = b"IVANDFS\0";
+/// 当前镜像格式版本
+pub const FORMAT_VERSION: u32 = 7;
+/// 仍然可以读出的最旧镜像格式版本
+pub const MIN_FORMAT_VERSION: u32 = 1;
+/// 超级块所在的簇
+pub const SUPERBLOCK_CLUSTER: usize = 0;
+/// 超级块的有效长度（字节）
+pub const SUPERBLOCK_SIZE: usize = 52;
+/// 每个FAT项的长度（字节）
+pub const FAT_ENTRY_SIZE: usize = 4;
+/// FAT区的起始簇
+pub const FAT_START_CLUSTER: usize = SUPERBLOCK_CLUSTER + 1;
+/// 根目录的首个数据簇
+pub const ROOT_CLUSTER: usize = 0;
+
+/// FAT项编码：未使用
+const FAT_NOT_USED: u32 = 0x0000_0000;
+/// FAT项编码：坏簇
+const FAT_BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+/// FAT项编码：文件结束
+const FAT_EOF: u32 = 0xFFFF_FFFF;
+/// 能够表示的最大簇号，再往上的值留给特殊标记
+pub const MAX_CLUSTER_NO: usize = 0x0FFF_FFFF;
+
+/// 保存`entries`个FAT项需要的簇数量
+pub fn fat_clusters(cluster_size: usize, entries: usize) -> usize {
+    (entries * FAT_ENTRY_SIZE).div_ceil(cluster_size)
+}
+
+/// 超级块中记录的内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Superblock {
+    /// 读出的镜像的格式版本，写入时总是当前版本
+    pub version: u32,
+    pub geometry: DiskGeometry,
+    /// 卷元数据的首个数据簇
+    pub meta_cluster: Option<usize>,
+    /// 分配策略
+    pub allocator: AllocatorKind,
+}
+
+/// 生成超级块，长度为一个簇。
+pub fn encode_superblock(superblock: &Superblock) -> Vec<u8> {
+    let geometry = &superblock.geometry;
+    let mut buffer = Vec::with_capacity(geometry.cluster_size);
+    buffer.extend_from_slice(MAGIC);
+    for field in [
+        FORMAT_VERSION,
+        geometry.cluster_size as u32,
+        geometry.cluster_count as u32,
+        geometry.reserved_clusters as u32,
+        FAT_START_CLUSTER as u32,
+        geometry.fat_clusters() as u32,
+        ROOT_CLUSTER as u32,
+        superblock.meta_cluster.unwrap_or(0) as u32,
+        superblock.allocator.id(),
+        if geometry.journal_clusters == 0 {
+            0
+        } else {
+            geometry.journal_start() as u32
+        },
+        geometry.journal_clusters as u32,
+    ] {
+        buffer.extend_from_slice(&field.to_le_bytes());
+    }
+    buffer.resize(geometry.cluster_size, 0u8);
+
+    buffer
+}
+
+/// 解析超级块
+pub fn decode_superblock(data: &[u8]) -> Result<Superblock, FsError> {
+    if data.len() < SUPERBLOCK_SIZE {
+        return Err(FsError::InvalidImage(String::from("truncated superblock")));
+    }
+    if &data[0..8] != MAGIC {
+        return Err(FsError::InvalidImage(String::from("bad magic")));
+    }
+    let field = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes) as usize
+    };
+
+    let version = field(8) as u32;
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(FsError::InvalidImage(format!(
+            "unsupported version {}, expected {} to {}",
+            version, MIN_FORMAT_VERSION, FORMAT_VERSION
+        )));
+    }
+    let geometry = DiskGeometry::with_reserved(field(12), field(16), field(20), field(48))
+        .map_err(|err| FsError::InvalidImage(err.to_string()))?;
+    let journal_start = match geometry.journal_clusters {
+        0 => 0,
+        _ => geometry.journal_start(),
+    };
+    if field(24) != FAT_START_CLUSTER
+        || field(28) != geometry.fat_clusters()
+        || field(32) != ROOT_CLUSTER
+        || field(44) != journal_start
+    {
+        return Err(FsError::InvalidImage(String::from("corrupt superblock")));
+    }
+    let meta_cluster = match field(36) {
+        0 => None,
+        cluster if cluster < geometry.data_clusters() => Some(cluster),
+        _ => return Err(FsError::InvalidImage(String::from("corrupt superblock"))),
+    };
+
+    let allocator = AllocatorKind::from_id(field(40) as u32)
+        .ok_or_else(|| FsError::InvalidImage(String::from("unknown allocator")))?;
+
+    Ok(Superblock {
+        version,
+        geometry,
+        meta_cluster,
+        allocator,
+    })
+}
+
+/// 将FAT项编码为定长的小端字节
+pub fn encode_fat_item(item: &FatItem) -> [u8; FAT_ENTRY_SIZE] {
+    let value = match item {
+        FatItem::NotUsed => FAT_NOT_USED,
+        FatItem::ClusterNo(cluster) => *cluster as u32,
+        FatItem::BadCluster => FAT_BAD_CLUSTER,
+        FatItem::EoF => FAT_EOF,
+    };
+
+    value.to_le_bytes()
+}
+
+/// 从定长的小端字节解码FAT项
+pub fn decode_fat_item(bytes: [u8; FAT_ENTRY_SIZE]) -> FatItem {
+    match u32::from_le_bytes(bytes) {
+        FAT_NOT_USED => FatItem::NotUsed,
+        FAT_BAD_CLUSTER => FatItem::BadCluster,
+        FAT_EOF => FatItem::EoF,
+        cluster => FatItem::ClusterNo(cluster as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn superblock() -> Superblock {
+        Superblock {
+            version: FORMAT_VERSION,
+            geometry: DiskGeometry::new(2048, 300).unwrap(),
+            meta_cluster: Some(5),
+            allocator: AllocatorKind::BestFit,
+        }
+    }
+
+    /// 解析失败，返回`InvalidImage`的说明
+    fn invalid(data: &[u8]) -> String {
+        match decode_superblock(data) {
+            Err(FsError::InvalidImage(reason)) => reason,
+            other => panic!("expected InvalidImage, got {:?}", other),
+        }
+    }
+
+    /// 改写超级块中`offset`处的字段
+    fn with_field(offset: usize, value: u32) -> Vec<u8> {
+        let mut data = encode_superblock(&superblock());
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn superblock_round_trips() {
+        let data = encode_superblock(&superblock());
+        assert_eq!(data.len(), 2048);
+        assert_eq!(&data[..8], MAGIC);
+        assert_eq!(decode_superblock(&data).unwrap(), superblock());
+        // 没有元数据和日志区
+        let bare = Superblock {
+            meta_cluster: None,
+            geometry: DiskGeometry::with_reserved(512, 100, 2, 0).unwrap(),
+            ..superblock()
+        };
+        let data = encode_superblock(&bare);
+        assert_eq!(data[36..40], [0u8; 4]);
+        assert_eq!(data[44..52], [0u8; 8]);
+        assert_eq!(decode_superblock(&data).unwrap(), bare);
+        // 旧版本照样读出，版本号保留下来
+        let data = with_field(8, MIN_FORMAT_VERSION);
+        assert_eq!(
+            decode_superblock(&data).unwrap().version,
+            MIN_FORMAT_VERSION
+        );
+    }
+
+    #[test]
+    fn bad_superblocks_are_invalid_images() {
+        let mut data = encode_superblock(&superblock());
+        data[0] = b'X';
+        assert_eq!(invalid(&data), "bad magic");
+        assert_eq!(invalid(b"IVANDFS\0"), "truncated superblock");
+        assert_eq!(invalid(&[]), "truncated superblock");
+        let data = encode_superblock(&superblock());
+        assert_eq!(
+            invalid(&data[..SUPERBLOCK_SIZE - 1]),
+            "truncated superblock"
+        );
+        assert!(decode_superblock(&data[..SUPERBLOCK_SIZE]).is_ok());
+
+        for version in [0, FORMAT_VERSION + 1, u32::MAX] {
+            assert!(invalid(&with_field(8, version)).starts_with("unsupported version"));
+        }
+        // 几何参数本身不合法
+        invalid(&with_field(12, 1000));
+        invalid(&with_field(16, 3));
+        // 与几何参数对不上的字段
+        let fat_clusters = superblock().geometry.fat_clusters() as u32;
+        let journal_start = superblock().geometry.journal_start() as u32;
+        for (offset, value) in [
+            (24, 2),
+            (28, fat_clusters + 1),
+            (32, 1),
+            (44, journal_start - 1),
+            (44, 0),
+        ] {
+            assert_eq!(invalid(&with_field(offset, value)), "corrupt superblock");
+        }
+        let data_clusters = superblock().geometry.data_clusters() as u32;
+        assert_eq!(
+            invalid(&with_field(36, data_clusters)),
+            "corrupt superblock"
+        );
+        assert!(decode_superblock(&with_field(36, data_clusters - 1)).is_ok());
+        assert_eq!(invalid(&with_field(40, 3)), "unknown allocator");
+    }
+
+    #[test]
+    fn fat_items_round_trip() {
+        for item in [
+            FatItem::NotUsed,
+            FatItem::EoF,
+            FatItem::BadCluster,
+            FatItem::ClusterNo(1),
+            FatItem::ClusterNo(MAX_CLUSTER_NO),
+        ] {
+            assert_eq!(decode_fat_item(encode_fat_item(&item)), item);
+        }
+        assert_eq!(
+            encode_fat_item(&FatItem::ClusterNo(0x0102_0304)),
+            [4, 3, 2, 1]
+        );
+        assert_eq!(encode_fat_item(&FatItem::NotUsed), [0; 4]);
+        assert_eq!(encode_fat_item(&FatItem::EoF), [0xFF; 4]);
+        assert_eq!(
+            encode_fat_item(&FatItem::BadCluster),
+            [0xF7, 0xFF, 0xFF, 0xFF]
+        );
+    }
+}
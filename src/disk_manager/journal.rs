@@ -0,0 +1,724 @@
+//! 元数据日志（预写日志）。
+//!
+//! 超级块、FAT块、目录文件和卷元数据的改动先作为一个事务整体写入保留区末尾的日志区，
+//! 提交之后才写到它们原来的位置；文件内容不经过日志，在提交之前直接写入新分配的簇。
+//!
+//! 日志区的第一部分是事务头，之后依次是事务中每个块的新内容：
+//!
+//! | 偏移 | 长度 | 内容                                  |
+//! | ---- | ---- | ------------------------------------- |
+//! | 0    | 8    | 魔数 `IVANJNL\0`，没有事务时全为0     |
+//! | 8    | 4    | 事务中的块数量`n`                     |
+//! | 12   | 4    | 校验和，覆盖块数量、块号和所有块的内容 |
+//! | 16   | 4×n  | 每个块在卷上的块号                    |
+//! | 16+4n| 4×s  | 溢出的`s`个块的新内容所在的块号        |
+//!
+//! 日志区放不下整个事务时，最后`s`个块的新内容放在数据区的空闲簇中（溢出），它们的位置记在块号之后，
+//! 也在校验和之内。这些簇在提交之前和提交之后都是空闲的，写入它们不会破坏任何一个状态；`s`由块数量和
+//! 日志区大小决定，没有溢出时事务头和以前完全相同。日志区连事务头都放不下，或者空闲簇不够时，
+//! 提交失败，什么也不写。
+//!
+//! 写入顺序：块的新内容 → 刷新 → 事务头（提交点） → 刷新 → 原位置 → 刷新 → 清空事务头 → 刷新。
+//! 加载时事务头完整且校验和正确，说明事务已经提交，重新写一遍原位置（重放）；
+//! 否则事务没有提交，原位置还没有被改动过，直接清空日志区（回滚）。
+//!
+//! `DiskManager::transaction`把一个操作的所有改动放进同一个事务，出错时撤销内存中的改动。
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use super::device::BlockDevice;
+use super::disk::DiskGeometry;
+use super::error::FsError;
+use super::meta::VolumeMeta;
+use super::{pdebug, Directory, DiskManager};
+
+/// 事务头魔数
+pub const JOURNAL_MAGIC: &[u8; 8] = b"IVANJNL\0";
+/// 事务头中块号之前的固定部分的长度（字节）
+const HEADER_FIXED_SIZE: usize = 16;
+/// 每个块号的长度（字节）
+const BLOCK_NO_SIZE: usize = 4;
+/// 除了超级块和FAT区以外，一个事务中还能放下的块数量，用于目录文件和卷元数据
+const EXTRA_CLUSTERS: usize = 16;
+
+/// 新磁盘的日志区大小：能放下事务头、超级块和整个FAT区，再加上一些目录文件的块
+pub fn default_clusters(fat_clusters: usize) -> usize {
+    1 + 1 + fat_clusters + EXTRA_CLUSTERS
+}
+
+/// 加载时日志区的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// 日志区是空的
+    Clean,
+    /// 重放了一个已经提交的事务，包含这么多块
+    Replayed(usize),
+    /// 丢弃了一个没有提交完的事务
+    Discarded,
+}
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recovery::Clean => write!(f, "journal is clean"),
+            Recovery::Replayed(blocks) => {
+                write!(f, "replayed a committed transaction of {} blocks", blocks)
+            }
+            Recovery::Discarded => write!(f, "discarded an unfinished transaction"),
+        }
+    }
+}
+
+/// 放下`numbers`个块号的事务头需要的簇数量
+fn header_clusters(cluster_size: usize, numbers: usize) -> usize {
+    (HEADER_FIXED_SIZE + numbers * BLOCK_NO_SIZE).div_ceil(cluster_size)
+}
+
+/// 不溢出时一个事务最多能包含的块数量
+pub fn capacity(geometry: &DiskGeometry) -> usize {
+    let mut blocks = geometry.journal_clusters;
+    while blocks > 0
+        && header_clusters(geometry.cluster_size, blocks) + blocks > geometry.journal_clusters
+    {
+        blocks -= 1;
+    }
+
+    blocks
+}
+
+/// `blocks`个块的事务中放在日志区里的块数量，其余的块溢出到空闲簇中。
+/// 每溢出一个块，事务头就要多记一个块号。日志区连事务头都放不下时返回`None`。
+fn inline_blocks(geometry: &DiskGeometry, blocks: usize) -> Option<usize> {
+    let journal = geometry.journal_clusters;
+    let fits = |inline: usize| {
+        let numbers = blocks + (blocks - inline);
+        header_clusters(geometry.cluster_size, numbers) + inline <= journal
+    };
+    // 放在日志区里的块越多，事务头越短，所以能放下的块数量可以二分查找
+    let (mut low, mut high) = (0, blocks.min(journal));
+    if !fits(low) {
+        return None;
+    }
+    while low < high {
+        let mid = high - (high - low) / 2;
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    Some(low)
+}
+
+/// 提交`blocks`个块的事务需要多少个溢出用的空闲簇。没有日志区时不需要，
+/// 日志区连事务头都放不下时返回`None`。
+pub fn spill_needed(geometry: &DiskGeometry, blocks: usize) -> Option<usize> {
+    if geometry.journal_clusters == 0 {
+        return Some(0);
+    }
+    inline_blocks(geometry, blocks).map(|inline| blocks - inline)
+}
+
+/// FNV-1a校验和
+fn checksum<'a>(parts: impl Iterator<Item = &'a [u8]>) -> u32 {
+    let mut hash: u32 = 0x811C_9DC5;
+    for part in parts {
+        for byte in part {
+            hash ^= *byte as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+    }
+
+    hash
+}
+
+/// 事务的校验和：块数量、块号、溢出位置和所有块的内容
+fn transaction_checksum(blocks: &BTreeMap<usize, Vec<u8>>, spill: &[usize]) -> u32 {
+    let count = (blocks.len() as u32).to_le_bytes();
+    let block_nos: Vec<[u8; BLOCK_NO_SIZE]> = blocks
+        .keys()
+        .chain(spill)
+        .map(|block| (*block as u32).to_le_bytes())
+        .collect();
+    checksum(
+        std::iter::once(&count[..])
+            .chain(block_nos.iter().map(|bytes| &bytes[..]))
+            .chain(blocks.values().map(|data| data.as_slice())),
+    )
+}
+
+/// 把一个事务写入磁盘：先写日志区并提交，再写原位置，最后清空日志区。
+/// 日志区放不下的最后几个块写到`spill`中的块上，数量必须是`spill_needed`。
+/// 没有日志区时直接写原位置，这时的写入不受保护。
+pub fn write_transaction(
+    device: &mut dyn BlockDevice,
+    geometry: &DiskGeometry,
+    blocks: &BTreeMap<usize, Vec<u8>>,
+    spill: &[usize],
+) -> io::Result<()> {
+    if blocks.is_empty() {
+        return Ok(());
+    }
+    let journaled = geometry.journal_clusters > 0;
+    if journaled {
+        let cluster_size = geometry.cluster_size;
+        let start = geometry.journal_start();
+        let inline = inline_blocks(geometry, blocks.len())
+            .filter(|inline| blocks.len() - inline == spill.len())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "transaction does not fit in the journal",
+                )
+            })?;
+        let header_len = header_clusters(cluster_size, blocks.len() + spill.len());
+        for (i, data) in blocks.values().enumerate() {
+            match i.checked_sub(inline) {
+                None => device.write_block(start + header_len + i, data)?,
+                Some(k) => device.write_block(spill[k], data)?,
+            }
+        }
+        device.flush()?;
+
+        let mut header = Vec::with_capacity(header_len * cluster_size);
+        header.extend_from_slice(JOURNAL_MAGIC);
+        header.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+        header.extend_from_slice(&transaction_checksum(blocks, spill).to_le_bytes());
+        for block in blocks.keys().chain(spill) {
+            header.extend_from_slice(&(*block as u32).to_le_bytes());
+        }
+        header.resize(header_len * cluster_size, 0u8);
+        // 第一块带着魔数，最后写入，它落到介质上才算提交
+        for (i, chunk) in header.chunks(cluster_size).enumerate().rev() {
+            device.write_block(start + i, chunk)?;
+        }
+        device.flush()?;
+    }
+
+    for (block, data) in blocks {
+        device.write_block(*block, data)?;
+    }
+    device.flush()?;
+
+    if journaled {
+        clear(device, geometry)?;
+    }
+
+    Ok(())
+}
+
+/// 清空事务头
+pub fn clear(device: &mut dyn BlockDevice, geometry: &DiskGeometry) -> io::Result<()> {
+    device.write_block(geometry.journal_start(), &vec![0u8; geometry.cluster_size])?;
+    device.flush()
+}
+
+/// 读出并检查日志区中的事务，已经提交的返回其中的块，没有提交完的返回`None`
+fn read_transaction(
+    device: &dyn BlockDevice,
+    geometry: &DiskGeometry,
+) -> io::Result<Option<BTreeMap<usize, Vec<u8>>>> {
+    let cluster_size = geometry.cluster_size;
+    let start = geometry.journal_start();
+    let mut first = vec![0u8; cluster_size];
+    device.read_block(start, &mut first)?;
+    let field = |data: &[u8], offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes) as usize
+    };
+    let count = field(&first, 8);
+    if count == 0 || count > geometry.cluster_count {
+        return Ok(None);
+    }
+    let inline = match inline_blocks(geometry, count) {
+        Some(inline) => inline,
+        None => return Ok(None),
+    };
+    let spilled = count - inline;
+
+    let header_len = header_clusters(cluster_size, count + spilled);
+    let mut header = first;
+    for i in 1..header_len {
+        let mut block = vec![0u8; cluster_size];
+        device.read_block(start + i, &mut block)?;
+        header.extend_from_slice(&block);
+    }
+    let number = |i: usize| field(&header, HEADER_FIXED_SIZE + i * BLOCK_NO_SIZE);
+    // 溢出的块只会放在数据区中
+    let spill: Vec<usize> = (count..count + spilled).map(number).collect();
+    if spill
+        .iter()
+        .any(|block| *block < geometry.reserved_clusters || *block >= geometry.cluster_count)
+    {
+        return Ok(None);
+    }
+    let mut blocks = BTreeMap::new();
+    for i in 0..count {
+        let block = number(i);
+        // 日志只会改动日志区以外的块
+        if block >= geometry.journal_start() && block < geometry.reserved_clusters
+            || block >= geometry.cluster_count
+        {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; cluster_size];
+        match i.checked_sub(inline) {
+            None => device.read_block(start + header_len + i, &mut data)?,
+            Some(k) => device.read_block(spill[k], &mut data)?,
+        }
+        blocks.insert(block, data);
+    }
+    if blocks.len() != count || transaction_checksum(&blocks, &spill) != field(&header, 12) as u32 {
+        return Ok(None);
+    }
+
+    Ok(Some(blocks))
+}
+
+/// 加载时处理日志区：重放已经提交的事务，丢弃没有提交完的事务
+pub fn recover(device: &mut dyn BlockDevice, geometry: &DiskGeometry) -> io::Result<Recovery> {
+    if geometry.journal_clusters == 0 {
+        return Ok(Recovery::Clean);
+    }
+    let mut first = vec![0u8; geometry.cluster_size];
+    device.read_block(geometry.journal_start(), &mut first)?;
+    if &first[0..8] != JOURNAL_MAGIC {
+        if first.iter().all(|byte| *byte == 0) {
+            return Ok(Recovery::Clean);
+        }
+        // 事务头只写了一半
+        clear(device, geometry)?;
+        return Ok(Recovery::Discarded);
+    }
+
+    match read_transaction(device, geometry)? {
+        Some(blocks) => {
+            for (block, data) in &blocks {
+                device.write_block(*block, data)?;
+            }
+            device.flush()?;
+            clear(device, geometry)?;
+            Ok(Recovery::Replayed(blocks.len()))
+        }
+        None => {
+            clear(device, geometry)?;
+            Ok(Recovery::Discarded)
+        }
+    }
+}
+
+/// 事务开始时内存中的状态，撤销时恢复
+pub(crate) struct Snapshot {
+    meta: VolumeMeta,
+    cur_dir: Directory,
+}
+
+impl DiskManager {
+    /// 把`f`中的所有改动作为一个事务：成功时提交，出错时撤销FAT表、目录和卷元数据的改动。
+    /// 已经在事务中时，改动并入外层事务。
+    pub fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut DiskManager) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        if self.snapshot.is_some() {
+            return f(self);
+        }
+        self.disk.begin();
+        self.snapshot = Some(Snapshot {
+            meta: self.meta.clone(),
+            cur_dir: self.cur_dir.clone(),
+        });
+        let result = f(self);
+        let snapshot = self.snapshot.take().unwrap();
+        // 提交之前就失败了（例如事务太大放不下）时同样撤销；
+        // 写到一半失败时改动仍然留在内存中，下次提交时一起写入
+        match result.and_then(|value| self.flush().map(|()| value)) {
+            Ok(value) => Ok(value),
+            Err(err) if self.disk.can_rollback() => {
+                pdebug();
+                println!("Rolling back: {}", err);
+                self.disk.rollback();
+                self.meta = snapshot.meta;
+                self.cur_dir = snapshot.cur_dir;
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Write;
+
+    use super::*;
+    use crate::disk_manager::alloc::AllocatorKind;
+    use crate::disk_manager::clock::ManualClock;
+    use crate::disk_manager::device::{FaultyDevice, MemoryDevice};
+    use crate::disk_manager::disk::Disk;
+    use crate::disk_manager::fsck::Repair;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::{SharedDevice, START};
+    use crate::disk_manager::FileType;
+
+    const CLUSTER_SIZE: usize = 512;
+    const CLUSTER_COUNT: usize = 256;
+
+    fn geometry() -> DiskGeometry {
+        DiskGeometry::new(CLUSTER_SIZE, CLUSTER_COUNT).unwrap()
+    }
+
+    /// 每个块的内容都是同一个字节
+    fn filled(blocks: &[usize], byte: u8) -> BTreeMap<usize, Vec<u8>> {
+        blocks
+            .iter()
+            .map(|block| (*block, vec![byte; CLUSTER_SIZE]))
+            .collect()
+    }
+
+    /// 这些块要么全是旧内容，要么全是新内容
+    fn assert_all_or_nothing(device: &dyn BlockDevice, blocks: &[usize]) {
+        let mut seen = Vec::new();
+        for block in blocks {
+            let mut data = vec![0u8; CLUSTER_SIZE];
+            device.read_block(*block, &mut data).unwrap();
+            assert!(data.iter().all(|byte| *byte == data[0]));
+            seen.push(data[0]);
+        }
+        seen.dedup();
+        assert_eq!(seen.len(), 1, "blocks {:?} are half written", blocks);
+    }
+
+    /// 在每一次写入之后崩溃，恢复（恢复本身也可能崩溃）之后这些块要么全是旧内容，要么全是新内容
+    fn check_crash_at_every_write(blocks: &[usize], spill: &[usize]) {
+        let geometry = geometry();
+        let mut base = MemoryDevice::new(CLUSTER_SIZE, CLUSTER_COUNT);
+        write_transaction(&mut base, &geometry, &filled(blocks, 1), spill).unwrap();
+
+        for k in 0.. {
+            let mut device =
+                FaultyDevice::new(SharedDevice::from_bytes(CLUSTER_SIZE, base.as_bytes()));
+            device.crash_after_writes(k);
+            let done = write_transaction(&mut device, &geometry, &filled(blocks, 2), spill).is_ok();
+            let crashed = device.inner().bytes();
+
+            // 恢复本身也可能在任何一次写入时崩溃，之后再恢复一次
+            for j in 0.. {
+                let mut device =
+                    FaultyDevice::new(SharedDevice::from_bytes(CLUSTER_SIZE, &crashed));
+                device.crash_after_writes(j);
+                let recovered = recover(&mut device, &geometry).is_ok();
+                device.clear_faults();
+                recover(&mut device, &geometry).unwrap();
+                assert_eq!(recover(&mut device, &geometry).unwrap(), Recovery::Clean);
+                assert_all_or_nothing(&device, blocks);
+                if recovered {
+                    break;
+                }
+            }
+
+            if done {
+                let mut device = SharedDevice::from_bytes(CLUSTER_SIZE, &crashed);
+                assert_eq!(recover(&mut device, &geometry).unwrap(), Recovery::Clean);
+                let mut data = vec![0u8; CLUSTER_SIZE];
+                device.read_block(blocks[0], &mut data).unwrap();
+                assert_eq!(data[0], 2);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_survives_crash_at_every_write() {
+        let geometry = geometry();
+        check_crash_at_every_write(
+            &[0, 1, geometry.reserved_clusters + 3, CLUSTER_COUNT - 1],
+            &[],
+        );
+    }
+
+    #[test]
+    fn oversized_transaction_spills_and_survives_crash() {
+        let geometry = geometry();
+        // 超级块加上数据区前半部分的块，比日志区能放下的多得多
+        let data_start = geometry.reserved_clusters;
+        let blocks: Vec<usize> = std::iter::once(0)
+            .chain(data_start..data_start + 3 * capacity(&geometry))
+            .collect();
+        let needed = spill_needed(&geometry, blocks.len()).unwrap();
+        assert!(needed > 0);
+        let spill: Vec<usize> = (CLUSTER_COUNT - needed..CLUSTER_COUNT).collect();
+        assert!(spill[0] > *blocks.last().unwrap());
+
+        // 溢出的数量不对时什么也不写
+        let mut device = MemoryDevice::new(CLUSTER_SIZE, CLUSTER_COUNT);
+        assert!(
+            write_transaction(&mut device, &geometry, &filled(&blocks, 1), &spill[1..]).is_err()
+        );
+        assert_eq!(recover(&mut device, &geometry).unwrap(), Recovery::Clean);
+
+        check_crash_at_every_write(&blocks, &spill);
+    }
+
+    #[test]
+    fn journal_too_small_for_header_is_refused() {
+        let geometry = geometry();
+        // 每个溢出的块在事务头里占两个块号，日志区总有放不下的时候
+        let too_many = geometry.journal_clusters * CLUSTER_SIZE / BLOCK_NO_SIZE / 2 + 1;
+        assert_eq!(spill_needed(&geometry, too_many), None);
+        assert_eq!(spill_needed(&geometry, capacity(&geometry)), Some(0));
+        assert_eq!(spill_needed(&geometry, capacity(&geometry) + 1), Some(1));
+    }
+
+    #[test]
+    fn committed_transaction_is_replayed() {
+        let geometry = geometry();
+        let blocks = [2, geometry.reserved_clusters];
+        let mut device = FaultyDevice::new(MemoryDevice::new(CLUSTER_SIZE, CLUSTER_COUNT));
+        // 日志区的数据块、事务头写完，原位置还没写就崩溃
+        let header_writes = header_clusters(CLUSTER_SIZE, blocks.len());
+        device.crash_after_writes(blocks.len() + header_writes);
+        assert!(write_transaction(&mut device, &geometry, &filled(&blocks, 7), &[]).is_err());
+        device.clear_faults();
+
+        assert_eq!(
+            recover(&mut device, &geometry).unwrap(),
+            Recovery::Replayed(blocks.len())
+        );
+        assert_all_or_nothing(&device, &blocks);
+        let mut data = vec![0u8; CLUSTER_SIZE];
+        device.read_block(blocks[1], &mut data).unwrap();
+        assert_eq!(data[0], 7);
+    }
+
+    #[test]
+    fn corrupt_transaction_is_discarded() {
+        let geometry = geometry();
+        let blocks = [2, 3];
+        let mut device = MemoryDevice::new(CLUSTER_SIZE, CLUSTER_COUNT);
+        write_transaction(&mut device, &geometry, &filled(&blocks, 1), &[]).unwrap();
+        let mut device = FaultyDevice::new(device);
+        let header_writes = header_clusters(CLUSTER_SIZE, blocks.len());
+        device.crash_after_writes(blocks.len() + header_writes);
+        assert!(write_transaction(&mut device, &geometry, &filled(&blocks, 2), &[]).is_err());
+        device.clear_faults();
+        // 日志区中的一个数据块坏了，校验和对不上
+        let start = geometry.journal_start() + header_writes;
+        device.write_block(start, &[9u8; CLUSTER_SIZE]).unwrap();
+
+        assert_eq!(
+            recover(&mut device, &geometry).unwrap(),
+            Recovery::Discarded
+        );
+        let mut data = vec![0u8; CLUSTER_SIZE];
+        device.read_block(blocks[0], &mut data).unwrap();
+        assert_eq!(data[0], 1);
+    }
+
+    /// 整棵目录树：路径 → 文件内容，目录的内容为空。再加上共享计数
+    type TreeState = (BTreeMap<String, Vec<u8>>, BTreeMap<usize, usize>);
+
+    /// 读取时不更新访问时间，不改动磁盘
+    fn tree_state(dm: &mut DiskManager) -> TreeState {
+        fn walk(dm: &mut DiskManager, path: &str, state: &mut BTreeMap<String, Vec<u8>>) {
+            let dir = dm.resolve_directory(path).unwrap();
+            for fcb in dir.files.iter().skip(2) {
+                let child = format!("{}/{}", path, fcb.name);
+                match fcb.file_type {
+                    FileType::File => {
+                        state.insert(child.clone(), dm.read_file_by_name(&child).unwrap());
+                    }
+                    FileType::Directory => {
+                        state.insert(format!("{}/", child), Vec::new());
+                        walk(dm, &child, state);
+                    }
+                    FileType::Symlink => {
+                        state.insert(child.clone(), dm.read_link(&child).unwrap().into_bytes());
+                    }
+                }
+            }
+        }
+        dm.noatime = true;
+        let mut state = BTreeMap::new();
+        walk(dm, "", &mut state);
+
+        (state, dm.meta.shared.clone())
+    }
+
+    /// 格式化并建好测试用的目录树，返回整个卷的内容
+    fn base_volume() -> Vec<u8> {
+        let device =
+            SharedDevice::from_bytes(CLUSTER_SIZE, &vec![0u8; CLUSTER_SIZE * CLUSTER_COUNT]);
+        let mut dm = DiskManager::with_device(
+            Box::new(device.clone()),
+            geometry(),
+            AllocatorKind::FirstFit,
+            Box::new(ManualClock::new(START)),
+        )
+        .unwrap();
+        dm.new_directory_to_disk("/d").unwrap();
+        dm.new_directory_to_disk("/x").unwrap();
+        dm.create_file_with_data("/d/f", &[b'f'; 1500]).unwrap();
+        dm.create_file_with_data("/d/g", b"g").unwrap();
+        dm.create_file_with_data("/top", &[b't'; 700]).unwrap();
+        drop(dm);
+
+        device.bytes()
+    }
+
+    fn open(device: Box<dyn BlockDevice>) -> Result<DiskManager, FsError> {
+        DiskManager::from_disk(Disk::open(device)?)
+    }
+
+    /// 在每一次写入之后崩溃，重新打开后磁盘必须能通过检查，并且停在操作之前或者之后的状态
+    fn check_crash_consistency(op: impl Fn(&mut DiskManager) -> Result<(), FsError>) {
+        let base = base_volume();
+        let before =
+            tree_state(&mut open(Box::new(SharedDevice::from_bytes(CLUSTER_SIZE, &base))).unwrap());
+        let after = {
+            let mut dm = open(Box::new(SharedDevice::from_bytes(CLUSTER_SIZE, &base))).unwrap();
+            op(&mut dm).unwrap();
+            dm.flush().unwrap();
+            tree_state(&mut dm)
+        };
+        assert_ne!(before, after);
+
+        for k in 0.. {
+            let shared = SharedDevice::from_bytes(CLUSTER_SIZE, &base);
+            let mut device = FaultyDevice::new(shared.clone());
+            device.crash_after_writes(k);
+            let mut dm = open(Box::new(device)).unwrap();
+            let done = op(&mut dm).and_then(|()| dm.flush()).is_ok();
+            drop(dm);
+
+            let mut dm = open(Box::new(SharedDevice::from_bytes(
+                CLUSTER_SIZE,
+                &shared.bytes(),
+            )))
+            .unwrap();
+            let report = dm.fsck(Repair::None).unwrap();
+            assert!(report.is_clean(), "crash after {} writes: {}", k, report);
+            let state = tree_state(&mut dm);
+            if done {
+                assert_eq!(state, after);
+                break;
+            }
+            assert!(
+                state == before || state == after,
+                "crash after {} writes left {:?}",
+                k,
+                state.0.keys().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn mkdir_is_crash_consistent() {
+        check_crash_consistency(|dm| dm.new_directory_to_disk("/d/new"));
+    }
+
+    #[test]
+    fn create_file_is_crash_consistent() {
+        check_crash_consistency(|dm| dm.create_file_with_data("/d/new", &[b'n'; 3000]));
+    }
+
+    #[test]
+    fn rm_is_crash_consistent() {
+        check_crash_consistency(|dm| dm.delete_file_by_name("/d/f"));
+    }
+
+    #[test]
+    fn rm_recursive_is_crash_consistent() {
+        check_crash_consistency(|dm| dm.delete_recursive("/d", false).map(|_report| ()));
+    }
+
+    #[test]
+    fn rename_is_crash_consistent() {
+        check_crash_consistency(|dm| dm.rename_file_by_name("/d", "e"));
+    }
+
+    #[test]
+    fn mv_is_crash_consistent() {
+        check_crash_consistency(|dm| dm.move_by_path("/d", "/x"));
+    }
+
+    #[test]
+    fn cp_cow_is_crash_consistent() {
+        check_crash_consistency(|dm| dm.copy_by_path("/d", "/x/c", true, true));
+    }
+
+    #[test]
+    fn file_write_is_crash_consistent() {
+        check_crash_consistency(|dm| {
+            let mut options = OpenOptions::new();
+            options.append(true);
+            let mut handle = dm.open("/d/f", &options)?;
+            handle.write_all(&[b'a'; 2000])?;
+            handle.close()
+        });
+    }
+
+    #[test]
+    fn transaction_larger_than_journal_is_crash_consistent() {
+        let journal = capacity(&geometry());
+        check_crash_consistency(move |dm| {
+            dm.transaction(|dm| {
+                // 每个新目录都要写一个目录块，加起来比日志区大
+                for i in 0..journal + 4 {
+                    dm.new_directory_to_disk(&format!("/x/d{}", i))?;
+                }
+                Ok(())
+            })
+        });
+    }
+
+    #[test]
+    fn transaction_too_large_to_commit_is_rolled_back() {
+        let mut dm = open(Box::new(SharedDevice::from_bytes(
+            CLUSTER_SIZE,
+            &base_volume(),
+        )))
+        .unwrap();
+        let before = tree_state(&mut dm);
+        let free = dm.disk.free_space().len();
+        // 一直新建目录，直到剩下的空闲簇放不下溢出的块；每个目录都建成功了，失败的是提交
+        let result = dm.transaction(|dm| {
+            let mut i = 0;
+            while dm.disk.free_space().len() > i {
+                dm.new_directory_to_disk(&format!("/x/d{}", i))?;
+                i += 1;
+            }
+            Ok(())
+        });
+        assert!(matches!(result, Err(FsError::NoSpace)));
+        assert_eq!(tree_state(&mut dm), before);
+        assert_eq!(dm.disk.free_space().len(), free);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+        dm.new_directory_to_disk("/x/after").unwrap();
+    }
+
+    #[test]
+    fn failed_operation_is_rolled_back() {
+        let mut dm = open(Box::new(SharedDevice::from_bytes(
+            CLUSTER_SIZE,
+            &base_volume(),
+        )))
+        .unwrap();
+        let before = tree_state(&mut dm);
+        let free = dm.disk.free_space().len();
+        // 复制到一半时目标已经存在
+        let result = dm.transaction(|dm| {
+            dm.create_file_with_data("/x/a", &[b'a'; 2000])?;
+            dm.copy_by_path("/d", "/x/d", true, false)?;
+            dm.create_file_with_data("/x/a", b"again")
+        });
+        assert!(matches!(result, Err(FsError::AlreadyExists(_))));
+        assert_eq!(tree_state(&mut dm), before);
+        assert_eq!(dm.disk.free_space().len(), free);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+}
@@ -0,0 +1,214 @@
+//! 硬链接。
+//!
+//! 同一个文件可以在多个目录项中出现，这些FCB指向同一条簇链，通过任何一个写入，其他的都能看到。
+//! 每条簇链的链接数像写时复制的共享计数一样记在卷元数据中，只记录多于1的链；删除一个链接只减少计数，
+//! 删除最后一个链接时才释放簇链。
+//!
+//! 长度、时间、权限和ACL仍然记在每个FCB中，通过一个链接修改时用`sync_links`同步到其他链接。
+//! 一条簇链不会既被写时复制共享又有多个硬链接：链接前先复制出独立的簇链，复制有链接的文件时也不共享。
+//! 目录不能建立硬链接。
+
+use std::collections::HashSet;
+
+use super::error::FsError;
+use super::users::{EXECUTE, READ, ROOT_UID, WRITE};
+use super::{check_file_name, pdebug, pinfo, DiskManager, Fcb, FileType, ROOT_CLUSTER};
+
+impl DiskManager {
+    /// 指向首簇为`first_cluster`的簇链的硬链接数量
+    pub fn link_count(&self, first_cluster: usize) -> usize {
+        self.meta.links.get(&first_cluster).copied().unwrap_or(1)
+    }
+
+    /// 为`src`新建硬链接`dst`，`dst`的含义同`move_by_path`。
+    /// 需要对`dst`所在目录有写和执行权限，并且是文件的属主，或者对文件有读写权限。
+    pub fn link_by_path(&mut self, src: &str, dst: &str) -> Result<(), FsError> {
+        pinfo();
+        println!("Linking '{}' to '{}'...", dst, src);
+        let (src_parent, src_name) = self.resolve_parent(src)?;
+        check_file_name(src_name)?;
+        let index = src_parent
+            .get_index_by_name(src_name)
+            .ok_or_else(|| FsError::NotFound(String::from(src)))?;
+        let fcb = src_parent.files[index].clone();
+        if let FileType::Directory = fcb.file_type {
+            return Err(FsError::IsADirectory(String::from(src)));
+        }
+        if self.uid() != ROOT_UID
+            && self.uid() != fcb.perm.owner
+            && !self.allowed(&fcb, READ | WRITE)
+        {
+            return Err(FsError::PermissionDenied(String::from(src)));
+        }
+        let (dst_parent, dst_name) = self.resolve_target(src_name, dst)?;
+        self.check_dir_access(&dst_parent, WRITE | EXECUTE, dst)?;
+        if dst_parent.get_fcb_by_name(&dst_name).is_some() {
+            return Err(FsError::AlreadyExists(String::from(dst)));
+        }
+        let src_cluster = src_parent.files[1].first_cluster;
+        let dst_cluster = dst_parent.files[1].first_cluster;
+        self.check_move_quota(&fcb, src_cluster, dst_cluster)?;
+
+        self.transaction(|dm| {
+            let mut src_parent = src_parent;
+            let mut dst_parent = dst_parent;
+            // 写时复制共享的簇链先复制一份，链接只指向这个文件自己的簇链
+            let first_cluster = dm.unshare_chain(fcb.first_cluster)?;
+            src_parent.files[index].first_cluster = first_cluster;
+            *dm.meta.links.entry(first_cluster).or_insert(1) += 1;
+            let link = Fcb {
+                name: dst_name,
+                first_cluster,
+                ..fcb
+            };
+            if src_cluster == dst_cluster {
+                src_parent.files.push(link);
+                dm.store_directory(&src_parent)?;
+            } else {
+                dst_parent.files.push(link);
+                dm.store_directory(&src_parent)?;
+                dm.store_directory(&dst_parent)?;
+            }
+            dm.touch_directory(dst_cluster)
+        })
+    }
+
+    /// 把`fcb`的长度、时间、权限和ACL同步到同一个文件的其他链接，没有其他链接时什么也不做。
+    /// 调用者负责提交。
+    pub(crate) fn sync_links(&mut self, fcb: &Fcb) -> Result<(), FsError> {
+        if let FileType::Directory = fcb.file_type {
+            return Ok(());
+        }
+        if self.link_count(fcb.first_cluster) <= 1 {
+            return Ok(());
+        }
+        pdebug();
+        println!("Syncing links of '{}'...", fcb.name);
+        self.sync_links_in(ROOT_CLUSTER, fcb, &mut HashSet::new())
+    }
+
+    /// 在首簇为`dir_cluster`的目录及其子目录中同步`fcb`的链接
+    fn sync_links_in(
+        &mut self,
+        dir_cluster: usize,
+        fcb: &Fcb,
+        visited: &mut HashSet<usize>,
+    ) -> Result<(), FsError> {
+        if !visited.insert(dir_cluster) {
+            return Ok(());
+        }
+        let mut dir = self.read_directory_quietly(dir_cluster)?;
+        let mut changed = false;
+        for link in dir.files.iter_mut().skip(2) {
+            if !matches!(link.file_type, FileType::Directory)
+                && link.first_cluster == fcb.first_cluster
+            {
+                link.length = fcb.length;
+                link.times = fcb.times;
+                link.perm = fcb.perm;
+                link.acl = fcb.acl.clone();
+                changed = true;
+            }
+        }
+        if changed {
+            self.store_directory(&dir)?;
+        }
+        for child in dir.files.iter().skip(2) {
+            if let FileType::Directory = child.file_type {
+                self.sync_links_in(child.first_cluster, fcb, visited)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::disk_manager::fsck::Repair;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::new_disk;
+
+    /// `/a`和它在`/d`中的链接`/d/b`
+    fn linked_disk() -> DiskManager {
+        let mut dm = new_disk();
+        dm.new_directory_to_disk("/d").unwrap();
+        dm.create_file_with_data("/a", b"hello").unwrap();
+        dm.link_by_path("/a", "/d/b").unwrap();
+        dm
+    }
+
+    fn append(dm: &mut DiskManager, path: &str, data: &[u8]) {
+        let mut file = dm.open(path, OpenOptions::new().append(true)).unwrap();
+        file.write_all(data).unwrap();
+        file.close().unwrap();
+    }
+
+    fn clean(dm: &mut DiskManager) -> bool {
+        dm.fsck(Repair::None).unwrap().is_clean()
+    }
+
+    #[test]
+    fn chain_is_freed_with_the_last_link() {
+        let mut dm = linked_disk();
+        assert_eq!(dm.stat("/a").unwrap().links, 2);
+        append(&mut dm, "/d/b", b" world");
+        assert_eq!(dm.read_file_by_name("/a").unwrap(), b"hello world");
+        assert_eq!(dm.stat("/a").unwrap().length, 11);
+        assert!(clean(&mut dm));
+
+        dm.delete_file_by_name("/a").unwrap();
+        assert_eq!(dm.read_file_by_name("/d/b").unwrap(), b"hello world");
+        assert_eq!(dm.stat("/d/b").unwrap().links, 1);
+        // 簇链还在，fsck不会把它当成丢失或者空闲的簇
+        assert!(clean(&mut dm));
+
+        let free = dm.get_disk_info().2;
+        dm.delete_file_by_name("/d/b").unwrap();
+        assert_eq!(dm.get_disk_info().2, free + 1);
+        assert!(dm.meta.links.is_empty());
+        assert!(clean(&mut dm));
+    }
+
+    #[test]
+    fn links_and_copy_on_write_stay_apart() {
+        let mut dm = new_disk();
+        dm.create_file_with_data("/a", b"one").unwrap();
+        dm.copy_by_path("/a", "/cow", false, true).unwrap();
+        // 链接前先和/cow分开
+        dm.link_by_path("/a", "/b").unwrap();
+        assert_eq!(dm.share_count(dm.stat("/cow").unwrap().first_cluster), 1);
+        dm.copy_by_path("/b", "/copy", false, true).unwrap();
+        append(&mut dm, "/b", b" two");
+
+        assert_eq!(dm.read_file_by_name("/a").unwrap(), b"one two");
+        assert_eq!(dm.read_file_by_name("/cow").unwrap(), b"one");
+        assert_eq!(dm.read_file_by_name("/copy").unwrap(), b"one");
+        dm.new_directory_to_disk("/dir").unwrap();
+        assert!(matches!(
+            dm.link_by_path("/dir", "/dir2"),
+            Err(FsError::IsADirectory(_))
+        ));
+        assert!(clean(&mut dm));
+    }
+
+    #[test]
+    fn links_survive_reload_and_tree_removal() {
+        let mut dm = linked_disk();
+        dm.chmod("/a", "600").unwrap();
+        assert_eq!(dm.stat("/d/b").unwrap().perm.mode, 0o600);
+
+        let mut image = Vec::new();
+        dm.write_image(&mut image).unwrap();
+        let mut dm = DiskManager::from_image(image).unwrap();
+        assert_eq!(dm.stat("/d/b").unwrap().links, 2);
+        assert!(dm.delete_recursive("/d", true).unwrap().clusters < 3);
+        dm.delete_recursive("/d", false).unwrap();
+        assert_eq!(dm.read_file_by_name("/a").unwrap(), b"hello");
+        assert_eq!(dm.stat("/a").unwrap().links, 1);
+        assert!(clean(&mut dm));
+    }
+}
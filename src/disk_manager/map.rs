@@ -0,0 +1,345 @@
+//! 簇的分布情况：簇位图和碎片报告。
+//!
+//! 两者都先生成结构化的数据，`Display`只负责在终端中显示。
+
+use std::collections::HashSet;
+use std::fmt;
+
+use ansi_rgb::Foreground;
+
+use super::disk::FatItem;
+use super::error::FsError;
+use super::image::ROOT_CLUSTER;
+use super::{Directory, DiskManager, Fcb, FileType};
+
+/// 簇位图中一格最多显示的列数
+const MAP_COLUMNS: usize = 64;
+/// 簇位图最多显示的格数，簇更多时一格代表多个簇
+const MAP_MAX_CELLS: usize = MAP_COLUMNS * 32;
+
+/// 簇在簇位图中的状态。一格代表多个簇时，显示其中排在最后的状态。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClusterState {
+    Free,
+    /// 文件簇链中间的簇
+    Used,
+    /// 文件簇链的最后一个簇
+    EoF,
+    /// 目录文件的簇
+    Directory,
+    Bad,
+    /// 选中的文件或目录的簇
+    Selected,
+}
+impl ClusterState {
+    pub const ALL: [ClusterState; 6] = [
+        ClusterState::Free,
+        ClusterState::Used,
+        ClusterState::EoF,
+        ClusterState::Directory,
+        ClusterState::Bad,
+        ClusterState::Selected,
+    ];
+
+    /// 带颜色的一格
+    fn cell(self) -> impl fmt::Display {
+        let color = match self {
+            ClusterState::Free => ansi_rgb::white(),
+            ClusterState::Used => ansi_rgb::green(),
+            ClusterState::EoF => ansi_rgb::cyan(),
+            ClusterState::Directory => ansi_rgb::blue_magenta(),
+            ClusterState::Bad => ansi_rgb::red(),
+            ClusterState::Selected => ansi_rgb::orange(),
+        };
+        "■".fg(color)
+    }
+}
+impl fmt::Display for ClusterState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ClusterState::Free => "free",
+            ClusterState::Used => "used",
+            ClusterState::EoF => "EoF",
+            ClusterState::Directory => "dir",
+            ClusterState::Bad => "bad",
+            ClusterState::Selected => "selected",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 簇位图：每个数据簇的状态
+#[derive(Debug, Clone)]
+pub struct ClusterMap {
+    pub clusters: Vec<ClusterState>,
+}
+impl fmt::Display for ClusterMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let per_cell = self.clusters.len().div_ceil(MAP_MAX_CELLS).max(1);
+        write!(f, "Legend:")?;
+        for state in ClusterState::ALL.iter() {
+            write!(f, " {} {}", state.cell(), state)?;
+        }
+        writeln!(f, "\t({} clusters per cell)", per_cell)?;
+
+        let cells: Vec<ClusterState> = self
+            .clusters
+            .chunks(per_cell)
+            .map(|chunk| *chunk.iter().max().unwrap())
+            .collect();
+        for (row, cells) in cells.chunks(MAP_COLUMNS).enumerate() {
+            write!(f, "{:>8} ", row * MAP_COLUMNS * per_cell)?;
+            for cell in cells {
+                write!(f, "{}", cell.cell())?;
+            }
+            writeln!(f)?;
+        }
+
+        fmt::Result::Ok(())
+    }
+}
+
+/// 一条簇链的碎片情况
+#[derive(Debug, Clone)]
+pub struct ChainFragments {
+    /// 文件的完整路径，目录以`/`结尾
+    pub path: String,
+    pub first_cluster: usize,
+    pub clusters: usize,
+    /// 簇链分成了几段连续的簇，1表示完全连续
+    pub fragments: usize,
+}
+
+/// 碎片报告
+#[derive(Debug, Clone, Default)]
+pub struct FragReport {
+    /// 每个文件和目录的簇链，按目录树的顺序排列
+    pub chains: Vec<ChainFragments>,
+    pub free_clusters: usize,
+    /// 空闲簇分成了几段
+    pub free_runs: usize,
+    pub largest_free_run: usize,
+    /// 簇链的平均长度（簇）
+    pub average_chain_length: f64,
+    /// 碎片率，见`DiskManager::fragmentation`
+    pub score: f64,
+}
+impl fmt::Display for FragReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chain in &self.chains {
+            writeln!(
+                f,
+                "{}\t\t{} clusters in {} fragments",
+                chain.path, chain.clusters, chain.fragments
+            )?;
+        }
+        let fragmented = self
+            .chains
+            .iter()
+            .filter(|chain| chain.fragments > 1)
+            .count();
+        writeln!(
+            f,
+            "{} of {} chains fragmented, {:.1} clusters per chain on average, fragmentation {:.1}%.",
+            fragmented,
+            self.chains.len(),
+            self.average_chain_length,
+            self.score
+        )?;
+        writeln!(
+            f,
+            "{} free clusters in {} runs, largest run {} clusters.",
+            self.free_clusters, self.free_runs, self.largest_free_run
+        )
+    }
+}
+
+/// 簇链分成了几段连续的簇
+fn count_fragments(clusters: &[usize]) -> usize {
+    1 + clusters
+        .windows(2)
+        .filter(|pair| pair[1] != pair[0] + 1)
+        .count()
+}
+
+impl DiskManager {
+    /// 从根目录开始列出所有文件和目录的完整路径和FCB，包括根目录自己，目录在它的内容之前。
+    fn walk_tree(&self) -> Result<Vec<(String, Fcb)>, FsError> {
+        let root = self.get_directory_by_cluster(ROOT_CLUSTER)?;
+        let mut entries = vec![(String::from("/"), root.files[1].clone())];
+        self.walk_directory(&root, "", &mut entries, &mut HashSet::new())?;
+
+        Ok(entries)
+    }
+
+    fn walk_directory(
+        &self,
+        dir: &Directory,
+        path: &str,
+        entries: &mut Vec<(String, Fcb)>,
+        visited: &mut HashSet<usize>,
+    ) -> Result<(), FsError> {
+        visited.insert(dir.files[1].first_cluster);
+        for fcb in dir.files.iter().skip(2) {
+            let child_path = format!("{}/{}", path, fcb.name);
+            match fcb.file_type {
+                FileType::File | FileType::Symlink => entries.push((child_path, fcb.clone())),
+                FileType::Directory => {
+                    entries.push((format!("{}/", child_path), fcb.clone()));
+                    if !visited.contains(&fcb.first_cluster) {
+                        let child = self.get_directory_by_fcb(fcb)?;
+                        self.walk_directory(&child, &child_path, entries, visited)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 生成簇位图。`selected`为某个文件或目录的路径时，标出它的簇链。
+    pub fn cluster_map(&self, selected: Option<&str>) -> Result<ClusterMap, FsError> {
+        let mut clusters: Vec<ClusterState> = self
+            .disk
+            .fat()
+            .iter()
+            .map(|item| match item {
+                FatItem::NotUsed => ClusterState::Free,
+                FatItem::ClusterNo(_) => ClusterState::Used,
+                FatItem::EoF => ClusterState::EoF,
+                FatItem::BadCluster => ClusterState::Bad,
+            })
+            .collect();
+        for (_path, fcb) in self.walk_tree()? {
+            if let FileType::Directory = fcb.file_type {
+                for cluster in self.get_file_clusters(fcb.first_cluster)? {
+                    clusters[cluster] = ClusterState::Directory;
+                }
+            }
+        }
+        if let Some(path) = selected {
+            let first_cluster = match self.resolve_directory(path) {
+                Ok(dir) => dir.files[1].first_cluster,
+                Err(_) => self.resolve_fcb(path)?.first_cluster,
+            };
+            for cluster in self.get_file_clusters(first_cluster)? {
+                clusters[cluster] = ClusterState::Selected;
+            }
+        }
+
+        Ok(ClusterMap { clusters })
+    }
+
+    /// 生成碎片报告
+    pub fn frag_report(&self) -> Result<FragReport, FsError> {
+        let mut report = FragReport {
+            score: self.fragmentation(),
+            ..FragReport::default()
+        };
+        for (path, fcb) in self.walk_tree()? {
+            let clusters = self.get_file_clusters(fcb.first_cluster)?;
+            report.chains.push(ChainFragments {
+                path,
+                first_cluster: fcb.first_cluster,
+                clusters: clusters.len(),
+                fragments: count_fragments(&clusters),
+            });
+        }
+        if !report.chains.is_empty() {
+            let total: usize = report.chains.iter().map(|chain| chain.clusters).sum();
+            report.average_chain_length = total as f64 / report.chains.len() as f64;
+        }
+        let free = self.disk.free_space();
+        let runs = free.runs();
+        report.free_clusters = free.len();
+        report.free_runs = runs.len();
+        report.largest_free_run = runs.iter().map(|(_start, len)| *len).max().unwrap_or(0);
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::testing::new_disk;
+
+    /// 首次适应分配出的布局：0根目录，1空闲（删掉的`/a`），2、4、5是`/big`，3是`/c`，6是目录`/d`
+    fn fragmented_disk() -> DiskManager {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        dm.create_file_with_data("/a", b"a").unwrap();
+        dm.create_file_with_data("/b", b"b").unwrap();
+        dm.create_file_with_data("/c", b"c").unwrap();
+        dm.delete_file_by_name("/b").unwrap();
+        dm.create_file_with_data("/big", &vec![1u8; 3 * cluster_size])
+            .unwrap();
+        dm.new_directory_to_disk("/d").unwrap();
+        dm.delete_file_by_name("/a").unwrap();
+        dm
+    }
+
+    #[test]
+    fn cluster_map_marks_each_state() {
+        use ClusterState::*;
+        let dm = fragmented_disk();
+        let map = dm.cluster_map(None).unwrap();
+        assert_eq!(map.clusters.len(), dm.disk.fat().len());
+        assert_eq!(
+            map.clusters[..8],
+            [Directory, Free, Used, EoF, Used, EoF, Directory, Free]
+        );
+        assert!(map.clusters[7..].iter().all(|state| *state == Free));
+
+        let map = dm.cluster_map(Some("/big")).unwrap();
+        assert_eq!(
+            map.clusters[..8],
+            [Directory, Free, Selected, EoF, Selected, Selected, Directory, Free]
+        );
+        let map = dm.cluster_map(Some("/d/")).unwrap();
+        assert_eq!(map.clusters[6], Selected);
+        assert_eq!(map.clusters[0], Directory);
+        assert!(dm.cluster_map(Some("/nosuch")).is_err());
+    }
+
+    #[test]
+    fn frag_report_counts_fragments_and_free_runs() {
+        let dm = fragmented_disk();
+        let report = dm.frag_report().unwrap();
+        let chains: Vec<(&str, usize, usize, usize)> = report
+            .chains
+            .iter()
+            .map(|chain| {
+                let ChainFragments {
+                    path,
+                    first_cluster,
+                    clusters,
+                    fragments,
+                } = chain;
+                (path.as_str(), *first_cluster, *clusters, *fragments)
+            })
+            .collect();
+        assert_eq!(
+            chains,
+            [
+                ("/", 0, 1, 1),
+                ("/c", 3, 1, 1),
+                ("/big", 2, 3, 2),
+                ("/d/", 6, 1, 1)
+            ]
+        );
+        let data_clusters = dm.disk.fat().len();
+        assert_eq!(report.free_clusters, data_clusters - 6);
+        assert_eq!(report.free_runs, 2);
+        assert_eq!(report.largest_free_run, data_clusters - 7);
+        assert_eq!(report.average_chain_length, 1.5);
+    }
+
+    #[test]
+    fn fragments_are_runs_of_consecutive_clusters() {
+        assert_eq!(count_fragments(&[5]), 1);
+        assert_eq!(count_fragments(&[1, 2, 3]), 1);
+        assert_eq!(count_fragments(&[2, 4, 5]), 2);
+        assert_eq!(count_fragments(&[3, 2, 1]), 3);
+    }
+}
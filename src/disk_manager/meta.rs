@@ -0,0 +1,209 @@
+//! 卷元数据。
+//!
+//! 不属于任何一个目录的信息，以bincode编码的`MetaRecord`列表存放在数据区的一条簇链中，
+//! 首簇记在超级块里，没有元数据时不占用簇。元数据只在`flush`时经过日志写回。
+//! 新的记录类型只能加在`MetaRecord`的末尾，这样旧镜像中的记录仍然可以读出。
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::FsError;
+use super::quota::Quotas;
+use super::users::Accounts;
+use super::{pdebug, DiskManager};
+
+/// 元数据中的一条记录
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum MetaRecord {
+    /// 写时复制共享的簇链：(首簇, 共享它的FCB数量)
+    SharedChains(Vec<(usize, usize)>),
+    /// 没有完成的碎片整理：已经整理好的簇数量
+    DefragProgress(usize),
+    /// 用户表和组表
+    Accounts(Accounts),
+    /// 用户和目录的配额
+    Quotas(Quotas),
+    /// 有多个硬链接的簇链：(首簇, 链接数量)
+    HardLinks(Vec<(usize, usize)>),
+}
+
+/// 内存中的卷元数据
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VolumeMeta {
+    /// 被多个FCB共享的簇链：首簇 → 共享它的FCB数量，只记录数量大于1的链
+    pub shared: BTreeMap<usize, usize>,
+    /// 碎片整理被中断时，已经整理好的簇数量
+    pub defrag: Option<usize>,
+    /// 用户和用户组，只有root时不保存
+    pub accounts: Accounts,
+    /// 磁盘配额
+    pub quotas: Quotas,
+    /// 有多个硬链接的簇链：首簇 → 链接数量，只记录数量大于1的链
+    pub links: BTreeMap<usize, usize>,
+}
+impl VolumeMeta {
+    pub fn is_empty(&self) -> bool {
+        self.shared.is_empty()
+            && self.defrag.is_none()
+            && self.accounts.is_default()
+            && self.quotas.is_empty()
+            && self.links.is_empty()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if !self.shared.is_empty() {
+            records.push(MetaRecord::SharedChains(
+                self.shared.iter().map(|(k, v)| (*k, *v)).collect(),
+            ));
+        }
+        if let Some(placed) = self.defrag {
+            records.push(MetaRecord::DefragProgress(placed));
+        }
+        if !self.accounts.is_default() {
+            records.push(MetaRecord::Accounts(self.accounts.clone()));
+        }
+        if !self.quotas.is_empty() {
+            records.push(MetaRecord::Quotas(self.quotas.clone()));
+        }
+        if !self.links.is_empty() {
+            records.push(MetaRecord::HardLinks(
+                self.links.iter().map(|(k, v)| (*k, *v)).collect(),
+            ));
+        }
+
+        bincode::serialize(&records).unwrap()
+    }
+
+    fn decode(data: &[u8], cluster: usize) -> Result<VolumeMeta, FsError> {
+        let records: Vec<MetaRecord> =
+            bincode::deserialize(data).map_err(|err| FsError::Corrupt {
+                cluster,
+                reason: format!("unreadable volume metadata: {}", err),
+            })?;
+        let mut meta = VolumeMeta::default();
+        for record in records {
+            match record {
+                MetaRecord::SharedChains(chains) => meta.shared.extend(chains),
+                MetaRecord::DefragProgress(placed) => meta.defrag = Some(placed),
+                MetaRecord::Accounts(accounts) => meta.accounts = accounts,
+                MetaRecord::Quotas(quotas) => meta.quotas = quotas,
+                MetaRecord::HardLinks(links) => meta.links.extend(links),
+            }
+        }
+
+        Ok(meta)
+    }
+}
+
+impl DiskManager {
+    /// 从超级块记录的簇链中读出卷元数据
+    pub(crate) fn load_meta(&mut self) -> Result<(), FsError> {
+        self.meta = match self.disk.meta_cluster {
+            Some(cluster) => {
+                let data = self.get_data_by_first_cluster(cluster, None)?;
+                VolumeMeta::decode(&data, cluster)?
+            }
+            None => VolumeMeta::default(),
+        };
+
+        Ok(())
+    }
+
+    /// 把卷元数据写回它的簇链，元数据为空时释放簇链
+    pub(crate) fn store_meta(&mut self) -> Result<(), FsError> {
+        pdebug();
+        println!("Storing volume metadata...");
+        match (self.meta.is_empty(), self.disk.meta_cluster) {
+            (true, None) => (),
+            (true, Some(cluster)) => {
+                self.delete_space_on_fat(cluster)?;
+                self.disk.meta_cluster = None;
+            }
+            (false, None) => {
+                let data = self.meta.encode();
+                let clusters =
+                    self.allocate_free_space_on_fat(self.calc_clusters_needed(data.len()))?;
+                self.disk.write_metadata_by_clusters(&data, &clusters);
+                self.disk.meta_cluster = Some(clusters[0]);
+            }
+            (false, Some(cluster)) => {
+                let data = self.meta.encode();
+                let clusters =
+                    self.resize_space_on_fat(cluster, self.calc_clusters_needed(data.len()))?;
+                self.disk.write_metadata_by_clusters(&data, &clusters);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 共享首簇为`first_cluster`的簇链的FCB数量
+    pub fn share_count(&self, first_cluster: usize) -> usize {
+        self.meta.shared.get(&first_cluster).copied().unwrap_or(1)
+    }
+
+    /// 多一个FCB共享这条簇链
+    pub(crate) fn share_chain(&mut self, first_cluster: usize) {
+        *self.meta.shared.entry(first_cluster).or_insert(1) += 1;
+    }
+
+    /// 共享或链接到这条簇链的FCB数量，最后一个FCB被删除时才释放簇链
+    pub(crate) fn references(&self, first_cluster: usize) -> usize {
+        self.share_count(first_cluster)
+            .max(self.link_count(first_cluster))
+    }
+
+    /// 一个FCB不再使用这条簇链。没有其他FCB共享或链接时释放所有簇，返回释放的簇数量。
+    pub(crate) fn release_chain(&mut self, first_cluster: usize) -> Result<usize, FsError> {
+        if let Some(count) = self.meta.links.get_mut(&first_cluster) {
+            *count -= 1;
+            if *count <= 1 {
+                self.meta.links.remove(&first_cluster);
+            }
+            return Ok(0);
+        }
+        match self.meta.shared.get_mut(&first_cluster) {
+            Some(count) => {
+                *count -= 1;
+                if *count <= 1 {
+                    self.meta.shared.remove(&first_cluster);
+                }
+                Ok(0)
+            }
+            None => Ok(self.delete_space_on_fat(first_cluster)?.len()),
+        }
+    }
+
+    /// 把簇链复制一份，返回新簇链的首簇
+    pub(crate) fn duplicate_chain(&mut self, first_cluster: usize) -> Result<usize, FsError> {
+        pdebug();
+        println!("Duplicating chain starting at {}...", first_cluster);
+        let clusters = self.get_file_clusters(first_cluster)?;
+        let new_clusters = self.allocate_free_space_on_fat(clusters.len())?;
+        for (from, to) in clusters.iter().zip(new_clusters.iter()) {
+            let copied = self
+                .disk
+                .read_data_by_cluster(*from)
+                .and_then(|data| self.disk.insert_data_by_cluster(&data, *to));
+            if let Err(err) = copied {
+                self.delete_space_on_fat(new_clusters[0])?;
+                return Err(err.into());
+            }
+        }
+
+        Ok(new_clusters[0])
+    }
+
+    /// 簇链被共享时，复制一份给这一个FCB单独使用，返回它现在的首簇
+    pub(crate) fn unshare_chain(&mut self, first_cluster: usize) -> Result<usize, FsError> {
+        if self.share_count(first_cluster) <= 1 {
+            return Ok(first_cluster);
+        }
+        let new_first = self.duplicate_chain(first_cluster)?;
+        self.release_chain(first_cluster)?;
+
+        Ok(new_first)
+    }
+}
@@ -0,0 +1,395 @@
+//! 路径解析。
+//!
+//! 以`/`开头的是绝对路径，从根目录开始查找；其他的是相对路径，从当前目录开始查找。
+//! 连续的`/`和末尾的`/`会被忽略，`.`表示目录本身，`..`表示上一级目录，根目录的`..`仍是根目录。
+//! 路径中间的符号链接总是被跟随，相对的目标从链接所在的目录开始查找；最后一项是否跟随由调用者决定。
+
+use super::acl::Acl;
+use super::clock::Times;
+use super::error::FsError;
+use super::image::ROOT_CLUSTER;
+use super::symlink::MAX_SYMLINKS;
+use super::users::{Perm, EXECUTE, READ};
+use super::{pdebug, Directory, DiskManager, FatItem, Fcb, FileType};
+
+/// 是否为绝对路径
+pub fn is_absolute(path: &str) -> bool {
+    path.starts_with('/')
+}
+
+/// 将路径拆成父目录部分和最后一项，忽略末尾的`/`。
+/// 例如`/a/b/`拆成`("/a", "b")`，`b`拆成`("", "b")`，`/b`拆成`("/", "b")`。
+pub fn split_last(path: &str) -> (&str, &str) {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rfind('/') {
+        Some(index) => {
+            let parent = trimmed[..index].trim_end_matches('/');
+            if parent.is_empty() {
+                // 父目录就是根目录
+                (&trimmed[..1], &trimmed[index + 1..])
+            } else {
+                (parent, &trimmed[index + 1..])
+            }
+        }
+        None => ("", trimmed),
+    }
+}
+
+impl DiskManager {
+    /// 读出首簇为`cluster`的目录
+    pub(crate) fn get_directory_by_cluster(&self, cluster: usize) -> Result<Directory, FsError> {
+        self.get_directory_by_fcb(&Fcb {
+            name: String::from("."),
+            file_type: FileType::Directory,
+            first_cluster: cluster,
+            length: 0,
+            times: Times::default(),
+            perm: Perm::legacy(&FileType::Directory),
+            acl: Acl::default(),
+        })
+    }
+
+    /// 按路径找到目录
+    pub fn resolve_directory(&self, path: &str) -> Result<Directory, FsError> {
+        pdebug();
+        println!("Resolving dir '{}'...", path);
+        self.follow_path(path, |cluster| self.get_directory_by_cluster(cluster))
+    }
+
+    /// 按路径列出目录，需要目录的读权限。`path`为`None`时列出当前目录。
+    pub fn list_directory(&self, path: Option<&str>) -> Result<Directory, FsError> {
+        let dir = match path {
+            Some(path) => self.resolve_directory(path)?,
+            None => self.cur_dir.clone(),
+        };
+        self.check_dir_access(&dir, READ, path.unwrap_or("."))?;
+
+        Ok(dir)
+    }
+
+    /// 按路径找到目录，不打印日志。用于在输入命令时补全路径，这时的输出会打乱正在编辑的一行。
+    pub fn peek_directory(&self, path: &str) -> Result<Directory, FsError> {
+        self.follow_path(path, |cluster| self.read_directory_quietly(cluster))
+    }
+
+    /// 列出目录中所有项的名字和类型，包括“..”和“.”，不打印日志。`path`为空时列出当前目录。
+    pub fn peek_entries(&self, path: &str) -> Result<Vec<(String, FileType)>, FsError> {
+        let dir = if path.is_empty() {
+            self.cur_dir.clone()
+        } else {
+            self.peek_directory(path)?
+        };
+
+        Ok(dir
+            .files
+            .into_iter()
+            .map(|fcb| (fcb.name, fcb.file_type))
+            .collect())
+    }
+
+    /// 沿路径逐级查找目录，用`read`按首簇读出每一级目录。进入每一级目录都要有执行权限。
+    fn follow_path<F>(&self, path: &str, read: F) -> Result<Directory, FsError>
+    where
+        F: Fn(usize) -> Result<Directory, FsError>,
+    {
+        self.walk_path(self.cur_dir.clone(), path, &read, &mut 0)
+    }
+
+    /// 从`base`开始沿路径逐级查找目录，遇到符号链接时从链接所在的目录继续查找它的目标。
+    /// `links`是已经跟随过的符号链接数量，超过`MAX_SYMLINKS`时返回`FsError::SymlinkLoop`。
+    pub(crate) fn walk_path<F>(
+        &self,
+        base: Directory,
+        path: &str,
+        read: &F,
+        links: &mut usize,
+    ) -> Result<Directory, FsError>
+    where
+        F: Fn(usize) -> Result<Directory, FsError>,
+    {
+        let enter = |cluster| -> Result<Directory, FsError> {
+            let dir = read(cluster)?;
+            self.check_dir_access(&dir, EXECUTE, path)?;
+            Ok(dir)
+        };
+        let mut dir = if is_absolute(path) {
+            enter(ROOT_CLUSTER)?
+        } else {
+            base
+        };
+        for name in path.split('/') {
+            match name {
+                "" | "." => (),
+                ".." => dir = enter(dir.files[0].first_cluster)?,
+                _ => {
+                    let (_index, fcb) = dir
+                        .get_fcb_by_name(name)
+                        .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+                    match fcb.file_type {
+                        FileType::Directory => dir = enter(fcb.first_cluster)?,
+                        FileType::Symlink => {
+                            *links += 1;
+                            if *links > MAX_SYMLINKS {
+                                return Err(FsError::SymlinkLoop(String::from(path)));
+                            }
+                            let target = self.link_target(fcb)?;
+                            dir = self.walk_path(dir.clone(), &target, read, links)?;
+                        }
+                        FileType::File => return Err(FsError::NotADirectory(String::from(path))),
+                    }
+                }
+            }
+        }
+
+        Ok(dir)
+    }
+
+    /// 读出首簇为`cluster`的目录，不打印日志
+    pub(crate) fn read_directory_quietly(&self, cluster: usize) -> Result<Directory, FsError> {
+        let clusters = self.chain_quietly(cluster)?;
+        let data = self
+            .disk
+            .read_data_by_clusters(&clusters, clusters.len() * self.disk.cluster_size())?;
+
+        let dir: Directory = bincode::deserialize(&data).map_err(|err| FsError::Corrupt {
+            cluster,
+            reason: format!("unreadable directory: {}", err),
+        })?;
+        // 每个目录至少要有“..”和“.”
+        if dir.files.len() < 2 {
+            return Err(FsError::Corrupt {
+                cluster,
+                reason: String::from("directory has no '.' or '..' entry"),
+            });
+        }
+
+        Ok(dir)
+    }
+
+    /// 沿FAT找出以`cluster`开头的簇链，不打印日志
+    pub(crate) fn chain_quietly(&self, cluster: usize) -> Result<Vec<usize>, FsError> {
+        let fat = self.disk.fat();
+        let mut clusters = vec![cluster];
+        let mut this_cluster = cluster;
+        while let Some(FatItem::ClusterNo(next)) = fat.get(this_cluster) {
+            // 簇链比FAT表还长，说明成环了
+            if clusters.len() > fat.len() {
+                return Err(FsError::Corrupt {
+                    cluster,
+                    reason: String::from("cluster chain loops"),
+                });
+            }
+            clusters.push(*next);
+            this_cluster = *next;
+        }
+
+        Ok(clusters)
+    }
+
+    /// 找到路径最后一项所在的目录，返回该目录和最后一项的名字。
+    pub(crate) fn resolve_parent<'a>(
+        &self,
+        path: &'a str,
+    ) -> Result<(Directory, &'a str), FsError> {
+        let (parent, name) = split_last(path);
+        if name.is_empty() {
+            return Err(FsError::InvalidArgument(format!(
+                "path '{}' does not name a file",
+                path
+            )));
+        }
+        let dir = if parent.is_empty() {
+            self.cur_dir.clone()
+        } else {
+            self.resolve_directory(parent)?
+        };
+
+        Ok((dir, name))
+    }
+
+    /// 按路径找到FCB
+    pub fn resolve_fcb(&self, path: &str) -> Result<Fcb, FsError> {
+        let (dir, name) = self.resolve_parent(path)?;
+        let (_index, fcb) = dir
+            .get_fcb_by_name(name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+
+        Ok(fcb.clone())
+    }
+
+    /// 将目录写回磁盘。如果写回的是当前目录，同时更新内存中的当前目录。
+    pub(crate) fn store_directory(&mut self, dir: &Directory) -> Result<(), FsError> {
+        self.save_directory_to_disk(dir)?;
+        if dir.files[1].first_cluster == self.cur_dir.files[1].first_cluster {
+            self.cur_dir = dir.clone();
+        }
+
+        Ok(())
+    }
+
+    /// 首簇为`cluster`的目录是否就是首簇为`ancestor`的目录，或者在它下面。沿“..”向上查找。
+    pub(crate) fn is_inside(&self, cluster: usize, ancestor: usize) -> Result<bool, FsError> {
+        let mut cluster = cluster;
+        for _ in 0..self.disk.fat().len() {
+            if cluster == ancestor {
+                return Ok(true);
+            }
+            if cluster == ROOT_CLUSTER {
+                return Ok(false);
+            }
+            cluster = self.get_directory_by_cluster(cluster)?.files[0].first_cluster;
+        }
+
+        Err(FsError::Corrupt {
+            cluster,
+            reason: String::from("'..' entries loop"),
+        })
+    }
+
+    /// 当前目录的完整路径。交互界面每次显示提示符都要用到，所以不打印日志。
+    pub fn current_path(&self) -> Result<String, FsError> {
+        self.directory_path(&self.cur_dir)
+    }
+
+    /// 目录的完整路径。沿“..”一路向上，在每一级父目录中找到指向自己的FCB，不打印日志。
+    pub(crate) fn directory_path(&self, dir: &Directory) -> Result<String, FsError> {
+        let mut names = Vec::new();
+        let mut dir = dir.clone();
+        let mut cluster = dir.files[1].first_cluster;
+        while cluster != ROOT_CLUSTER {
+            // 目录层数不可能比簇数还多，否则“..”成环了
+            if names.len() > self.disk.fat().len() {
+                return Err(FsError::Corrupt {
+                    cluster,
+                    reason: String::from("'..' entries loop"),
+                });
+            }
+            let parent = self.read_directory_quietly(dir.files[0].first_cluster)?;
+            let fcb = parent
+                .files
+                .iter()
+                .skip(2)
+                .find(|fcb| fcb.first_cluster == cluster)
+                .ok_or_else(|| FsError::Corrupt {
+                    cluster,
+                    reason: String::from("directory is missing from its parent"),
+                })?;
+            names.push(fcb.name.clone());
+            cluster = parent.files[1].first_cluster;
+            dir = parent;
+        }
+        names.reverse();
+
+        Ok(format!("/{}", names.join("/")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::testing::new_disk;
+
+    /// 有`/a/b/c`三级目录和文件`/a/f`的磁盘
+    fn nested_disk() -> DiskManager {
+        let mut dm = new_disk();
+        dm.new_directory_to_disk("/a").unwrap();
+        dm.new_directory_to_disk("/a/b").unwrap();
+        dm.new_directory_to_disk("/a/b/c").unwrap();
+        dm.create_file_with_data("/a/f", b"file").unwrap();
+        dm
+    }
+
+    /// 路径指向的目录的首簇
+    fn cluster(dm: &DiskManager, path: &str) -> usize {
+        dm.resolve_directory(path).unwrap().files[1].first_cluster
+    }
+
+    #[test]
+    fn split_last_ignores_trailing_slashes() {
+        assert_eq!(split_last("/a/b/"), ("/a", "b"));
+        assert_eq!(split_last("/a//b"), ("/a", "b"));
+        assert_eq!(split_last("a/b"), ("a", "b"));
+        assert_eq!(split_last("b"), ("", "b"));
+        assert_eq!(split_last("/b"), ("/", "b"));
+        assert_eq!(split_last("//b//"), ("/", "b"));
+        assert_eq!(split_last("/"), ("", ""));
+        assert_eq!(split_last(""), ("", ""));
+    }
+
+    #[test]
+    fn absolute_and_relative_lookup() {
+        let mut dm = nested_disk();
+        let b = cluster(&dm, "/a/b");
+        dm.set_current_directory("/a").unwrap();
+        assert_eq!(cluster(&dm, "b"), b);
+        assert_eq!(cluster(&dm, "/a/b"), b);
+        // 相对路径从当前目录开始，`/b`不存在
+        assert!(matches!(
+            dm.resolve_directory("/b"),
+            Err(FsError::NotFound(_))
+        ));
+        assert_eq!(cluster(&dm, ""), cluster(&dm, "/a"));
+    }
+
+    #[test]
+    fn dots_and_slashes() {
+        let dm = nested_disk();
+        let b = cluster(&dm, "/a/b");
+        assert_eq!(cluster(&dm, "/a//b"), b);
+        assert_eq!(cluster(&dm, "/a/b/"), b);
+        assert_eq!(cluster(&dm, "./a/./b/."), b);
+        assert_eq!(cluster(&dm, "/a/b/c/.."), b);
+        // 根目录的“..”仍是根目录
+        assert_eq!(cluster(&dm, "/.."), ROOT_CLUSTER);
+        assert_eq!(cluster(&dm, "/../../a/../.."), ROOT_CLUSTER);
+        assert_eq!(cluster(&dm, "/../a/b"), b);
+    }
+
+    #[test]
+    fn bad_components_are_reported() {
+        let dm = nested_disk();
+        assert!(matches!(
+            dm.resolve_directory("/a/f/b"),
+            Err(FsError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            dm.resolve_directory("/a/f"),
+            Err(FsError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            dm.resolve_directory("/a/x/c"),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(matches!(
+            dm.resolve_fcb("/a/x/f"),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(matches!(
+            dm.resolve_parent("/"),
+            Err(FsError::InvalidArgument(_))
+        ));
+        let (parent, name) = dm.resolve_parent("/a/b/").unwrap();
+        assert_eq!(
+            (parent.files[1].first_cluster, name),
+            (cluster(&dm, "/a"), "b")
+        );
+    }
+
+    #[test]
+    fn current_path_follows_cd() {
+        let mut dm = nested_disk();
+        assert_eq!(dm.current_path().unwrap(), "/");
+        dm.set_current_directory("a/b").unwrap();
+        assert_eq!(dm.current_path().unwrap(), "/a/b");
+        dm.set_current_directory("c").unwrap();
+        assert_eq!(dm.current_path().unwrap(), "/a/b/c");
+        dm.set_current_directory("../..").unwrap();
+        assert_eq!(dm.current_path().unwrap(), "/a");
+        dm.set_current_directory("../..").unwrap();
+        assert_eq!(dm.current_path().unwrap(), "/");
+        // 改名之后按新名字显示
+        dm.set_current_directory("/a/b").unwrap();
+        dm.rename_file_by_name("/a", "z").unwrap();
+        assert_eq!(dm.current_path().unwrap(), "/z/b");
+    }
+}
@@ -0,0 +1,479 @@
+//! 磁盘配额。
+//!
+//! 配额以簇为单位，可以限制每个用户拥有的文件，也可以限制一棵目录子树，记在卷元数据中。
+//! 用户的用量是所有属于该用户的FCB的簇链长度之和，目录的用量是子树中所有FCB（包括目录自己）的簇链长度之和，
+//! 写时复制共享的簇链在每个FCB上各算一次，同一个文件的多个硬链接只算一次。用量不单独记录，检查时扫描目录树得出。
+//!
+//! 分配簇之前按`DiskManager::charge`记下的用户和目录检查配额：超过硬配额时返回
+//! `FsError::QuotaExceeded`，什么也不分配；第一次越过软配额时只打印警告。
+//! 复制和跨目录移动在开始之前就按整棵子树的大小检查。
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::FsError;
+use super::users::ROOT_UID;
+use super::{pinfo, DiskManager, Fcb, FileType, ROOT_CLUSTER};
+
+/// 一项配额的上限（簇），0表示不限制
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// 超过时只警告
+    pub soft: usize,
+    /// 不能超过
+    pub hard: usize,
+}
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.soft == 0 && self.hard == 0
+    }
+}
+
+/// 卷上所有的配额
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Quotas {
+    /// 用户编号 → 上限
+    pub users: BTreeMap<u32, Limits>,
+    /// 目录首簇 → 整棵子树的上限
+    pub dirs: BTreeMap<usize, Limits>,
+}
+impl Quotas {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.dirs.is_empty()
+    }
+}
+
+/// 接下来分配的簇记在谁的账上
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Charge {
+    /// 新簇属于的用户
+    pub owner: u32,
+    /// 新簇所在的目录，它和它的所有上级目录的配额都要检查
+    pub dir: usize,
+}
+
+/// `quota`命令输出的一行
+#[derive(Debug, Clone)]
+pub struct QuotaRow {
+    /// “user alice”或者“dir /share”
+    pub target: String,
+    pub used: usize,
+    pub limits: Limits,
+}
+
+/// 配额和用量
+#[derive(Debug, Clone)]
+pub struct QuotaReport {
+    pub rows: Vec<QuotaRow>,
+}
+impl fmt::Display for QuotaReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limit = |n: usize| {
+            if n == 0 {
+                String::from("-")
+            } else {
+                n.to_string()
+            }
+        };
+        write!(
+            f,
+            "{:<24} {:>8} {:>8} {:>8}",
+            "Target", "Used", "Soft", "Hard"
+        )?;
+        for row in &self.rows {
+            // 超过软配额的行加上*
+            let over = row.limits.soft != 0 && row.used > row.limits.soft;
+            write!(
+                f,
+                "\n{:<24} {:>8} {:>8} {:>8}{}",
+                row.target,
+                row.used,
+                limit(row.limits.soft),
+                limit(row.limits.hard),
+                if over { " *" } else { "" }
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskManager {
+    /// 在`charge`的账上执行`f`，结束后恢复原来的账
+    pub(crate) fn charged<T>(
+        &mut self,
+        charge: Charge,
+        f: impl FnOnce(&mut DiskManager) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let outer = self.charge.replace(charge);
+        let result = f(self);
+        self.charge = outer;
+
+        result
+    }
+
+    /// 分配`needed`个簇之前检查当前账上的配额
+    pub(crate) fn check_quota(&self, needed: usize) -> Result<(), FsError> {
+        match self.charge {
+            Some(charge) if !self.meta.quotas.is_empty() => {
+                self.check_user_quota(charge.owner, needed)?;
+                self.check_dir_quotas(&self.ancestors(charge.dir)?, needed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 用户`uid`再多`needed`个簇是否超出配额
+    pub(crate) fn check_user_quota(&self, uid: u32, needed: usize) -> Result<(), FsError> {
+        match self.meta.quotas.users.get(&uid) {
+            Some(limits) => {
+                let used = self.tree_usage(ROOT_CLUSTER, Some(uid))?;
+                let target = format!("user '{}'", self.meta.accounts.user_name(uid));
+                check_limits(&target, limits, used, needed)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// `dirs`中每个有配额的目录再多`needed`个簇是否超出配额
+    pub(crate) fn check_dir_quotas(&self, dirs: &[usize], needed: usize) -> Result<(), FsError> {
+        for cluster in dirs {
+            if let Some(limits) = self.meta.quotas.dirs.get(cluster) {
+                let used = self.tree_usage(*cluster, None)?;
+                let target = format!("dir '{}'", self.quota_dir_path(*cluster));
+                check_limits(&target, limits, used, needed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 把`fcb`从目录`from`移到目录`to`时，检查`to`那一侧新增的上级目录的配额。属主不变，用户的用量也不变。
+    pub(crate) fn check_move_quota(
+        &self,
+        fcb: &Fcb,
+        from: usize,
+        to: usize,
+    ) -> Result<(), FsError> {
+        if self.meta.quotas.dirs.is_empty() {
+            return Ok(());
+        }
+        let from = self.ancestors(from)?;
+        let dirs: Vec<usize> = self
+            .ancestors(to)?
+            .into_iter()
+            .filter(|cluster| !from.contains(cluster))
+            .collect();
+
+        self.check_dir_quotas(&dirs, self.fcb_usage(fcb)?)
+    }
+
+    /// 把`fcb`复制到目录`to`之前检查配额，复制出来的文件都属于当前用户
+    pub(crate) fn check_copy_quota(&self, fcb: &Fcb, to: usize) -> Result<(), FsError> {
+        if self.meta.quotas.is_empty() {
+            return Ok(());
+        }
+        let needed = self.fcb_usage(fcb)?;
+        self.check_user_quota(self.uid(), needed)?;
+
+        self.check_dir_quotas(&self.ancestors(to)?, needed)
+    }
+
+    /// `fcb`占用的簇数量，目录包括整棵子树
+    fn fcb_usage(&self, fcb: &Fcb) -> Result<usize, FsError> {
+        match fcb.file_type {
+            FileType::Directory => self.tree_usage(fcb.first_cluster, None),
+            FileType::File | FileType::Symlink => Ok(self.chain_quietly(fcb.first_cluster)?.len()),
+        }
+    }
+
+    /// 首簇为`cluster`的目录和它的所有上级目录，不打印日志
+    pub(crate) fn ancestors(&self, mut cluster: usize) -> Result<Vec<usize>, FsError> {
+        let mut dirs = vec![cluster];
+        while cluster != ROOT_CLUSTER {
+            cluster = self.read_directory_quietly(cluster)?.files[0].first_cluster;
+            // 上级目录比簇还多，说明成环了
+            if dirs.len() > self.disk.fat().len() {
+                return Err(FsError::Corrupt {
+                    cluster,
+                    reason: String::from("directory tree loops"),
+                });
+            }
+            dirs.push(cluster);
+        }
+
+        Ok(dirs)
+    }
+
+    /// 首簇为`cluster`的目录子树占用的簇数量，`owner`不为空时只算属于该用户的FCB。不打印日志。
+    pub(crate) fn tree_usage(&self, cluster: usize, owner: Option<u32>) -> Result<usize, FsError> {
+        let dir = self.read_directory_quietly(cluster)?;
+        let mut used = 0;
+        if owner.is_none_or(|uid| dir.files[1].perm.owner == uid) {
+            used += self.chain_quietly(cluster)?.len();
+        }
+        let mut seen = HashSet::from([cluster]);
+        self.children_usage(&dir.files[2..], owner, &mut used, &mut seen)?;
+
+        Ok(used)
+    }
+
+    /// 把`files`及其子树的用量加到`used`上。`seen`记录走过的目录和有多个链接的文件，它们都只算一次。
+    fn children_usage(
+        &self,
+        files: &[Fcb],
+        owner: Option<u32>,
+        used: &mut usize,
+        seen: &mut HashSet<usize>,
+    ) -> Result<(), FsError> {
+        for fcb in files {
+            let linked = self.link_count(fcb.first_cluster) > 1;
+            if linked && !seen.insert(fcb.first_cluster) {
+                continue;
+            }
+            if owner.is_none_or(|uid| fcb.perm.owner == uid) {
+                *used += self.chain_quietly(fcb.first_cluster)?.len();
+            }
+            if let FileType::Directory = fcb.file_type {
+                if !seen.insert(fcb.first_cluster) {
+                    return Err(FsError::Corrupt {
+                        cluster: fcb.first_cluster,
+                        reason: String::from("directory tree loops"),
+                    });
+                }
+                let dir = self.read_directory_quietly(fcb.first_cluster)?;
+                self.children_usage(&dir.files[2..], owner, used, seen)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 有配额的目录的路径，读不出来时用首簇代替
+    fn quota_dir_path(&self, cluster: usize) -> String {
+        self.read_directory_quietly(cluster)
+            .and_then(|dir| self.directory_path(&dir))
+            .unwrap_or_else(|_| format!("#{}", cluster))
+    }
+
+    /// 设置用户的配额，只有root可以。上限都为0时取消配额。
+    pub fn set_user_quota(&mut self, name: &str, limits: Limits) -> Result<(), FsError> {
+        self.require_root("setquota")?;
+        check_limits_order(&limits)?;
+        let uid = self
+            .meta
+            .accounts
+            .user_by_name(name)
+            .ok_or_else(|| FsError::InvalidArgument(format!("no user named '{}'", name)))?
+            .uid;
+        self.transaction(|dm| {
+            if limits.is_empty() {
+                dm.meta.quotas.users.remove(&uid);
+            } else {
+                dm.meta.quotas.users.insert(uid, limits);
+            }
+            Ok(())
+        })
+    }
+
+    /// 设置目录子树的配额，只有root可以。上限都为0时取消配额。
+    pub fn set_dir_quota(&mut self, path: &str, limits: Limits) -> Result<(), FsError> {
+        self.require_root("setquota")?;
+        check_limits_order(&limits)?;
+        let cluster = self.resolve_directory(path)?.files[1].first_cluster;
+        self.transaction(|dm| {
+            if limits.is_empty() {
+                dm.meta.quotas.dirs.remove(&cluster);
+            } else {
+                dm.meta.quotas.dirs.insert(cluster, limits);
+            }
+            Ok(())
+        })
+    }
+
+    /// 当前用户的用量，以及所有目录的配额和用量；root还能看到所有用户的配额
+    pub fn quota_report(&self) -> Result<QuotaReport, FsError> {
+        let accounts = &self.meta.accounts;
+        let mut users = vec![self.uid()];
+        if self.uid() == ROOT_UID {
+            users.extend(
+                self.meta
+                    .quotas
+                    .users
+                    .keys()
+                    .filter(|uid| **uid != ROOT_UID),
+            );
+        }
+        let mut rows = Vec::new();
+        for uid in users {
+            rows.push(QuotaRow {
+                target: format!("user {}", accounts.user_name(uid)),
+                used: self.tree_usage(ROOT_CLUSTER, Some(uid))?,
+                limits: self
+                    .meta
+                    .quotas
+                    .users
+                    .get(&uid)
+                    .copied()
+                    .unwrap_or_default(),
+            });
+        }
+        for (cluster, limits) in &self.meta.quotas.dirs {
+            rows.push(QuotaRow {
+                target: format!("dir {}", self.quota_dir_path(*cluster)),
+                used: self.tree_usage(*cluster, None)?,
+                limits: *limits,
+            });
+        }
+
+        Ok(QuotaReport { rows })
+    }
+}
+
+/// 用量`used`再加`needed`个簇时检查上限
+fn check_limits(target: &str, limits: &Limits, used: usize, needed: usize) -> Result<(), FsError> {
+    let after = used + needed;
+    if limits.hard != 0 && after > limits.hard {
+        return Err(FsError::QuotaExceeded(String::from(target)));
+    }
+    if limits.soft != 0 && used <= limits.soft && after > limits.soft {
+        pinfo();
+        println!(
+            "Soft quota of {} exceeded: {} of {} clusters.",
+            target, after, limits.soft
+        );
+    }
+
+    Ok(())
+}
+
+/// 软配额不能高于硬配额
+fn check_limits_order(limits: &Limits) -> Result<(), FsError> {
+    if limits.hard != 0 && limits.soft > limits.hard {
+        return Err(FsError::InvalidArgument(String::from(
+            "soft limit is above the hard limit",
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::new_disk;
+
+    /// alice拥有`/home`，目录自己占1个簇
+    fn quota_disk() -> DiskManager {
+        let mut dm = new_disk();
+        dm.useradd("alice", None).unwrap();
+        dm.new_directory_to_disk("/home").unwrap();
+        dm.chown("/home", Some("alice"), None).unwrap();
+        dm
+    }
+
+    /// 刚好占`n`个簇的数据
+    fn clusters(dm: &DiskManager, n: usize) -> Vec<u8> {
+        vec![1u8; n * dm.disk.cluster_size()]
+    }
+
+    fn exceeded<T>(result: Result<T, FsError>) -> bool {
+        matches!(result, Err(FsError::QuotaExceeded(_)))
+    }
+
+    #[test]
+    fn hard_limit_stops_allocation() {
+        let mut dm = quota_disk();
+        dm.set_user_quota("alice", Limits { soft: 0, hard: 4 })
+            .unwrap();
+        dm.login("alice").unwrap();
+        assert!(matches!(
+            dm.set_user_quota("alice", Limits::default()),
+            Err(FsError::PermissionDenied(_))
+        ));
+
+        let data = clusters(&dm, 3);
+        dm.create_file_with_data("/home/a", &data).unwrap();
+        let free = dm.get_disk_info().2;
+        assert!(exceeded(dm.create_file_with_data("/home/b", b"x")));
+        assert_eq!(dm.get_disk_info().2, free);
+        assert!(dm.stat("/home/b").is_err());
+        assert!(exceeded(dm.copy_by_path("/home/a", "/home/c", false, true)));
+
+        // 写入时变长也受限制
+        let mut file = dm.open("/home/a", OpenOptions::new().append(true)).unwrap();
+        let err = file.write_all(b"more").unwrap_err();
+        assert!(matches!(FsError::from(err), FsError::QuotaExceeded(_)));
+        drop(file);
+
+        // 配额只限制alice，root不受影响
+        dm.logout();
+        dm.create_file_with_data("/home/b", &data).unwrap();
+    }
+
+    #[test]
+    fn soft_limit_only_warns() {
+        let mut dm = quota_disk();
+        dm.set_user_quota("alice", Limits { soft: 2, hard: 0 })
+            .unwrap();
+        dm.login("alice").unwrap();
+        let data = clusters(&dm, 3);
+        dm.create_file_with_data("/home/a", &data).unwrap();
+
+        let report = dm.quota_report().unwrap();
+        assert_eq!(report.rows.len(), 1);
+        assert_eq!(report.rows[0].used, 4);
+        assert!(report.to_string().ends_with('*'));
+    }
+
+    #[test]
+    fn dir_limit_covers_subtree() {
+        let mut dm = quota_disk();
+        dm.new_directory_to_disk("/home/sub").unwrap();
+        dm.set_dir_quota("/home", Limits { soft: 0, hard: 4 })
+            .unwrap();
+        let data = clusters(&dm, 2);
+        dm.create_file_with_data("/home/sub/a", &data).unwrap();
+        // 子目录中的分配也算在/home的账上
+        assert!(exceeded(dm.create_file_with_data("/home/sub/b", b"x")));
+        assert!(exceeded(dm.new_directory_to_disk("/home/sub/dir")));
+
+        // 移进来的文件也算
+        dm.create_file_with_data("/b", b"x").unwrap();
+        assert!(exceeded(dm.move_by_path("/b", "/home/sub/b")));
+        dm.delete_file_by_name("/home/sub/a").unwrap();
+        dm.move_by_path("/b", "/home/sub/b").unwrap();
+        // 在子树里移动不会多占
+        dm.move_by_path("/home/sub/b", "/home/b").unwrap();
+    }
+
+    #[test]
+    fn quotas_survive_reload_and_go_with_their_dir() {
+        let mut dm = quota_disk();
+        dm.set_user_quota("alice", Limits { soft: 5, hard: 9 })
+            .unwrap();
+        dm.new_directory_to_disk("/tmp").unwrap();
+        dm.set_dir_quota("/tmp", Limits { soft: 0, hard: 3 })
+            .unwrap();
+
+        let mut image = Vec::new();
+        dm.write_image(&mut image).unwrap();
+        let mut dm = DiskManager::from_image(image).unwrap();
+        let uid = dm.meta.accounts.user_by_name("alice").unwrap().uid;
+        assert_eq!(dm.meta.quotas.users[&uid], Limits { soft: 5, hard: 9 });
+        let report = dm.quota_report().unwrap().to_string();
+        assert!(report.contains("user alice"));
+        assert!(report.contains("dir /tmp"));
+
+        // 目录删掉以后它的配额也没有了
+        dm.delete_file_by_name("/tmp").unwrap();
+        assert!(dm.meta.quotas.dirs.is_empty());
+        dm.set_user_quota("alice", Limits::default()).unwrap();
+        assert!(dm.meta.quotas.is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+//! 把镜像保存到宿主机文件，以及从宿主机文件加载镜像。
+//!
+//! 保存时先写入同目录下的临时文件并同步到介质，再改名覆盖原文件，改名是原子的，
+//! 任何时候原文件要么是旧镜像，要么是新镜像。覆盖之前原文件留作备份`<文件名>.1`，
+//! 更早的备份依次后移为`.2`、`.3`……，最多保留指定的份数。原文件已经损坏、加载不了时直接覆盖，
+//! 不留作备份。
+//! 加载时原文件读不出来，就从最新的备份开始依次尝试。
+//!
+//! 加载的镜像不会整个读入内存：块在用到时才从文件中读出，改动过的块暂存起来，
+//! 内存中最多放`OVERLAY_MEMORY_BYTES`字节，其余的放在临时目录的暂存文件中。
+//! 保存之后，磁盘改为从刚写出的文件读取，暂存的块随之丢掉。
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use super::error::FsError;
+use super::{perror, pinfo, DiskManager};
+
+/// 默认保留的备份数量
+pub const DEFAULT_BACKUPS: usize = 2;
+
+/// 在文件名后面加上后缀
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// 第`n`份备份的路径，从1开始，1是最新的
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+    with_suffix(path, &format!(".{}", n))
+}
+
+/// 保存时使用的临时文件
+fn temp_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".tmp")
+}
+
+/// 把目录的改动（改名）也同步到介质上
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+/// Windows上不能打开目录进行同步，改名由文件系统自己保证
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// 把现有的备份依次后移一位，原文件留作第1份备份，超出`backups`份的最旧备份被覆盖。
+/// 原文件加载不了时不留作备份，否则它会挤掉一份好的备份，现有的备份保持不动。
+fn rotate_backups(path: &Path, backups: usize) -> io::Result<()> {
+    if backups == 0 || !path.exists() {
+        return Ok(());
+    }
+    if let Err(err) = DiskManager::open_file(path) {
+        perror();
+        println!(
+            "Not keeping '{}' as a backup, it does not load: {}",
+            path.display(),
+            err
+        );
+        return Ok(());
+    }
+    for n in (1..backups).rev() {
+        let from = backup_path(path, n);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, n + 1))?;
+        }
+    }
+    let newest = backup_path(path, 1);
+    if newest.exists() {
+        fs::remove_file(&newest)?;
+    }
+    // 硬链接不用复制数据，原文件也一直都在；不支持硬链接时退回复制
+    if fs::hard_link(path, &newest).is_err() {
+        fs::copy(path, &newest)?;
+    }
+
+    Ok(())
+}
+
+impl DiskManager {
+    /// 把整个镜像写入新文件`path`，并同步到介质上
+    fn write_synced(&mut self, path: &Path) -> Result<(), FsError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_image(&mut writer)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// 把整个镜像原子地保存到宿主机文件`path`，保留`backups`份旧镜像。
+    pub fn save_to_file<P: AsRef<Path>>(&mut self, path: P, backups: usize) -> Result<(), FsError> {
+        let path = path.as_ref();
+        let temp = temp_path(path);
+        if let Err(err) = self.write_synced(&temp) {
+            // 写了一半的临时文件没有用，原文件和备份都没有动过
+            let _ = fs::remove_file(&temp);
+            return Err(err);
+        }
+
+        rotate_backups(path, backups)?;
+        fs::rename(&temp, path)?;
+        sync_parent(path)?;
+        // 新文件里已经有了整个卷，不必再把改动放在内存中
+        self.disk.reopen_file(path)?;
+
+        Ok(())
+    }
+
+    /// 在宿主机文件`path`上打开镜像，见`open_file`。原文件读不出来时，
+    /// 从`.1`开始依次尝试最多`backups`份备份，返回加载成功的磁盘和实际读取的文件。
+    pub fn load_file<P: AsRef<Path>>(
+        path: P,
+        backups: usize,
+    ) -> Result<(DiskManager, PathBuf), FsError> {
+        let path = path.as_ref();
+        let load = |path: &Path| DiskManager::open_file(path);
+        let err = match load(path) {
+            Ok(virtual_disk) => return Ok((virtual_disk, path.to_path_buf())),
+            Err(err) => err,
+        };
+        perror();
+        println!("Loading '{}' failed: {}", path.display(), err);
+
+        for n in 1..=backups {
+            let backup = backup_path(path, n);
+            if !backup.exists() {
+                continue;
+            }
+            pinfo();
+            println!("Trying backup '{}'...", backup.display());
+            match load(&backup) {
+                Ok(virtual_disk) => return Ok((virtual_disk, backup)),
+                Err(err) => {
+                    perror();
+                    println!("Loading '{}' failed: {}", backup.display(), err);
+                }
+            }
+        }
+
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::testing::{new_disk, temp_path};
+
+    /// 删除镜像和它的备份
+    fn remove_all(path: &Path, backups: usize) {
+        let _ = fs::remove_file(path);
+        for n in 1..=backups {
+            let _ = fs::remove_file(backup_path(path, n));
+        }
+    }
+
+    #[test]
+    fn opened_image_is_only_changed_by_save() {
+        let path = temp_path("save-overlay.vd");
+        let mut dm = new_disk();
+        dm.create_file_with_data("/a", b"one").unwrap();
+        dm.save_to_file(&path, 0).unwrap();
+        let saved = fs::read(&path).unwrap();
+
+        let (mut dm, loaded) = DiskManager::load_file(&path, 0).unwrap();
+        assert_eq!(loaded, path);
+        dm.create_file_with_data("/b", b"two").unwrap();
+        dm.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), saved);
+
+        dm.save_to_file(&path, 0).unwrap();
+        // 保存之后从新文件读取，之后的改动仍然不会写进文件
+        dm.create_file_with_data("/c", b"three").unwrap();
+        assert_eq!(dm.read_file_by_name("/b").unwrap(), b"two");
+        let (mut dm, _loaded) = DiskManager::load_file(&path, 0).unwrap();
+        assert_eq!(dm.read_file_by_name("/b").unwrap(), b"two");
+        assert!(dm.read_file_by_name("/c").is_err());
+        remove_all(&path, 0);
+    }
+
+    /// 镜像中只有一个文件`/n`，内容是`n`
+    fn save_generation(path: &Path, n: u8, backups: usize) {
+        let mut dm = new_disk();
+        dm.create_file_with_data("/n", &[n]).unwrap();
+        dm.save_to_file(path, backups).unwrap();
+    }
+
+    fn generation(path: &Path) -> u8 {
+        let mut dm = DiskManager::open_file(path).unwrap();
+        dm.read_file_by_name("/n").unwrap()[0]
+    }
+
+    #[test]
+    fn saves_rotate_backups() {
+        let path = temp_path("save-rotate.vd");
+        for n in 1..=4 {
+            save_generation(&path, n, 2);
+        }
+        assert_eq!(generation(&path), 4);
+        assert_eq!(generation(&backup_path(&path, 1)), 3);
+        assert_eq!(generation(&backup_path(&path, 2)), 2);
+        // 最多保留2份
+        assert!(!backup_path(&path, 3).exists());
+        assert!(!with_suffix(&path, ".tmp").exists());
+        remove_all(&path, 2);
+    }
+
+    #[test]
+    fn corrupt_image_is_not_kept_as_backup() {
+        let path = temp_path("save-corrupt.vd");
+        save_generation(&path, 1, 2);
+        save_generation(&path, 2, 2);
+        fs::write(&path, b"garbage").unwrap();
+
+        save_generation(&path, 3, 2);
+        assert_eq!(generation(&path), 3);
+        // 坏掉的原文件没有挤掉好的备份
+        assert_eq!(generation(&backup_path(&path, 1)), 1);
+        assert!(!backup_path(&path, 2).exists());
+        remove_all(&path, 2);
+    }
+
+    #[test]
+    fn load_falls_back_to_backups() {
+        let path = temp_path("save-fallback.vd");
+        for n in 1..=3 {
+            save_generation(&path, n, 2);
+        }
+        fs::write(&path, b"garbage").unwrap();
+        let (mut dm, loaded) = DiskManager::load_file(&path, 2).unwrap();
+        assert_eq!(loaded, backup_path(&path, 1));
+        assert_eq!(dm.read_file_by_name("/n").unwrap(), [2]);
+
+        // 最新的备份也坏了，再往前找
+        fs::write(backup_path(&path, 1), b"").unwrap();
+        let (_dm, loaded) = DiskManager::load_file(&path, 2).unwrap();
+        assert_eq!(loaded, backup_path(&path, 2));
+        // 只允许看1份备份时找不到能用的
+        assert!(DiskManager::load_file(&path, 1).is_err());
+        remove_all(&path, 2);
+    }
+}
@@ -0,0 +1,337 @@
+//! 文件的详细信息：`stat`和`ls -l`显示的内容。
+
+use std::fmt;
+
+use super::acl::Acl;
+use super::clock::{format_time, Times};
+use super::error::FsError;
+use super::image::ROOT_CLUSTER;
+use super::path::split_last;
+use super::users::{Accounts, Perm, ROOT_UID};
+use super::{Directory, DiskManager, Fcb, FileType};
+
+/// 一个文件或目录的详细信息
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub name: String,
+    pub file_type: FileType,
+    /// 文件长度，目录为0
+    pub length: usize,
+    pub first_cluster: usize,
+    /// 簇链的长度
+    pub clusters: usize,
+    pub times: Times,
+    pub perm: Perm,
+    /// 属主的用户名
+    pub owner: String,
+    /// 属组的组名
+    pub group: String,
+    pub acl: Acl,
+    /// 硬链接数量，目录为1
+    pub links: usize,
+}
+impl fmt::Display for Stat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  File: {}", self.name)?;
+        writeln!(f, "  Type: {}", self.file_type)?;
+        writeln!(
+            f,
+            "  Mode: {:04o} ({})  Owner: {}  Group: {}",
+            self.perm.mode, self.perm, self.owner, self.group
+        )?;
+        writeln!(
+            f,
+            "  Size: {} Bytes in {} clusters, first cluster {}",
+            self.length, self.clusters, self.first_cluster
+        )?;
+        writeln!(f, " Links: {}", self.links)?;
+        writeln!(f, " Birth: {}", format_time(self.times.created))?;
+        writeln!(f, "Modify: {}", format_time(self.times.modified))?;
+        write!(f, "Access: {}", format_time(self.times.accessed))
+    }
+}
+
+/// `ls`显示的目录列表，见`DiskManager::listing`。符号链接在名字后面显示“-> 目标”。
+/// `long`时带有权限、属主、长度和修改时间，带有ACL的项在权限后面标上`+`
+pub struct Listing<'a> {
+    dir: &'a Directory,
+    accounts: &'a Accounts,
+    /// 每一项的显示名，符号链接带上目标
+    names: Vec<String>,
+    long: bool,
+}
+impl fmt::Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Listing {
+            dir,
+            accounts,
+            names,
+            long,
+        } = self;
+        writeln!(f, "Directroy '{}' Files:", dir.name)?;
+        for (fcb, name) in dir.files.iter().zip(names) {
+            if !long {
+                writeln!(f, "{}\t\t{}\t\tLength: {}", name, fcb.file_type, fcb.length)?;
+                continue;
+            }
+            let kind = match fcb.file_type {
+                FileType::Directory => 'd',
+                FileType::File => '-',
+                FileType::Symlink => 'l',
+            };
+            let acl = if fcb.acl.is_empty() { " " } else { "+" };
+            writeln!(
+                f,
+                "{}{}{} {:<8} {:<8} {:>10}  {}  {}",
+                kind,
+                fcb.perm,
+                acl,
+                accounts.user_name(fcb.perm.owner),
+                accounts.group_name(fcb.perm.group),
+                fcb.length,
+                format_time(fcb.times.modified),
+                name
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskManager {
+    /// 按`ls`的格式显示目录，`long`时按`ls -l`的格式。符号链接的目标读不出来时显示为“?”
+    pub fn listing<'a>(&'a self, dir: &'a Directory, long: bool) -> Listing<'a> {
+        let names = dir
+            .files
+            .iter()
+            .map(|fcb| match fcb.file_type {
+                FileType::Symlink => {
+                    let target = self.link_target(fcb).unwrap_or_else(|_| String::from("?"));
+                    format!("{} -> {}", fcb.name, target)
+                }
+                _ => fcb.name.clone(),
+            })
+            .collect();
+
+        Listing {
+            dir,
+            accounts: &self.meta.accounts,
+            names,
+            long,
+        }
+    }
+
+    /// 按路径取得文件或目录的详细信息，不会更新访问时间
+    pub fn stat(&self, path: &str) -> Result<Stat, FsError> {
+        let fcb = self.find_fcb(path)?;
+        let clusters = self.get_file_clusters(fcb.first_cluster)?.len();
+        let links = match fcb.file_type {
+            FileType::Directory => 1,
+            _ => self.link_count(fcb.first_cluster),
+        };
+
+        Ok(Stat {
+            name: fcb.name,
+            file_type: fcb.file_type,
+            length: fcb.length,
+            first_cluster: fcb.first_cluster,
+            clusters,
+            times: fcb.times,
+            perm: fcb.perm,
+            owner: self.meta.accounts.user_name(fcb.perm.owner),
+            group: self.meta.accounts.group_name(fcb.perm.group),
+            links,
+            acl: fcb.acl,
+        })
+    }
+
+    /// 设置`path`的时间戳，导入宿主机文件时用来保留原来的时间。只有属主和root可以设置。
+    pub fn set_times(&mut self, path: &str, times: Times) -> Result<(), FsError> {
+        let (mut parent, name) = self.resolve_parent(path)?;
+        let index = parent
+            .get_index_by_name(name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        let owner = parent.files[index].perm.owner;
+        if self.uid() != ROOT_UID && self.uid() != owner {
+            return Err(FsError::PermissionDenied(String::from(path)));
+        }
+        parent.files[index].times = times;
+
+        self.transaction(|dm| {
+            dm.store_directory(&parent)?;
+            dm.sync_links(&parent.files[index])
+        })
+    }
+
+    /// 目录中增删了项或者有项改名之后，更新目录的修改时间。目录的时间记在上一级目录中指向它的FCB里，
+    /// 自己的“.”也一起更新，根目录只有“.”。两者都重新读出，调用者要先写回自己改过的目录。
+    pub(crate) fn touch_directory(&mut self, cluster: usize) -> Result<(), FsError> {
+        let now = self.now();
+        let mut dir = self.get_directory_by_cluster(cluster)?;
+        dir.files[1].times.modified = now;
+        self.store_directory(&dir)?;
+        if cluster == ROOT_CLUSTER {
+            return Ok(());
+        }
+        let mut parent = self.get_directory_by_cluster(dir.files[0].first_cluster)?;
+        let fcb = parent
+            .files
+            .iter_mut()
+            .skip(2)
+            .find(|fcb| fcb.first_cluster == cluster)
+            .ok_or_else(|| FsError::Corrupt {
+                cluster,
+                reason: String::from("directory is missing from its parent"),
+            })?;
+        fcb.times.modified = now;
+        self.store_directory(&parent)
+    }
+
+    /// 找到路径指向的FCB。以“.”、“..”结尾的路径和根目录，在上一级目录中找到指向该目录的FCB，
+    /// 根目录没有上一级，使用它自己的“.”。
+    fn find_fcb(&self, path: &str) -> Result<Fcb, FsError> {
+        match split_last(path).1 {
+            "" | "." | ".." => {
+                let dir = self.resolve_directory(path)?;
+                let cluster = dir.files[1].first_cluster;
+                if cluster == ROOT_CLUSTER {
+                    return Ok(Fcb {
+                        name: String::from("/"),
+                        ..dir.files[1].clone()
+                    });
+                }
+                let parent = self.get_directory_by_cluster(dir.files[0].first_cluster)?;
+                parent
+                    .files
+                    .iter()
+                    .skip(2)
+                    .find(|fcb| fcb.first_cluster == cluster)
+                    .cloned()
+                    .ok_or_else(|| FsError::Corrupt {
+                        cluster,
+                        reason: String::from("directory is missing from its parent"),
+                    })
+            }
+            _ => self.resolve_fcb(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::disk_at;
+
+    fn times(dm: &DiskManager, path: &str) -> (u64, u64, u64) {
+        let times = dm.stat(path).unwrap().times;
+        (times.created, times.modified, times.accessed)
+    }
+
+    #[test]
+    fn times_follow_create_write_rename_and_read() {
+        let (mut dm, clock) = disk_at(1000);
+        dm.new_directory_to_disk("/d").unwrap();
+        clock.advance(5);
+        dm.create_file_with_data("/d/f", b"hello").unwrap();
+        assert_eq!(times(&dm, "/d"), (1000, 1005, 1000));
+        assert_eq!(times(&dm, "/d/f"), (1005, 1005, 1005));
+
+        clock.advance(5);
+        let mut handle = dm.open("/d/f", OpenOptions::new().append(true)).unwrap();
+        handle.write_all(b" world").unwrap();
+        handle.close().unwrap();
+        assert_eq!(times(&dm, "/d/f"), (1005, 1010, 1005));
+
+        clock.advance(10);
+        assert_eq!(dm.read_file_by_name("/d/f").unwrap(), b"hello world");
+        assert_eq!(times(&dm, "/d/f"), (1005, 1010, 1020));
+        // 写入和读取文件不改变目录的时间
+        assert_eq!(times(&dm, "/d"), (1000, 1005, 1000));
+
+        clock.advance(10);
+        dm.rename_file_by_name("/d/f", "g").unwrap();
+        assert_eq!(times(&dm, "/d/g"), (1005, 1030, 1020));
+        // 目录中的项改名，目录的修改时间随之更新，“.”也一样
+        assert_eq!(times(&dm, "/d"), (1000, 1030, 1000));
+        assert_eq!(times(&dm, "/d/."), (1000, 1030, 1000));
+        assert_eq!(
+            dm.resolve_directory("/d").unwrap().files[1].times.modified,
+            1030
+        );
+    }
+
+    #[test]
+    fn directory_times_follow_entry_changes() {
+        let (mut dm, clock) = disk_at(1000);
+        dm.new_directory_to_disk("/a").unwrap();
+        dm.new_directory_to_disk("/b").unwrap();
+        dm.create_file_with_data("/a/f", b"data").unwrap();
+        let modified = |dm: &DiskManager, path: &str| times(dm, path).1;
+
+        clock.advance(10);
+        dm.move_by_path("/a/f", "/b").unwrap();
+        assert_eq!((modified(&dm, "/a"), modified(&dm, "/b")), (1010, 1010));
+        // 根目录的时间记在它自己的“.”里，`/a`、`/b`的时间变了，根目录的项没有增删
+        assert_eq!(modified(&dm, "/"), 1000);
+
+        clock.advance(10);
+        dm.copy_by_path("/b/f", "/a/g", false, false).unwrap();
+        dm.link_by_path("/b/f", "/a/h").unwrap();
+        assert_eq!((modified(&dm, "/a"), modified(&dm, "/b")), (1020, 1010));
+
+        clock.advance(10);
+        dm.delete_file_by_name("/a/g").unwrap();
+        assert_eq!(modified(&dm, "/a"), 1030);
+        clock.advance(10);
+        dm.symlink("/b/f", "/b/s").unwrap();
+        assert_eq!(modified(&dm, "/b"), 1040);
+
+        clock.advance(10);
+        dm.delete_recursive("/a", false).unwrap();
+        dm.new_directory_to_disk("/c").unwrap();
+        assert_eq!(modified(&dm, "/"), 1050);
+        assert_eq!(times(&dm, "/."), times(&dm, "/"));
+    }
+
+    #[test]
+    fn noatime_keeps_access_time() {
+        let (mut dm, clock) = disk_at(1000);
+        dm.create_file_with_data("/f", b"data").unwrap();
+        dm.noatime = true;
+        clock.advance(10);
+        dm.read_file_by_name("/f").unwrap();
+        let mut buf = Vec::new();
+        let mut handle = dm.open("/f", OpenOptions::new().read(true)).unwrap();
+        handle.read_to_end(&mut buf).unwrap();
+        handle.close().unwrap();
+        assert_eq!(times(&dm, "/f"), (1000, 1000, 1000));
+
+        dm.noatime = false;
+        let mut handle = dm.open("/f", OpenOptions::new().read(true)).unwrap();
+        handle.read_to_end(&mut buf).unwrap();
+        handle.close().unwrap();
+        assert_eq!(times(&dm, "/f"), (1000, 1000, 1010));
+    }
+
+    #[test]
+    fn copies_are_new_files() {
+        let (mut dm, clock) = disk_at(1000);
+        dm.create_file_with_data("/f", b"data").unwrap();
+        clock.advance(10);
+        dm.copy_by_path("/f", "/g", false, true).unwrap();
+        dm.move_by_path("/f", "/h").unwrap();
+        assert_eq!(times(&dm, "/g"), (1010, 1010, 1010));
+        assert_eq!(times(&dm, "/h"), (1000, 1000, 1000));
+    }
+
+    #[test]
+    fn times_are_formatted_in_utc() {
+        assert_eq!(format_time(0), "-");
+        assert_eq!(format_time(1), "1970-01-01 00:00:01");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13:20");
+    }
+}
@@ -0,0 +1,207 @@
+//! 符号链接。
+//!
+//! 符号链接是`FileType::Symlink`类型的FCB，目标路径按UTF-8存放在它自己的簇链中，长度就是目标的字节数，
+//! 所以复制、删除、碎片整理和fsck都可以像普通文件一样处理它的簇链。目标不必存在，悬空的链接由fsck报告。
+//!
+//! 路径中间的符号链接总是被跟随；最后一项只在打开、读取文件和修改权限时跟随，
+//! 删除、改名、移动、复制和`stat`作用于链接自己。连续跟随超过`MAX_SYMLINKS`个链接时返回
+//! `FsError::SymlinkLoop`。符号链接自己的权限位总是`rwxrwxrwx`，不参与权限检查。
+
+use super::error::FsError;
+use super::path::split_last;
+use super::{pinfo, Directory, DiskManager, Fcb, FileType};
+
+/// 查找一个路径时最多跟随的符号链接数量
+pub const MAX_SYMLINKS: usize = 16;
+
+impl DiskManager {
+    /// 新建指向`target`的符号链接`path`
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<(), FsError> {
+        pinfo();
+        println!("Linking '{}' to '{}'...", path, target);
+        if target.is_empty() {
+            return Err(FsError::InvalidArgument(String::from(
+                "symlink target is empty",
+            )));
+        }
+        self.create_entry(path, FileType::Symlink, target.as_bytes())
+    }
+
+    /// 读出符号链接`path`的目标，最后一项不跟随
+    pub fn read_link(&self, path: &str) -> Result<String, FsError> {
+        let fcb = self.resolve_fcb(path)?;
+        match fcb.file_type {
+            FileType::Symlink => self.link_target(&fcb),
+            _ => Err(FsError::InvalidArgument(format!(
+                "'{}' is not a symlink",
+                path
+            ))),
+        }
+    }
+
+    /// 读出符号链接的目标，不打印日志
+    pub(crate) fn link_target(&self, fcb: &Fcb) -> Result<String, FsError> {
+        let clusters = self.chain_quietly(fcb.first_cluster)?;
+        let data = self.disk.read_data_by_clusters(&clusters, fcb.length)?;
+
+        String::from_utf8(data).map_err(|_| FsError::Corrupt {
+            cluster: fcb.first_cluster,
+            reason: String::from("symlink target is not UTF-8"),
+        })
+    }
+
+    /// 最后一项是符号链接时，返回它最终指向的绝对路径，否则原样返回`path`。最终指向的文件可以不存在。
+    pub(crate) fn follow_final(&self, path: &str) -> Result<String, FsError> {
+        match self.resolve_fcb(path) {
+            Ok(fcb) if matches!(fcb.file_type, FileType::Symlink) => (),
+            _ => return Ok(String::from(path)),
+        }
+        let (dir, name) =
+            self.resolve_last(path, &|cluster| self.get_directory_by_cluster(cluster))?;
+        let dir_path = self.directory_path(&dir)?;
+
+        Ok(format!("{}/{}", dir_path.trim_end_matches('/'), name))
+    }
+
+    /// `path`最终指向的文件或目录是否存在，不打印日志。fsck用它找出悬空的符号链接。
+    pub(crate) fn target_exists(&self, path: &str) -> Result<bool, FsError> {
+        match self.resolve_last(path, &|cluster| self.read_directory_quietly(cluster)) {
+            Ok((dir, name)) => Ok(dir.get_fcb_by_name(&name).is_some()),
+            Err(FsError::NotFound(_))
+            | Err(FsError::NotADirectory(_))
+            | Err(FsError::SymlinkLoop(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// 同`resolve_parent`，但最后一项是符号链接时跟随它，直到最后一项不再是符号链接。
+    /// 目标是目录本身（例如`/`或`..`）时，返回该目录和“.”。
+    fn resolve_last<F>(&self, path: &str, read: &F) -> Result<(Directory, String), FsError>
+    where
+        F: Fn(usize) -> Result<Directory, FsError>,
+    {
+        let mut links = 0;
+        let (parent, name) = split_last(path);
+        if name.is_empty() {
+            return Err(FsError::InvalidArgument(format!(
+                "path '{}' does not name a file",
+                path
+            )));
+        }
+        let mut dir = self.walk_path(self.cur_dir.clone(), parent, read, &mut links)?;
+        let mut name = String::from(name);
+        loop {
+            let target = match dir.get_fcb_by_name(&name) {
+                Some((_index, fcb)) if matches!(fcb.file_type, FileType::Symlink) => {
+                    self.link_target(fcb)?
+                }
+                _ => return Ok((dir, name)),
+            };
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(FsError::SymlinkLoop(String::from(path)));
+            }
+            // 相对的目标从链接所在的目录开始查找
+            let (parent, last) = split_last(&target);
+            match last {
+                "" | "." | ".." => {
+                    dir = self.walk_path(dir, &target, read, &mut links)?;
+                    name = String::from(".");
+                }
+                _ => {
+                    dir = self.walk_path(dir, parent, read, &mut links)?;
+                    name = String::from(last);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::disk_manager::fsck::Repair;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::new_disk;
+
+    /// `/d/a`，链接都指向它
+    fn target_disk() -> DiskManager {
+        let mut dm = new_disk();
+        dm.new_directory_to_disk("/d").unwrap();
+        dm.create_file_with_data("/d/a", b"hello").unwrap();
+        dm
+    }
+
+    #[test]
+    fn links_are_followed() {
+        let mut dm = target_disk();
+        dm.symlink("/d/a", "/abs").unwrap();
+        // 相对的目标从链接所在的目录开始查找
+        dm.symlink("a", "/d/rel").unwrap();
+        dm.symlink("d", "/dir").unwrap();
+        assert_eq!(dm.read_link("/abs").unwrap(), "/d/a");
+        assert_eq!(dm.read_file_by_name("/d/rel").unwrap(), b"hello");
+        assert_eq!(dm.read_file_by_name("/dir/rel").unwrap(), b"hello");
+
+        let mut file = dm.open("/abs", OpenOptions::new().append(true)).unwrap();
+        file.write_all(b" world").unwrap();
+        file.close().unwrap();
+        assert_eq!(dm.read_file_by_name("/d/a").unwrap(), b"hello world");
+        assert_eq!(dm.stat("/abs").unwrap().length, 4);
+
+        dm.set_current_directory("/dir").unwrap();
+        let listing = dm.listing(&dm.cur_dir.clone(), false).to_string();
+        assert!(listing.contains("rel -> a"));
+        assert!(matches!(
+            dm.read_link("/d/a"),
+            Err(FsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn loops_and_dangling_links() {
+        let mut dm = target_disk();
+        dm.symlink("/loop2", "/loop1").unwrap();
+        dm.symlink("/loop1", "/loop2").unwrap();
+        dm.symlink("/missing", "/dangling").unwrap();
+        assert!(matches!(
+            dm.read_file_by_name("/loop1"),
+            Err(FsError::SymlinkLoop(_))
+        ));
+        assert!(matches!(
+            dm.read_file_by_name("/loop1/x"),
+            Err(FsError::SymlinkLoop(_))
+        ));
+        assert!(matches!(
+            dm.read_file_by_name("/dangling"),
+            Err(FsError::NotFound(_))
+        ));
+
+        let report = dm.fsck(Repair::None).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.dangling.len(), 3);
+        assert!(report
+            .dangling
+            .contains(&(String::from("/dangling"), String::from("/missing"))));
+
+        // 目标建好以后链接就不再悬空
+        dm.create_file_with_data("/missing", b"x").unwrap();
+        assert_eq!(dm.fsck(Repair::None).unwrap().dangling.len(), 2);
+    }
+
+    #[test]
+    fn removal_and_reload_keep_the_target() {
+        let mut dm = target_disk();
+        dm.symlink("/d/a", "/abs").unwrap();
+        let mut image = Vec::new();
+        dm.write_image(&mut image).unwrap();
+        let mut dm = DiskManager::from_image(image).unwrap();
+        assert_eq!(dm.read_link("/abs").unwrap(), "/d/a");
+
+        dm.delete_file_by_name("/abs").unwrap();
+        assert_eq!(dm.read_file_by_name("/d/a").unwrap(), b"hello");
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+}
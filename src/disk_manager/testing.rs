@@ -0,0 +1,99 @@
+//! 测试共用的磁盘和断言。
+
+use std::cell::RefCell;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+
+use super::alloc::AllocatorKind;
+use super::clock::ManualClock;
+use super::device::{BlockDevice, MemoryDevice};
+use super::disk::DiskGeometry;
+use super::error::FsError;
+use super::DiskManager;
+
+/// 测试开始时的时间戳
+pub const START: u64 = 1000;
+
+/// 默认几何参数、首次适应分配的新磁盘，时钟停在`START`
+pub fn new_disk() -> DiskManager {
+    disk_at(START).0
+}
+
+/// 同`new_disk`，时钟停在`now`，返回的时钟可以拨动
+pub fn disk_at(now: u64) -> (DiskManager, ManualClock) {
+    let clock = ManualClock::new(now);
+    let dm = DiskManager::new(
+        None,
+        DiskGeometry::default(),
+        AllocatorKind::FirstFit,
+        Box::new(clock.clone()),
+    );
+
+    (dm, clock)
+}
+
+/// 有alice、bob和在dev组中的carol三个用户，alice拥有`/home/alice`，里面有一个文件`f`。
+/// 返回时以alice的身份登录。
+pub fn shared_disk() -> DiskManager {
+    let mut dm = new_disk();
+    dm.useradd("alice", None).unwrap();
+    dm.useradd("bob", None).unwrap();
+    dm.useradd("carol", Some("dev")).unwrap();
+    dm.new_directory_to_disk("/home").unwrap();
+    dm.new_directory_to_disk("/home/alice").unwrap();
+    dm.chown("/home/alice", Some("alice"), Some("alice"))
+        .unwrap();
+    dm.login("alice").unwrap();
+    dm.create_file_with_data("/home/alice/f", b"secret")
+        .unwrap();
+    dm
+}
+
+/// 操作因为没有权限而失败
+pub fn denied<T>(result: Result<T, FsError>) -> bool {
+    matches!(result, Err(FsError::PermissionDenied(_)))
+}
+
+/// 系统临时目录中供一个测试使用的路径，`name`在所有测试中不能重复。用完由测试自己删除。
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("file-system-{}-{}", process::id(), name))
+}
+
+/// 多个地方共用的内存块设备，DiskManager丢掉之后还能读出磁盘上剩下的内容
+#[derive(Clone)]
+pub struct SharedDevice(Rc<RefCell<MemoryDevice>>);
+impl SharedDevice {
+    pub fn from_bytes(block_size: usize, bytes: &[u8]) -> SharedDevice {
+        SharedDevice(Rc::new(RefCell::new(
+            MemoryDevice::from_bytes(block_size, bytes.to_vec()).unwrap(),
+        )))
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().as_bytes().to_vec()
+    }
+}
+impl BlockDevice for SharedDevice {
+    fn block_size(&self) -> usize {
+        self.0.borrow().block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.0.borrow().block_count()
+    }
+
+    fn read_block(&self, index: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.0.borrow().read_block(index, buf)
+    }
+
+    fn write_block(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        self.0.borrow_mut().write_block(index, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+//! 整棵目录树的操作。
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::clock::Times;
+use super::error::FsError;
+use super::users::{EXECUTE, READ, WRITE};
+use super::{check_file_name, pdebug, pinfo, Directory, DiskManager, Fcb, FileType};
+
+/// 递归删除的统计
+#[derive(Debug, Default, Clone)]
+pub struct RemoveReport {
+    pub files: usize,
+    pub directories: usize,
+    /// 回收的簇数量
+    pub clusters: usize,
+    /// 只是统计，没有真正删除
+    pub dry_run: bool,
+}
+impl fmt::Display for RemoveReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (verb, reclaimed) = if self.dry_run {
+            ("Would remove", "would be reclaimed")
+        } else {
+            ("Removed", "reclaimed")
+        };
+        write!(
+            f,
+            "{} {} files and {} dirs, {} clusters {}.",
+            verb, self.files, self.directories, self.clusters, reclaimed
+        )
+    }
+}
+
+impl DiskManager {
+    /// 删除文件或者整个目录树。先深度优先释放每个文件的簇链，再释放目录自己的簇链。
+    /// `dry_run`为真时只统计，不做任何修改。
+    pub fn delete_recursive(&mut self, path: &str, dry_run: bool) -> Result<RemoveReport, FsError> {
+        pinfo();
+        println!("Deleting '{}' recursively...", path);
+        let (mut parent, name) = self.resolve_parent(path)?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidArgument(format!(
+                "cannot delete '{}'",
+                path
+            )));
+        }
+        let index = parent
+            .get_index_by_name(name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        self.check_dir_access(&parent, WRITE | EXECUTE, path)?;
+        let fcb = parent.files[index].clone();
+        if let FileType::Directory = fcb.file_type {
+            if self.is_inside(self.cur_dir.files[1].first_cluster, fcb.first_cluster)? {
+                return Err(FsError::InvalidArgument(format!(
+                    "cannot delete '{}', current dir is inside it",
+                    path
+                )));
+            }
+        }
+
+        // 先空跑一遍，目录树有损坏时什么都不删
+        let mut report = RemoveReport {
+            dry_run: true,
+            ..RemoveReport::default()
+        };
+        self.remove_tree(
+            &fcb,
+            true,
+            &mut report,
+            &mut HashSet::new(),
+            &mut HashMap::new(),
+        )?;
+        if dry_run {
+            return Ok(report);
+        }
+
+        self.transaction(|dm| {
+            let mut report = RemoveReport::default();
+            dm.remove_tree(
+                &fcb,
+                false,
+                &mut report,
+                &mut HashSet::new(),
+                &mut HashMap::new(),
+            )?;
+            parent.files.remove(index);
+            dm.store_directory(&parent)?;
+            dm.touch_directory(parent.files[1].first_cluster)?;
+
+            Ok(report)
+        })
+    }
+
+    /// 深度优先释放`fcb`下的所有簇链，`visited`记录已经走过的目录，防止目录树成环。
+    /// 空跑时`released`记录每条共享簇链已经被释放了几次。要删空的目录需要读、写和执行权限，
+    /// 空跑时就会发现没有权限的目录。
+    fn remove_tree(
+        &mut self,
+        fcb: &Fcb,
+        dry_run: bool,
+        report: &mut RemoveReport,
+        visited: &mut HashSet<usize>,
+        released: &mut HashMap<usize, usize>,
+    ) -> Result<(), FsError> {
+        if let FileType::Directory = fcb.file_type {
+            if !visited.insert(fcb.first_cluster) {
+                return Err(FsError::Corrupt {
+                    cluster: fcb.first_cluster,
+                    reason: String::from("directory tree loops"),
+                });
+            }
+            let dir = self.get_directory_by_fcb(fcb)?;
+            self.check_dir_access(&dir, READ | WRITE | EXECUTE, &fcb.name)?;
+            for child in dir.files.iter().skip(2) {
+                self.remove_tree(child, dry_run, report, visited, released)?;
+            }
+            report.directories += 1;
+            pdebug();
+            println!("Freeing clusters of '{}'...", fcb.name);
+            report.clusters += if dry_run {
+                self.get_file_clusters(fcb.first_cluster)?.len()
+            } else {
+                self.meta.quotas.dirs.remove(&fcb.first_cluster);
+                self.delete_space_on_fat(fcb.first_cluster)?.len()
+            };
+        } else {
+            report.files += 1;
+            pdebug();
+            println!("Freeing clusters of '{}'...", fcb.name);
+            report.clusters += if dry_run {
+                // 共享或有多个链接的簇链要等最后一个FCB被删除时才释放
+                let count = released.entry(fcb.first_cluster).or_insert(0);
+                *count += 1;
+                let clusters = self.get_file_clusters(fcb.first_cluster)?.len();
+                if *count >= self.references(fcb.first_cluster) {
+                    clusters
+                } else {
+                    0
+                }
+            } else {
+                self.release_chain(fcb.first_cluster)?
+            };
+        }
+
+        Ok(())
+    }
+
+    /// 把`src`移动到`dst`。`dst`是已经存在的目录时移动到该目录中，否则移动并改名为`dst`。
+    /// 移动目录时同时改写它的“..”，不能把目录移动到它自己下面。
+    pub fn move_by_path(&mut self, src: &str, dst: &str) -> Result<(), FsError> {
+        pinfo();
+        println!("Moving '{}' to '{}'...", src, dst);
+        let (src_parent, src_name) = self.resolve_parent(src)?;
+        check_file_name(src_name)?;
+        let index = src_parent
+            .get_index_by_name(src_name)
+            .ok_or_else(|| FsError::NotFound(String::from(src)))?;
+        let mut fcb = src_parent.files[index].clone();
+        let (dst_parent, dst_name) = self.resolve_target(src_name, dst)?;
+        let src_cluster = src_parent.files[1].first_cluster;
+        let dst_cluster = dst_parent.files[1].first_cluster;
+        self.check_dir_access(&src_parent, WRITE | EXECUTE, src)?;
+        self.check_dir_access(&dst_parent, WRITE | EXECUTE, dst)?;
+        if src_cluster != dst_cluster {
+            // 要改写目录自己的“..”
+            if let FileType::Directory = fcb.file_type {
+                self.check_access(&fcb, WRITE, src)?;
+            }
+        }
+        if let FileType::Directory = fcb.file_type {
+            if self.is_inside(dst_cluster, fcb.first_cluster)? {
+                return Err(FsError::InvalidArgument(format!(
+                    "cannot move '{}' into itself",
+                    src
+                )));
+            }
+        }
+        if dst_parent.get_fcb_by_name(&dst_name).is_some() {
+            return Err(FsError::AlreadyExists(String::from(dst)));
+        }
+        if src_cluster != dst_cluster {
+            self.check_move_quota(&fcb, src_cluster, dst_cluster)?;
+        }
+        fcb.name = dst_name;
+        let dst_access = (dst_parent.files[1].perm, dst_parent.files[1].acl.clone());
+
+        // 修改后的目录
+        let mut changes = Vec::new();
+        if src_cluster == dst_cluster {
+            let mut parent = src_parent;
+            parent.files[index] = fcb.clone();
+            changes.push(parent);
+        } else {
+            let mut new_dst_parent = dst_parent;
+            new_dst_parent.files.push(fcb.clone());
+            changes.push(new_dst_parent);
+            let mut new_src_parent = src_parent;
+            new_src_parent.files.remove(index);
+            changes.push(new_src_parent);
+        }
+        if let FileType::Directory = fcb.file_type {
+            // 目录记着自己的名字和上一级目录
+            let mut dir = self.get_directory_by_fcb(&fcb)?;
+            dir.name = fcb.name.clone();
+            dir.files[0].first_cluster = dst_cluster;
+            (dir.files[0].perm, dir.files[0].acl) = dst_access;
+            changes.push(dir);
+        }
+
+        // 几个目录在同一个事务中写回，要么都改了，要么都没改
+        self.transaction(|dm| {
+            for dir in &changes {
+                dm.store_directory(dir)?;
+            }
+            dm.touch_directory(src_cluster)?;
+            if dst_cluster != src_cluster {
+                dm.touch_directory(dst_cluster)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// 复制`src`到`dst`，`dst`的含义同`move_by_path`。复制目录需要`recursive`。
+    /// `cow`为真时文件不复制簇，而是与原文件共享簇链，直到其中一个被写入。
+    pub fn copy_by_path(
+        &mut self,
+        src: &str,
+        dst: &str,
+        recursive: bool,
+        cow: bool,
+    ) -> Result<(), FsError> {
+        pinfo();
+        println!("Copying '{}' to '{}'...", src, dst);
+        let (src_parent, src_name) = self.resolve_parent(src)?;
+        check_file_name(src_name)?;
+        let (_index, fcb) = src_parent
+            .get_fcb_by_name(src_name)
+            .ok_or_else(|| FsError::NotFound(String::from(src)))?;
+        let fcb = fcb.clone();
+        let (mut dst_parent, dst_name) = self.resolve_target(src_name, dst)?;
+        self.check_dir_access(&dst_parent, WRITE | EXECUTE, dst)?;
+        if let FileType::Directory = fcb.file_type {
+            if !recursive {
+                return Err(FsError::IsADirectory(String::from(src)));
+            }
+            if self.is_inside(dst_parent.files[1].first_cluster, fcb.first_cluster)? {
+                return Err(FsError::InvalidArgument(format!(
+                    "cannot copy '{}' into itself",
+                    src
+                )));
+            }
+        }
+        if dst_parent.get_fcb_by_name(&dst_name).is_some() {
+            return Err(FsError::AlreadyExists(String::from(dst)));
+        }
+        self.check_copy_quota(&fcb, dst_parent.files[1].first_cluster)?;
+
+        self.transaction(|dm| {
+            dm.copy_tree(&fcb, &mut dst_parent, &dst_name, cow)?;
+            dm.store_directory(&dst_parent)?;
+            dm.touch_directory(dst_parent.files[1].first_cluster)
+        })
+    }
+
+    /// 把`fcb`复制到`dest`中，名字为`name`，返回新的FCB。`dest`由调用者写回。
+    fn copy_tree(
+        &mut self,
+        fcb: &Fcb,
+        dest: &mut Directory,
+        name: &str,
+        cow: bool,
+    ) -> Result<Fcb, FsError> {
+        match fcb.file_type {
+            // 符号链接复制成指向同一个目标的新链接
+            FileType::File | FileType::Symlink => {
+                self.check_access(fcb, READ, &fcb.name)?;
+                // 有多个硬链接的簇链不能再共享，否则写入时复制出来的新簇链会脱离其他链接
+                let first_cluster = if cow && self.link_count(fcb.first_cluster) <= 1 {
+                    self.share_chain(fcb.first_cluster);
+                    fcb.first_cluster
+                } else {
+                    self.duplicate_chain(fcb.first_cluster)?
+                };
+                // 复制出来的是新文件，时间从现在开始，属于复制它的用户
+                let new_fcb = Fcb {
+                    name: String::from(name),
+                    first_cluster,
+                    times: Times::at(self.now()),
+                    perm: self.new_perm(&fcb.file_type),
+                    acl: dest.files[1].acl.inherited(&fcb.file_type),
+                    ..fcb.clone()
+                };
+                dest.files.push(new_fcb.clone());
+
+                Ok(new_fcb)
+            }
+            FileType::Directory => {
+                let src_dir = self.get_directory_by_fcb(fcb)?;
+                self.check_dir_access(&src_dir, READ | EXECUTE, &fcb.name)?;
+                let new_fcb = self.create_directory_in(dest, name)?;
+                let mut new_dir = self.get_directory_by_fcb(&new_fcb)?;
+                for child in src_dir.files.iter().skip(2) {
+                    if let Err(err) = self.copy_tree(child, &mut new_dir, &child.name, cow) {
+                        // 删掉已经复制的部分
+                        self.store_directory(&new_dir)?;
+                        self.remove_tree(
+                            &new_fcb,
+                            false,
+                            &mut RemoveReport::default(),
+                            &mut HashSet::new(),
+                            &mut HashMap::new(),
+                        )?;
+                        dest.files.pop();
+                        return Err(err);
+                    }
+                }
+                self.store_directory(&new_dir)?;
+
+                Ok(new_fcb)
+            }
+        }
+    }
+
+    /// 找到移动或复制的目标：`dst`是已经存在的目录时，返回该目录和原来的名字`name`，
+    /// 否则返回`dst`的父目录和最后一项。
+    pub(crate) fn resolve_target(
+        &self,
+        name: &str,
+        dst: &str,
+    ) -> Result<(Directory, String), FsError> {
+        if let Ok(dir) = self.resolve_directory(dst) {
+            return Ok((dir, String::from(name)));
+        }
+        let (parent, name) = self.resolve_parent(dst)?;
+        check_file_name(name)?;
+
+        Ok((parent, String::from(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::fsck::Repair;
+    use crate::disk_manager::testing::{denied, new_disk, shared_disk};
+
+    #[test]
+    fn dir_cannot_move_into_itself() {
+        let mut dm = new_disk();
+        dm.new_directory_to_disk("/a").unwrap();
+        dm.new_directory_to_disk("/a/b").unwrap();
+        for dst in ["/a", "/a/b", "/a/b/c"] {
+            assert!(matches!(
+                dm.move_by_path("/a", dst),
+                Err(FsError::InvalidArgument(_))
+            ));
+        }
+        assert!(dm.stat("/a/b").is_ok());
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+
+    #[test]
+    fn moved_dir_points_to_new_parent() {
+        let mut dm = new_disk();
+        dm.new_directory_to_disk("/a").unwrap();
+        dm.new_directory_to_disk("/a/c").unwrap();
+        dm.new_directory_to_disk("/b").unwrap();
+        dm.create_file_with_data("/a/c/f", b"data").unwrap();
+        dm.move_by_path("/a/c", "/b/d").unwrap();
+
+        let dir = dm.resolve_directory("/b/d").unwrap();
+        assert_eq!(dir.name, "d");
+        assert_eq!(
+            dir.files[0].first_cluster,
+            dm.stat("/b").unwrap().first_cluster
+        );
+        assert_eq!(dm.resolve_directory("/b/d/..").unwrap().name, "b");
+        assert_eq!(dm.read_file_by_name("/b/d/../d/f").unwrap(), b"data");
+        assert!(dm.stat("/a/c").is_err());
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+
+    /// 目录中各项的名字
+    fn names(dm: &DiskManager, path: &str) -> Vec<String> {
+        let dir = dm.resolve_directory(path).unwrap();
+        dir.files.iter().map(|fcb| fcb.name.clone()).collect()
+    }
+
+    #[test]
+    fn delete_recursive_reports_what_it_reclaims() {
+        let mut dm = new_disk();
+        let cluster_size = dm.disk.cluster_size();
+        dm.create_file_with_data("/keep", b"keep").unwrap();
+        let free = dm.get_disk_info().2;
+        dm.new_directory_to_disk("/t").unwrap();
+        dm.new_directory_to_disk("/t/s").unwrap();
+        dm.new_directory_to_disk("/t/s/empty").unwrap();
+        dm.create_file_with_data("/t/a", &vec![1u8; 3 * cluster_size])
+            .unwrap();
+        dm.create_file_with_data("/t/s/b", &vec![2u8; 2 * cluster_size - 1])
+            .unwrap();
+        dm.create_file_with_data("/t/s/c", b"c").unwrap();
+        let used = free - dm.get_disk_info().2;
+        // 三个目录各占一个簇，文件占3、2、1个簇
+        assert_eq!(used, 3 + 3 + 2 + 1);
+        let root = names(&dm, "/");
+        let inner = names(&dm, "/t/s");
+
+        let report = dm.delete_recursive("/t", true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(
+            (report.files, report.directories, report.clusters),
+            (3, 3, used)
+        );
+        assert_eq!(dm.get_disk_info().2, free - used);
+        assert_eq!(names(&dm, "/"), root);
+        assert_eq!(names(&dm, "/t/s"), inner);
+        assert_eq!(
+            dm.read_file_by_name("/t/s/b").unwrap().len(),
+            2 * cluster_size - 1
+        );
+
+        let report = dm.delete_recursive("/t", false).unwrap();
+        assert!(!report.dry_run);
+        assert_eq!(
+            (report.files, report.directories, report.clusters),
+            (3, 3, used)
+        );
+        assert_eq!(dm.get_disk_info().2, free);
+        assert!(dm.stat("/t").is_err());
+        assert_eq!(dm.read_file_by_name("/keep").unwrap(), b"keep");
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+    }
+
+    #[test]
+    fn failed_copy_leaves_nothing() {
+        let mut dm = shared_disk();
+        dm.new_directory_to_disk("/home/alice/d").unwrap();
+        dm.create_file_with_data("/home/alice/d/a", b"one").unwrap();
+        dm.create_file_with_data("/home/alice/d/b", b"two").unwrap();
+        dm.chmod("/home/alice/d/b", "200").unwrap();
+        let free = dm.get_disk_info().2;
+
+        assert!(matches!(
+            dm.copy_by_path("/home/alice/d", "/home/alice/e", false, false),
+            Err(FsError::IsADirectory(_))
+        ));
+        // a已经复制了，b读不出来，复制了一半的目录整个撤销
+        assert!(denied(dm.copy_by_path(
+            "/home/alice/d",
+            "/home/alice/e",
+            true,
+            false
+        )));
+        assert!(dm.stat("/home/alice/e").is_err());
+        assert_eq!(dm.get_disk_info().2, free);
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+
+        dm.chmod("/home/alice/d/b", "600").unwrap();
+        dm.copy_by_path("/home/alice/d", "/home/alice/e", true, false)
+            .unwrap();
+        assert_eq!(dm.read_file_by_name("/home/alice/e/b").unwrap(), b"two");
+    }
+}
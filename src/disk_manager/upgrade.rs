@@ -0,0 +1,300 @@
+//! 旧版本镜像的升级。
+//!
+//! 版本5给FCB加上了时间戳，版本6加上了属主、属组和权限位，版本7又加上了ACL，
+//! 目录文件的编码因此改变。加载更早的镜像时，按旧的格式读出所有目录，时间戳记为不知道（0），
+//! 文件和目录都属于root，没有ACL，再在一个事务中按新的格式写回，提交时超级块也写成新版本。
+
+use std::collections::HashSet;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use super::acl::Acl;
+use super::clock::Times;
+use super::error::FsError;
+use super::image::{FORMAT_VERSION, ROOT_CLUSTER};
+use super::users::Perm;
+use super::{pinfo, Directory, DiskManager, Fcb, FileType};
+
+/// FCB带有时间戳的第一个格式版本
+pub const TIMES_VERSION: u32 = 5;
+/// FCB带有属主、属组和权限位的第一个格式版本
+pub const PERM_VERSION: u32 = 6;
+
+/// 版本5以前的FCB
+#[derive(Deserialize)]
+struct LegacyFcb {
+    name: String,
+    file_type: FileType,
+    first_cluster: usize,
+    length: usize,
+}
+impl From<LegacyFcb> for Fcb {
+    fn from(fcb: LegacyFcb) -> Fcb {
+        Fcb {
+            perm: Perm::legacy(&fcb.file_type),
+            name: fcb.name,
+            file_type: fcb.file_type,
+            first_cluster: fcb.first_cluster,
+            length: fcb.length,
+            times: Times::default(),
+            acl: Acl::default(),
+        }
+    }
+}
+
+/// 版本5的FCB，有时间戳，没有权限
+#[derive(Deserialize)]
+struct TimedFcb {
+    name: String,
+    file_type: FileType,
+    first_cluster: usize,
+    length: usize,
+    times: Times,
+}
+impl From<TimedFcb> for Fcb {
+    fn from(fcb: TimedFcb) -> Fcb {
+        Fcb {
+            perm: Perm::legacy(&fcb.file_type),
+            name: fcb.name,
+            file_type: fcb.file_type,
+            first_cluster: fcb.first_cluster,
+            length: fcb.length,
+            times: fcb.times,
+            acl: Acl::default(),
+        }
+    }
+}
+
+/// 版本6的FCB，有权限位，没有ACL
+#[derive(Deserialize)]
+struct OwnedFcb {
+    name: String,
+    file_type: FileType,
+    first_cluster: usize,
+    length: usize,
+    times: Times,
+    perm: Perm,
+}
+impl From<OwnedFcb> for Fcb {
+    fn from(fcb: OwnedFcb) -> Fcb {
+        Fcb {
+            name: fcb.name,
+            file_type: fcb.file_type,
+            first_cluster: fcb.first_cluster,
+            length: fcb.length,
+            times: fcb.times,
+            perm: fcb.perm,
+            acl: Acl::default(),
+        }
+    }
+}
+
+/// 旧版本的目录，`F`是该版本的FCB
+#[derive(Deserialize)]
+struct LegacyDirectory<F> {
+    name: String,
+    files: Vec<F>,
+}
+
+/// 按旧的格式解码目录，转换成当前的格式
+fn decode_legacy<F>(data: &[u8]) -> Result<Directory, bincode::Error>
+where
+    F: DeserializeOwned + Into<Fcb>,
+{
+    let legacy: LegacyDirectory<F> = bincode::deserialize(data)?;
+
+    Ok(Directory {
+        name: legacy.name,
+        files: legacy.files.into_iter().map(Into::into).collect(),
+    })
+}
+
+impl DiskManager {
+    /// 把整棵目录树转换成当前格式
+    pub(crate) fn upgrade_directories(&mut self) -> Result<(), FsError> {
+        pinfo();
+        println!(
+            "Upgrading dirs from format version {}...",
+            self.disk.version
+        );
+        // 先全部读出，再一起写回
+        let mut dirs = Vec::new();
+        let mut stack = vec![ROOT_CLUSTER];
+        let mut visited = HashSet::new();
+        while let Some(cluster) = stack.pop() {
+            // 被多个FCB指向的目录只转换一次，交叉链接留给fsck报告
+            if !visited.insert(cluster) {
+                continue;
+            }
+            let data = self.get_data_by_first_cluster(cluster, None)?;
+            let decoded = if self.disk.version < TIMES_VERSION {
+                decode_legacy::<LegacyFcb>(&data)
+            } else if self.disk.version < PERM_VERSION {
+                decode_legacy::<TimedFcb>(&data)
+            } else {
+                decode_legacy::<OwnedFcb>(&data)
+            };
+            let dir = decoded.map_err(|err| FsError::Corrupt {
+                cluster,
+                reason: format!("unreadable directory: {}", err),
+            })?;
+            if dir.files.len() < 2 {
+                return Err(FsError::Corrupt {
+                    cluster,
+                    reason: String::from("directory has no '.' or '..' entry"),
+                });
+            }
+            stack.extend(
+                dir.files
+                    .iter()
+                    .skip(2)
+                    .filter(|fcb| matches!(fcb.file_type, FileType::Directory))
+                    .map(|fcb| fcb.first_cluster),
+            );
+            dirs.push(dir);
+        }
+
+        // 目录变长时簇链跟着变长，当前目录还没有读出，不能用`store_directory`
+        self.transaction(|dm| {
+            for dir in &dirs {
+                dm.save_directory_to_disk(dir)?;
+            }
+
+            Ok(())
+        })?;
+        self.disk.version = FORMAT_VERSION;
+        pinfo();
+        println!(
+            "Upgraded {} dirs to format version {}.",
+            dirs.len(),
+            FORMAT_VERSION
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::fsck::Repair;
+    use crate::disk_manager::image::{decode_superblock, SUPERBLOCK_SIZE};
+    use crate::disk_manager::stat::Stat;
+    use crate::disk_manager::testing::new_disk;
+
+    /// 按版本`version`的格式编码目录
+    fn encode_legacy(version: u32, dir: &Directory) -> Vec<u8> {
+        let files = dir.files.iter();
+        if version < TIMES_VERSION {
+            let files: Vec<_> = files
+                .map(|fcb| (&fcb.name, &fcb.file_type, fcb.first_cluster, fcb.length))
+                .collect();
+            bincode::serialize(&(&dir.name, files))
+        } else if version < PERM_VERSION {
+            let files: Vec<_> = files
+                .map(|fcb| {
+                    let shape = (&fcb.name, &fcb.file_type, fcb.first_cluster, fcb.length);
+                    (shape, fcb.times)
+                })
+                .collect();
+            bincode::serialize(&(&dir.name, files))
+        } else {
+            let files: Vec<_> = files
+                .map(|fcb| {
+                    let shape = (&fcb.name, &fcb.file_type, fcb.first_cluster, fcb.length);
+                    (shape, fcb.times, fcb.perm)
+                })
+                .collect();
+            bincode::serialize(&(&dir.name, files))
+        }
+        .unwrap()
+    }
+
+    const PATHS: [&str; 4] = ["/d", "/d/e", "/d/f", "/g"];
+
+    /// `/d/e`两级目录，`/d/f`占两个簇，`/g`是改过权限的小文件。返回各项原来的信息、
+    /// `/d/f`的内容和改写成版本`version`格式的镜像
+    fn legacy_image(version: u32) -> (Vec<Stat>, Vec<u8>, Vec<u8>) {
+        let mut dm = new_disk();
+        let data = vec![6u8; dm.disk.cluster_size() + 1];
+        dm.new_directory_to_disk("/d").unwrap();
+        dm.new_directory_to_disk("/d/e").unwrap();
+        dm.create_file_with_data("/d/f", &data).unwrap();
+        dm.create_file_with_data("/g", b"g").unwrap();
+        dm.chmod("/g", "600").unwrap();
+        let stats = PATHS.iter().map(|path| dm.stat(path).unwrap()).collect();
+
+        // 目录按旧格式写回原来的簇链，旧格式更短，放得下
+        let dirs: Vec<Directory> = ["/", "/d", "/d/e"]
+            .iter()
+            .map(|path| dm.resolve_directory(path).unwrap())
+            .collect();
+        for dir in &dirs {
+            let clusters = dm.get_file_clusters(dir.files[1].first_cluster).unwrap();
+            dm.disk
+                .write_metadata_by_clusters(&encode_legacy(version, dir), &clusters);
+        }
+        let mut image = Vec::new();
+        dm.disk.flush().unwrap();
+        dm.disk.write_image(&mut image).unwrap();
+        image[8..12].copy_from_slice(&version.to_le_bytes());
+
+        (stats, data, image)
+    }
+
+    fn check_upgrade(version: u32) {
+        let (stats, data, image) = legacy_image(version);
+        let mut dm = DiskManager::from_image(image).unwrap();
+        assert_eq!(dm.disk.version, FORMAT_VERSION);
+        for (path, old) in PATHS.iter().zip(&stats) {
+            let new = dm.stat(path).unwrap();
+            assert_eq!(new.name, old.name);
+            assert_eq!(new.length, old.length);
+            assert_eq!(
+                (new.first_cluster, new.clusters),
+                (old.first_cluster, old.clusters)
+            );
+            let expected = if version < TIMES_VERSION {
+                Times::default()
+            } else {
+                old.times
+            };
+            assert_eq!(new.times, expected, "{} from version {}", path, version);
+            let expected = if version < PERM_VERSION {
+                Perm::legacy(&old.file_type)
+            } else {
+                old.perm
+            };
+            assert_eq!(new.perm, expected, "{} from version {}", path, version);
+            assert!(new.acl.is_empty());
+        }
+        assert_eq!(dm.read_file_by_name("/d/f").unwrap(), data);
+        let e = dm.resolve_directory("/d/e").unwrap();
+        assert_eq!(dm.directory_path(&e).unwrap(), "/d/e");
+        assert!(dm.fsck(Repair::None).unwrap().is_clean());
+
+        // 写回的镜像是当前版本，再加载时不再转换
+        let mut saved = Vec::new();
+        dm.write_image(&mut saved).unwrap();
+        let superblock = decode_superblock(&saved[..SUPERBLOCK_SIZE]).unwrap();
+        assert_eq!(superblock.version, FORMAT_VERSION);
+        let mut reloaded = DiskManager::from_image(saved).unwrap();
+        assert_eq!(reloaded.read_file_by_name("/g").unwrap(), b"g");
+    }
+
+    #[test]
+    fn untimed_directories_are_upgraded() {
+        check_upgrade(TIMES_VERSION - 1);
+    }
+
+    #[test]
+    fn unowned_directories_are_upgraded() {
+        check_upgrade(PERM_VERSION - 1);
+    }
+
+    #[test]
+    fn acl_less_directories_are_upgraded() {
+        check_upgrade(FORMAT_VERSION - 1);
+    }
+}
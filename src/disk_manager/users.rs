@@ -0,0 +1,637 @@
+//! 用户、用户组和权限。
+//!
+//! 用户表和组表存放在卷元数据中，只有root时不占用空间。每个FCB带有属主、属组和rwx权限位，
+//! 目录的“.”项与上一级目录中指向它的FCB保持相同的权限，所以读出的每个目录都知道自己的权限；
+//! “..”项的权限只在建立和移动目录时照抄一份，仅供显示。
+//! 权限按属主、属组、其他人的顺序检查，root不受限制。
+//!
+//! 登录不需要口令：镜像文件本身没有加密，拿到镜像的人总可以直接改写它，
+//! 所以权限只用来在共用镜像时防止误操作，不是安全边界。
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::acl::Acl;
+use super::error::FsError;
+use super::image::ROOT_CLUSTER;
+use super::path::split_last;
+use super::{Directory, DiskManager, Fcb, FileType};
+
+/// root的用户编号
+pub const ROOT_UID: u32 = 0;
+/// root用户组的编号
+pub const ROOT_GID: u32 = 0;
+/// 第一个普通用户和用户组的编号
+const FIRST_ID: u32 = 1000;
+
+/// 读权限
+pub const READ: u16 = 0o4;
+/// 写权限
+pub const WRITE: u16 = 0o2;
+/// 执行权限，对目录来说是进入和查找的权限
+pub const EXECUTE: u16 = 0o1;
+/// 新文件的权限
+pub const FILE_MODE: u16 = 0o644;
+/// 新目录的权限
+pub const DIR_MODE: u16 = 0o755;
+/// 符号链接的权限，不参与检查
+pub const SYMLINK_MODE: u16 = 0o777;
+
+/// 一个用户
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub uid: u32,
+    pub name: String,
+    /// 主组，新文件属于这个组
+    pub gid: u32,
+    /// 附加的用户组
+    pub groups: Vec<u32>,
+}
+
+/// 一个用户组
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub gid: u32,
+    pub name: String,
+}
+
+/// 用户表和组表
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Accounts {
+    pub users: Vec<User>,
+    pub groups: Vec<Group>,
+}
+impl Default for Accounts {
+    /// 只有root
+    fn default() -> Self {
+        Accounts {
+            users: vec![User {
+                uid: ROOT_UID,
+                name: String::from("root"),
+                gid: ROOT_GID,
+                groups: Vec::new(),
+            }],
+            groups: vec![Group {
+                gid: ROOT_GID,
+                name: String::from("root"),
+            }],
+        }
+    }
+}
+impl Accounts {
+    /// 是否只有root，这时不需要保存
+    pub fn is_default(&self) -> bool {
+        *self == Accounts::default()
+    }
+
+    pub fn user(&self, uid: u32) -> Option<&User> {
+        self.users.iter().find(|user| user.uid == uid)
+    }
+
+    pub fn user_by_name(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.name == name)
+    }
+
+    pub fn group(&self, gid: u32) -> Option<&Group> {
+        self.groups.iter().find(|group| group.gid == gid)
+    }
+
+    pub fn group_by_name(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|group| group.name == name)
+    }
+
+    /// 用户名，找不到时显示编号
+    pub fn user_name(&self, uid: u32) -> String {
+        self.user(uid)
+            .map(|user| user.name.clone())
+            .unwrap_or_else(|| uid.to_string())
+    }
+
+    /// 用户组名，找不到时显示编号
+    pub fn group_name(&self, gid: u32) -> String {
+        self.group(gid)
+            .map(|group| group.name.clone())
+            .unwrap_or_else(|| gid.to_string())
+    }
+
+    /// 用户`uid`是否属于用户组`gid`
+    pub fn in_group(&self, uid: u32, gid: u32) -> bool {
+        self.user(uid)
+            .is_some_and(|user| user.gid == gid || user.groups.contains(&gid))
+    }
+
+    /// 新建用户，返回用户编号。`group`是主组，为`None`时使用与用户同名的组，组不存在时一并新建。
+    pub fn add_user(&mut self, name: &str, group: Option<&str>) -> Result<u32, FsError> {
+        check_account_name(name)?;
+        if self.user_by_name(name).is_some() {
+            return Err(FsError::AlreadyExists(String::from(name)));
+        }
+        let group = group.unwrap_or(name);
+        let gid = match self.group_by_name(group) {
+            Some(group) => group.gid,
+            None => self.add_group(group)?,
+        };
+        let uid = next_id(self.users.iter().map(|user| user.uid));
+        self.users.push(User {
+            uid,
+            name: String::from(name),
+            gid,
+            groups: Vec::new(),
+        });
+
+        Ok(uid)
+    }
+
+    /// 新建用户组，返回组编号
+    pub fn add_group(&mut self, name: &str) -> Result<u32, FsError> {
+        check_account_name(name)?;
+        if self.group_by_name(name).is_some() {
+            return Err(FsError::AlreadyExists(String::from(name)));
+        }
+        let gid = next_id(self.groups.iter().map(|group| group.gid));
+        self.groups.push(Group {
+            gid,
+            name: String::from(name),
+        });
+
+        Ok(gid)
+    }
+}
+
+/// 普通用户和用户组从`FIRST_ID`开始编号
+fn next_id(ids: impl Iterator<Item = u32>) -> u32 {
+    ids.map(|id| id + 1).max().unwrap_or(0).max(FIRST_ID)
+}
+
+/// 用户名和组名只能由字母、数字、`_`和`-`组成，不能以`-`开头
+fn check_account_name(name: &str) -> Result<(), FsError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(FsError::InvalidArgument(format!(
+            "'{}' is not a valid user or group name",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+/// 文件或目录的属主、属组和权限位
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perm {
+    pub owner: u32,
+    pub group: u32,
+    /// 低9位是属主、属组、其他人各自的rwx
+    pub mode: u16,
+}
+impl Perm {
+    /// 旧版本镜像中的文件和目录属于root
+    pub fn legacy(file_type: &FileType) -> Perm {
+        Perm {
+            owner: ROOT_UID,
+            group: ROOT_GID,
+            mode: default_mode(file_type),
+        }
+    }
+}
+impl fmt::Display for Perm {
+    /// 显示为`rwxr-xr-x`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for shift in [6, 3, 0] {
+            let bits = self.mode >> shift;
+            for (bit, c) in [(READ, 'r'), (WRITE, 'w'), (EXECUTE, 'x')] {
+                let c = if bits & bit != 0 { c } else { '-' };
+                write!(f, "{}", c)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// 新文件或新目录的权限位
+fn default_mode(file_type: &FileType) -> u16 {
+    match file_type {
+        FileType::File => FILE_MODE,
+        FileType::Directory => DIR_MODE,
+        FileType::Symlink => SYMLINK_MODE,
+    }
+}
+
+/// 解析`chmod`的模式：八进制数如`750`，或者`u+x`、`go-w`、`a=r`这样的符号，多项用`,`分隔。
+pub fn parse_mode(spec: &str, mode: u16) -> Result<u16, FsError> {
+    let invalid = || FsError::InvalidArgument(format!("'{}' is not a valid mode", spec));
+    if spec.chars().all(|c| c.is_digit(8)) {
+        return match u16::from_str_radix(spec, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(mode),
+            _ => Err(invalid()),
+        };
+    }
+
+    let mut mode = mode;
+    for clause in spec.split(',') {
+        let op_index = clause.find(['+', '-', '=']).ok_or_else(invalid)?;
+        let (who, rest) = clause.split_at(op_index);
+        let mut shifts = Vec::new();
+        for c in who.chars() {
+            match c {
+                'u' => shifts.push(6),
+                'g' => shifts.push(3),
+                'o' => shifts.push(0),
+                'a' => shifts.extend([6, 3, 0]),
+                _ => return Err(invalid()),
+            }
+        }
+        if shifts.is_empty() {
+            shifts.extend([6, 3, 0]);
+        }
+        let mut bits = 0;
+        for c in rest[1..].chars() {
+            bits |= match c {
+                'r' => READ,
+                'w' => WRITE,
+                'x' => EXECUTE,
+                _ => return Err(invalid()),
+            };
+        }
+        for shift in shifts {
+            match &rest[..1] {
+                "+" => mode |= bits << shift,
+                "-" => mode &= !(bits << shift),
+                _ => mode = (mode & !(0o7 << shift)) | (bits << shift),
+            }
+        }
+    }
+
+    Ok(mode)
+}
+
+impl DiskManager {
+    /// 当前登录的用户编号
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// 当前登录的用户名
+    pub fn user_name(&self) -> String {
+        self.meta.accounts.user_name(self.uid)
+    }
+
+    /// 当前登录的用户名和所属的用户组
+    pub fn whoami(&self) -> String {
+        let accounts = &self.meta.accounts;
+        let groups = match accounts.user(self.uid) {
+            Some(user) => std::iter::once(user.gid)
+                .chain(user.groups.iter().copied())
+                .map(|gid| format!("{}({})", accounts.group_name(gid), gid))
+                .collect::<Vec<_>>()
+                .join(", "),
+            None => String::new(),
+        };
+
+        format!(
+            "{} (uid {}), groups: {}",
+            accounts.user_name(self.uid),
+            self.uid,
+            groups
+        )
+    }
+
+    /// 以用户`name`的身份继续操作，当前目录不变
+    pub fn login(&mut self, name: &str) -> Result<(), FsError> {
+        let user = self
+            .meta
+            .accounts
+            .user_by_name(name)
+            .ok_or_else(|| FsError::InvalidArgument(format!("no user named '{}'", name)))?;
+        self.uid = user.uid;
+
+        Ok(())
+    }
+
+    /// 回到root
+    pub fn logout(&mut self) {
+        self.uid = ROOT_UID;
+    }
+
+    /// 新建用户，只有root可以。`group`的含义见`Accounts::add_user`
+    pub fn useradd(&mut self, name: &str, group: Option<&str>) -> Result<u32, FsError> {
+        self.require_root("useradd")?;
+        self.transaction(|dm| dm.meta.accounts.add_user(name, group))
+    }
+
+    /// 当前用户对`fcb`是否有`access`权限。先按属主、属组、其他人取出权限位，再按ACL增减，见`acl`
+    pub fn allowed(&self, fcb: &Fcb, access: u16) -> bool {
+        if self.uid == ROOT_UID {
+            return true;
+        }
+        let perm = &fcb.perm;
+        let shift = if perm.owner == self.uid {
+            6
+        } else if self.meta.accounts.in_group(self.uid, perm.group) {
+            3
+        } else {
+            0
+        };
+
+        let bits = fcb
+            .acl
+            .apply((perm.mode >> shift) & 0o7, |who| self.is_principal(who));
+
+        bits & access == access
+    }
+
+    /// 没有权限时返回`FsError::PermissionDenied`
+    pub(crate) fn check_access(&self, fcb: &Fcb, access: u16, path: &str) -> Result<(), FsError> {
+        if self.allowed(fcb, access) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied(String::from(path)))
+        }
+    }
+
+    /// 检查当前用户对目录`dir`自己是否有`access`权限
+    pub(crate) fn check_dir_access(
+        &self,
+        dir: &Directory,
+        access: u16,
+        path: &str,
+    ) -> Result<(), FsError> {
+        self.check_access(&dir.files[1], access, path)
+    }
+
+    /// 只有root能做的操作
+    pub(crate) fn require_root(&self, operation: &str) -> Result<(), FsError> {
+        if self.uid == ROOT_UID {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied(String::from(operation)))
+        }
+    }
+
+    /// 当前用户新建的文件或目录的权限
+    pub(crate) fn new_perm(&self, file_type: &FileType) -> Perm {
+        let group = self
+            .meta
+            .accounts
+            .user(self.uid)
+            .map(|user| user.gid)
+            .unwrap_or(ROOT_GID);
+
+        Perm {
+            owner: self.uid,
+            group,
+            mode: default_mode(file_type),
+        }
+    }
+
+    /// 修改权限位，只有属主和root可以
+    pub fn chmod(&mut self, path: &str, mode: &str) -> Result<(), FsError> {
+        self.update_access(path, |dm, perm, _acl| {
+            if dm.uid != ROOT_UID && dm.uid != perm.owner {
+                return Err(FsError::PermissionDenied(String::from(path)));
+            }
+            perm.mode = parse_mode(mode, perm.mode)?;
+            Ok(())
+        })
+    }
+
+    /// 修改属主和属组。只有root可以修改属主；属主可以把属组改成自己所在的组。
+    pub fn chown(
+        &mut self,
+        path: &str,
+        owner: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<(), FsError> {
+        let accounts = &self.meta.accounts;
+        let uid = match owner {
+            Some(name) => Some(
+                accounts
+                    .user_by_name(name)
+                    .ok_or_else(|| FsError::InvalidArgument(format!("no user named '{}'", name)))?
+                    .uid,
+            ),
+            None => None,
+        };
+        let gid = match group {
+            Some(name) => Some(
+                accounts
+                    .group_by_name(name)
+                    .ok_or_else(|| FsError::InvalidArgument(format!("no group named '{}'", name)))?
+                    .gid,
+            ),
+            None => None,
+        };
+        self.update_access(path, |dm, perm, _acl| {
+            let allowed = dm.uid == ROOT_UID
+                || (uid.is_none()
+                    && dm.uid == perm.owner
+                    && gid.is_none_or(|gid| dm.meta.accounts.in_group(dm.uid, gid)));
+            if !allowed {
+                return Err(FsError::PermissionDenied(String::from(path)));
+            }
+            perm.owner = uid.unwrap_or(perm.owner);
+            perm.group = gid.unwrap_or(perm.group);
+            Ok(())
+        })
+    }
+
+    /// 修改`path`的权限位和ACL。目录的权限同时记在上一级目录的FCB和它自己的“.”中，两处一起修改。
+    /// 最后一项是符号链接时修改它指向的文件或目录。
+    pub(crate) fn update_access(
+        &mut self,
+        path: &str,
+        update: impl FnOnce(&DiskManager, &mut Perm, &mut Acl) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let path = &self.follow_final(path)?;
+        // 上一级目录和其中指向`path`的FCB的位置，根目录没有上一级
+        let (parent, dir) = match split_last(path).1 {
+            "" | "." | ".." => {
+                let dir = self.resolve_directory(path)?;
+                let cluster = dir.files[1].first_cluster;
+                if cluster == ROOT_CLUSTER {
+                    (None, Some(dir))
+                } else {
+                    let parent = self.get_directory_by_cluster(dir.files[0].first_cluster)?;
+                    let index = parent
+                        .files
+                        .iter()
+                        .skip(2)
+                        .position(|fcb| fcb.first_cluster == cluster)
+                        .ok_or_else(|| FsError::Corrupt {
+                            cluster,
+                            reason: String::from("directory is missing from its parent"),
+                        })?;
+                    (Some((parent, index + 2)), Some(dir))
+                }
+            }
+            _ => {
+                let (parent, name) = self.resolve_parent(path)?;
+                let index = parent
+                    .get_index_by_name(name)
+                    .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+                let dir = match parent.files[index].file_type {
+                    FileType::Directory => Some(self.get_directory_by_fcb(&parent.files[index])?),
+                    FileType::File | FileType::Symlink => None,
+                };
+                (Some((parent, index)), dir)
+            }
+        };
+
+        let fcb = match (&parent, &dir) {
+            (Some((parent, index)), _) => &parent.files[*index],
+            (None, Some(dir)) => &dir.files[1],
+            (None, None) => unreachable!(),
+        };
+        let (mut perm, mut acl) = (fcb.perm, fcb.acl.clone());
+        update(self, &mut perm, &mut acl)?;
+        self.transaction(|dm| {
+            if let Some((mut parent, index)) = parent {
+                parent.files[index].perm = perm;
+                parent.files[index].acl = acl.clone();
+                dm.store_directory(&parent)?;
+                dm.sync_links(&parent.files[index])?;
+            }
+            if let Some(mut dir) = dir {
+                dir.files[1].perm = perm;
+                dir.files[1].acl = acl;
+                dm.store_directory(&dir)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::disk_manager::handle::OpenOptions;
+    use crate::disk_manager::testing::{denied, shared_disk};
+
+    #[test]
+    fn others_can_read_but_not_write() {
+        let mut dm = shared_disk();
+        assert!(denied(dm.new_directory_to_disk("/mine")));
+        assert_eq!(dm.stat("/home/alice/f").unwrap().owner, "alice");
+
+        dm.login("bob").unwrap();
+        assert_eq!(dm.read_file_by_name("/home/alice/f").unwrap(), b"secret");
+        assert!(denied(
+            dm.open("/home/alice/f", OpenOptions::new().write(true))
+        ));
+        assert!(denied(dm.create_file_with_data("/home/alice/g", b"")));
+        assert!(denied(dm.delete_file_by_name("/home/alice/f")));
+        assert!(denied(dm.rename_file_by_name("/home/alice/f", "g")));
+        assert!(denied(dm.move_by_path("/home/alice/f", "/home")));
+        assert!(denied(dm.delete_recursive("/home/alice", true)));
+        assert!(denied(dm.chmod("/home/alice/f", "666")));
+
+        dm.logout();
+        let mut handle = dm
+            .open("/home/alice/f", OpenOptions::new().append(true))
+            .unwrap();
+        handle.write_all(b"!").unwrap();
+        handle.close().unwrap();
+    }
+
+    #[test]
+    fn mode_bits_guard_reading_and_entering() {
+        let mut dm = shared_disk();
+        dm.chmod("/home/alice/f", "600").unwrap();
+        dm.chmod("/home/alice", "go-rx").unwrap();
+        assert_eq!(dm.stat("/home/alice").unwrap().perm.mode, 0o700);
+        // 目录的“.”跟着一起改
+        assert_eq!(dm.stat("/home/alice/.").unwrap().perm.mode, 0o700);
+        dm.logout();
+        dm.chmod("/home", "777").unwrap();
+
+        dm.login("bob").unwrap();
+        assert!(denied(dm.read_file_by_name("/home/alice/f")));
+        // 不是普通文件时同样先检查读权限
+        assert!(denied(dm.read_file_by_name("/home/alice")));
+        assert!(denied(dm.set_current_directory("/home/alice")));
+        assert!(denied(dm.list_directory(Some("/home/alice"))));
+        assert!(denied(dm.copy_by_path(
+            "/home/alice",
+            "/home/copy",
+            true,
+            false
+        )));
+        dm.set_current_directory("/home").unwrap();
+        dm.new_directory_to_disk("bob").unwrap();
+        assert_eq!(dm.list_directory(None).unwrap().files.len(), 4);
+        assert_eq!(dm.stat("bob").unwrap().owner, "bob");
+
+        dm.login("alice").unwrap();
+        dm.set_current_directory("/home/alice").unwrap();
+        assert_eq!(dm.read_file_by_name("f").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn only_root_manages_accounts_and_owners() {
+        let mut dm = shared_disk();
+        assert!(denied(dm.useradd("dave", None)));
+        assert!(denied(dm.chown("/home/alice/f", Some("bob"), None)));
+        assert!(denied(dm.chown("/home/alice/f", None, Some("bob"))));
+        assert!(denied(dm.defragment()));
+
+        dm.logout();
+        assert_eq!(dm.useradd("dave", Some("bob")).unwrap(), 1003);
+        assert!(matches!(
+            dm.useradd("dave", None),
+            Err(FsError::AlreadyExists(_))
+        ));
+        dm.chown("/home/alice/f", None, Some("bob")).unwrap();
+        dm.chmod("/home/alice/f", "g+w").unwrap();
+        // dave的主组是bob，可以按组权限写，但不是属主，不能改权限
+        dm.login("dave").unwrap();
+        assert!(denied(dm.chmod("/home/alice/f", "o+w")));
+        dm.open("/home/alice/f", OpenOptions::new().write(true))
+            .unwrap()
+            .close()
+            .unwrap();
+    }
+
+    #[test]
+    fn accounts_and_owners_survive_reload() {
+        let mut dm = shared_disk();
+        let mut image = Vec::new();
+        dm.write_image(&mut image).unwrap();
+
+        let mut dm = DiskManager::from_image(image).unwrap();
+        assert_eq!(dm.uid(), ROOT_UID);
+        assert_eq!(dm.meta.accounts.users.len(), 4);
+        let stat = dm.stat("/home/alice/f").unwrap();
+        assert_eq!(
+            (stat.owner.as_str(), stat.group.as_str()),
+            ("alice", "alice")
+        );
+        dm.login("bob").unwrap();
+        assert!(denied(dm.delete_file_by_name("/home/alice/f")));
+    }
+
+    #[test]
+    fn modes_parse_and_display() {
+        assert_eq!(parse_mode("750", 0).unwrap(), 0o750);
+        assert_eq!(parse_mode("u+x,go-w", 0o666).unwrap(), 0o744);
+        assert_eq!(parse_mode("a=r", 0o777).unwrap(), 0o444);
+        assert_eq!(parse_mode("+x", 0o644).unwrap(), 0o755);
+        assert!(parse_mode("800", 0).is_err());
+        assert!(parse_mode("u+q", 0).is_err());
+        assert!(parse_mode("", 0).is_err());
+        let perm = Perm {
+            owner: ROOT_UID,
+            group: ROOT_GID,
+            mode: 0o751,
+        };
+        assert_eq!(perm.to_string(), "rwxr-x--x");
+    }
+}
@@ -1,73 +1,155 @@
 #![allow(dead_code)]
 
+mod cli;
 mod disk_manager;
-use std::fs;
+mod shell;
+use std::cell::RefCell;
+use std::fmt;
 use std::io::{stdin, stdout, Write};
-use std::str;
-use std::time::SystemTime;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
 
+use disk_manager::alloc::AllocatorKind;
+use disk_manager::clock::SystemClock;
 use disk_manager::disk::*;
+use disk_manager::save::DEFAULT_BACKUPS;
 use disk_manager::*;
 
-// 多线程不安全：程序逻辑-下一个空块必定是要分配的簇，但实际上会在写入之前预先检查下一个空簇，然后在写入时再次检查下一个空簇。单线程下两个结果必定相同，多线程下不一定。
-// TODO：预先分配首簇，然后根据首簇写数据。
+use cli::{Args, Batch, Start};
+use shell::{editor, Registry};
+
+use rustyline::error::ReadlineError;
 
 fn main() {
-    // 是否从磁盘中读取vd文件初始化
-    let mut virtual_disk = ui_load_dm_loop(SAVE_FILE_NAME);
-    ui_loop(&mut virtual_disk);
+    let args = match Args::parse(std::env::args().skip(1), SAVE_FILE_NAME) {
+        Ok(args) => args,
+        Err(err) => {
+            perror();
+            println!("{}", err);
+            println!("{}", cli::USAGE);
+            process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
+    let mut virtual_disk = match args.start {
+        // 是否从磁盘中读取vd文件初始化
+        Start::Ask => ui_load_dm_loop(&args.image),
+        Start::New => {
+            pinfo();
+            println!("Formatting new disk...");
+            let (geometry, allocator) = if args.batch.is_none() {
+                ui_read_new_disk()
+            } else {
+                (DiskGeometry::default(), AllocatorKind::default())
+            };
+            DiskManager::new(None, geometry, allocator, Box::new(SystemClock))
+        }
+        Start::Load => match DiskManager::load_file(&args.image, DEFAULT_BACKUPS) {
+            Ok((virtual_disk, loaded)) => {
+                if loaded != args.image {
+                    pinfo();
+                    println!("Loaded backup '{}'.", loaded.display());
+                }
+                virtual_disk
+            }
+            Err(err) => {
+                perror();
+                println!("Loading '{}' failed: {}", args.image.display(), err);
+                process::exit(1);
+            }
+        },
+    };
+    virtual_disk.noatime = args.noatime;
+    match &args.batch {
+        None => ui_loop(virtual_disk, &args.image),
+        Some(batch) => {
+            if let Err(err) = run_batch(&mut virtual_disk, &args.image, batch) {
+                perror();
+                println!("{}", err);
+                process::exit(1);
+            }
+            // 全部成功才保存，失败的一批命令不会改动镜像
+            if !args.no_save {
+                pinfo();
+                println!("Saving to '{}'...", args.image.display());
+                if let Err(err) = virtual_disk.save_to_file(&args.image, DEFAULT_BACKUPS) {
+                    perror();
+                    println!("Saving '{}' failed: {}", args.image.display(), err);
+                    process::exit(1);
+                }
+                pinfo();
+                println!("The virtual disk system has been saved.");
+            }
+        }
+    }
+}
+
+/// 非交互地依次执行命令，遇到第一个错误就停下，返回带着出错位置的错误。不保存镜像。
+fn run_batch(virtual_disk: &mut DiskManager, image: &Path, batch: &Batch) -> Result<(), String> {
+    let registry = Registry::new();
+    let commands = batch.commands().map_err(|err| err.to_string())?;
+    for (location, command_line) in commands {
+        pinfo();
+        println!("Running '{}'...", command_line);
+        let exit = registry
+            .run_line(virtual_disk, image, &command_line)
+            .map_err(|err| format!("{}: '{}' failed: {}", location, command_line, err))?;
+        if exit {
+            break;
+        }
+    }
+
+    Ok(())
 }
 
 /// 默认保存的文件名
 const SAVE_FILE_NAME: &str = "./file-sys.vd";
-/// 系统UI默认提示
-const UI_HELP: &str = "\
-\n==================================================\
-\n           IvanD's Basic File System\
-\n==================================================\
-\nHelp:\
-\n\tcd <dirname>: Change current dir.\
-\n\tmkdir <dir name>: Create a new dir.\
-\n\tls : List all files and dir in current dir.\
-\n\tcat <filename>: Show the file content.\
-\n\trm <filename>: Delete a file on disk.\
-\n\tdiskinfo : Show some info about disk.\
-\n\tsave : Save this virtual disk to file 'file-sys.vd'\
-\n\texit : Exit the system. 
-\n\
-\nTesting:\
-\n\ttest create: Create a random file to test.\
-\n\
-\nSystem Inner Function:\
-\n\tfn create_file_with_data(&mut self, name: &str, data: &[u8])\
-\n\tfn rename_file(&mut self, old: &str, new: &str)\
-\n\tfn delete_file_by_name(&mut self, name: &str)\
-\n\tfn read_file_by_name(&self, name: &str) -> Vec<u8>\
-\n"; // UI主菜单
-
 /// 使用交互式让用户选择是否从硬盘中加载DiskManager进行使用
-fn ui_load_dm_loop(filename: &str) -> DiskManager {
+fn ui_load_dm_loop(filename: &Path) -> DiskManager {
     let mut buf_str = String::new();
     loop {
+        buf_str.clear();
         pinfo();
-        print!("Do you want to try to load file-sys.vd? [Y/N] ");
+        print!("Do you want to try to load {}? [Y/N] ", filename.display());
         stdout().flush().unwrap();
-        stdin().read_line(&mut buf_str).unwrap();
-        let first_char = buf_str.as_str().trim().chars().next().unwrap();
+        if stdin().read_line(&mut buf_str).unwrap_or(0) == 0 {
+            // 输入已经结束，直接退出
+            println!();
+            std::process::exit(0);
+        }
+        let first_char = buf_str.trim().chars().next();
 
         match first_char {
-            'N' | 'n' => {
+            Some('N') | Some('n') => {
                 pinfo();
                 println!("Will not load vd file from disk.\n");
+                let (geometry, allocator) = ui_read_new_disk();
 
-                break DiskManager::new(None);
+                break DiskManager::new(None, geometry, allocator, Box::new(SystemClock));
             }
-            'Y' | 'y' => {
+            Some('Y') | Some('y') => {
                 pinfo();
                 println!("Trying to load vd file from disk...\n");
-                let data = fs::read(filename).unwrap();
-
-                break bincode::deserialize(data.as_slice()).unwrap();
+                match DiskManager::load_file(filename, DEFAULT_BACKUPS) {
+                    Ok((virtual_disk, loaded)) => {
+                        if loaded != filename {
+                            pinfo();
+                            println!("Loaded backup '{}'.", loaded.display());
+                        }
+                        break virtual_disk;
+                    }
+                    Err(err) => {
+                        perror();
+                        println!("Loading '{}' failed: {}", filename.display(), err);
+                        continue;
+                    }
+                }
             }
             _ => {
                 println!("\nIncorrect input.");
@@ -77,86 +159,109 @@ fn ui_load_dm_loop(filename: &str) -> DiskManager {
     }
 }
 
-/// 一个简单的交互式界面。
-fn ui_loop(virtual_disk: &mut DiskManager) {
-    // 交互界面
-    println!("{}", UI_HELP);
-
+/// 读取一行输入并解析，直接回车则使用默认值。
+fn ui_read_value<T: FromStr + fmt::Display>(prompt: &str, default: T) -> T {
     let mut buf_str = String::new();
-
     loop {
-        // 清空buffer
         buf_str.clear();
-        print!("> ");
+        print!("{} [{}]: ", prompt, default);
         stdout().flush().unwrap();
         stdin().read_line(&mut buf_str).unwrap();
-        // 去除首尾空格
-        let command_line = String::from(buf_str.trim());
-
-        // 分支-test
-        if let Some(cl) = command_line.strip_prefix("test ") {
-            // 分支-create
-            if let Some(cl) = cl.strip_prefix("create") {
-                let data = format!("File has been created at {:?} .", SystemTime::now());
-                let cl_trim = cl.trim();
-                let name = if cl_trim.is_empty() {
-                    // 没有输入名字
-                    format!("test-{}", (rand::random::<f32>() * 100_f32) as usize)
-                } else {
-                    // 输入了名字
-                    cl_trim.to_string()
-                };
-                virtual_disk.create_file_with_data(name.as_str(), data.as_bytes());
+        let input = buf_str.trim();
+        if input.is_empty() {
+            break default;
+        }
+        match input.parse() {
+            Ok(value) => break value,
+            Err(_) => println!("\nIncorrect input."),
+        }
+    }
+}
+
+/// 使用交互式让用户选择新磁盘的几何参数和分配策略
+fn ui_read_new_disk() -> (DiskGeometry, AllocatorKind) {
+    let geometry = ui_read_geometry();
+    let allocator = ui_read_value(
+        "Allocator (first-fit, next-fit, best-fit)",
+        AllocatorKind::default(),
+    );
+
+    (geometry, allocator)
+}
+
+/// 使用交互式让用户选择新磁盘的几何参数
+fn ui_read_geometry() -> DiskGeometry {
+    loop {
+        pinfo();
+        println!("Formatting new virtual disk.");
+        let cluster_size = ui_read_value("Cluster size in Bytes", DEFAULT_CLUSTER_SIZE);
+        let cluster_count = ui_read_value("Cluster count", DEFAULT_CLUSTER_COUNT);
+        match DiskGeometry::new(cluster_size, cluster_count) {
+            Ok(geometry) => break geometry,
+            Err(err) => {
+                perror();
+                println!("{}", err);
             }
-        } else if command_line.starts_with("help") {
-            // 显示菜单
-            println!("{}", UI_HELP);
-        } else if command_line.starts_with("exit") {
-            // 跳出循环，结束程序
-            pinfo();
-            println!("Exiting system...\n");
-            break;
-        } else if command_line.starts_with("save") {
-            // 保存系统
-            pinfo();
-            println!("Saving...");
-            let data = bincode::serialize(&virtual_disk).unwrap();
-            fs::write(SAVE_FILE_NAME, data.as_slice()).unwrap();
-            pinfo();
-            println!("The virtual disk system has been saved.\n");
-        } else if command_line.starts_with("ls") {
-            // 列出目录文件
-            println!("{}", virtual_disk.cur_dir);
-        } else if let Some(name) = command_line.strip_prefix("cd ") {
-            // 切换到当前目录的某个文件夹
-            pinfo();
-            println!("Set Location to: {} ...", name);
-            virtual_disk.set_current_directory(name);
-        } else if let Some(command_line) = command_line.strip_prefix("cat ") {
-            // 显示文件内容
-            let name = command_line.trim();
-            let data = virtual_disk.read_file_by_name(name);
-            println!("{}", str::from_utf8(data.as_slice()).unwrap());
-        } else if let Some(command_line) = command_line.strip_prefix("mkdir ") {
-            // 创建新文件夹
-            let name = command_line.trim();
-            virtual_disk.new_directory_to_disk(name).unwrap();
-        } else if command_line.starts_with("diskinfo") {
-            // 返回磁盘信息
-            let (disk_size, num_used, num_not_used) = virtual_disk.get_disk_info();
-            println!(
-                "Disk sized {} Bytes, {} Bytes used, {} Bytes available.",
-                disk_size,
-                num_used * BLOCK_SIZE,
-                num_not_used * BLOCK_SIZE
-            );
-        } else if let Some(command_line) = command_line.strip_prefix("rm ") {
-            let name = command_line.trim();
-            virtual_disk
-                .delete_file_by_name(name)
-                .expect("[ERROR]\tDELETE FILE FAILED!");
-        } else {
-            println!("Unknown Command.");
         }
     }
 }
+
+/// 一个简单的交互式界面。提示符显示当前路径，支持行编辑、历史记录和Tab补全。
+fn ui_loop(virtual_disk: DiskManager, image: &Path) {
+    let registry = Registry::new();
+    // 交互界面
+    println!("{}", registry.help());
+
+    // 补全时也要读取磁盘
+    let virtual_disk = Rc::new(RefCell::new(virtual_disk));
+    let mut editor = match editor::new_editor(Rc::clone(&virtual_disk), &registry) {
+        Ok(editor) => editor,
+        Err(err) => {
+            perror();
+            println!("Starting line editor failed: {}", err);
+            return;
+        }
+    };
+    let history = editor::history_path(image);
+    // 第一次运行时还没有历史记录
+    let _ = editor.load_history(&history);
+
+    loop {
+        let prompt = {
+            let virtual_disk = virtual_disk.borrow();
+            let path = virtual_disk
+                .current_path()
+                .unwrap_or_else(|_| String::from("?"));
+            format!("{}:{}> ", virtual_disk.user_name(), path)
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C放弃正在输入的一行
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => {
+                // 输入已经结束
+                println!();
+                break;
+            }
+            Err(err) => {
+                perror();
+                println!("Reading input failed: {}", err);
+                break;
+            }
+        };
+        // 命令失败时只打印错误，继续交互
+        match registry.run_line(&mut virtual_disk.borrow_mut(), image, &line) {
+            Ok(true) => break,
+            Ok(false) => (),
+            Err(err) => {
+                perror();
+                println!("{}", err);
+            }
+        }
+    }
+
+    if let Err(err) = editor.save_history(&history) {
+        perror();
+        println!("Saving history to '{}' failed: {}", history.display(), err);
+    }
+}
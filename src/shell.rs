@@ -0,0 +1,342 @@
+//! 交互界面和脚本共用的命令解释器。
+//!
+//! 每个命令实现`Command`特征，声明自己的名字、参数写法、说明和接受的参数，注册在`Registry`中。
+//! 一行命令先由`parse::tokenize`拆成参数，再按命令声明的`ArgSpec`分出选项和位置参数，
+//! 最后交给命令执行。帮助信息也由`Registry`按注册的命令生成。
+
+pub mod commands;
+pub mod editor;
+pub mod parse;
+
+use std::path::Path;
+
+use crate::disk_manager::error::FsError;
+use crate::disk_manager::DiskManager;
+
+/// 命令接受的参数：选项和位置参数的数量
+#[derive(Debug, Clone, Copy)]
+pub struct ArgSpec {
+    /// 接受的选项，例如`-r`、`--cow`。单字母选项可以合写，例如`-rf`
+    pub flags: &'static [&'static str],
+    /// 位置参数最少的数量
+    pub min: usize,
+    /// 位置参数最多的数量
+    pub max: usize,
+}
+impl ArgSpec {
+    /// 没有任何参数
+    pub const NONE: ArgSpec = ArgSpec::between(0, 0);
+
+    /// 恰好`count`个位置参数
+    pub const fn exactly(count: usize) -> ArgSpec {
+        ArgSpec::between(count, count)
+    }
+
+    /// `min`到`max`个位置参数
+    pub const fn between(min: usize, max: usize) -> ArgSpec {
+        ArgSpec {
+            flags: &[],
+            min,
+            max,
+        }
+    }
+
+    /// 同时接受这些选项
+    pub const fn with_flags(self, flags: &'static [&'static str]) -> ArgSpec {
+        ArgSpec { flags, ..self }
+    }
+
+    /// 按声明分出选项和位置参数。`--`之后的参数都是位置参数，即使以`-`开头。
+    fn parse(&self, tokens: &[String]) -> Result<Args, String> {
+        let mut args = Args::default();
+        let mut only_positional = false;
+        for token in tokens {
+            if only_positional || !token.starts_with('-') || token == "-" {
+                args.positional.push(token.clone());
+            } else if token == "--" {
+                only_positional = true;
+            } else if let Some(flag) = self.flags.iter().find(|flag| **flag == token) {
+                args.flags.push(flag);
+            } else if !token.starts_with("--") {
+                // 合写的单字母选项
+                for c in token.chars().skip(1) {
+                    let flag = self
+                        .flags
+                        .iter()
+                        .find(|flag| flag.len() == 2 && flag.ends_with(c))
+                        .ok_or_else(|| format!("unknown option '-{}'", c))?;
+                    args.flags.push(flag);
+                }
+            } else {
+                return Err(format!("unknown option '{}'", token));
+            }
+        }
+        if args.positional.len() < self.min {
+            return Err(String::from("missing arguments"));
+        }
+        if args.positional.len() > self.max {
+            return Err(String::from("too many arguments"));
+        }
+
+        Ok(args)
+    }
+}
+
+/// 分好的参数
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    /// 位置参数，数量已经按`ArgSpec`检查过
+    pub positional: Vec<String>,
+    flags: Vec<&'static str>,
+}
+impl Args {
+    /// 是否给出了选项`flag`
+    pub fn flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// 第`index`个位置参数
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.positional.get(index).map(String::as_str)
+    }
+}
+
+/// 执行命令时能用到的东西
+pub struct Context<'a> {
+    pub virtual_disk: &'a mut DiskManager,
+    /// `save`写入的镜像文件
+    pub image: &'a Path,
+    pub registry: &'a Registry,
+    /// 执行了`exit`
+    pub exit: bool,
+}
+
+/// 一个命令
+pub trait Command {
+    /// 命令名，一行命令的第一个参数
+    fn name(&self) -> &'static str;
+    /// 参数的写法，用于帮助信息和参数出错时的提示
+    fn usage(&self) -> &'static str {
+        ""
+    }
+    /// 一句话说明
+    fn help(&self) -> &'static str;
+    /// 接受的参数
+    fn args(&self) -> ArgSpec {
+        ArgSpec::NONE
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError>;
+}
+
+/// 帮助信息的开头
+const HELP_HEADER: &str = "\
+\n==================================================\
+\n           IvanD's Basic File System\
+\n==================================================\
+\nHelp:\
+\n\tPaths starting with '/' are absolute, others are relative to current dir. '.' and '..' are supported.\
+\n\tQuote arguments with spaces in '...' or \"...\", or escape single characters with '\\'.\
+\n\tArguments after '--' are never options, e.g. 'rm -- -name'.";
+
+/// 帮助信息的结尾
+const HELP_FOOTER: &str = "\
+\nSystem Inner Function:\
+\n\tfn create_file_with_data(&mut self, path: &str, data: &[u8])\
+\n\tfn rename_file_by_name(&mut self, path: &str, new: &str)\
+\n\tfn delete_file_by_name(&mut self, path: &str)\
+\n\tfn read_file_by_name(&mut self, path: &str) -> Vec<u8>\
+\n";
+
+/// 所有命令
+pub struct Registry {
+    commands: Vec<Box<dyn Command>>,
+}
+impl Registry {
+    /// 注册了所有内置命令的解释器
+    pub fn new() -> Registry {
+        Registry {
+            commands: commands::all(),
+        }
+    }
+
+    /// 按名字找到命令
+    pub fn find(&self, name: &str) -> Option<&dyn Command> {
+        self.commands
+            .iter()
+            .find(|command| command.name() == name)
+            .map(|command| command.as_ref())
+    }
+
+    /// 按注册顺序排列的所有命令
+    pub fn commands(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.iter().map(|command| command.as_ref())
+    }
+
+    /// 按注册的命令生成帮助信息
+    pub fn help(&self) -> String {
+        let mut help = String::from(HELP_HEADER);
+        for command in self.commands() {
+            // 没有参数的命令在冒号前留一个空格
+            let space = if command.usage().is_empty() { " " } else { "" };
+            help.push_str(&format!(
+                "\n\t{}{}: {}",
+                usage_line(command),
+                space,
+                command.help()
+            ));
+        }
+        help.push('\n');
+        help.push_str(HELP_FOOTER);
+
+        help
+    }
+
+    /// 执行一行命令，返回是否执行了`exit`
+    pub fn run_line(
+        &self,
+        virtual_disk: &mut DiskManager,
+        image: &Path,
+        line: &str,
+    ) -> Result<bool, FsError> {
+        let tokens = parse::tokenize(line)?;
+        let (name, rest) = match tokens.split_first() {
+            Some(split) => split,
+            None => return Ok(false),
+        };
+        let command = self.find(name).ok_or_else(|| {
+            FsError::InvalidArgument(format!("unknown command '{}', try 'help'", name))
+        })?;
+        let args = command.args().parse(rest).map_err(|reason| {
+            FsError::InvalidArgument(format!("{}, usage: {}", reason, usage_line(command)))
+        })?;
+        let mut ctx = Context {
+            virtual_disk,
+            image,
+            registry: self,
+            exit: false,
+        };
+        command.run(&mut ctx, &args)?;
+
+        Ok(ctx.exit)
+    }
+}
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+/// 命令名和参数写法
+fn usage_line(command: &dyn Command) -> String {
+    if command.usage().is_empty() {
+        String::from(command.name())
+    } else {
+        format!("{} {}", command.name(), command.usage())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::disk_manager::testing::new_disk;
+
+    const SPEC: ArgSpec = ArgSpec::between(1, 2).with_flags(&["-r", "-f", "--cow"]);
+
+    fn parse(spec: &ArgSpec, tokens: &[&str]) -> Result<Args, String> {
+        let tokens: Vec<String> = tokens.iter().map(|token| token.to_string()).collect();
+        spec.parse(&tokens)
+    }
+
+    #[test]
+    fn flags_and_positional_args() {
+        let args = parse(&SPEC, &["-r", "a", "--cow", "b"]).unwrap();
+        assert_eq!(args.positional, ["a", "b"]);
+        assert!(args.flag("-r") && args.flag("--cow") && !args.flag("-f"));
+        // 合写的单字母选项
+        let args = parse(&SPEC, &["-rf", "a"]).unwrap();
+        assert!(args.flag("-r") && args.flag("-f"));
+        // `-`和`--`之后的参数都是位置参数，空参数也是
+        let args = parse(&SPEC, &["-", "--", "-r"]).unwrap();
+        assert_eq!(args.positional, ["-", "-r"]);
+        assert!(!args.flag("-r"));
+        let args = parse(&SPEC, &[""]).unwrap();
+        assert_eq!(args.get(0), Some(""));
+        assert_eq!(args.get(1), None);
+    }
+
+    #[test]
+    fn unknown_flags_and_wrong_counts_are_rejected() {
+        assert_eq!(
+            parse(&SPEC, &["-x", "a"]).unwrap_err(),
+            "unknown option '-x'"
+        );
+        assert_eq!(
+            parse(&SPEC, &["-rx", "a"]).unwrap_err(),
+            "unknown option '-x'"
+        );
+        assert_eq!(
+            parse(&SPEC, &["--all", "a"]).unwrap_err(),
+            "unknown option '--all'"
+        );
+        // 长选项不能拆成单字母
+        assert!(parse(&SPEC, &["--rf", "a"]).is_err());
+        assert_eq!(parse(&SPEC, &["-r"]).unwrap_err(), "missing arguments");
+        assert_eq!(
+            parse(&SPEC, &["a", "b", "c"]).unwrap_err(),
+            "too many arguments"
+        );
+        assert!(parse(&ArgSpec::NONE, &[]).is_ok());
+        assert!(parse(&ArgSpec::NONE, &["-r"]).is_err());
+    }
+
+    #[test]
+    fn rm_r_needs_f_to_delete() {
+        let mut dm = new_disk();
+        let registry = Registry::new();
+        let image = Path::new("unused.vd");
+        for line in ["mkdir t", "mkdir t/s"] {
+            registry.run_line(&mut dm, image, line).unwrap();
+        }
+        dm.create_file_with_data("/t/s/f", b"data").unwrap();
+        let free = dm.get_disk_info().2;
+
+        registry.run_line(&mut dm, image, "rm -r t").unwrap();
+        assert_eq!(dm.get_disk_info().2, free);
+        assert_eq!(dm.read_file_by_name("/t/s/f").unwrap(), b"data");
+        assert!(registry.run_line(&mut dm, image, "rm -f t").is_err());
+        assert!(dm.stat("/t").is_ok());
+
+        registry.run_line(&mut dm, image, "rm -rf t").unwrap();
+        assert!(dm.stat("/t").is_err());
+        // 两个目录和一个文件
+        assert_eq!(dm.get_disk_info().2, free + 3);
+    }
+
+    #[test]
+    fn ln_s_into_dir_needs_a_name() {
+        let mut dm = new_disk();
+        let registry = Registry::new();
+        let image = Path::new("unused.vd");
+        for line in ["mkdir d", "mkdir x", "ln -s ../x/ d/", "ln -s /x d/y"] {
+            registry.run_line(&mut dm, image, line).unwrap();
+        }
+        assert_eq!(dm.read_link("/d/x").unwrap(), "../x/");
+        assert_eq!(dm.read_link("/d/y").unwrap(), "/x");
+        for line in ["ln -s / d", "ln -s .. d", "ln -s x/. d/", "ln -s '' d"] {
+            assert!(
+                matches!(
+                    registry.run_line(&mut dm, image, line),
+                    Err(FsError::InvalidArgument(_))
+                ),
+                "{}",
+                line
+            );
+        }
+        // 只有“..”、“.”、x和y
+        assert_eq!(dm.peek_entries("/d").unwrap().len(), 4);
+        // 给出完整的链接路径就可以
+        registry.run_line(&mut dm, image, "ln -s .. d/up").unwrap();
+        assert_eq!(dm.read_link("/d/up").unwrap(), "..");
+    }
+}
@@ -0,0 +1,854 @@
+//! 内置命令。注册顺序就是帮助信息中的顺序。
+
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::{ArgSpec, Args, Command, Context};
+use crate::disk_manager::error::FsError;
+use crate::disk_manager::fsck::Repair;
+use crate::disk_manager::path::split_last;
+use crate::disk_manager::quota::Limits;
+use crate::disk_manager::save::DEFAULT_BACKUPS;
+use crate::disk_manager::{check_file_name, pinfo, DiskManager};
+
+/// 所有内置命令
+pub fn all() -> Vec<Box<dyn Command>> {
+    vec![
+        Box::new(Cd),
+        Box::new(Pwd),
+        Box::new(Mkdir),
+        Box::new(Ls),
+        Box::new(Cat),
+        Box::new(Stat),
+        Box::new(Chmod),
+        Box::new(Chown),
+        Box::new(Getfacl),
+        Box::new(Setfacl),
+        Box::new(Rm),
+        Box::new(Rename),
+        Box::new(Mv),
+        Box::new(Cp),
+        Box::new(Ln),
+        Box::new(Readlink),
+        Box::new(Put),
+        Box::new(Get),
+        Box::new(Login),
+        Box::new(Logout),
+        Box::new(Whoami),
+        Box::new(Useradd),
+        Box::new(Quota),
+        Box::new(Setquota),
+        Box::new(DiskInfo),
+        Box::new(Fsck),
+        Box::new(Map),
+        Box::new(Frag),
+        Box::new(Defrag),
+        Box::new(Save),
+        Box::new(Help),
+        Box::new(Exit),
+        Box::new(Test),
+    ]
+}
+
+struct Cd;
+impl Command for Cd {
+    fn name(&self) -> &'static str {
+        "cd"
+    }
+    fn usage(&self) -> &'static str {
+        "<dir path>"
+    }
+    fn help(&self) -> &'static str {
+        "Change current dir."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::exactly(1)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let path = &args.positional[0];
+        pinfo();
+        println!("Set Location to: {} ...", path);
+        ctx.virtual_disk.set_current_directory(path)
+    }
+}
+
+struct Pwd;
+impl Command for Pwd {
+    fn name(&self) -> &'static str {
+        "pwd"
+    }
+    fn help(&self) -> &'static str {
+        "Show the full path of current dir."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        println!("{}", ctx.virtual_disk.current_path()?);
+        Ok(())
+    }
+}
+
+struct Mkdir;
+impl Command for Mkdir {
+    fn name(&self) -> &'static str {
+        "mkdir"
+    }
+    fn usage(&self) -> &'static str {
+        "<dir path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Create new dirs."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        for path in &args.positional {
+            ctx.virtual_disk.new_directory_to_disk(path)?;
+        }
+        Ok(())
+    }
+}
+
+struct Ls;
+impl Command for Ls {
+    fn name(&self) -> &'static str {
+        "ls"
+    }
+    fn usage(&self) -> &'static str {
+        "[-l] [dir path]"
+    }
+    fn help(&self) -> &'static str {
+        "List all files and dir in current dir or the given dir, with mode, owner and modified time if -l."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(0, 1).with_flags(&["-l"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let dir = ctx.virtual_disk.list_directory(args.get(0))?;
+        println!("{}", ctx.virtual_disk.listing(&dir, args.flag("-l")));
+        Ok(())
+    }
+}
+
+struct Cat;
+impl Command for Cat {
+    fn name(&self) -> &'static str {
+        "cat"
+    }
+    fn usage(&self) -> &'static str {
+        "<file path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Show the file contents."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        for path in &args.positional {
+            // 非UTF-8的字节用替换字符显示
+            let data = ctx.virtual_disk.read_file_by_name(path)?;
+            println!("{}", String::from_utf8_lossy(data.as_slice()));
+        }
+        Ok(())
+    }
+}
+
+struct Stat;
+impl Command for Stat {
+    fn name(&self) -> &'static str {
+        "stat"
+    }
+    fn usage(&self) -> &'static str {
+        "<path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Show size, clusters, mode, owner and created, modified and accessed times (UTC)."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        for path in &args.positional {
+            println!("{}", ctx.virtual_disk.stat(path)?);
+        }
+        Ok(())
+    }
+}
+
+struct Chmod;
+impl Command for Chmod {
+    fn name(&self) -> &'static str {
+        "chmod"
+    }
+    fn usage(&self) -> &'static str {
+        "<mode> <path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Change the rwx mode, octal like 750 or symbolic like u+x,go-w. Only the owner or root can."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(2, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let (mode, paths) = args.positional.split_first().unwrap();
+        for path in paths {
+            ctx.virtual_disk.chmod(path, mode)?;
+        }
+        Ok(())
+    }
+}
+
+struct Chown;
+impl Command for Chown {
+    fn name(&self) -> &'static str {
+        "chown"
+    }
+    fn usage(&self) -> &'static str {
+        "<user>[:<group>] <path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Change the owner (root only) and group. The owner can change the group to one of their own with ':<group>'."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(2, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let (spec, paths) = args.positional.split_first().unwrap();
+        let (owner, group) = match spec.split_once(':') {
+            Some((owner, group)) => (owner, Some(group)),
+            None => (spec.as_str(), None),
+        };
+        let owner = Some(owner).filter(|owner| !owner.is_empty());
+        let group = group.filter(|group| !group.is_empty());
+        if owner.is_none() && group.is_none() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' names no user or group",
+                spec
+            )));
+        }
+        for path in paths {
+            ctx.virtual_disk.chown(path, owner, group)?;
+        }
+        Ok(())
+    }
+}
+
+struct Getfacl;
+impl Command for Getfacl {
+    fn name(&self) -> &'static str {
+        "getfacl"
+    }
+    fn usage(&self) -> &'static str {
+        "<path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Show the owner, group, mode bits and ACL entries."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        for path in &args.positional {
+            println!("{}\n", ctx.virtual_disk.getfacl(path)?);
+        }
+        Ok(())
+    }
+}
+
+struct Setfacl;
+impl Command for Setfacl {
+    fn name(&self) -> &'static str {
+        "setfacl"
+    }
+    fn usage(&self) -> &'static str {
+        "[-x] <entry>[,<entry>...] <path>... | -b <path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Add or replace ACL entries like allow:user:bob:rw- or default:deny:group:dev:w, remove them with -x, or all with -b. Only the owner or root can."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, usize::MAX).with_flags(&["-x", "-b"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        if args.flag("-b") {
+            if args.flag("-x") {
+                return Err(FsError::InvalidArgument(String::from(
+                    "'-b' and '-x' cannot be used together",
+                )));
+            }
+            for path in &args.positional {
+                ctx.virtual_disk.setfacl(path, |acl| acl.0.clear())?;
+            }
+            return Ok(());
+        }
+        let (spec, paths) = args.positional.split_first().unwrap();
+        if paths.is_empty() {
+            return Err(FsError::InvalidArgument(String::from("missing path")));
+        }
+        let remove = args.flag("-x");
+        let entries = ctx.virtual_disk.parse_acl(spec, !remove)?;
+        for path in paths {
+            ctx.virtual_disk.setfacl(path, |acl| {
+                for entry in &entries {
+                    if remove {
+                        acl.remove(entry);
+                    } else {
+                        acl.set(*entry);
+                    }
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+struct Rm;
+impl Command for Rm {
+    fn name(&self) -> &'static str {
+        "rm"
+    }
+    fn usage(&self) -> &'static str {
+        "[-r [-f]] <path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Delete files or empty dirs. With -r show what deleting a dir tree would reclaim, delete it with -f."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, usize::MAX).with_flags(&["-r", "-f"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let force = args.flag("-f");
+        if !args.flag("-r") {
+            if force {
+                return Err(FsError::InvalidArgument(String::from("'-f' needs '-r'")));
+            }
+            for path in &args.positional {
+                ctx.virtual_disk.delete_file_by_name(path)?;
+            }
+            return Ok(());
+        }
+        // 递归删除默认只空跑，加上-f才真正删除
+        for path in &args.positional {
+            let report = ctx.virtual_disk.delete_recursive(path, !force)?;
+            pinfo();
+            println!("{}", report);
+            if !force {
+                pinfo();
+                println!("Nothing deleted yet, use 'rm -r -f {}' to delete.", path);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Rename;
+impl Command for Rename {
+    fn name(&self) -> &'static str {
+        "rename"
+    }
+    fn usage(&self) -> &'static str {
+        "<path> <new name>"
+    }
+    fn help(&self) -> &'static str {
+        "Rename a file or dir."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::exactly(2)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        ctx.virtual_disk
+            .rename_file_by_name(&args.positional[0], &args.positional[1])
+    }
+}
+
+struct Mv;
+impl Command for Mv {
+    fn name(&self) -> &'static str {
+        "mv"
+    }
+    fn usage(&self) -> &'static str {
+        "<src path>... <dst path>"
+    }
+    fn help(&self) -> &'static str {
+        "Move files or dirs, into dst if it is a dir."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(2, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let (dst, srcs) = args.positional.split_last().unwrap();
+        check_many_into_dir(ctx.virtual_disk, srcs, dst)?;
+        for src in srcs {
+            ctx.virtual_disk.move_by_path(src, dst)?;
+        }
+        Ok(())
+    }
+}
+
+struct Cp;
+impl Command for Cp {
+    fn name(&self) -> &'static str {
+        "cp"
+    }
+    fn usage(&self) -> &'static str {
+        "[-r] [--cow] <src path>... <dst path>"
+    }
+    fn help(&self) -> &'static str {
+        "Copy files (or dir trees with -r), sharing clusters copy-on-write with --cow."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(2, usize::MAX).with_flags(&["-r", "--cow"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let (dst, srcs) = args.positional.split_last().unwrap();
+        check_many_into_dir(ctx.virtual_disk, srcs, dst)?;
+        for src in srcs {
+            ctx.virtual_disk
+                .copy_by_path(src, dst, args.flag("-r"), args.flag("--cow"))?;
+        }
+        Ok(())
+    }
+}
+
+struct Ln;
+impl Command for Ln {
+    fn name(&self) -> &'static str {
+        "ln"
+    }
+    fn usage(&self) -> &'static str {
+        "[-s] <file path>... <dst path>"
+    }
+    fn help(&self) -> &'static str {
+        "Create hard links to files, or symlinks to any target path with -s."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(2, usize::MAX).with_flags(&["-s"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let (dst, srcs) = args.positional.split_last().unwrap();
+        check_many_into_dir(ctx.virtual_disk, srcs, dst)?;
+        for src in srcs {
+            if !args.flag("-s") {
+                ctx.virtual_disk.link_by_path(src, dst)?;
+                continue;
+            }
+            // 目标不必存在。放进已经存在的目录时，用目标的最后一项作为链接的名字，
+            // 目标是“/”、“.”或“..”时取不出名字
+            let path = match ctx.virtual_disk.resolve_directory(dst) {
+                Ok(_) => {
+                    let name = split_last(src).1;
+                    check_file_name(name).map_err(|_| {
+                        FsError::InvalidArgument(format!(
+                            "cannot name a link to '{}' inside '{}', give the full link path",
+                            src, dst
+                        ))
+                    })?;
+                    format!("{}/{}", dst.trim_end_matches('/'), name)
+                }
+                Err(_) => dst.clone(),
+            };
+            ctx.virtual_disk.symlink(src, &path)?;
+        }
+        Ok(())
+    }
+}
+
+struct Readlink;
+impl Command for Readlink {
+    fn name(&self) -> &'static str {
+        "readlink"
+    }
+    fn usage(&self) -> &'static str {
+        "<symlink path>..."
+    }
+    fn help(&self) -> &'static str {
+        "Print the target path of symlinks."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, usize::MAX)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        for path in &args.positional {
+            println!("{}", ctx.virtual_disk.read_link(path)?);
+        }
+        Ok(())
+    }
+}
+
+/// 有多个源时，目标必须是已经存在的目录
+fn check_many_into_dir(
+    virtual_disk: &DiskManager,
+    srcs: &[String],
+    dst: &str,
+) -> Result<(), FsError> {
+    if srcs.len() > 1 {
+        virtual_disk.resolve_directory(dst)?;
+    }
+    Ok(())
+}
+
+struct Put;
+impl Command for Put {
+    fn name(&self) -> &'static str {
+        "put"
+    }
+    fn usage(&self) -> &'static str {
+        "[-r] <host path> [path]"
+    }
+    fn help(&self) -> &'static str {
+        "Import a file (or a dir tree with -r) from host, keeping its times."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, 2).with_flags(&["-r"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let report = ctx.virtual_disk.import_from_host(
+            Path::new(&args.positional[0]),
+            args.get(1),
+            args.flag("-r"),
+        )?;
+        pinfo();
+        println!("Imported {}", report);
+        Ok(())
+    }
+}
+
+struct Get;
+impl Command for Get {
+    fn name(&self) -> &'static str {
+        "get"
+    }
+    fn usage(&self) -> &'static str {
+        "[-r] <path> [host path]"
+    }
+    fn help(&self) -> &'static str {
+        "Export a file (or a dir tree with -r) to host, keeping its times."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, 2).with_flags(&["-r"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let report = ctx.virtual_disk.export_to_host(
+            &args.positional[0],
+            args.get(1).map(Path::new),
+            args.flag("-r"),
+        )?;
+        pinfo();
+        println!("Exported {}", report);
+        Ok(())
+    }
+}
+
+struct Login;
+impl Command for Login {
+    fn name(&self) -> &'static str {
+        "login"
+    }
+    fn usage(&self) -> &'static str {
+        "<user>"
+    }
+    fn help(&self) -> &'static str {
+        "Work as another user. There are no passwords, permissions only guard against mistakes."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::exactly(1)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        ctx.virtual_disk.login(&args.positional[0])?;
+        pinfo();
+        println!("Logged in as {}.", args.positional[0]);
+        Ok(())
+    }
+}
+
+struct Logout;
+impl Command for Logout {
+    fn name(&self) -> &'static str {
+        "logout"
+    }
+    fn help(&self) -> &'static str {
+        "Go back to root."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        ctx.virtual_disk.logout();
+        pinfo();
+        println!("Logged out, back to root.");
+        Ok(())
+    }
+}
+
+struct Whoami;
+impl Command for Whoami {
+    fn name(&self) -> &'static str {
+        "whoami"
+    }
+    fn help(&self) -> &'static str {
+        "Show the current user and their groups."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        println!("{}", ctx.virtual_disk.whoami());
+        Ok(())
+    }
+}
+
+struct Useradd;
+impl Command for Useradd {
+    fn name(&self) -> &'static str {
+        "useradd"
+    }
+    fn usage(&self) -> &'static str {
+        "<user> [group]"
+    }
+    fn help(&self) -> &'static str {
+        "Add a user (root only) whose primary group is the given one or a new group named after them."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, 2)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let uid = ctx.virtual_disk.useradd(&args.positional[0], args.get(1))?;
+        pinfo();
+        println!("Added user {} with uid {}.", args.positional[0], uid);
+        Ok(())
+    }
+}
+
+struct Quota;
+impl Command for Quota {
+    fn name(&self) -> &'static str {
+        "quota"
+    }
+    fn help(&self) -> &'static str {
+        "Show cluster usage against the quotas, '*' marks a soft limit exceeded."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        println!("{}", ctx.virtual_disk.quota_report()?);
+        Ok(())
+    }
+}
+
+struct Setquota;
+impl Command for Setquota {
+    fn name(&self) -> &'static str {
+        "setquota"
+    }
+    fn usage(&self) -> &'static str {
+        "user|dir <user or dir path> <soft> <hard>"
+    }
+    fn help(&self) -> &'static str {
+        "Set soft and hard limits in clusters (root only), 0 means no limit."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::exactly(4)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let limit = |text: &str| {
+            text.parse::<usize>()
+                .map_err(|_| FsError::InvalidArgument(format!("bad limit '{}'", text)))
+        };
+        let limits = Limits {
+            soft: limit(&args.positional[2])?,
+            hard: limit(&args.positional[3])?,
+        };
+        let target = &args.positional[1];
+        match args.positional[0].as_str() {
+            "user" => ctx.virtual_disk.set_user_quota(target, limits),
+            "dir" => ctx.virtual_disk.set_dir_quota(target, limits),
+            kind => Err(FsError::InvalidArgument(format!(
+                "unknown quota kind '{}'",
+                kind
+            ))),
+        }
+    }
+}
+
+struct DiskInfo;
+impl Command for DiskInfo {
+    fn name(&self) -> &'static str {
+        "diskinfo"
+    }
+    fn help(&self) -> &'static str {
+        "Show some info about disk."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        let virtual_disk = &ctx.virtual_disk;
+        let (disk_size, num_used, num_not_used) = virtual_disk.get_disk_info();
+        let geometry = virtual_disk.disk.geometry;
+        println!(
+            "Disk sized {} Bytes, {} Bytes used, {} Bytes available.",
+            disk_size,
+            num_used * geometry.cluster_size,
+            num_not_used * geometry.cluster_size
+        );
+        println!(
+            "Cluster size {} Bytes, {} clusters, {} reserved.",
+            geometry.cluster_size, geometry.cluster_count, geometry.reserved_clusters
+        );
+        let free = virtual_disk.disk.free_space();
+        println!(
+            "Allocator {}, {} free clusters in {} runs.",
+            virtual_disk.disk.allocator(),
+            free.len(),
+            free.runs().len()
+        );
+        Ok(())
+    }
+}
+
+struct Fsck;
+impl Command for Fsck {
+    fn name(&self) -> &'static str {
+        "fsck"
+    }
+    fn usage(&self) -> &'static str {
+        "[--repair [--free]]"
+    }
+    fn help(&self) -> &'static str {
+        "Check disk consistency, optionally move lost chains to /lost+found or free them."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::NONE.with_flags(&["--repair", "--free"])
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        let repair = if !args.flag("--repair") {
+            Repair::None
+        } else if args.flag("--free") {
+            Repair::FreeLostChains
+        } else {
+            Repair::LostAndFound
+        };
+        let report = ctx.virtual_disk.fsck(repair)?;
+        print!("{}", report);
+        Ok(())
+    }
+}
+
+struct Map;
+impl Command for Map {
+    fn name(&self) -> &'static str {
+        "map"
+    }
+    fn usage(&self) -> &'static str {
+        "[path]"
+    }
+    fn help(&self) -> &'static str {
+        "Draw the clusters as a coloured grid, highlighting the chain of a file or dir."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(0, 1)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        print!("{}", ctx.virtual_disk.cluster_map(args.get(0))?);
+        Ok(())
+    }
+}
+
+struct Frag;
+impl Command for Frag {
+    fn name(&self) -> &'static str {
+        "frag"
+    }
+    fn help(&self) -> &'static str {
+        "Show fragments per file, the largest free run and the average chain length."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        print!("{}", ctx.virtual_disk.frag_report()?);
+        Ok(())
+    }
+}
+
+struct Defrag;
+impl Command for Defrag {
+    fn name(&self) -> &'static str {
+        "defrag"
+    }
+    fn help(&self) -> &'static str {
+        "Move every cluster chain into one contiguous run, resuming an interrupted defrag."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        let report = ctx.virtual_disk.defragment()?;
+        pinfo();
+        println!("{}", report);
+        Ok(())
+    }
+}
+
+struct Save;
+impl Command for Save {
+    fn name(&self) -> &'static str {
+        "save"
+    }
+    fn help(&self) -> &'static str {
+        "Save this virtual disk to the image file ('file-sys.vd' unless --image is given), keeping the last copies as '.1' and '.2'."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        pinfo();
+        println!("Saving...");
+        ctx.virtual_disk.save_to_file(ctx.image, DEFAULT_BACKUPS)?;
+        pinfo();
+        println!("The virtual disk system has been saved.\n");
+        Ok(())
+    }
+}
+
+struct Help;
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+    fn help(&self) -> &'static str {
+        "Show this help."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        println!("{}", ctx.registry.help());
+        Ok(())
+    }
+}
+
+struct Exit;
+impl Command for Exit {
+    fn name(&self) -> &'static str {
+        "exit"
+    }
+    fn help(&self) -> &'static str {
+        "Exit the system."
+    }
+    fn run(&self, ctx: &mut Context, _args: &Args) -> Result<(), FsError> {
+        pinfo();
+        println!("Exiting system...\n");
+        ctx.exit = true;
+        Ok(())
+    }
+}
+
+struct Test;
+impl Command for Test {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+    fn usage(&self) -> &'static str {
+        "create [file path]"
+    }
+    fn help(&self) -> &'static str {
+        "Create a random file to test."
+    }
+    fn args(&self) -> ArgSpec {
+        ArgSpec::between(1, 2)
+    }
+    fn run(&self, ctx: &mut Context, args: &Args) -> Result<(), FsError> {
+        if args.positional[0] != "create" {
+            return Err(FsError::InvalidArgument(format!(
+                "unknown test '{}'",
+                args.positional[0]
+            )));
+        }
+        let data = format!("File has been created at {:?} .", SystemTime::now());
+        let name = match args.get(1) {
+            Some(name) => name.to_string(),
+            // 没有输入名字
+            None => format!("test-{}", (rand::random::<f32>() * 100_f32) as usize),
+        };
+        ctx.virtual_disk
+            .create_file_with_data(name.as_str(), data.as_bytes())
+    }
+}
@@ -0,0 +1,219 @@
+//! 交互界面的行编辑：方向键翻历史、编辑当前行、Tab补全。
+//!
+//! 命令名从`Registry`补全；其他参数当作虚拟磁盘上的路径，从当前目录或路径指向的目录中补全文件名和目录名。
+//! 历史记录保存在镜像文件旁边的`<镜像文件名>.history`中。
+
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Editor, Helper};
+
+use super::parse;
+use super::Registry;
+use crate::disk_manager::{DiskManager, FileType};
+
+/// 保存历史记录的文件
+pub fn history_path(image: &Path) -> PathBuf {
+    let mut name = OsString::from(image.as_os_str());
+    name.push(".history");
+    PathBuf::from(name)
+}
+
+/// 建立行编辑器
+pub fn new_editor(
+    virtual_disk: Rc<RefCell<DiskManager>>,
+    registry: &Registry,
+) -> rustyline::Result<Editor<ShellHelper, DefaultHistory>> {
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .auto_add_history(true)
+        .build();
+    let mut editor = Editor::with_config(config)?;
+    editor.set_helper(Some(ShellHelper {
+        virtual_disk,
+        names: registry.commands().map(|command| command.name()).collect(),
+    }));
+
+    Ok(editor)
+}
+
+/// 补全命令名和虚拟磁盘上的路径
+pub struct ShellHelper {
+    /// 与交互界面共用的磁盘，补全时只读
+    virtual_disk: Rc<RefCell<DiskManager>>,
+    /// 所有命令名
+    names: Vec<&'static str>,
+}
+impl ShellHelper {
+    /// 以`prefix`开头的命令名
+    fn complete_command(&self, prefix: &str) -> Vec<Pair> {
+        self.names
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| Pair {
+                display: String::from(*name),
+                replacement: format!("{} ", name),
+            })
+            .collect()
+    }
+
+    /// 补全路径`path`的最后一项
+    fn complete_path(&self, path: &str) -> Vec<Pair> {
+        let (parent, prefix) = match path.rfind('/') {
+            Some(index) => path.split_at(index + 1),
+            None => ("", path),
+        };
+        let virtual_disk = match self.virtual_disk.try_borrow() {
+            Ok(virtual_disk) => virtual_disk,
+            Err(_) => return Vec::new(),
+        };
+        let entries = match virtual_disk.peek_entries(parent) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        entries
+            .into_iter()
+            .enumerate()
+            // “..”和“.”只在明确输入了“.”时才补全
+            .filter(|(index, (name, _file_type))| {
+                name.starts_with(prefix) && (*index >= 2 || prefix.starts_with('.'))
+            })
+            .map(|(_index, (name, file_type))| {
+                let replacement = parse::escape(&format!("{}{}", parent, name));
+                match file_type {
+                    FileType::Directory => Pair {
+                        display: format!("{}/", name),
+                        replacement: format!("{}/", replacement),
+                    },
+                    _ => Pair {
+                        display: name,
+                        replacement: format!("{} ", replacement),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let word = parse::last_word(&line[..pos]);
+        let candidates = if word.first {
+            self.complete_command(&word.text)
+        } else {
+            self.complete_path(&word.text)
+        };
+
+        Ok((word.start, candidates))
+    }
+}
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+impl Highlighter for ShellHelper {}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_manager::testing::new_disk;
+
+    fn helper() -> ShellHelper {
+        let mut dm = new_disk();
+        dm.new_directory_to_disk("/docs").unwrap();
+        dm.new_directory_to_disk("/docs/my dir").unwrap();
+        dm.create_file_with_data("/docs/a;b", b"").unwrap();
+        dm.create_file_with_data("/docs/notes", b"").unwrap();
+        dm.create_file_with_data("/top", b"").unwrap();
+        ShellHelper {
+            virtual_disk: Rc::new(RefCell::new(dm)),
+            names: Registry::new()
+                .commands()
+                .map(|command| command.name())
+                .collect(),
+        }
+    }
+
+    fn replacements(pairs: Vec<Pair>) -> Vec<String> {
+        let mut replacements: Vec<String> =
+            pairs.into_iter().map(|pair| pair.replacement).collect();
+        replacements.sort();
+        replacements
+    }
+
+    #[test]
+    fn commands_complete_by_prefix() {
+        let helper = helper();
+        let pairs = helper.complete_command("mkd");
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(
+            (pairs[0].display.as_str(), pairs[0].replacement.as_str()),
+            ("mkdir", "mkdir ")
+        );
+        assert!(helper.complete_command("nosuch").is_empty());
+    }
+
+    #[test]
+    fn paths_complete_in_subdirectories() {
+        let helper = helper();
+        // 目录补上`/`，文件补上空格，名字经过转义
+        assert_eq!(
+            replacements(helper.complete_path("docs/")),
+            ["docs/a\\;b ", "docs/my\\ dir/", "docs/notes "]
+        );
+        assert_eq!(
+            replacements(helper.complete_path("/docs/n")),
+            ["/docs/notes "]
+        );
+        let pairs = helper.complete_path("docs/m");
+        assert_eq!(pairs[0].display, "my dir/");
+        assert_eq!(replacements(helper.complete_path("d")), ["docs/"]);
+        // “.”和“..”只在输入了“.”时出现
+        assert!(replacements(helper.complete_path(""))
+            .iter()
+            .all(|name| !name.starts_with('.')));
+        assert_eq!(
+            replacements(helper.complete_path("docs/.")),
+            ["docs/../", "docs/./"]
+        );
+        assert!(helper.complete_path("nosuch/").is_empty());
+        assert!(helper.complete_path("top/").is_empty());
+    }
+
+    #[test]
+    fn completions_survive_tokenize() {
+        let helper = helper();
+        let pairs = helper.complete_path("docs/a");
+        let line = format!("cat {}", pairs[0].replacement);
+        assert_eq!(parse::tokenize(&line).unwrap(), ["cat", "docs/a;b"]);
+        assert_eq!(parse::split_commands(&line).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn history_is_kept_beside_the_image() {
+        assert_eq!(
+            history_path(Path::new("/tmp/images/disk.vd")),
+            PathBuf::from("/tmp/images/disk.vd.history")
+        );
+        assert_eq!(
+            history_path(Path::new("disk")),
+            PathBuf::from("disk.history")
+        );
+    }
+}
@@ -0,0 +1,273 @@
+//! 把一行命令拆成参数。
+//!
+//! 规则与常见的shell相同：空白分隔参数；单引号中的内容原样保留；双引号中只有`\"`和`\\`是转义；
+//! 引号以外的`\`转义下一个字符，包括空白和引号。引号和转义可以出现在参数的任何位置，
+//! `a" b"c`是一个参数`a bc`，`''`是一个空参数。
+
+use crate::disk_manager::error::FsError;
+
+/// 拆分时的状态
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+}
+
+/// 把一行命令拆成参数
+pub fn tokenize(line: &str) -> Result<Vec<String>, FsError> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    // 引号可以产生空参数，所以不能只看`token`是否为空
+    let mut in_token = false;
+    let mut quote = Quote::None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Quote::None, c) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            (Quote::None, '\'') => {
+                quote = Quote::Single;
+                in_token = true;
+            }
+            (Quote::None, '"') => {
+                quote = Quote::Double;
+                in_token = true;
+            }
+            (Quote::None, '\\') => {
+                let escaped = chars.next().ok_or_else(|| {
+                    FsError::InvalidArgument(String::from("line ends with a lone '\\'"))
+                })?;
+                token.push(escaped);
+                in_token = true;
+            }
+            (Quote::Single, '\'') | (Quote::Double, '"') => quote = Quote::None,
+            (Quote::Double, '\\') => match chars.clone().next() {
+                Some(next @ '"') | Some(next @ '\\') => {
+                    chars.next();
+                    token.push(next);
+                }
+                _ => token.push('\\'),
+            },
+            (_, c) => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if quote != Quote::None {
+        return Err(FsError::InvalidArgument(String::from("unterminated quote")));
+    }
+    if in_token {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// 在引号和转义以外的`;`处把一行拆成多条命令，各条命令保持原样，之后再各自拆分参数
+pub fn split_commands(line: &str) -> Result<Vec<String>, FsError> {
+    let mut commands = Vec::new();
+    let mut start = 0;
+    let mut quote = Quote::None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Quote::None, ';') => {
+                commands.push(String::from(&line[start..i]));
+                start = i + 1;
+            }
+            (Quote::None, '\'') => quote = Quote::Single,
+            (Quote::None, '"') => quote = Quote::Double,
+            (Quote::None, '\\') | (Quote::Double, '\\') => escaped = true,
+            (Quote::Single, '\'') | (Quote::Double, '"') => quote = Quote::None,
+            _ => (),
+        }
+    }
+    if quote != Quote::None {
+        return Err(FsError::InvalidArgument(String::from("unterminated quote")));
+    }
+    commands.push(String::from(&line[start..]));
+
+    Ok(commands)
+}
+
+/// 正在输入的最后一个参数，用于补全
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Word {
+    /// 参数在行中开始的字节位置，包括开头的引号
+    pub start: usize,
+    /// 去掉引号和转义以后的内容
+    pub text: String,
+    /// 是否为这条命令的第一个参数，也就是命令名
+    pub first: bool,
+}
+
+/// 找出一行末尾正在输入的参数。引号没有闭合时，参数从引号开始。
+pub fn last_word(line: &str) -> Word {
+    let mut start = None;
+    let mut text = String::new();
+    // 当前这条命令中已经输入完的参数个数
+    let mut before = 0;
+    let mut quote = Quote::None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            text.push(c);
+            continue;
+        }
+        match (quote, c) {
+            (Quote::None, c) if c.is_whitespace() || c == ';' => {
+                if start.take().is_some() {
+                    before += 1;
+                    text.clear();
+                }
+                if c == ';' {
+                    before = 0;
+                }
+                continue;
+            }
+            (Quote::None, '\'') => quote = Quote::Single,
+            (Quote::None, '"') => quote = Quote::Double,
+            (Quote::None, '\\') => escaped = true,
+            (Quote::Single, '\'') | (Quote::Double, '"') => quote = Quote::None,
+            (Quote::Double, '\\') => match line[i + 1..].chars().next() {
+                Some('"') | Some('\\') => escaped = true,
+                _ => text.push(c),
+            },
+            (_, c) => text.push(c),
+        }
+        start.get_or_insert(i);
+    }
+
+    Word {
+        start: start.unwrap_or(line.len()),
+        text,
+        first: before == 0,
+    }
+}
+
+/// 转义空白、引号、`\`和`;`，使`text`作为一个参数原样传给命令
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_whitespace() || matches!(c, '\'' | '"' | '\\' | ';') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    #[test]
+    fn tokenize_quotes_and_escapes() {
+        assert_eq!(tokens("  mkdir   a\tb  "), ["mkdir", "a", "b"]);
+        assert_eq!(
+            tokens(r#"mkdir "my dir" 'a "b"'"#),
+            ["mkdir", "my dir", r#"a "b""#]
+        );
+        // 引号可以出现在参数中间
+        assert_eq!(tokens(r#"a" b"c"#), ["a bc"]);
+        assert_eq!(tokens(r"mkdir my\ dir \'x\;"), ["mkdir", "my dir", "'x;"]);
+        // 单引号中原样保留，双引号中只转义`"`和`\`
+        assert_eq!(tokens(r"'a\b'"), [r"a\b"]);
+        assert_eq!(tokens(r#""a\"b\\c\d""#), [r#"a"b\c\d"#]);
+        assert!(tokens("").is_empty());
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_keeps_empty_args() {
+        assert_eq!(tokens("echo '' \"\" x"), ["echo", "", "", "x"]);
+        assert_eq!(tokens("''"), [""]);
+    }
+
+    #[test]
+    fn tokenize_rejects_unterminated_input() {
+        for line in ["echo 'a", "echo \"a", r"echo a\", r#"echo "a\""#] {
+            assert!(
+                matches!(tokenize(line), Err(FsError::InvalidArgument(_))),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn split_commands_outside_quotes() {
+        assert_eq!(
+            split_commands(r#"mkdir a; echo 'x;y' "z;" a\;b;cd a"#).unwrap(),
+            ["mkdir a", r#" echo 'x;y' "z;" a\;b"#, "cd a"]
+        );
+        // 末尾的`;`和连续的`;`留下空命令，由调用者跳过
+        assert_eq!(split_commands("ls;").unwrap(), ["ls", ""]);
+        assert_eq!(split_commands("ls;;pwd").unwrap(), ["ls", "", "pwd"]);
+        assert_eq!(split_commands("").unwrap(), [""]);
+        assert!(split_commands("echo 'a; ls").is_err());
+    }
+
+    #[test]
+    fn last_word_starts_at_quotes_and_escapes() {
+        let word = |line: &str| {
+            let word = last_word(line);
+            (word.start, word.text)
+        };
+        assert_eq!(word("cat ab"), (4, String::from("ab")));
+        assert_eq!(word("cat 'my f"), (4, String::from("my f")));
+        assert_eq!(word("cat \"my\\\" f"), (4, String::from("my\" f")));
+        assert_eq!(word("cat my\\ f"), (4, String::from("my f")));
+        assert_eq!(word("cat a\"b c"), (4, String::from("ab c")));
+        // 行尾是空白时正在输入一个新的空参数
+        assert_eq!(word("cat a "), (6, String::new()));
+        assert_eq!(word(""), (0, String::new()));
+    }
+
+    #[test]
+    fn last_word_knows_command_names() {
+        assert!(last_word("").first);
+        assert!(last_word("  ca").first);
+        assert!(!last_word("cat ").first);
+        assert!(!last_word("  cat a").first);
+        assert!(last_word("cd a;").first);
+        assert!(last_word("cd a; l").first);
+        assert!(!last_word("cd a; ls ").first);
+        // 引号中的`;`不分隔命令
+        assert!(!last_word("echo ';").first);
+    }
+
+    #[test]
+    fn escape_round_trips_through_tokenize() {
+        for name in [
+            "my file",
+            "it's",
+            "say \"hi\"",
+            "a;b",
+            "back\\slash",
+            "tab\there",
+            "plain",
+        ] {
+            let line = format!("cat {}", escape(name));
+            assert_eq!(tokens(&line), ["cat", name], "{}", line);
+            assert_eq!(split_commands(&line).unwrap().len(), 1);
+            assert_eq!(last_word(&line).text, name);
+        }
+    }
+}